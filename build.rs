@@ -0,0 +1,15 @@
+use std::process::Command;
+
+fn main() {
+    // Embed the hash of the current commit in the kernel, so it can be reported by `uname`
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_else(|| String::from("unknown"));
+
+    println!("cargo:rustc-env=SILICIUM_GIT_HASH={}", hash.trim());
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
@@ -0,0 +1,34 @@
+/// The maximum exponent of the backoff: a waiting CPU pauses at most `2^MAX_STEP` times between
+/// two checks of the lock.
+const MAX_STEP: u32 = 6;
+
+/// An exponential backoff for the spin loops. Each call to [`Backoff::spin`] pauses twice as long
+/// as the previous one, up to a limit, so that the CPUs waiting for a contended lock do not keep
+/// reading its cache line while the owner tries to release it.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    /// Create a new backoff, starting with a single pause.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    /// Pause the CPU with the `pause` instruction, and double the duration of the next call.
+    pub fn spin(&mut self) {
+        for _ in 0..1u32 << self.step {
+            core::hint::spin_loop();
+        }
+        if self.step < MAX_STEP {
+            self.step += 1;
+        }
+    }
+
+    /// Start again with a single pause.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+}
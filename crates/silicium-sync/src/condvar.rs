@@ -0,0 +1,207 @@
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    parker::{self, ParkResult},
+    MutexGuard,
+};
+
+/// A condition variable, used with a [`Mutex`](crate::Mutex) to wait until a condition on the
+/// data protected by the mutex becomes true. As with any condition variable, a thread may be woken
+/// up spuriously: the condition must be checked again in a loop after each wait.
+///
+/// A waiting thread is queued on the condition variable before the mutex is unlocked, so a
+/// notification sent by a thread that modified the data after locking the mutex cannot be lost.
+pub struct CondVar {
+    /// Incremented on each notification. A thread only parks if no notification was sent since it
+    /// started to wait, in case the notifier did not lock the mutex.
+    sequence: AtomicU32,
+}
+
+/// Tells whether [`CondVar::wait_timeout`] returned because its timeout expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns true if the timeout expired before the thread was notified.
+    #[must_use]
+    pub const fn timed_out(self) -> bool {
+        self.0
+    }
+}
+
+impl CondVar {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlock the mutex of the guard and wait until the condition variable is notified, then lock
+    /// the mutex again and return its guard.
+    #[must_use = "the mutex is locked again when the wait returns"]
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_until(guard, None).0
+    }
+
+    /// Same as [`CondVar::wait`], but stop waiting once the given timeout has expired. The mutex is
+    /// locked again in both cases.
+    #[must_use = "the mutex is locked again when the wait returns"]
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        self.wait_until(guard, Some(timeout))
+    }
+
+    /// Wake up one of the threads waiting on the condition variable, if any.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        parker::unpark_one(self.key());
+    }
+
+    /// Wake up all the threads waiting on the condition variable.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        parker::unpark_all(self.key());
+    }
+
+    fn wait_until<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let mutex = guard.mutex;
+        let sequence = self.sequence.load(Ordering::Relaxed);
+
+        // The mutex is unlocked by the parker once the thread is queued, or right away if the
+        // thread does not park: the guard must not unlock it a second time
+        core::mem::forget(guard);
+        let validate = || self.sequence.load(Ordering::Relaxed) == sequence;
+        let result = parker::park(self.key(), &validate, &|| mutex.unlock(), timeout);
+        if result == ParkResult::Invalid {
+            mutex.unlock();
+        }
+
+        (
+            mutex.lock(),
+            WaitTimeoutResult(result == ParkResult::TimedOut),
+        )
+    }
+
+    /// The key on which the waiting threads are parked.
+    fn key(&self) -> usize {
+        core::ptr::addr_of!(self.sequence) as usize
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use std::thread;
+
+    use super::CondVar;
+    use crate::{parker::testing, Mutex};
+
+    /// The timeout after which a waiting thread is considered to have missed its notification.
+    const LOST: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn wait_notify() {
+        testing::register();
+        let ready = Mutex::new(false);
+        let condvar = CondVar::new();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                *ready.lock() = true;
+                condvar.notify_one();
+            });
+
+            let mut guard = ready.lock();
+            while !*guard {
+                guard = condvar.wait(guard);
+            }
+        });
+    }
+
+    /// Bounce a counter between two threads: a lost wakeup leaves both threads waiting.
+    #[test]
+    fn no_lost_wakeup() {
+        testing::register();
+        let turn = Mutex::new(0);
+        let condvar = CondVar::new();
+
+        thread::scope(|scope| {
+            for player in 0..2 {
+                let (turn, condvar) = (&turn, &condvar);
+                scope.spawn(move || {
+                    for round in 0..1000 {
+                        let mut guard = turn.lock();
+                        while *guard % 2 != player {
+                            let (next, result) = condvar.wait_timeout(guard, LOST);
+                            assert!(!result.timed_out(), "Wakeup lost at round {round}");
+                            guard = next;
+                        }
+                        *guard += 1;
+                        condvar.notify_one();
+                    }
+                });
+            }
+        });
+        assert_eq!(turn.into_inner(), 2000);
+    }
+
+    /// A notification sent without locking the mutex, between the check of the condition and the
+    /// wait, must not be lost either.
+    #[test]
+    fn notify_without_mutex() {
+        testing::register();
+        let mutex = Mutex::new(());
+        let condvar = CondVar::new();
+
+        for _ in 0..100 {
+            let ready = AtomicBool::new(false);
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    ready.store(true, Ordering::Relaxed);
+                    condvar.notify_one();
+                });
+
+                let mut guard = mutex.lock();
+                while !ready.load(Ordering::Relaxed) {
+                    let (next, result) = condvar.wait_timeout(guard, LOST);
+                    assert!(!result.timed_out(), "Wakeup lost");
+                    guard = next;
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        testing::register();
+        let mutex = Mutex::new(());
+        let condvar = CondVar::new();
+
+        let (guard, result) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(10));
+        assert!(result.timed_out());
+        drop(guard);
+        assert!(!mutex.is_locked());
+    }
+}
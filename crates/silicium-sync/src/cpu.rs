@@ -0,0 +1,24 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The function returning the id of the current CPU registered by the kernel, or 0 if none is
+/// registered yet.
+static CURRENT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Register the function returning the id of the current CPU, used to detect a primitive used
+/// re-entrantly on the same CPU, for example from an interrupt handler. The function must return
+/// `None` while the id of the current CPU is not known.
+pub fn register(current_id: fn() -> Option<u32>) {
+    CURRENT_ID.store(current_id as usize, Ordering::Release);
+}
+
+/// Returns the id of the current CPU, or `None` if it is not known.
+pub(crate) fn current_id() -> Option<u32> {
+    match CURRENT_ID.load(Ordering::Acquire) {
+        0 => None,
+        function => {
+            // SAFETY: A non-zero value is always a function pointer stored by `register`
+            let current_id: fn() -> Option<u32> = unsafe { core::mem::transmute(function) };
+            current_id()
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Save and restore the interrupt state of the current CPU around the critical sections that must
+//! not be interrupted.
+
+#[cfg(test)]
+pub(crate) use emulated::enabled;
+#[cfg(test)]
+use emulated::{enable, save_and_disable};
+
+/// The interrupt flag of RFLAGS.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// The interrupt state of the CPU saved by [`IrqState::save`]: this is the whole RFLAGS value, not
+/// just whether interrupts were enabled, so that the state can be inspected when debugging. The
+/// state is not `Clone`, so it can only be restored once.
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "the saved state must be restored to enable the interrupts again"]
+pub struct IrqState(u64);
+
+impl IrqState {
+    /// Save the interrupt state of the current CPU and disable the interrupts.
+    pub fn save() -> Self {
+        Self(save_and_disable())
+    }
+
+    /// Restore the saved interrupt state: the interrupts are enabled again only if they were
+    /// enabled when the state was saved. The states must be restored in the reverse order they
+    /// were saved, otherwise the interrupts could be enabled inside a critical section.
+    pub fn restore(self) {
+        if self.interrupts_enabled() {
+            enable();
+        }
+    }
+
+    /// Returns true if the interrupts were enabled when the state was saved.
+    #[must_use]
+    pub const fn interrupts_enabled(&self) -> bool {
+        self.0 & RFLAGS_IF != 0
+    }
+
+    /// Returns the value of RFLAGS when the state was saved.
+    #[must_use]
+    pub const fn rflags(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Returns RFLAGS and disable the interrupts.
+#[cfg(not(test))]
+fn save_and_disable() -> u64 {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq", "pop {}", "cli", out(reg) rflags, options(nomem));
+    }
+    rflags
+}
+
+#[cfg(not(test))]
+fn enable() {
+    unsafe {
+        core::arch::asm!("sti", options(nomem, nostack));
+    }
+}
+
+/// Execute the given closure with interrupts disabled, and restore the interrupt state afterwards.
+/// Unlike a bool saved in a static, the state is kept on the stack, so the calls can be nested.
+pub fn without<T>(f: impl FnOnce() -> T) -> T {
+    let state = IrqState::save();
+    let ret = f();
+    state.restore();
+    ret
+}
+
+/// The host tests run in user mode, where `cli` and `sti` fault: the interrupt flag of each thread
+/// is emulated instead.
+#[cfg(test)]
+mod emulated {
+    extern crate std;
+
+    use core::cell::Cell;
+
+    use super::RFLAGS_IF;
+
+    std::thread_local! {
+        static INTERRUPTS_ENABLED: Cell<bool> = const { Cell::new(true) };
+    }
+
+    pub fn save_and_disable() -> u64 {
+        if INTERRUPTS_ENABLED.replace(false) {
+            RFLAGS_IF
+        } else {
+            0
+        }
+    }
+
+    pub fn enable() {
+        INTERRUPTS_ENABLED.set(true);
+    }
+
+    /// Returns true if the emulated interrupts of the current thread are enabled.
+    pub fn enabled() -> bool {
+        INTERRUPTS_ENABLED.get()
+    }
+}
@@ -0,0 +1,45 @@
+use core::{cell::Cell, ops::Deref};
+
+use crate::Once;
+
+/// A value initialized on its first access with the function given at its creation. The
+/// initialization has the same guarantees as [`Once::call_once`].
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+// SAFETY: The function is only taken by the CPU running the initializer of the value.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F> Lazy<T, F> {
+    /// Create a value that will be initialized with the given function on its first access.
+    #[must_use]
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Initialize the value if it is not initialized yet, and return a reference to it.
+    ///
+    /// # Panics
+    /// Panics if the value is poisoned, or if the current CPU is already initializing the value.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| match this.init.take() {
+            Some(init) => init(),
+            None => unreachable!("Lazy initializer already taken"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
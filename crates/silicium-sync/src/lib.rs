@@ -0,0 +1,34 @@
+//! Synchronization primitives for the Silicium kernel. This crate does not depend on the kernel:
+//! the primitives that put threads to sleep rely on a [`Parker`] registered by the kernel once its
+//! scheduler is running, and spin until then. Likewise, the kernel registers how to get the id of
+//! the current CPU (see [`cpu::register`]).
+#![no_std]
+#![warn(clippy::all)]
+#![warn(clippy::pedantic)]
+
+pub mod backoff;
+pub mod condvar;
+pub mod cpu;
+pub mod irq;
+pub mod lazy;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+#[cfg(feature = "lockstat")]
+pub mod lockstat;
+pub mod mutex;
+pub mod once;
+pub mod parker;
+pub mod queue;
+pub mod spinlock;
+pub mod spinlock_irq;
+
+pub use backoff::Backoff;
+pub use condvar::{CondVar, WaitTimeoutResult};
+pub use irq::IrqState;
+pub use lazy::Lazy;
+pub use mutex::{Mutex, MutexGuard};
+pub use once::Once;
+pub use parker::{ParkResult, Parker};
+pub use queue::{MpscQueue, SpscQueue};
+pub use spinlock::{Spinlock, SpinlockGuard};
+pub use spinlock_irq::{SpinlockIrq, SpinlockIrqGuard};
@@ -0,0 +1,718 @@
+//! A lock dependency checker, enabled with the `lockdep` feature. It records the order in which
+//! the lock classes are acquired, and reports the orders that could deadlock before they actually
+//! do:
+//! - A lock acquired while holding another one, when the opposite order was already seen (even
+//!   through other locks).
+//! - A spinlock acquired both in interrupt context and with interrupts enabled: an interrupt
+//!   handler could spin forever on the lock held by the code it interrupted.
+//! - A sleeping lock acquired in interrupt context or while holding a spinlock.
+//!
+//! A lock class is the place in the code where the locks are created: all the locks created by
+//! the same call to `new` share the same class. Locks of the same class nested in each other are
+//! not checked. Only the first problem is reported: the checker disables itself afterwards.
+//!
+//! The spinlocks held are tracked for each CPU. A thread can sleep and migrate to another CPU
+//! while holding a sleeping lock, so the sleeping locks held are tracked for each thread instead.
+//! A spinlock cannot be held while acquiring a sleeping lock, so the orders between the sleeping
+//! locks and the spinlocks cannot deadlock and only the orders between sleeping locks are checked.
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+};
+
+use crate::cpu;
+
+/// The maximum number of lock classes.
+const MAX_CLASSES: usize = 512;
+
+/// The maximum number of dependencies between the lock classes.
+const MAX_DEPENDENCIES: usize = 4096;
+
+/// The maximum number of spinlocks held at the same time by a CPU, and of sleeping locks held at
+/// the same time by a thread.
+const MAX_HELD: usize = 32;
+
+/// The maximum number of threads holding sleeping locks at the same time.
+const MAX_THREADS: usize = 64;
+
+/// The maximum number of CPUs tracked. The locks taken on the other CPUs are not checked.
+const MAX_CPUS: usize = 64;
+
+/// The number of words of a row of the dependency matrix.
+const WORDS: usize = MAX_CLASSES / 64;
+
+/// The class of a lock, identified by the place where the lock was created.
+pub struct Class {
+    site: &'static Location<'static>,
+
+    /// The index of the class in the dependency graph plus one, or 0 if the class was never
+    /// acquired yet.
+    index: AtomicU16,
+}
+
+impl Class {
+    #[must_use]
+    pub const fn new(site: &'static Location<'static>) -> Self {
+        Self {
+            site,
+            index: AtomicU16::new(0),
+        }
+    }
+}
+
+/// The kind of a lock, which tells what can be done while holding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// A spinlock, which can be taken anywhere.
+    Spin,
+
+    /// A sleeping lock, which cannot be taken in interrupt context or while holding a spinlock.
+    Sleep,
+}
+
+/// A dependency between two lock classes: `to` was acquired while holding `from`.
+#[derive(Clone, Copy)]
+struct Dependency {
+    from: u16,
+    to: u16,
+    from_site: &'static Location<'static>,
+    to_site: &'static Location<'static>,
+}
+
+/// The dependency graph between the lock classes.
+struct Graph {
+    /// The place where each class was created.
+    classes: [Option<&'static Location<'static>>; MAX_CLASSES],
+    class_count: usize,
+
+    /// One bit for each dependency from the class of the row to the class of the column.
+    matrix: [[u64; WORDS]; MAX_CLASSES],
+
+    /// The dependencies, in the order they were recorded, with the places where the locks were
+    /// acquired the first time.
+    dependencies: [Option<Dependency>; MAX_DEPENDENCIES],
+    dependency_count: usize,
+
+    /// The first place where each class was acquired in interrupt context.
+    in_interrupt: [Option<&'static Location<'static>>; MAX_CLASSES],
+
+    /// The first place where each class was acquired with interrupts enabled.
+    interrupts_enabled: [Option<&'static Location<'static>>; MAX_CLASSES],
+}
+
+/// The locks held by a CPU or a thread, in the order they were acquired, with the place where they
+/// were acquired.
+struct Held {
+    locks: [Option<(u16, &'static Location<'static>)>; MAX_HELD],
+    depth: usize,
+}
+
+/// The sleeping locks held by the threads. A slot is assigned to a thread when it acquires its
+/// first sleeping lock, and freed when it releases its last one.
+struct Threads {
+    owners: [Option<u64>; MAX_THREADS],
+    held: [Held; MAX_THREADS],
+}
+
+/// The state of the checker. The graph and the sleeping locks held by the threads are protected by
+/// a raw spinlock, which cannot be checked itself, and each CPU only accesses its own held
+/// spinlocks.
+struct State {
+    graph_lock: AtomicBool,
+    graph: UnsafeCell<Graph>,
+    threads: UnsafeCell<Threads>,
+    held: [UnsafeCell<Held>; MAX_CPUS],
+
+    /// Set while a CPU runs the checker, so that the locks acquired by an interrupt handler (or by
+    /// the report itself) in the meantime are ignored rather than deadlocking on the graph.
+    busy: [AtomicBool; MAX_CPUS],
+}
+
+// SAFETY: The graph and the threads are only accessed with `graph_lock` held, and the held locks
+// of a CPU are only accessed by this CPU while it is marked as busy.
+unsafe impl Sync for State {}
+
+static STATE: State = State {
+    graph_lock: AtomicBool::new(false),
+    graph: UnsafeCell::new(Graph::new()),
+    threads: UnsafeCell::new(Threads::new()),
+    held: [const { UnsafeCell::new(Held::new()) }; MAX_CPUS],
+    busy: [const { AtomicBool::new(false) }; MAX_CPUS],
+};
+
+/// Set once a problem was reported, or when the checker ran out of space.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// The functions telling whether the current CPU runs in interrupt context, whether its interrupts
+/// are enabled and which thread it runs, registered by the kernel. The checker does nothing until
+/// they are.
+static IN_INTERRUPT: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_THREAD: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPTS_ENABLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Register the functions telling whether the current CPU runs in interrupt context, whether its
+/// interrupts are enabled and which thread it runs, and start checking the locks. The identifier
+/// returned by `current_thread` must be unique among the running threads, and can be `None` while
+/// the thread is not known: the sleeping locks are not tracked then. The id of the current CPU
+/// must also be registered (see [`cpu::register`]).
+pub fn register(
+    in_interrupt: fn() -> bool,
+    interrupts_enabled: fn() -> bool,
+    current_thread: fn() -> Option<u64>,
+) {
+    IN_INTERRUPT.store(in_interrupt as usize, Ordering::Relaxed);
+    CURRENT_THREAD.store(current_thread as usize, Ordering::Relaxed);
+    INTERRUPTS_ENABLED.store(interrupts_enabled as usize, Ordering::Release);
+}
+
+/// Check the acquisition of a lock of the given class by the current CPU, and record it.
+#[track_caller]
+pub fn acquire(class: &Class, kind: Kind) {
+    let site = Location::caller();
+    with_state(|graph, held, threads, hooks| {
+        let Some(index) = graph.index(class) else {
+            disable("too many lock classes");
+            return;
+        };
+        let in_interrupt = hooks.in_interrupt();
+
+        if kind == Kind::Spin {
+            graph.check_interrupts(index, site, in_interrupt, hooks.interrupts_enabled());
+            check_order(graph, held, (index, site));
+            held.push(index, site);
+            return;
+        }
+
+        if in_interrupt {
+            report(format_args!(
+                "sleeping lock {} acquired at {site} in interrupt context",
+                class.site
+            ));
+        } else if let Some((spinlock, spinlock_site)) = held.top() {
+            report(format_args!(
+                "sleeping lock {} acquired at {site} while holding spinlock {} acquired at \
+                 {spinlock_site}",
+                class.site,
+                graph.site(spinlock)
+            ));
+        } else if let Some(thread) = hooks.current_thread() {
+            let Some(sleeping) = threads.held(thread) else {
+                disable("too many threads holding sleeping locks");
+                return;
+            };
+            check_order(graph, sleeping, (index, site));
+            sleeping.push(index, site);
+        }
+    });
+}
+
+/// Record the release of a lock of the given class by the current CPU, or by the current thread
+/// for a sleeping lock.
+pub fn release(class: &Class, kind: Kind) {
+    let Some(index) = class.index.load(Ordering::Relaxed).checked_sub(1) else {
+        return;
+    };
+    match kind {
+        Kind::Spin => with_held(|held| held.remove(index)),
+        Kind::Sleep => with_state(|_, _, threads, hooks| {
+            if let Some(thread) = hooks.current_thread() {
+                threads.release(thread, index);
+            }
+        }),
+    }
+}
+
+/// The functions registered with [`register`].
+struct Hooks {
+    in_interrupt: fn() -> bool,
+    interrupts_enabled: fn() -> bool,
+    current_thread: fn() -> Option<u64>,
+}
+
+impl Hooks {
+    fn get() -> Option<Self> {
+        let interrupts_enabled = INTERRUPTS_ENABLED.load(Ordering::Acquire);
+        let in_interrupt = IN_INTERRUPT.load(Ordering::Relaxed);
+        let current_thread = CURRENT_THREAD.load(Ordering::Relaxed);
+        if interrupts_enabled == 0 || in_interrupt == 0 || current_thread == 0 {
+            return None;
+        }
+        // SAFETY: Non-zero values are function pointers stored by `register`
+        unsafe {
+            Some(Self {
+                in_interrupt: core::mem::transmute::<usize, fn() -> bool>(in_interrupt),
+                interrupts_enabled: core::mem::transmute::<usize, fn() -> bool>(interrupts_enabled),
+                current_thread: core::mem::transmute::<usize, fn() -> Option<u64>>(current_thread),
+            })
+        }
+    }
+
+    fn in_interrupt(&self) -> bool {
+        (self.in_interrupt)()
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        (self.interrupts_enabled)()
+    }
+
+    fn current_thread(&self) -> Option<u64> {
+        (self.current_thread)()
+    }
+}
+
+impl Graph {
+    /// Create an empty graph. The arrays are large, but the graph of the checker is built at
+    /// compile time (see [`STATE`]).
+    #[allow(clippy::large_stack_arrays)]
+    const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            class_count: 0,
+            matrix: [[0; WORDS]; MAX_CLASSES],
+            dependencies: [None; MAX_DEPENDENCIES],
+            dependency_count: 0,
+            in_interrupt: [None; MAX_CLASSES],
+            interrupts_enabled: [None; MAX_CLASSES],
+        }
+    }
+
+    /// Returns the index of the given class, and assigns one if no lock created at the same place
+    /// was acquired yet. Returns `None` if there are too many classes.
+    fn index(&mut self, class: &Class) -> Option<u16> {
+        if let Some(index) = class.index.load(Ordering::Relaxed).checked_sub(1) {
+            return Some(index);
+        }
+
+        let known = self.classes[..self.class_count]
+            .iter()
+            .position(|&site| site == Some(class.site));
+        let index = match known {
+            Some(index) => index,
+            None if self.class_count == MAX_CLASSES => return None,
+            None => {
+                self.classes[self.class_count] = Some(class.site);
+                self.class_count += 1;
+                self.class_count - 1
+            }
+        };
+        let index = u16::try_from(index).ok()?;
+        class.index.store(index + 1, Ordering::Relaxed);
+        Some(index)
+    }
+
+    /// Returns the place where the given class was created.
+    fn site(&self, class: u16) -> &'static Location<'static> {
+        self.classes[usize::from(class)].expect("Unknown lock class")
+    }
+
+    /// Returns true if the first class was already acquired while holding the second one.
+    fn depends_directly(&self, from: u16, to: u16) -> bool {
+        let to = usize::from(to);
+        self.matrix[usize::from(from)][to / 64] & (1 << (to % 64)) != 0
+    }
+
+    /// Returns true if the second class was acquired while holding the first one, directly or
+    /// through other classes.
+    fn depends(&self, from: u16, to: u16) -> bool {
+        self.path(from, to, |_, _| ())
+    }
+
+    /// Search a path of dependencies between the two classes with a breadth-first search, and call
+    /// the given function with each dependency of the path, from the last to the first. Returns
+    /// false if there is no path.
+    fn path(&self, from: u16, to: u16, mut f: impl FnMut(u16, u16)) -> bool {
+        let mut parents = [u16::MAX; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        parents[usize::from(from)] = from;
+
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+            if class == to {
+                let mut class = to;
+                while class != from {
+                    let parent = parents[usize::from(class)];
+                    f(parent, class);
+                    class = parent;
+                }
+                return true;
+            }
+            for next in 0..self.class_count {
+                #[allow(clippy::cast_possible_truncation)]
+                let next = next as u16;
+                if parents[usize::from(next)] == u16::MAX && self.depends_directly(class, next) {
+                    parents[usize::from(next)] = class;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        false
+    }
+
+    fn add_dependency(
+        &mut self,
+        from: u16,
+        from_site: &'static Location<'static>,
+        to: u16,
+        to_site: &'static Location<'static>,
+    ) {
+        if self.depends_directly(from, to) {
+            return;
+        }
+        if self.dependency_count == MAX_DEPENDENCIES {
+            disable("too many lock dependencies");
+            return;
+        }
+        let column = usize::from(to);
+        self.matrix[usize::from(from)][column / 64] |= 1 << (column % 64);
+        self.dependencies[self.dependency_count] = Some(Dependency {
+            from,
+            to,
+            from_site,
+            to_site,
+        });
+        self.dependency_count += 1;
+    }
+
+    /// Returns the first recorded dependency between the two classes.
+    fn dependency(&self, from: u16, to: u16) -> Option<Dependency> {
+        self.dependencies[..self.dependency_count]
+            .iter()
+            .flatten()
+            .find(|dependency| dependency.from == from && dependency.to == to)
+            .copied()
+    }
+
+    /// Record the interrupt state in which the given spinlock class is acquired, and report the
+    /// class if it is acquired both in interrupt context and with interrupts enabled.
+    fn check_interrupts(
+        &mut self,
+        class: u16,
+        site: &'static Location<'static>,
+        in_interrupt: bool,
+        interrupts_enabled: bool,
+    ) {
+        let index = usize::from(class);
+        let conflict = if in_interrupt {
+            self.in_interrupt[index].get_or_insert(site);
+            self.interrupts_enabled[index].map(|enabled_site| (site, enabled_site))
+        } else if interrupts_enabled {
+            self.interrupts_enabled[index].get_or_insert(site);
+            self.in_interrupt[index].map(|interrupt_site| (interrupt_site, site))
+        } else {
+            None
+        };
+
+        if let Some((interrupt_site, enabled_site)) = conflict {
+            report(format_args!(
+                "spinlock {} acquired in interrupt context at {interrupt_site} and with \
+                 interrupts enabled at {enabled_site}: the interrupt handler can spin forever on \
+                 it",
+                self.site(class)
+            ));
+        }
+    }
+}
+
+impl Held {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    /// Returns the locks held, from the first acquired to the last.
+    fn iter(&self) -> impl Iterator<Item = (u16, &'static Location<'static>)> + '_ {
+        self.locks[..self.depth].iter().flatten().copied()
+    }
+
+    /// Returns the last lock acquired, if any.
+    fn top(&self) -> Option<(u16, &'static Location<'static>)> {
+        self.depth.checked_sub(1).and_then(|top| self.locks[top])
+    }
+
+    fn push(&mut self, class: u16, site: &'static Location<'static>) {
+        if self.depth == MAX_HELD {
+            disable("too many locks held");
+            return;
+        }
+        self.locks[self.depth] = Some((class, site));
+        self.depth += 1;
+    }
+
+    /// Remove the last acquired lock of the given class. The locks are not always released in the
+    /// reverse order of their acquisition.
+    fn remove(&mut self, class: u16) {
+        let position = self.locks[..self.depth]
+            .iter()
+            .rposition(|lock| matches!(lock, Some((held, _)) if *held == class));
+        if let Some(position) = position {
+            self.locks.copy_within(position + 1..self.depth, position);
+            self.depth -= 1;
+            self.locks[self.depth] = None;
+        }
+    }
+}
+
+impl Threads {
+    /// Create the table with no slot assigned. It is built at compile time like the graph.
+    #[allow(clippy::large_stack_arrays)]
+    const fn new() -> Self {
+        Self {
+            owners: [None; MAX_THREADS],
+            held: [const { Held::new() }; MAX_THREADS],
+        }
+    }
+
+    /// Returns the sleeping locks held by the given thread, and assigns it a slot if it does not
+    /// hold any yet. Returns `None` if there is no free slot.
+    fn held(&mut self, thread: u64) -> Option<&mut Held> {
+        let slot = if let Some(slot) = self.owners.iter().position(|&owner| owner == Some(thread)) {
+            slot
+        } else {
+            let slot = self.owners.iter().position(Option::is_none)?;
+            self.owners[slot] = Some(thread);
+            slot
+        };
+        Some(&mut self.held[slot])
+    }
+
+    /// Remove the last acquired sleeping lock of the given class from the locks held by the given
+    /// thread, and free the slot of the thread if it was its last one.
+    fn release(&mut self, thread: u64, class: u16) {
+        if let Some(slot) = self.owners.iter().position(|&owner| owner == Some(thread)) {
+            self.held[slot].remove(class);
+            if self.held[slot].depth == 0 {
+                self.owners[slot] = None;
+            }
+        }
+    }
+}
+
+/// Check the order in which a lock is acquired against the locks already held, and record the new
+/// dependencies. Only the first inversion is reported. Returns false if an inversion was found.
+fn check_order(
+    graph: &mut Graph,
+    held: &Held,
+    (class, site): (u16, &'static Location<'static>),
+) -> bool {
+    for (previous, previous_site) in held.iter() {
+        if previous == class {
+            continue;
+        }
+        if graph.depends(class, previous) {
+            report_inversion(graph, held, (previous, previous_site), (class, site));
+            return false;
+        }
+        graph.add_dependency(previous, previous_site, class, site);
+    }
+    true
+}
+
+/// Run the given function with the graph, the locks held by the current CPU and the sleeping locks
+/// held by the threads, unless the checker is disabled, not registered yet, or already running on
+/// the current CPU.
+fn with_state(f: impl FnOnce(&mut Graph, &mut Held, &mut Threads, &Hooks)) {
+    let Some(hooks) = Hooks::get() else {
+        return;
+    };
+    with_held(|held| {
+        while STATE
+            .graph_lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: The graph lock is held
+        let (graph, threads) = unsafe { (&mut *STATE.graph.get(), &mut *STATE.threads.get()) };
+        f(graph, held, threads, &hooks);
+        STATE.graph_lock.store(false, Ordering::Release);
+    });
+}
+
+/// Run the given function with the locks held by the current CPU, unless the checker is disabled
+/// or already running on the current CPU.
+fn with_held(f: impl FnOnce(&mut Held)) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(cpu) = cpu::current_id()
+        .map(|cpu| cpu as usize)
+        .filter(|&cpu| cpu < MAX_CPUS)
+    else {
+        return;
+    };
+    if STATE.busy[cpu].swap(true, Ordering::Acquire) {
+        return;
+    }
+    f(unsafe { &mut *STATE.held[cpu].get() });
+    STATE.busy[cpu].store(false, Ordering::Release);
+}
+
+/// Report a lock acquired in the opposite order of a recorded dependency, with the locks held by
+/// the current CPU or thread and the dependencies recorded in the opposite order.
+fn report_inversion(
+    graph: &Graph,
+    held: &Held,
+    (previous, previous_site): (u16, &'static Location<'static>),
+    (class, site): (u16, &'static Location<'static>),
+) {
+    report(format_args!(
+        "lock {} acquired at {site} while holding lock {} acquired at {previous_site}, but the \
+         opposite order was seen before",
+        graph.site(class),
+        graph.site(previous)
+    ));
+    log::error!("Locks held:");
+    for (held, held_site) in held.iter() {
+        log::error!("  lock {} acquired at {held_site}", graph.site(held));
+    }
+    log::error!("Opposite order, from the last dependency to the first:");
+    graph.path(class, previous, |from, to| {
+        if let Some(dependency) = graph.dependency(from, to) {
+            log::error!(
+                "  lock {} acquired at {} while holding lock {} acquired at {}",
+                graph.site(to),
+                dependency.to_site,
+                graph.site(from),
+                dependency.from_site
+            );
+        }
+    });
+}
+
+/// Report a problem and disable the checker.
+fn report(message: fmt::Arguments) {
+    DISABLED.store(true, Ordering::Relaxed);
+    log::error!("lockdep: possible deadlock: {message}");
+}
+
+/// Disable the checker because it ran out of space.
+fn disable(reason: &str) {
+    DISABLED.store(true, Ordering::Relaxed);
+    log::warn!("lockdep: {reason}, lock dependency checking disabled");
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::panic::Location;
+    use std::{boxed::Box, vec::Vec};
+
+    use super::{check_order, Class, Graph, Threads};
+
+    /// Create a lock class identified by the place where this function is called.
+    #[track_caller]
+    fn class() -> Class {
+        Class::new(Location::caller())
+    }
+
+    /// Record that the second class was acquired while holding the first one.
+    fn add(graph: &mut Graph, from: u16, to: u16) {
+        let site = Location::caller();
+        graph.add_dependency(from, site, to, site);
+    }
+
+    #[test]
+    fn class_indexes() {
+        let mut graph = Box::new(Graph::new());
+        let (first, second) = (class(), class());
+        let first_again = Class::new(first.site);
+
+        assert_eq!(graph.index(&first), Some(0));
+        assert_eq!(graph.index(&second), Some(1));
+        assert_eq!(graph.index(&first), Some(0));
+        assert_eq!(graph.index(&first_again), Some(0));
+        assert_eq!(graph.site(1), second.site);
+    }
+
+    #[test]
+    fn direct_inversion() {
+        let mut graph = Box::new(Graph::new());
+        graph.class_count = 2;
+        add(&mut graph, 0, 1);
+
+        // Acquiring 0 while holding 1 is reported, because 1 was acquired while holding 0
+        assert!(graph.depends(0, 1));
+        assert!(!graph.depends(1, 0));
+    }
+
+    #[test]
+    fn transitive_cycle() {
+        let mut graph = Box::new(Graph::new());
+        graph.class_count = 4;
+        add(&mut graph, 0, 1);
+        add(&mut graph, 1, 2);
+
+        assert!(graph.depends(0, 2));
+        assert!(!graph.depends(2, 0));
+        assert!(!graph.depends(0, 3));
+
+        // The path is given from the last dependency to the first
+        let mut path = Vec::new();
+        assert!(graph.path(0, 2, |from, to| path.push((from, to))));
+        assert_eq!(path, [(1, 2), (0, 1)]);
+        assert!(graph.dependency(1, 2).is_some());
+        assert!(graph.dependency(2, 1).is_none());
+    }
+
+    /// The sleeping locks are checked like the spinlocks, but with the locks held by the thread.
+    #[test]
+    fn sleeping_inversion() {
+        let mut graph = Box::new(Graph::new());
+        let mut threads = Box::new(Threads::new());
+        let (first, second) = (class(), class());
+        let first = graph.index(&first).unwrap();
+        let second = graph.index(&second).unwrap();
+        let site = Location::caller();
+
+        let held = threads.held(1).unwrap();
+        held.push(first, site);
+        assert!(check_order(&mut graph, held, (second, site)));
+        held.push(second, site);
+        threads.release(1, second);
+        threads.release(1, first);
+
+        // Another thread acquires the locks in the opposite order
+        let held = threads.held(2).unwrap();
+        held.push(second, site);
+        assert!(!check_order(&mut graph, held, (first, site)));
+    }
+
+    #[test]
+    fn thread_slots() {
+        let mut threads = Box::new(Threads::new());
+        let site = Location::caller();
+
+        threads.held(7).unwrap().push(0, site);
+        threads.held(7).unwrap().push(1, site);
+        threads.held(8).unwrap().push(0, site);
+        assert_eq!(threads.held(7).unwrap().depth, 2);
+        assert_eq!(threads.owners.iter().flatten().count(), 2);
+
+        // The slot of a thread is freed with its last sleeping lock
+        threads.release(7, 0);
+        assert_eq!(threads.owners.iter().flatten().count(), 2);
+        threads.release(7, 1);
+        assert_eq!(threads.owners.iter().flatten().count(), 1);
+        threads.release(8, 0);
+        assert!(threads.owners.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn dependencies_recorded_once() {
+        let mut graph = Box::new(Graph::new());
+        graph.class_count = 2;
+        add(&mut graph, 0, 1);
+        add(&mut graph, 0, 1);
+        assert_eq!(graph.dependency_count, 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Contention statistics of the spinlocks, enabled with the `lockstat` feature. As for lockdep,
+//! the spinlocks are identified by the place where they were created: the statistics of all the
+//! spinlocks created by the same call to `new` are merged. For each place, the number of
+//! acquisitions, the number of acquisitions that had to wait, and the time spent waiting are
+//! recorded.
+//!
+//! The counters are shared by all the CPUs, so recording them slows down the locks: this is only
+//! meant to find out which locks are contended.
+use core::{
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU16, AtomicU64, Ordering},
+};
+
+/// The maximum number of places tracked. The spinlocks created elsewhere are not tracked.
+const MAX_SITES: usize = 256;
+
+static SITES: [Counters; MAX_SITES] = [const { Counters::new() }; MAX_SITES];
+
+/// The place where a spinlock was created, and the index of its counters once they are found.
+pub struct Site {
+    site: &'static Location<'static>,
+
+    /// The index of the counters of the place plus one, or 0 if they were not looked up yet.
+    index: AtomicU16,
+}
+
+/// The statistics of the spinlocks created at a place in the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockStats {
+    /// The place where the spinlocks were created.
+    pub site: &'static Location<'static>,
+
+    /// The number of times the spinlocks were locked.
+    pub acquisitions: u64,
+
+    /// The number of times the spinlocks were already locked and had to be waited for.
+    pub contentions: u64,
+
+    /// The cumulative time spent waiting for the spinlocks, in TSC cycles.
+    pub wait_cycles: u64,
+}
+
+struct Counters {
+    site: AtomicPtr<Location<'static>>,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    wait_cycles: AtomicU64,
+}
+
+impl Site {
+    #[must_use]
+    pub const fn new(site: &'static Location<'static>) -> Self {
+        Self {
+            site,
+            index: AtomicU16::new(0),
+        }
+    }
+
+    /// Record an acquisition of a spinlock created at this place. `contended` is the timestamp at
+    /// which the spinlock was found locked, if it was.
+    pub fn record(&self, contended: Option<u64>) {
+        let Some(counters) = self.counters() else {
+            return;
+        };
+
+        counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(start) = contended {
+            let waited = timestamp().saturating_sub(start);
+            counters.contentions.fetch_add(1, Ordering::Relaxed);
+            counters.wait_cycles.fetch_add(waited, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the counters of this place, claiming free ones the first time. Returns `None` if
+    /// all the counters are used by other places.
+    fn counters(&self) -> Option<&'static Counters> {
+        let index = self.index.load(Ordering::Relaxed);
+        if index != 0 {
+            return Some(&SITES[usize::from(index) - 1]);
+        }
+
+        let site = ptr::from_ref(self.site).cast_mut();
+        let start = site as usize / core::mem::align_of::<Location>() % MAX_SITES;
+        let index = (0..MAX_SITES)
+            .map(|offset| (start + offset) % MAX_SITES)
+            .find(|&index| {
+                let claimed = SITES[index].site.compare_exchange(
+                    ptr::null_mut(),
+                    site,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+                claimed.is_ok() || claimed == Err(site)
+            })?;
+
+        self.index
+            .store(u16::try_from(index + 1).unwrap(), Ordering::Relaxed);
+        Some(&SITES[index])
+    }
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            site: AtomicPtr::new(ptr::null_mut()),
+            acquisitions: AtomicU64::new(0),
+            contentions: AtomicU64::new(0),
+            wait_cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Call the given closure with the statistics of each place where spinlocks were created and
+/// locked at least once.
+pub fn for_each(mut f: impl FnMut(LockStats)) {
+    for counters in &SITES {
+        let site = counters.site.load(Ordering::Acquire);
+        if site.is_null() {
+            continue;
+        }
+
+        f(LockStats {
+            site: unsafe { &*site },
+            acquisitions: counters.acquisitions.load(Ordering::Relaxed),
+            contentions: counters.contentions.load(Ordering::Relaxed),
+            wait_cycles: counters.wait_cycles.load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// Reset the statistics of all the places, for example before a benchmark.
+pub fn reset() {
+    for counters in &SITES {
+        counters.acquisitions.store(0, Ordering::Relaxed);
+        counters.contentions.store(0, Ordering::Relaxed);
+        counters.wait_cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Returns the current value of the TSC, used to measure the time spent waiting for a spinlock.
+#[must_use]
+pub fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
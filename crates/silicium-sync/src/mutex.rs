@@ -0,0 +1,233 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+#[cfg(feature = "lockdep")]
+use crate::lockdep;
+use crate::parker;
+
+/// The mutex is not locked.
+const UNLOCKED: u8 = 0;
+
+/// The mutex is locked, and no thread is parked on it.
+const LOCKED: u8 = 1;
+
+/// The mutex is locked, and some threads may be parked on it: they must be unparked when the
+/// mutex is unlocked.
+const CONTENDED: u8 = 2;
+
+/// The number of times a thread spins on a locked mutex before parking. The critical sections are
+/// usually short, and parking a thread costs two context switches.
+const SPIN_LIMIT: u32 = 100;
+
+/// A mutual exclusion lock that puts the waiting threads to sleep instead of spinning, once a
+/// [`Parker`](crate::Parker) is registered. Unlike a spinlock, it must not be used from an
+/// interrupt handler or with interrupts disabled, because the owner of the mutex may sleep.
+///
+/// The waiting threads first spin for a while, because the owner is likely to unlock the mutex
+/// soon. The state of the mutex tells whether threads may be parked on it, so that an unlock
+/// without contention does not have to call the parker.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU8,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::Class,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// A guard giving access to the data protected by a [`Mutex`]. The mutex is unlocked when the
+/// guard is dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(crate) mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    /// Create a new unlocked mutex protecting the given data. With the `lockdep` feature, the
+    /// mutexes created at the same place in the code belong to the same lock class.
+    #[must_use]
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU8::new(UNLOCKED),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::Class::new(core::panic::Location::caller()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the mutex and returns the data it protects.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Lock the mutex, sleeping until it is available.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, lockdep::Kind::Sleep);
+
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Try to lock the mutex without waiting. Returns `None` if the mutex is already locked.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| {
+                // A failed attempt cannot deadlock, so only the successful ones are tracked
+                #[cfg(feature = "lockdep")]
+                lockdep::acquire(&self.class, lockdep::Kind::Sleep);
+                MutexGuard { mutex: self }
+            })
+    }
+
+    /// Returns true if the mutex is locked. The result may be outdated as soon as it is returned.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    /// Returns a mutable reference to the protected data. No locking is needed, because the
+    /// mutable borrow guarantees that no other reference to the mutex exists.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The slow path of [`Mutex::lock`]: spin while the owner is likely to unlock the mutex soon,
+    /// then mark the mutex as contended and park until it is unlocked.
+    #[cold]
+    fn lock_contended(&self) {
+        for _ in 0..SPIN_LIMIT {
+            if self.state.load(Ordering::Relaxed) == UNLOCKED
+                && self
+                    .state
+                    .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+
+        // The mutex is locked as contended, because other threads may still be parked on it when
+        // the current thread gets it
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            let validate = || self.state.load(Ordering::Relaxed) == CONTENDED;
+            parker::park(self.key(), &validate, &|| {}, None);
+        }
+    }
+
+    /// Unlock the mutex, and unpark one of the threads waiting for it.
+    pub(crate) fn unlock(&self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.class, lockdep::Kind::Sleep);
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            parker::unpark_one(self.key());
+        }
+    }
+
+    /// The key on which the threads waiting for the mutex are parked.
+    fn key(&self) -> usize {
+        core::ptr::addr_of!(self.state) as usize
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use super::Mutex;
+    use crate::parker::testing;
+
+    #[test]
+    fn contention() {
+        testing::register();
+        let mutex = Mutex::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        *mutex.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(mutex.into_inner(), 8000);
+    }
+
+    #[test]
+    fn unparked_on_unlock() {
+        testing::register();
+        let mutex = Mutex::new(false);
+        let mut guard = mutex.lock();
+
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| *mutex.lock());
+
+            // The waiter parks once it gave up spinning, and must be unparked by the unlock
+            while testing::parked(mutex.key()) == 0 {
+                thread::yield_now();
+            }
+            *guard = true;
+            drop(guard);
+            assert!(waiter.join().unwrap());
+        });
+        assert!(!mutex.is_locked());
+        assert_eq!(testing::parked(mutex.key()), 0);
+    }
+
+    #[test]
+    fn try_lock() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+}
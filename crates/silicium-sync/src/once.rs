@@ -0,0 +1,261 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use crate::cpu;
+
+/// The value is not initialized, and no initializer is running.
+const INCOMPLETE: u8 = 0;
+
+/// An initializer is running.
+const RUNNING: u8 = 1;
+
+/// The value is initialized.
+const COMPLETE: u8 = 2;
+
+/// An initializer panicked: the value will never be initialized.
+const POISONED: u8 = 3;
+
+/// The owner of an initializer running on a CPU whose id is not known.
+const UNKNOWN_CPU: u32 = u32::MAX;
+
+/// A value initialized once, by the first caller of [`Once::call_once`]. The other callers wait
+/// until the value is initialized, spinning because the value may be needed before the scheduler
+/// is running.
+///
+/// A CPU calling [`Once::call_once`] while it is already running the initializer of the same
+/// value, for example from an interrupt handler, would wait for itself forever: this is detected
+/// and causes a panic instead, once the kernel has registered how to get the id of the current CPU
+/// (see [`crate::cpu::register`]). If an initializer panics, the value is poisoned and all the
+/// later accesses panic as well.
+pub struct Once<T> {
+    state: AtomicU8,
+    owner: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+/// Poisons the value if the initializer panics before completing. The kernel is built with
+/// `panic = "abort"`, so this only matters when the crate is used with unwinding panics, as in its
+/// tests.
+struct PoisonOnPanic<'a> {
+    state: &'a AtomicU8,
+}
+
+impl Drop for PoisonOnPanic<'_> {
+    fn drop(&mut self) {
+        self.state.store(POISONED, Ordering::Release);
+    }
+}
+
+impl<T> Once<T> {
+    /// Create an uninitialized value.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            owner: AtomicU32::new(UNKNOWN_CPU),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Create a value that is already initialized.
+    #[must_use]
+    pub const fn initialized(value: T) -> Self {
+        Self {
+            state: AtomicU8::new(COMPLETE),
+            owner: AtomicU32::new(UNKNOWN_CPU),
+            value: UnsafeCell::new(MaybeUninit::new(value)),
+        }
+    }
+
+    /// Initialize the value with the given function if it is not initialized yet, and return a
+    /// reference to it. If another CPU is initializing the value, wait until it is done.
+    ///
+    /// # Panics
+    /// Panics if the value is poisoned, or if the current CPU is already initializing the value.
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                let owner = cpu::current_id().unwrap_or(UNKNOWN_CPU);
+                self.owner.store(owner, Ordering::Relaxed);
+
+                let guard = PoisonOnPanic { state: &self.state };
+                unsafe {
+                    (*self.value.get()).write(init());
+                }
+                core::mem::forget(guard);
+                self.state.store(COMPLETE, Ordering::Release);
+                unsafe { self.get_unchecked() }
+            }
+            Err(_) => self.wait(),
+        }
+    }
+
+    /// Returns a reference to the value if it is initialized.
+    ///
+    /// # Panics
+    /// Panics if the value is poisoned.
+    #[must_use]
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            COMPLETE => Some(unsafe { self.get_unchecked() }),
+            POISONED => panic!("Once poisoned by a panic during its initialization"),
+            _ => None,
+        }
+    }
+
+    /// Returns a mutable reference to the value if it is initialized.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        (*self.state.get_mut() == COMPLETE)
+            .then(|| unsafe { (*self.value.get()).assume_init_mut() })
+    }
+
+    /// Returns true if the value is initialized.
+    #[must_use]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Wait until the value is initialized by another CPU and return a reference to it.
+    ///
+    /// # Panics
+    /// Panics if the value is poisoned, or if the current CPU is the one initializing the value.
+    fn wait(&self) -> &T {
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => return unsafe { self.get_unchecked() },
+                POISONED => panic!("Once poisoned by a panic during its initialization"),
+                _ => (),
+            }
+
+            let owner = self.owner.load(Ordering::Relaxed);
+            assert!(
+                owner == UNKNOWN_CPU || cpu::current_id() != Some(owner),
+                "Once initialized re-entrantly on CPU {owner}"
+            );
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Returns a reference to the value without checking that it is initialized.
+    ///
+    /// # Safety
+    /// The value must be initialized.
+    unsafe fn get_unchecked(&self) -> &T {
+        (*self.value.get()).assume_init_ref()
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe {
+                self.value.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::{panic, sync::Barrier, thread};
+
+    use super::Once;
+
+    /// Register a CPU id function giving a different id to each thread, so that the threads of
+    /// the tests behave like different CPUs.
+    fn register_thread_ids() {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+        std::thread_local! {
+            static ID: u32 = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        }
+        crate::cpu::register(|| Some(ID.with(|id| *id)));
+    }
+
+    #[test]
+    fn initialized_once() {
+        register_thread_ids();
+        let once = Once::new();
+        let calls = AtomicUsize::new(0);
+        let barrier = Barrier::new(8);
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    let value = once.call_once(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        42
+                    });
+                    assert_eq!(*value, 42);
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(once.get(), Some(&42));
+    }
+
+    #[test]
+    #[should_panic(expected = "re-entrantly")]
+    fn reentrant_initialization() {
+        register_thread_ids();
+        let once = Once::new();
+        once.call_once(|| *once.call_once(|| 1) + 1);
+    }
+
+    #[test]
+    fn poisoned_by_panic() {
+        register_thread_ids();
+        let once = Once::<u32>::new();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            once.call_once(|| panic!("initializer failed"));
+        }));
+        assert!(result.is_err());
+        assert!(!once.is_completed());
+
+        let get = panic::catch_unwind(panic::AssertUnwindSafe(|| once.get().copied()));
+        assert!(get.is_err());
+        let call = panic::catch_unwind(panic::AssertUnwindSafe(|| *once.call_once(|| 1)));
+        assert!(call.is_err());
+    }
+
+    #[test]
+    fn waiter_sees_poison() {
+        register_thread_ids();
+        let once = Once::<u32>::new();
+        let started = Barrier::new(2);
+
+        thread::scope(|scope| {
+            let initializer = scope.spawn(|| {
+                once.call_once(|| {
+                    started.wait();
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    panic!("initializer failed");
+                });
+            });
+            let waiter = scope.spawn(|| {
+                started.wait();
+                *once.call_once(|| 1)
+            });
+            assert!(initializer.join().is_err());
+            assert!(waiter.join().is_err());
+        });
+    }
+}
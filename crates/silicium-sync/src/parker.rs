@@ -0,0 +1,243 @@
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+/// The interface between the sleeping primitives of this crate and the scheduler of the kernel.
+/// The threads are parked on a key, which is the address of the primitive they wait for, so that
+/// the primitives do not have to embed a wait queue.
+pub trait Parker: Sync {
+    /// Park the current thread on the given key if `validate` returns true, until it is unparked
+    /// or until the timeout expires. The kernel must call `validate` while holding the lock
+    /// protecting the threads parked on the key, so that a thread cannot miss a call to
+    /// [`Parker::unpark_one`] made after it has validated its condition. Once the thread is
+    /// queued on the key and this lock is released, `before_sleep` is called: a wakeup sent after
+    /// this point is not lost.
+    ///
+    /// This function may return [`ParkResult::Spurious`], for example when the current thread
+    /// cannot sleep because it runs in an interrupt handler: the caller must check its condition
+    /// again.
+    fn park(
+        &self,
+        key: usize,
+        validate: &dyn Fn() -> bool,
+        before_sleep: &dyn Fn(),
+        timeout: Option<Duration>,
+    ) -> ParkResult;
+
+    /// Unpark one of the threads parked on the given key, in the order they were parked. Returns
+    /// true if a thread was unparked.
+    fn unpark_one(&self, key: usize) -> bool;
+
+    /// Unpark all the threads parked on the given key. Returns the number of threads unparked.
+    fn unpark_all(&self, key: usize) -> usize;
+}
+
+/// The reason why [`Parker::park`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParkResult {
+    /// The thread was unparked by [`Parker::unpark_one`] or [`Parker::unpark_all`].
+    Unparked,
+
+    /// The thread was not parked, because `validate` returned false.
+    Invalid,
+
+    /// The timeout expired before the thread was unparked.
+    TimedOut,
+
+    /// The thread returned without being unparked, or did not sleep at all.
+    Spurious,
+}
+
+/// The parker is not registered yet.
+const UNREGISTERED: u8 = 0;
+
+/// The parker is being registered.
+const REGISTERING: u8 = 1;
+
+/// The parker is registered and can be used.
+const REGISTERED: u8 = 2;
+
+/// The parker registered by the kernel, if any.
+struct Registration {
+    state: AtomicU8,
+    parker: UnsafeCell<Option<&'static dyn Parker>>,
+}
+
+// SAFETY: The parker is only written once, before the state becomes `REGISTERED`, and is only
+// read after the state is `REGISTERED`.
+unsafe impl Sync for Registration {}
+
+static PARKER: Registration = Registration {
+    state: AtomicU8::new(UNREGISTERED),
+    parker: UnsafeCell::new(None),
+};
+
+/// Register the parker used by the sleeping primitives. Until a parker is registered, the
+/// primitives spin instead of sleeping.
+///
+/// # Panics
+/// Panics if a parker is already registered.
+pub fn register(parker: &'static dyn Parker) {
+    PARKER
+        .state
+        .compare_exchange(
+            UNREGISTERED,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .expect("A parker is already registered");
+    unsafe {
+        *PARKER.parker.get() = Some(parker);
+    }
+    PARKER.state.store(REGISTERED, Ordering::Release);
+}
+
+/// Returns the registered parker, if any.
+#[must_use]
+pub fn get() -> Option<&'static dyn Parker> {
+    if PARKER.state.load(Ordering::Acquire) == REGISTERED {
+        unsafe { *PARKER.parker.get() }
+    } else {
+        None
+    }
+}
+
+/// Park the current thread on the given key with the registered parker, or spin for a while if
+/// there is none (see [`Parker::park`]).
+pub(crate) fn park(
+    key: usize,
+    validate: &dyn Fn() -> bool,
+    before_sleep: &dyn Fn(),
+    timeout: Option<Duration>,
+) -> ParkResult {
+    if let Some(parker) = get() {
+        return parker.park(key, validate, before_sleep, timeout);
+    }
+    if !validate() {
+        return ParkResult::Invalid;
+    }
+    before_sleep();
+    core::hint::spin_loop();
+    ParkResult::Spurious
+}
+
+/// Unpark one of the threads parked on the given key with the registered parker, if any (see
+/// [`Parker::unpark_one`]).
+pub(crate) fn unpark_one(key: usize) -> bool {
+    get().is_some_and(|parker| parker.unpark_one(key))
+}
+
+/// Unpark all the threads parked on the given key with the registered parker, if any (see
+/// [`Parker::unpark_all`]).
+pub(crate) fn unpark_all(key: usize) -> usize {
+    get().map_or(0, |parker| parker.unpark_all(key))
+}
+
+/// A parker for the host tests, which puts the threads of the standard library to sleep.
+#[cfg(test)]
+pub(crate) mod testing {
+    extern crate std;
+
+    use core::time::Duration;
+    use std::{
+        sync::{Condvar, Mutex, Once},
+        time::Instant,
+        vec::Vec,
+    };
+
+    use super::{ParkResult, Parker};
+
+    /// The threads parked on each key, identified by a ticket. A thread is unparked by removing
+    /// its ticket and waking up all the parked threads, which check whether their ticket is still
+    /// there.
+    struct HostParker {
+        queue: Mutex<Queue>,
+        wakeup: Condvar,
+    }
+
+    struct Queue {
+        parked: Vec<(usize, u64)>,
+        next_ticket: u64,
+    }
+
+    static PARKER: HostParker = HostParker {
+        queue: Mutex::new(Queue {
+            parked: Vec::new(),
+            next_ticket: 0,
+        }),
+        wakeup: Condvar::new(),
+    };
+
+    impl Parker for HostParker {
+        fn park(
+            &self,
+            key: usize,
+            validate: &dyn Fn() -> bool,
+            before_sleep: &dyn Fn(),
+            timeout: Option<Duration>,
+        ) -> ParkResult {
+            let mut queue = self.queue.lock().unwrap();
+            if !validate() {
+                return ParkResult::Invalid;
+            }
+            queue.next_ticket += 1;
+            let waiter = (key, queue.next_ticket);
+            queue.parked.push(waiter);
+            drop(queue);
+            before_sleep();
+
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let mut queue = self.queue.lock().unwrap();
+            while queue.parked.contains(&waiter) {
+                let Some(deadline) = deadline else {
+                    queue = self.wakeup.wait(queue).unwrap();
+                    continue;
+                };
+                let now = Instant::now();
+                if now >= deadline {
+                    queue.parked.retain(|&other| other != waiter);
+                    return ParkResult::TimedOut;
+                }
+                queue = self.wakeup.wait_timeout(queue, deadline - now).unwrap().0;
+            }
+            ParkResult::Unparked
+        }
+
+        fn unpark_one(&self, key: usize) -> bool {
+            let mut queue = self.queue.lock().unwrap();
+            let Some(position) = queue.parked.iter().position(|&(other, _)| other == key) else {
+                return false;
+            };
+            queue.parked.remove(position);
+            self.wakeup.notify_all();
+            true
+        }
+
+        fn unpark_all(&self, key: usize) -> usize {
+            let mut queue = self.queue.lock().unwrap();
+            let count = queue.parked.len();
+            queue.parked.retain(|&(other, _)| other != key);
+            self.wakeup.notify_all();
+            count - queue.parked.len()
+        }
+    }
+
+    /// Register the host parker, if it is not registered yet.
+    pub fn register() {
+        static REGISTER: Once = Once::new();
+        REGISTER.call_once(|| super::register(&PARKER));
+    }
+
+    /// Returns the number of threads parked on the given key.
+    pub fn parked(key: usize) -> usize {
+        let queue = PARKER.queue.lock().unwrap();
+        queue
+            .parked
+            .iter()
+            .filter(|&&(other, _)| other == key)
+            .count()
+    }
+}
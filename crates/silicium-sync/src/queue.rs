@@ -0,0 +1,398 @@
+//! Fixed-capacity lock-free queues. They do not allocate memory and never block, so they can be
+//! used to pass data out of an interrupt handler, or between CPUs without taking a lock.
+use core::{
+    cell::UnsafeCell,
+    cmp,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// A slot of a [`MpscQueue`].
+struct Slot<T> {
+    /// The position of the next push or pop allowed on this slot, minus the index of the slot so
+    /// that all the slots start at 0. The slot is empty when it equals the position of the next
+    /// push, and full when it equals the position of the push plus one.
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A bounded multi-producer queue, for example to pass data from interrupt handlers running on
+/// any CPU to a thread. Each slot has a sequence number telling whether it can be written or read,
+/// so that a producer never waits for another one (this is the bounded queue of Dmitry Vyukov).
+/// The capacity must be a power of two.
+///
+/// The queue can also be used with several consumers, but it is meant for a single one, which
+/// receives the items in the order they were pushed.
+pub struct MpscQueue<T, const N: usize> {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    slots: [Slot<T>; N],
+}
+
+unsafe impl<T: Send, const N: usize> Send for MpscQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    /// Create an empty queue.
+    ///
+    /// # Panics
+    /// Panics if the capacity is not a power of two.
+    #[must_use]
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "The capacity must be a power of two");
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            slots: [const {
+                Slot {
+                    sequence: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+        }
+    }
+
+    /// Push an item at the end of the queue.
+    ///
+    /// # Errors
+    /// Returns the item if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let (index, slot) = self.slot(position);
+            let sequence = slot.sequence.load(Ordering::Acquire).wrapping_add(index);
+
+            #[allow(clippy::cast_possible_wrap)]
+            let lag = sequence.wrapping_sub(position) as isize;
+            match lag.cmp(&0) {
+                cmp::Ordering::Equal => match self.tail.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        let sequence = position.wrapping_add(1).wrapping_sub(index);
+                        slot.sequence.store(sequence, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                },
+                // The slot still holds the item pushed one lap before
+                cmp::Ordering::Less => return Err(value),
+                cmp::Ordering::Greater => position = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Pop the item at the front of the queue, if any.
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.head.load(Ordering::Relaxed);
+        loop {
+            let (index, slot) = self.slot(position);
+            let sequence = slot.sequence.load(Ordering::Acquire).wrapping_add(index);
+
+            #[allow(clippy::cast_possible_wrap)]
+            let lag = sequence.wrapping_sub(position.wrapping_add(1)) as isize;
+            match lag.cmp(&0) {
+                cmp::Ordering::Equal => match self.head.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        let sequence = position.wrapping_add(N).wrapping_sub(index);
+                        slot.sequence.store(sequence, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                },
+                // The slot was not pushed yet
+                cmp::Ordering::Less => return None,
+                cmp::Ordering::Greater => position = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Returns true if the queue is empty. The result may be outdated as soon as it is returned.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Relaxed)
+    }
+
+    /// Returns the index and the slot of the given position.
+    fn slot(&self, position: usize) -> (usize, &Slot<T>) {
+        let index = position & (N - 1);
+        (index, &self.slots[index])
+    }
+}
+
+impl<T, const N: usize> Default for MpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// A bounded single-producer single-consumer queue, for example a pipe between two CPUs. The
+/// producer and the consumer each own one end of the queue (see [`SpscQueue::producer`] and
+/// [`SpscQueue::consumer`]), so they never write to the same index. The capacity must be a power
+/// of two.
+pub struct SpscQueue<T, const N: usize> {
+    /// The position of the next pop, only written by the consumer.
+    head: AtomicUsize,
+
+    /// The position of the next push, only written by the producer.
+    tail: AtomicUsize,
+
+    /// Set once the producer or the consumer end was taken.
+    producer_taken: AtomicBool,
+    consumer_taken: AtomicBool,
+
+    values: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+/// The producer end of a [`SpscQueue`].
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a SpscQueue<T, N>,
+}
+
+/// The consumer end of a [`SpscQueue`].
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a SpscQueue<T, N>,
+}
+
+impl<T, const N: usize> SpscQueue<T, N> {
+    /// Create an empty queue.
+    ///
+    /// # Panics
+    /// Panics if the capacity is not a power of two.
+    #[must_use]
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "The capacity must be a power of two");
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producer_taken: AtomicBool::new(false),
+            consumer_taken: AtomicBool::new(false),
+            values: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+        }
+    }
+
+    /// Returns the producer end of the queue, or `None` if it was already taken.
+    pub fn producer(&self) -> Option<Producer<'_, T, N>> {
+        (!self.producer_taken.swap(true, Ordering::Acquire)).then_some(Producer { queue: self })
+    }
+
+    /// Returns the consumer end of the queue, or `None` if it was already taken.
+    pub fn consumer(&self) -> Option<Consumer<'_, T, N>> {
+        (!self.consumer_taken.swap(true, Ordering::Acquire)).then_some(Consumer { queue: self })
+    }
+
+    /// Returns the number of items in the queue. The result may be outdated as soon as it is
+    /// returned.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Returns true if the queue is empty. The result may be outdated as soon as it is returned.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, const N: usize> Default for SpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscQueue<T, N> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        for position in 0..tail.wrapping_sub(head) {
+            let index = head.wrapping_add(position) & (N - 1);
+            unsafe { self.values[index].get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Push an item at the end of the queue.
+    ///
+    /// # Errors
+    /// Returns the item if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let queue = self.queue;
+        let tail = queue.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(queue.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { (*queue.values[tail & (N - 1)].get()).write(value) };
+        queue.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Pop the item at the front of the queue, if any.
+    pub fn pop(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        if head == queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*queue.values[head & (N - 1)].get()).assume_init_read() };
+        queue.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{rc::Rc, thread, vec::Vec};
+
+    use super::{MpscQueue, SpscQueue};
+
+    const PRODUCERS: usize = 4;
+    const ITEMS: usize = 10_000;
+
+    #[test]
+    fn mpsc_capacity() {
+        let queue = MpscQueue::<usize, 4>::new();
+        assert!(queue.is_empty());
+        for item in 0..4 {
+            assert_eq!(queue.push(item), Ok(()));
+        }
+        assert_eq!(queue.push(4), Err(4));
+        for item in 0..4 {
+            assert_eq!(queue.pop(), Some(item));
+        }
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn mpsc_concurrent_producers() {
+        let queue = MpscQueue::<(usize, usize), 64>::new();
+        let mut next = [0; PRODUCERS];
+
+        thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for item in 0..ITEMS {
+                        while queue.push((producer, item)).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            // The items of each producer are received in the order they were pushed
+            let mut received = 0;
+            while received < PRODUCERS * ITEMS {
+                match queue.pop() {
+                    Some((producer, item)) => {
+                        assert_eq!(item, next[producer]);
+                        next[producer] += 1;
+                        received += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+        });
+        assert_eq!(next, [ITEMS; PRODUCERS]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn mpsc_drops_remaining_items() {
+        let item = Rc::new(());
+        let queue = MpscQueue::<Rc<()>, 4>::new();
+        queue.push(Rc::clone(&item)).unwrap();
+        queue.push(Rc::clone(&item)).unwrap();
+        drop(queue);
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn spsc_ends_taken_once() {
+        let queue = SpscQueue::<usize, 4>::new();
+        assert!(queue.producer().is_some());
+        assert!(queue.producer().is_none());
+        assert!(queue.consumer().is_some());
+        assert!(queue.consumer().is_none());
+    }
+
+    #[test]
+    fn spsc_capacity() {
+        let queue = SpscQueue::<usize, 4>::new();
+        let mut producer = queue.producer().unwrap();
+        let mut consumer = queue.consumer().unwrap();
+        for item in 0..4 {
+            assert_eq!(producer.push(item), Ok(()));
+        }
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(queue.len(), 4);
+        for item in 0..4 {
+            assert_eq!(consumer.pop(), Some(item));
+        }
+        assert_eq!(consumer.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn spsc_concurrent() {
+        let queue = SpscQueue::<usize, 64>::new();
+        let mut producer = queue.producer().unwrap();
+        let mut consumer = queue.consumer().unwrap();
+
+        let received = thread::scope(|scope| {
+            scope.spawn(move || {
+                for item in 0..PRODUCERS * ITEMS {
+                    while producer.push(item).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            });
+
+            let mut received = Vec::with_capacity(PRODUCERS * ITEMS);
+            while received.len() < PRODUCERS * ITEMS {
+                match consumer.pop() {
+                    Some(item) => received.push(item),
+                    None => thread::yield_now(),
+                }
+            }
+            received
+        });
+        assert!(received.iter().copied().eq(0..PRODUCERS * ITEMS));
+    }
+
+    #[test]
+    fn spsc_drops_remaining_items() {
+        let item = Rc::new(());
+        let queue = SpscQueue::<Rc<()>, 4>::new();
+        let mut producer = queue.producer().unwrap();
+        producer.push(Rc::clone(&item)).unwrap();
+        producer.push(Rc::clone(&item)).unwrap();
+        drop(queue);
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+}
@@ -0,0 +1,155 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(feature = "lockdep")]
+use crate::lockdep;
+#[cfg(feature = "lockstat")]
+use crate::lockstat;
+use crate::{irq::IrqState, Backoff};
+
+/// A mutual exclusion lock that spins until the lock is available. It never sleeps, so it can be
+/// used anywhere, including in interrupt handlers, but the critical sections must be short.
+///
+/// A spinlock that is also locked by an interrupt handler must always be locked with interrupts
+/// disabled, otherwise the handler could spin forever on a lock held by the code it interrupted.
+pub struct Spinlock<T: ?Sized> {
+    locked: AtomicBool,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::Class,
+    #[cfg(feature = "lockstat")]
+    site: lockstat::Site,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> {}
+
+/// A guard giving access to the data protected by a [`Spinlock`]. The spinlock is unlocked when
+/// the guard is dropped.
+pub struct SpinlockGuard<'a, T: ?Sized> {
+    lock: &'a Spinlock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SpinlockGuard<'_, T> {}
+
+impl<T> Spinlock<T> {
+    /// Create a new unlocked spinlock protecting the given data. With the `lockdep` feature, the
+    /// spinlocks created at the same place in the code belong to the same lock class.
+    #[must_use]
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::Class::new(core::panic::Location::caller()),
+            #[cfg(feature = "lockstat")]
+            site: lockstat::Site::new(core::panic::Location::caller()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the spinlock and returns the data it protects.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Spinlock<T> {
+    /// Lock the spinlock, spinning until it is available. The waiting CPU backs off exponentially
+    /// while the spinlock is locked (see [`Backoff`]).
+    #[track_caller]
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, lockdep::Kind::Spin);
+
+        #[cfg(feature = "lockstat")]
+        let mut contended = None;
+        let mut backoff = Backoff::new();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            #[cfg(feature = "lockstat")]
+            contended.get_or_insert_with(lockstat::timestamp);
+            while self.locked.load(Ordering::Relaxed) {
+                backoff.spin();
+            }
+        }
+
+        #[cfg(feature = "lockstat")]
+        self.site.record(contended);
+        SpinlockGuard { lock: self }
+    }
+
+    /// Disable the interrupts and lock the spinlock, spinning until it is available. Returns the
+    /// guard and the interrupt state saved before the interrupts were disabled, which the caller
+    /// must restore after having dropped the guard. See [`SpinlockIrq`](crate::SpinlockIrq) for a
+    /// guard that restores it automatically.
+    #[track_caller]
+    pub fn lock_saving(&self) -> (SpinlockGuard<'_, T>, IrqState) {
+        let state = IrqState::save();
+        (self.lock(), state)
+    }
+
+    /// Try to lock the spinlock without spinning. Returns `None` if it is already locked.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| {
+                // A failed attempt cannot deadlock, so only the successful ones are tracked
+                #[cfg(feature = "lockdep")]
+                lockdep::acquire(&self.class, lockdep::Kind::Spin);
+                #[cfg(feature = "lockstat")]
+                self.site.record(None);
+                SpinlockGuard { lock: self }
+            })
+    }
+
+    /// Returns true if the spinlock is locked. The result may be outdated as soon as it is
+    /// returned.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Returns a mutable reference to the protected data. No locking is needed, because the
+    /// mutable borrow guarantees that no other reference to the spinlock exists.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Spinlock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.lock.class, lockdep::Kind::Spin);
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,204 @@
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{irq::IrqState, Spinlock, SpinlockGuard};
+
+/// A spinlock that disables interrupts while it is locked, for the data also used by interrupt
+/// handlers. The interrupt state is saved in the guard rather than in the lock, so that several
+/// of these locks can be nested and each guard restores the state it saved when it is dropped.
+pub struct SpinlockIrq<T: ?Sized> {
+    inner: Spinlock<T>,
+}
+
+/// A guard giving access to the data protected by a [`SpinlockIrq`]. When dropped, the spinlock
+/// is unlocked and then the interrupt state saved when it was locked is restored.
+pub struct SpinlockIrqGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<SpinlockGuard<'a, T>>,
+    state: ManuallyDrop<IrqState>,
+}
+
+impl<T> SpinlockIrq<T> {
+    /// Create a new unlocked spinlock protecting the given data.
+    #[must_use]
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: Spinlock::new(data),
+        }
+    }
+
+    /// Consume the spinlock and returns the data it protects.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinlockIrq<T> {
+    /// Disable the interrupts and lock the spinlock, spinning until it is available.
+    #[track_caller]
+    pub fn lock(&self) -> SpinlockIrqGuard<'_, T> {
+        let (guard, state) = self.lock_saving();
+        SpinlockIrqGuard::new(guard, state)
+    }
+
+    /// Try to lock the spinlock without spinning. Returns `None` if it is already locked, in which
+    /// case the interrupt state is left untouched.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinlockIrqGuard<'_, T>> {
+        let state = IrqState::save();
+        if let Some(guard) = self.inner.try_lock() {
+            Some(SpinlockIrqGuard::new(guard, state))
+        } else {
+            state.restore();
+            None
+        }
+    }
+
+    /// Disable the interrupts and lock the spinlock like [`SpinlockIrq::lock`], but return the
+    /// saved interrupt state separately from the guard: the caller restores it when it wants, for
+    /// example after having released several locks in a different order they were taken.
+    #[track_caller]
+    pub fn lock_saving(&self) -> (SpinlockGuard<'_, T>, IrqState) {
+        self.inner.lock_saving()
+    }
+
+    /// Returns true if the spinlock is locked. The result may be outdated as soon as it is
+    /// returned.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Returns a mutable reference to the protected data. No locking is needed, because the
+    /// mutable borrow guarantees that no other reference to the spinlock exists.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for SpinlockIrq<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<'a, T: ?Sized> SpinlockIrqGuard<'a, T> {
+    fn new(guard: SpinlockGuard<'a, T>, state: IrqState) -> Self {
+        Self {
+            guard: ManuallyDrop::new(guard),
+            state: ManuallyDrop::new(state),
+        }
+    }
+
+    /// Split the guard into the guard of the spinlock and the saved interrupt state. The
+    /// interrupts stay disabled until the caller restores the state.
+    pub fn into_parts(self) -> (SpinlockGuard<'a, T>, IrqState) {
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            (
+                ManuallyDrop::take(&mut this.guard),
+                ManuallyDrop::take(&mut this.state),
+            )
+        }
+    }
+
+    /// Returns the interrupt state saved when the spinlock was locked.
+    pub fn saved_state(&self) -> &IrqState {
+        &self.state
+    }
+}
+
+impl<T: ?Sized> Deref for SpinlockIrqGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinlockIrqGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinlockIrqGuard<'_, T> {
+    fn drop(&mut self) {
+        // The spinlock must be unlocked before the interrupts are enabled again
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            ManuallyDrop::take(&mut self.state).restore();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpinlockIrq;
+    use crate::irq;
+
+    #[test]
+    fn nested_guards() {
+        let (outer, inner) = (SpinlockIrq::new(()), SpinlockIrq::new(()));
+        let outer_guard = outer.lock();
+        let inner_guard = inner.lock();
+        assert!(outer_guard.saved_state().interrupts_enabled());
+        assert!(!inner_guard.saved_state().interrupts_enabled());
+
+        drop(inner_guard);
+        assert!(!irq::enabled());
+        drop(outer_guard);
+        assert!(irq::enabled());
+    }
+
+    /// Each guard restores the state it saved, so the interrupts are enabled again once both
+    /// guards are dropped, whatever the order.
+    #[test]
+    fn guards_dropped_out_of_order() {
+        let (outer, inner) = (SpinlockIrq::new(()), SpinlockIrq::new(()));
+        let outer_guard = outer.lock();
+        let inner_guard = inner.lock();
+
+        drop(outer_guard);
+        assert!(!outer.is_locked());
+        drop(inner_guard);
+        assert!(!inner.is_locked());
+        assert!(irq::enabled());
+    }
+
+    /// The locks are released in the order they were taken, but the interrupts stay disabled until
+    /// the state saved by the first lock is restored.
+    #[test]
+    fn parts_released_out_of_order() {
+        let (first, second) = (SpinlockIrq::new(()), SpinlockIrq::new(()));
+        let (first_guard, first_state) = first.lock().into_parts();
+        let (second_guard, second_state) = second.lock().into_parts();
+
+        drop(first_guard);
+        assert!(!first.is_locked());
+        assert!(!irq::enabled());
+        drop(second_guard);
+        second_state.restore();
+        assert!(!irq::enabled());
+        first_state.restore();
+        assert!(irq::enabled());
+    }
+
+    #[test]
+    fn failed_try_lock_keeps_state() {
+        let lock = SpinlockIrq::new(());
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        assert!(!irq::enabled());
+        drop(guard);
+        assert!(irq::enabled());
+
+        let guard = lock.try_lock().unwrap();
+        assert!(!irq::enabled());
+        drop(guard);
+        assert!(irq::enabled());
+    }
+}
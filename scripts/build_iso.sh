@@ -22,8 +22,11 @@ cp -v                                   \
     bin/src/limine/limine.sys           \
     iso/boot/
 
-# Verify if debug and release builds coexist
-if [ -e target/x86_64/debug/silicium ] && [ -e target/x86_64/release/silicium ]; then
+# Use the kernel given as argument if any, otherwise verify if debug and release builds coexist
+if [ -n "$1" ]; then
+    [ -e "$1" ] || die "$1: kernel executable not found"
+    cp -v "$1" iso/boot/silicium.elf
+elif [ -e target/x86_64/debug/silicium ] && [ -e target/x86_64/release/silicium ]; then
     # Copy the most recent build
     if [ target/x86_64/debug/silicium -nt target/x86_64/release/silicium ]; then
         cp -v target/x86_64/debug/silicium iso/boot/silicium.elf
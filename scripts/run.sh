@@ -13,4 +13,5 @@ qemu-system-x86_64 -m 128                                   \
     -no-reboot                                              \
     -no-shutdown                                            \
     -serial stdio                                           \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04          \
     -smp 4
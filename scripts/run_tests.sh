@@ -10,6 +10,7 @@ die() {
 
 # Run the "normal" tests (i.e on the same machine)
 cargo +nightly test -p silicium-x86_64 --target=x86_64-unknown-linux-gnu -Z build-std
+cargo +nightly test -p silicium-sync --all-features --target=x86_64-unknown-linux-gnu -Z build-std
 
-# Run the "cross" tests (i.e through QEMU)
-# ...
\ No newline at end of file
+# Run the "cross" tests (i.e through QEMU, under several machine configurations)
+cargo +nightly xtask test-matrix
\ No newline at end of file
@@ -0,0 +1,355 @@
+use alloc::{boxed::Box, format, vec::Vec};
+use x86_64::address::Physical;
+
+use ::aml::{
+    pci_routing::{PciRoutingTable, Pin},
+    resource::{self, InterruptPolarity, InterruptTrigger, Resource},
+    AmlContext, AmlError, AmlName, AmlValue, Args, DebugVerbosity,
+};
+use acpi::AmlTable;
+
+use crate::{
+    arch::{
+        address::phys_to_virt,
+        ioapic::{Polarity, TriggerMode},
+        port::Port,
+    },
+    Spinlock,
+};
+
+/// The value given to `\_PIC` when the interrupts are routed through the IOAPICs. The routing
+/// table of the PCI bus may depend on it.
+const PIC_MODE_APIC: u64 = 1;
+
+/// The routing table of the root PCI bus. Most firmwares, including the ones of QEMU, name the
+/// root bus `PCI0`.
+const ROOT_PRT: &str = "\\_SB.PCI0._PRT";
+
+/// The AML namespace built from the DSDT and the SSDTs by [`setup`].
+static NAMESPACE: Spinlock<Option<Namespace>> = Spinlock::new(None);
+
+struct Namespace {
+    context: AmlContext,
+
+    /// The routing table of the root PCI bus, evaluated once the interrupt mode is known.
+    prt: Option<PciRoutingTable>,
+}
+
+/// How a legacy PCI interrupt pin is connected to the interrupt controllers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PciRoute {
+    pub gsi: u32,
+    pub trigger: TriggerMode,
+    pub polarity: Polarity,
+    pub shared: bool,
+}
+
+/// The operation regions accessed by the AML code, in memory, in the I/O space and in the PCI
+/// configuration space.
+struct Handler;
+
+/// Parse the DSDT and the SSDTs into the AML namespace. The tables that cannot be parsed are
+/// skipped: the objects they define are then missing from the namespace.
+///
+/// # Safety
+/// The tables must be valid ACPI tables.
+pub(super) unsafe fn setup(dsdt: Option<&AmlTable>, ssdts: &[AmlTable]) {
+    let mut context = AmlContext::new(Box::new(Handler), DebugVerbosity::None);
+    for (index, table) in dsdt.into_iter().chain(ssdts).enumerate() {
+        if let Err(error) =
+            super::map_table(table.address, table.length, |aml| context.parse_table(aml))
+        {
+            log::warn!("Failed to parse the AML table {index}: {error:?}");
+        }
+    }
+    x86_64::irq::without(|| {
+        *NAMESPACE.lock() = Some(Namespace { context, prt: None });
+    });
+}
+
+/// Tell the firmware how the interrupts are routed with `\_PIC`, initialize the devices of the
+/// namespace with their `_STA` and `_INI` methods, and evaluate the routing table of the root PCI
+/// bus, which may depend on the interrupt mode.
+pub(super) fn initialize(apic: bool) {
+    x86_64::irq::without(|| {
+        let mut namespace = NAMESPACE.lock();
+        let Some(namespace) = namespace.as_mut() else {
+            return;
+        };
+        let context = &mut namespace.context;
+
+        if apic {
+            let args = Args::from_list(alloc::vec![AmlValue::Integer(PIC_MODE_APIC)]);
+            match args.and_then(|args| context.invoke_method(&name("\\_PIC"), args)) {
+                Ok(_) | Err(AmlError::ValueDoesNotExist(_)) => (),
+                Err(error) => log::warn!("Failed to evaluate \\_PIC: {error:?}"),
+            }
+        }
+        if let Err(error) = context.initialize_objects() {
+            log::warn!("Failed to initialize the AML devices: {error:?}");
+        }
+
+        namespace.prt = PciRoutingTable::from_prt_path(&name(ROOT_PRT), context)
+            .map_err(|error| log::info!("No routing table for the root PCI bus: {error:?}"))
+            .ok();
+    });
+}
+
+/// Returns the values of the `SLP_TYP` fields of the two PM1 control registers that put the
+/// system in the given sleep state, from the `\_Sx` object, or `None` if the state is not
+/// supported.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn sleep_type(state: u8) -> Option<(u8, u8)> {
+    with_context(|context| {
+        let AmlValue::Package(package) = evaluate(context, &format!("\\_S{state}")).ok()? else {
+            return None;
+        };
+        let a = package.first()?.as_integer(context).ok()?;
+        let b = package.get(1)?.as_integer(context).ok()?;
+        Some((a as u8, b as u8))
+    })
+    .flatten()
+}
+
+/// Returns how the given interrupt pin (1 for `INTA#` to 4 for `INTD#`, as in the configuration
+/// space) of the given function of the root PCI bus is connected, according to its routing table.
+/// The functions behind a bridge are not supported.
+#[must_use]
+pub fn pci_route(device: u8, function: u8, pin: u8) -> Option<PciRoute> {
+    let pin = match pin {
+        1 => Pin::IntA,
+        2 => Pin::IntB,
+        3 => Pin::IntC,
+        4 => Pin::IntD,
+        _ => return None,
+    };
+    x86_64::irq::without(|| {
+        let mut namespace = NAMESPACE.lock();
+        let namespace = namespace.as_mut()?;
+        let irq = namespace
+            .prt
+            .as_ref()?
+            .route(
+                u16::from(device),
+                u16::from(function),
+                pin,
+                &mut namespace.context,
+            )
+            .ok()?;
+        Some(PciRoute {
+            gsi: irq.irq,
+            trigger: match irq.trigger {
+                InterruptTrigger::Edge => TriggerMode::Edge,
+                InterruptTrigger::Level => TriggerMode::Level,
+            },
+            polarity: match irq.polarity {
+                InterruptPolarity::ActiveHigh => Polarity::ActiveHigh,
+                InterruptPolarity::ActiveLow => Polarity::ActiveLow,
+            },
+            shared: irq.is_shared,
+        })
+    })
+}
+
+/// Returns the resources currently used by the device at the given path of the namespace (for
+/// example `\_SB.PCI0.SF8.COM1`), from its `_CRS` object.
+///
+/// # Errors
+/// Returns the error of the interpreter if the device or its `_CRS` object does not exist, or if
+/// the resources cannot be decoded.
+pub fn current_resources(device: &str) -> Result<Vec<Resource>, AmlError> {
+    with_context(|context| {
+        let crs = evaluate(context, &format!("{device}._CRS"))?;
+        resource::resource_descriptor_list(&crs)
+    })
+    .unwrap_or(Err(AmlError::ValueDoesNotExist(AmlName::root())))
+}
+
+/// Execute the given closure with the interpreter, or returns `None` if the namespace was not
+/// built.
+fn with_context<T>(f: impl FnOnce(&mut AmlContext) -> T) -> Option<T> {
+    x86_64::irq::without(|| {
+        let mut namespace = NAMESPACE.lock();
+        namespace
+            .as_mut()
+            .map(|namespace| f(&mut namespace.context))
+    })
+}
+
+/// Returns the value of the object at the given path: a method is invoked without arguments, and
+/// the value of any other object is returned as is.
+fn evaluate(context: &mut AmlContext, path: &str) -> Result<AmlValue, AmlError> {
+    let path = AmlName::from_str(path)?;
+    match context.namespace.get_by_path(&path)? {
+        AmlValue::Method { .. } => context.invoke_method(&path, Args::EMPTY),
+        value => Ok(value.clone()),
+    }
+}
+
+/// Returns the name of a path known to be valid.
+fn name(path: &str) -> AmlName {
+    AmlName::from_str(path).expect("Invalid AML path")
+}
+
+/// Returns the virtual address of the given physical address of an operation region. The
+/// operation regions are assumed to be in the direct mapping of the physical memory.
+fn region(address: usize) -> *mut u8 {
+    phys_to_virt(Physical::new(address as u64)).as_mut_ptr::<u8>()
+}
+
+/// Wait for the given number of microseconds, by counting the cycles of the TSC calibrated at
+/// boot. The AML code may be evaluated before the clock sources are registered.
+fn delay(microseconds: u64) {
+    let cycles = super::super::timer::calibration().tsc_hz / 1_000_000 * microseconds;
+    let start = super::super::tsc::rdtsc();
+    while super::super::tsc::rdtsc().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the PCI function whose configuration space is accessed by the AML code, or `None` if it
+/// is outside of the configuration space reachable through the I/O ports.
+fn pci_function(
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+    offset: u16,
+) -> Option<(crate::drivers::pci::Address, u8)> {
+    let offset = u8::try_from(offset).ok().filter(|_| segment == 0)?;
+    Some((
+        crate::drivers::pci::Address::new(bus, device, function),
+        offset,
+    ))
+}
+
+#[allow(clippy::cast_ptr_alignment)]
+impl ::aml::Handler for Handler {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { region(address).read_volatile() }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { region(address).cast::<u16>().read_volatile() }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { region(address).cast::<u32>().read_volatile() }
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { region(address).cast::<u64>().read_volatile() }
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        unsafe { region(address).write_volatile(value) };
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        unsafe { region(address).cast::<u16>().write_volatile(value) };
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        unsafe { region(address).cast::<u32>().write_volatile(value) };
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        unsafe { region(address).cast::<u64>().write_volatile(value) };
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) };
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) };
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) };
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        pci_function(segment, bus, device, function, offset)
+            .map_or(u8::MAX, |(address, offset)| address.read8(offset))
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        pci_function(segment, bus, device, function, offset)
+            .map_or(u16::MAX, |(address, offset)| address.read16(offset))
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        pci_function(segment, bus, device, function, offset)
+            .map_or(u32::MAX, |(address, offset)| address.read32(offset))
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        if let Some((address, offset)) = pci_function(segment, bus, device, function, offset) {
+            address.write8(offset, value);
+        }
+    }
+
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        if let Some((address, offset)) = pci_function(segment, bus, device, function, offset) {
+            address.write16(offset, value);
+        }
+    }
+
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        if let Some((address, offset)) = pci_function(segment, bus, device, function, offset) {
+            address.write32(offset, value);
+        }
+    }
+
+    fn stall(&self, microseconds: u64) {
+        delay(microseconds);
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        delay(milliseconds * 1000);
+    }
+
+    fn handle_fatal_error(&self, fatal_type: u8, fatal_code: u32, fatal_arg: u64) {
+        panic!(
+            "AML fatal error: type {fatal_type:#x}, code {fatal_code:#x}, argument {fatal_arg:#x}"
+        );
+    }
+}
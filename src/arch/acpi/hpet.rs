@@ -0,0 +1,65 @@
+use super::{read16, read32, read64, ADDRESS_SPACE_MEMORY};
+
+/// The offsets of the fields of the HPET table, after the SDT header.
+const EVENT_TIMER_BLOCK_ID: usize = 36;
+const BASE_ADDRESS_SPACE: usize = 40;
+const BASE_ADDRESS: usize = 44;
+const HPET_NUMBER: usize = 52;
+const MINIMUM_TICK: usize = 53;
+
+/// The fields of the event timer block id: the index of the last comparator, whether the main
+/// counter is 64 bits wide, whether the legacy replacement routing is supported, and the PCI vendor
+/// id of the HPET.
+const BLOCK_ID_LAST_COMPARATOR_SHIFT: u32 = 8;
+const BLOCK_ID_LAST_COMPARATOR_MASK: u32 = 0x1F;
+const BLOCK_ID_COUNTER_64_BITS: u32 = 1 << 13;
+const BLOCK_ID_LEGACY_REPLACEMENT: u32 = 1 << 15;
+const BLOCK_ID_VENDOR_SHIFT: u32 = 16;
+
+/// An HPET, as described by the HPET table. The period of the main counter is not in the table:
+/// it is read from the capabilities register of the HPET itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HpetTable {
+    /// The sequence number of the HPET, when the system has several of them.
+    pub number: u8,
+    pub vendor_id: u16,
+
+    /// The physical address of the registers of the HPET.
+    pub base_address: u64,
+
+    /// The number of comparators, at least 1, and whether the main counter is 64 bits wide.
+    pub comparators: u8,
+    pub counter_64_bits: bool,
+
+    /// Set if the HPET can replace the PIT and the RTC by routing its two first comparators to
+    /// the IRQ 0 and 8.
+    pub legacy_replacement: bool,
+
+    /// The smallest number of ticks of the main counter that can be programmed in periodic mode
+    /// without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    /// Parse the given HPET table. Returns `None` if it is truncated or if the registers are not
+    /// memory-mapped.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn parse(table: &[u8]) -> Option<Self> {
+        let block_id = read32(table, EVENT_TIMER_BLOCK_ID)?;
+        if *table.get(BASE_ADDRESS_SPACE)? != ADDRESS_SPACE_MEMORY {
+            return None;
+        }
+        Some(Self {
+            number: *table.get(HPET_NUMBER)?,
+            vendor_id: (block_id >> BLOCK_ID_VENDOR_SHIFT) as u16,
+            base_address: read64(table, BASE_ADDRESS)?,
+            comparators: ((block_id >> BLOCK_ID_LAST_COMPARATOR_SHIFT)
+                & BLOCK_ID_LAST_COMPARATOR_MASK) as u8
+                + 1,
+            counter_64_bits: block_id & BLOCK_ID_COUNTER_64_BITS != 0,
+            legacy_replacement: block_id & BLOCK_ID_LEGACY_REPLACEMENT != 0,
+            minimum_tick: read16(table, MINIMUM_TICK)?,
+        })
+    }
+}
@@ -0,0 +1,231 @@
+use alloc::vec::Vec;
+
+use crate::arch::ioapic::{Polarity, TriggerMode};
+
+use super::{read16, read32, read64};
+
+/// The offset of the first entry of the MADT, after the SDT header, the LAPIC address and the
+/// flags.
+const ENTRIES_OFFSET: usize = 44;
+
+/// Set in the flags of the MADT if the system also has 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+/// The types of the MADT entries.
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_NMI_SOURCE: u8 = 3;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+const ENTRY_LOCAL_X2APIC_NMI: u8 = 10;
+
+/// The flags of a processor entry: the processor is enabled, or can be enabled later.
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The processor identifiers meaning that a local NMI is connected to all the processors.
+const ALL_PROCESSORS: u8 = 0xFF;
+const ALL_X2APIC_PROCESSORS: u32 = 0xFFFF_FFFF;
+
+/// The interrupt topology described by the MADT: the processors and their LAPICs, the IOAPICs, and
+/// how the interrupts are connected to them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topology {
+    /// The physical address of the LAPIC registers, from the LAPIC address override entry if any.
+    pub local_apic_address: u64,
+
+    /// Set if the system also has 8259 PICs, which must be disabled to use the IOAPICs.
+    pub legacy_pics: bool,
+
+    /// All the processors, including the disabled ones, in the order of the MADT.
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmi_sources: Vec<NmiSource>,
+    pub local_nmis: Vec<LocalNmi>,
+}
+
+/// A processor, described by a LAPIC or a x2APIC entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Processor {
+    /// The ACPI processor UID, which the local NMI entries refer to.
+    pub uid: u32,
+    pub apic_id: u32,
+
+    /// Set if the processor is described by a x2APIC entry, which is used for the APIC ids that
+    /// do not fit in 8 bits.
+    pub x2apic: bool,
+
+    /// Set if the processor is usable. A processor that is not enabled can be hot-plugged later
+    /// if it is online capable.
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IoApic {
+    pub id: u8,
+
+    /// The physical address of the registers of the IOAPIC.
+    pub address: u32,
+
+    /// The first global system interrupt handled by the IOAPIC.
+    pub gsi_base: u32,
+}
+
+/// An interrupt source override: the interrupt `source` of the `bus` (always the ISA bus in
+/// practice) is connected to another global system interrupt, or with another polarity or trigger
+/// mode than the bus default. A polarity or trigger mode that conforms to the bus is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Option<Polarity>,
+    pub trigger: Option<TriggerMode>,
+}
+
+/// A global system interrupt that must be delivered as a NMI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub polarity: Option<Polarity>,
+    pub trigger: Option<TriggerMode>,
+}
+
+/// A local interrupt pin of a LAPIC connected to the NMI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LocalNmi {
+    /// The UID of the processor, or `None` for all the processors.
+    pub processor: Option<u32>,
+
+    /// The LINT pin, 0 or 1.
+    pub lint: u8,
+    pub polarity: Option<Polarity>,
+    pub trigger: Option<TriggerMode>,
+}
+
+impl Topology {
+    /// Parse the given MADT. The unknown or truncated entries are ignored.
+    #[must_use]
+    pub fn parse(madt: &[u8]) -> Self {
+        let mut topology = Self {
+            local_apic_address: read32(madt, 36).map_or(0, u64::from),
+            legacy_pics: read32(madt, 40).is_some_and(|flags| flags & MADT_PCAT_COMPAT != 0),
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmi_sources: Vec::new(),
+            local_nmis: Vec::new(),
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while let (Some(&kind), Some(&len)) = (madt.get(offset), madt.get(offset + 1)) {
+            let len = usize::from(len);
+            let Some(entry) = madt.get(offset..offset + len).filter(|_| len >= 2) else {
+                log::warn!("Truncated MADT entry at offset {offset}");
+                break;
+            };
+            if topology.parse_entry(kind, entry).is_none() {
+                log::debug!("Ignoring MADT entry of type {kind} and length {len}");
+            }
+            offset += len;
+        }
+        topology
+    }
+
+    /// Returns the processors that are enabled.
+    pub fn enabled_processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors.iter().filter(|processor| processor.enabled)
+    }
+
+    /// Parse an entry of the MADT. Returns `None` if the entry is unknown or too short.
+    fn parse_entry(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            ENTRY_LOCAL_APIC => {
+                let flags = read32(entry, 4)?;
+                self.processors.push(Processor {
+                    uid: u32::from(*entry.get(2)?),
+                    apic_id: u32::from(*entry.get(3)?),
+                    x2apic: false,
+                    enabled: flags & PROCESSOR_ENABLED != 0,
+                    online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                });
+            }
+            ENTRY_IO_APIC => self.io_apics.push(IoApic {
+                id: *entry.get(2)?,
+                address: read32(entry, 4)?,
+                gsi_base: read32(entry, 8)?,
+            }),
+            ENTRY_INTERRUPT_OVERRIDE => {
+                let (polarity, trigger) = inti_flags(read16(entry, 8)?);
+                self.overrides.push(InterruptOverride {
+                    bus: *entry.get(2)?,
+                    source: *entry.get(3)?,
+                    gsi: read32(entry, 4)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            ENTRY_NMI_SOURCE => {
+                let (polarity, trigger) = inti_flags(read16(entry, 2)?);
+                self.nmi_sources.push(NmiSource {
+                    gsi: read32(entry, 4)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                let uid = *entry.get(2)?;
+                let (polarity, trigger) = inti_flags(read16(entry, 3)?);
+                self.local_nmis.push(LocalNmi {
+                    processor: (uid != ALL_PROCESSORS).then_some(u32::from(uid)),
+                    lint: *entry.get(5)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS => self.local_apic_address = read64(entry, 4)?,
+            ENTRY_LOCAL_X2APIC => {
+                let flags = read32(entry, 8)?;
+                self.processors.push(Processor {
+                    uid: read32(entry, 12)?,
+                    apic_id: read32(entry, 4)?,
+                    x2apic: true,
+                    enabled: flags & PROCESSOR_ENABLED != 0,
+                    online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                });
+            }
+            ENTRY_LOCAL_X2APIC_NMI => {
+                let uid = read32(entry, 4)?;
+                let (polarity, trigger) = inti_flags(read16(entry, 2)?);
+                self.local_nmis.push(LocalNmi {
+                    processor: (uid != ALL_X2APIC_PROCESSORS).then_some(uid),
+                    lint: *entry.get(8)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Decode the MPS INTI flags of an entry: the polarity in the bits 0 and 1, and the trigger mode
+/// in the bits 2 and 3. The values that conform to the bus, and the reserved ones, are `None`.
+fn inti_flags(flags: u16) -> (Option<Polarity>, Option<TriggerMode>) {
+    let polarity = match flags & 0b11 {
+        0b01 => Some(Polarity::ActiveHigh),
+        0b11 => Some(Polarity::ActiveLow),
+        _ => None,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => Some(TriggerMode::Edge),
+        0b11 => Some(TriggerMode::Level),
+        _ => None,
+    };
+    (polarity, trigger)
+}
@@ -0,0 +1,619 @@
+use crate::{
+    mm::{
+        frame::Frame,
+        vmm::{self, AllocationFlags},
+    },
+    LIMINE_RSDP,
+};
+
+use super::{
+    address::virt_to_phys,
+    msr::Pat,
+    mtrr::{self, Mtrrs},
+    paging::{self, MapFlags},
+};
+use acpi::{fadt::Fadt, sdt::Signature, AcpiHandler as _, AmlTable, Sdt};
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use sync::Once;
+use x86_64::{
+    address::{Virtual, VirtualRange},
+    paging::PAGE_SIZE,
+};
+
+pub mod aml;
+pub mod hpet;
+pub mod madt;
+pub mod numa;
+
+pub use hpet::HpetTable;
+pub use madt::Topology;
+pub use numa::NumaTopology;
+
+pub const CALL_FUNCTION_VECTOR: u8 = 0xF0;
+pub const CLOCK_TICK_VECTOR: u8 = 0xF1;
+pub const HPET_VECTOR: u8 = 0xF2;
+pub const RESCHEDULE_VECTOR: u8 = 0xF3;
+pub const PMU_VECTOR: u8 = 0xF4;
+pub const LAPIC_ERROR_VECTOR: u8 = 0xFE;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The entry of the PAT selected by the PCD and PWT bits of the device registers mappings.
+const MMIO_PAT_ENTRY: usize = 3;
+
+/// The frequency of the ACPI power management timer, in Hz.
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+/// Set in the flags of the FADT if the PM timer has 32 bits instead of 24 bits.
+const FADT_TIMER_EXTENDED: u32 = 1 << 8;
+
+/// Set in the flags of the FADT if the reset register is supported.
+const FADT_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+/// The address spaces of a generic address structure.
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+
+/// The signature of the RSDP, and the offsets of its fields.
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const RSDP_REVISION: usize = 15;
+const RSDP_RSDT_ADDRESS: usize = 16;
+const RSDP_XSDT_ADDRESS: usize = 24;
+
+/// The length of the RSDP of the revision 0, covered by the first checksum, and of the RSDP of
+/// the revision 2, covered by the extended checksum.
+const RSDP_V1_LENGTH: u32 = 20;
+const RSDP_V2_LENGTH: u32 = 36;
+
+/// The size of the header of the system description tables, and the offset of the length of the
+/// table in it.
+const SDT_HEADER_SIZE: u32 = 36;
+const SDT_LENGTH: usize = 4;
+
+/// The AML opcodes used to encode the `\_S5` object.
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_PREFIX: u8 = b'\\';
+
+/// The interrupt topology described by the MADT, saved by [`setup`].
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// The NUMA topology described by the SRAT and the SLIT, saved by [`setup`] if there is a SRAT.
+static NUMA_TOPOLOGY: Once<NumaTopology> = Once::new();
+
+/// The power management registers described in the FADT, saved by [`setup`].
+static FIXED_HARDWARE: Once<FixedHardware> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AcpiError {
+    /// The bootloader did not provide the RSDP.
+    NoRsdp,
+
+    /// The signature or a checksum of the RSDP is invalid.
+    InvalidRsdp,
+
+    /// Neither the XSDT nor the RSDT is valid, so no table can be found.
+    InvalidRootTable,
+
+    /// The MADT is missing or corrupted, so the LAPICs and the IOAPICs cannot be found.
+    NoMadt,
+}
+
+/// The ACPI fixed hardware registers used by the kernel, as described by the FADT. The register
+/// blocks are I/O ports, and an absent block is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FixedHardware {
+    /// The ISA IRQ line of the System Control Interrupt, if it is connected to one.
+    pub sci: Option<u8>,
+
+    /// The port receiving the ACPI enable command, and the value of this command. ACPI is
+    /// already enabled, or cannot be enabled by the kernel, if they are absent.
+    pub smi_command: Option<u16>,
+    pub acpi_enable: u8,
+
+    /// The PM1 event blocks: the status register fills the first half of a block, and the enable
+    /// register the second half.
+    pub pm1a_event: Option<u16>,
+    pub pm1b_event: Option<u16>,
+    pub pm1_event_length: u8,
+
+    /// The PM1 control blocks.
+    pub pm1a_control: Option<u16>,
+    pub pm1b_control: Option<u16>,
+    pub pm1_control_length: u8,
+
+    /// The ACPI power management timer, if the platform has one.
+    pub pm_timer: Option<PmTimer>,
+
+    /// The index of the CMOS register holding the century of the RTC date, if any.
+    pub century: Option<u8>,
+
+    /// The values of the `SLP_TYP` fields of the two PM1 control registers that put the
+    /// system in the S5 state (soft off), found in the DSDT.
+    pub s5_sleep_type: Option<(u8, u8)>,
+
+    /// The register resetting the system when the reset value is written to it, if the platform
+    /// supports it.
+    pub reset_register: Option<ResetRegister>,
+    pub reset_value: u8,
+}
+
+/// The ACPI power management timer: a free-running counter at [`PM_TIMER_FREQUENCY`], read from
+/// an I/O port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PmTimer {
+    pub port: u16,
+
+    /// Set if the counter has 32 bits instead of 24 bits.
+    pub extended: bool,
+}
+
+/// The location of the ACPI reset register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResetRegister {
+    /// An I/O port.
+    Io(u16),
+
+    /// A memory-mapped register, at the given virtual address. It is mapped once by [`setup`], so
+    /// that the system can be reset without allocating memory.
+    Memory(u64),
+
+    /// A register in the configuration space of a function of the PCI bus 0.
+    PciConfig {
+        device: u8,
+        function: u8,
+        offset: u8,
+    },
+}
+
+impl FixedHardware {
+    fn new(fadt: &Fadt, sci: Option<u8>, s5_sleep_type: Option<(u8, u8)>) -> Self {
+        let port = |block: u32| u16::try_from(block).ok().filter(|&port| port != 0);
+        let acpi_enable = fadt.acpi_enable;
+        let flags = fadt.flags;
+        Self {
+            sci,
+            smi_command: port(fadt.smi_cmd_port).filter(|_| acpi_enable != 0),
+            acpi_enable,
+            pm1a_event: port(fadt.pm1a_event_block),
+            pm1b_event: port(fadt.pm1b_event_block),
+            pm1_event_length: fadt.pm1_event_length,
+            pm1a_control: port(fadt.pm1a_control_block),
+            pm1b_control: port(fadt.pm1b_control_block),
+            pm1_control_length: fadt.pm1_control_length,
+            pm_timer: port(fadt.pm_timer_block)
+                .filter(|_| fadt.pm_timer_length >= 4)
+                .map(|port| PmTimer {
+                    port,
+                    extended: flags & FADT_TIMER_EXTENDED != 0,
+                }),
+            century: Some(fadt.century).filter(|&century| century != 0),
+            s5_sleep_type,
+            reset_register: ResetRegister::new(fadt),
+            reset_value: fadt.reset_value,
+        }
+    }
+}
+
+impl ResetRegister {
+    /// Returns the reset register described in the FADT, if the platform supports it. It only
+    /// exists since the revision 2 of the FADT.
+    #[allow(clippy::cast_possible_truncation)]
+    fn new(fadt: &Fadt) -> Option<Self> {
+        let revision = fadt.header.revision;
+        let flags = fadt.flags;
+        let register = fadt.reset_reg;
+        let address = register.address;
+        if revision < 2 || flags & FADT_RESET_REGISTER_SUPPORTED == 0 || address == 0 {
+            return None;
+        }
+
+        match register.address_space {
+            ADDRESS_SPACE_MEMORY => {
+                unsafe { remap_mmio(address, 1) }.map(|virt| Self::Memory(virt.as_u64()))
+            }
+            ADDRESS_SPACE_IO => u16::try_from(address).ok().map(Self::Io),
+            ADDRESS_SPACE_PCI_CONFIG => Some(Self::PciConfig {
+                device: (address >> 32) as u8,
+                function: (address >> 16) as u8,
+                offset: address as u8,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash)]
+struct AcpiHandler {}
+
+impl AcpiHandler {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {}
+    }
+}
+
+impl acpi::AcpiHandler for AcpiHandler {
+    unsafe fn map_physical_region<T>(
+        &self,
+        phys: usize,
+        size: usize,
+    ) -> acpi::PhysicalMapping<Self, T> {
+        // The region may cross a page boundary even if it is smaller than a page
+        let aligned_phys = phys - (phys % PAGE_SIZE);
+        let offset = phys - aligned_phys;
+        let aligned_size = (offset + size).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let flags = MapFlags::PRESENT
+            | MapFlags::WRITABLE
+            | MapFlags::NO_EXECUTE
+            | MapFlags::NO_CACHE
+            | MapFlags::WRITE_THROUGH;
+        let virt = vmm::allocate(aligned_size, AllocationFlags::NONE)
+            .unwrap()
+            .start();
+
+        for i in (aligned_phys..(aligned_phys + aligned_size)).step_by(PAGE_SIZE) {
+            paging::map_current(virt + (i - aligned_phys), Frame::from_u64(i as u64), flags)
+                .unwrap();
+        }
+
+        acpi::PhysicalMapping::new(
+            aligned_phys + offset,
+            NonNull::new((virt + offset).as_mut_ptr()).unwrap(),
+            size,
+            aligned_size - offset,
+            *self,
+        )
+    }
+
+    fn unmap_physical_region<T>(mapping: &acpi::PhysicalMapping<Self, T>) {
+        let virt = Virtual::new(mapping.virtual_start().as_ptr() as u64);
+        let start = virt.page_align_down();
+        let end = (virt + mapping.mapped_length() as u64).page_align_up();
+
+        for i in (start..end).step_by(PAGE_SIZE) {
+            unsafe {
+                _ = paging::unmap_current(i).unwrap();
+            }
+        }
+
+        let range = VirtualRange::new(start, end);
+        vmm::deallocate(range);
+    }
+}
+
+/// Setup ACPI and everything related to it.
+/// Currently, this function initializes the LAPIC and enable it on the core which called this
+/// function, starts its timer as the clock tick source, and routes the legacy IRQs through the
+/// IOAPICs described in the MADT. Other cores will have to enable their LAPIC and their timer
+/// themselves.
+///
+/// The checksums of the RSDP and of the tables are verified: the XSDT is preferred, but the RSDT
+/// is used if the XSDT is absent or corrupted, and the other corrupted tables are skipped.
+///
+/// # Errors
+/// If the RSDP, both root tables or the MADT are missing or corrupted, nothing is initialized and
+/// an error is returned: the kernel can then only use the 8259 PIC and the PIT.
+pub fn setup() -> Result<(), AcpiError> {
+    let rsdp = LIMINE_RSDP
+        .get_response()
+        .get()
+        .and_then(|response| response.address.as_ptr())
+        .ok_or(AcpiError::NoRsdp)?;
+    let address = usize::try_from(virt_to_phys(Virtual::new(rsdp as u64)).as_u64()).unwrap();
+    let rsdp = unsafe { root_tables(address)? };
+
+    // The interrupt topology is parsed once, for the LAPICs, the IOAPICs and the IRQ routing
+    let madt = table(&rsdp, Signature::MADT).ok_or(AcpiError::NoMadt)?;
+    let madt = unsafe { map_table(madt.physical_address, madt.length, Topology::parse) };
+    let topology = TOPOLOGY.call_once(|| madt);
+    log::info!(
+        "MADT: {} processors ({} enabled), {} IOAPICs",
+        topology.processors.len(),
+        topology.enabled_processors().count(),
+        topology.io_apics.len()
+    );
+
+    // The SLIT is only meaningful with the proximity domains of the SRAT
+    if let Some(srat) = table(&rsdp, Signature::SRAT) {
+        let slit = table(&rsdp, Signature::SLIT);
+        let numa = unsafe {
+            map_table(srat.physical_address, srat.length, |srat| match slit {
+                Some(slit) => map_table(slit.physical_address, slit.length, |slit| {
+                    NumaTopology::parse(srat, Some(slit))
+                }),
+                None => NumaTopology::parse(srat, None),
+            })
+        };
+        let numa = NUMA_TOPOLOGY.call_once(|| numa);
+        log::info!(
+            "SRAT: {} proximity domains, {} processors, {} memory ranges",
+            numa.domains(),
+            numa.processors.len(),
+            numa.memory.len()
+        );
+    }
+
+    super::lapic::setup(topology.local_apic_address);
+    super::spurious::enable();
+    super::pmu::setup();
+
+    // The HPET is used as the reference clock to calibrate the LAPIC timer
+    let hpet = table(&rsdp, Signature::HPET).and_then(|hpet| unsafe {
+        map_table(hpet.physical_address, hpet.length, HpetTable::parse)
+    });
+    match hpet {
+        Some(hpet) => super::hpet::setup(&hpet),
+        None => log::info!("No HPET found, the PIT will be used for calibration"),
+    }
+    super::timer::setup();
+
+    // The SCI is usually connected to an ISA IRQ, with its own polarity and trigger mode
+    let fadt = unsafe { rsdp.get_sdt::<Fadt>(Signature::FADT) }
+        .inspect_err(|error| log::warn!("Skipping the corrupted FADT: {error:?}"))
+        .ok()
+        .flatten();
+    let sci = fadt.as_ref().and_then(|fadt| {
+        u8::try_from(fadt.sci_interrupt)
+            .ok()
+            .filter(|&irq| irq < super::ioapic::ISA_IRQ_COUNT)
+    });
+    super::ioapic::setup(topology, sci);
+
+    // The AML namespace is initialized once the interrupt mode is known, because the firmware
+    // may route the PCI interrupts differently with the IOAPICs
+    let dsdt = rsdp.dsdt.as_ref().filter(|dsdt| aml_table_valid(dsdt));
+    let ssdts = rsdp
+        .ssdts
+        .iter()
+        .copied()
+        .filter(aml_table_valid)
+        .collect::<Vec<_>>();
+    unsafe { aml::setup(dsdt, &ssdts) };
+    aml::initialize(!topology.io_apics.is_empty());
+
+    if let Some(fadt) = &fadt {
+        let s5 =
+            aml::sleep_type(5).or_else(|| dsdt.and_then(|dsdt| unsafe { s5_sleep_type(dsdt) }));
+        if s5.is_none() {
+            log::warn!("No \\_S5 object found in the DSDT, ACPI cannot power off the system");
+        }
+        FIXED_HARDWARE.call_once(|| FixedHardware::new(fadt, sci, s5));
+    }
+    super::hpet::setup_events();
+
+    // The LAPIC timer produces the clock tick, the PIT is only used as a reference clock
+    super::pit::retire();
+    Ok(())
+}
+
+/// Validate the RSDP at the given physical address, and parse the root table it points to: the
+/// XSDT if the RSDP has one and it is valid, or the RSDT otherwise.
+///
+/// # Errors
+/// - [`AcpiError::InvalidRsdp`]: The signature or a checksum of the RSDP is invalid.
+/// - [`AcpiError::InvalidRootTable`]: Neither the XSDT nor the RSDT is valid.
+unsafe fn root_tables(address: usize) -> Result<acpi::AcpiTables<AcpiHandler>, AcpiError> {
+    let rsdp = map_table(address, RSDP_V2_LENGTH, <[u8]>::to_vec);
+    let v1 = &rsdp[..RSDP_V1_LENGTH as usize];
+    if !v1.starts_with(RSDP_SIGNATURE) || !checksum_valid(v1) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    // The extended fields only exist since the revision 2 of the RSDP
+    let revision = rsdp[RSDP_REVISION];
+    let xsdt_address = if revision >= 2 {
+        if !checksum_valid(&rsdp) {
+            return Err(AcpiError::InvalidRsdp);
+        }
+        read64(&rsdp, RSDP_XSDT_ADDRESS)
+            .and_then(|address| usize::try_from(address).ok())
+            .filter(|&address| address != 0)
+    } else {
+        None
+    };
+
+    if let Some(xsdt) = xsdt_address {
+        if root_table_valid(xsdt, Signature::XSDT) {
+            if let Ok(tables) = acpi::AcpiTables::from_rsdt(AcpiHandler::new(), revision, xsdt) {
+                return Ok(tables);
+            }
+        }
+        log::warn!("Corrupted XSDT, falling back to the RSDT");
+    }
+
+    let rsdt_address = read32(&rsdp, RSDP_RSDT_ADDRESS)
+        .and_then(|address| usize::try_from(address).ok())
+        .filter(|&address| address != 0 && root_table_valid(address, Signature::RSDT))
+        .ok_or(AcpiError::InvalidRootTable)?;
+    acpi::AcpiTables::from_rsdt(AcpiHandler::new(), 0, rsdt_address)
+        .inspect_err(|error| log::error!("Failed to parse the RSDT: {error:?}"))
+        .map_err(|_| AcpiError::InvalidRootTable)
+}
+
+/// Returns true if the root table at the given physical address has the given signature and a
+/// valid checksum.
+unsafe fn root_table_valid(address: usize, signature: Signature) -> bool {
+    let len = map_table(address, SDT_HEADER_SIZE, |header| {
+        read32(header, SDT_LENGTH)
+    });
+    let Some(len) = len.filter(|&len| len >= SDT_HEADER_SIZE) else {
+        return false;
+    };
+    map_table(address, len, |table| {
+        table.starts_with(signature.as_str().as_bytes()) && checksum_valid(table)
+    })
+}
+
+/// Returns the table with the given signature, or `None` if it is absent or if its checksum is
+/// invalid. A corrupted table is logged and skipped.
+fn table(tables: &acpi::AcpiTables<AcpiHandler>, signature: Signature) -> Option<Sdt> {
+    let sdt = *tables.sdts.get(&signature)?;
+    if unsafe { map_table(sdt.physical_address, sdt.length, checksum_valid) } {
+        Some(sdt)
+    } else {
+        log::warn!("Skipping the corrupted {signature} table");
+        None
+    }
+}
+
+/// Returns true if the checksum of the given AML table, including its header, is valid. A corrupted
+/// table is logged.
+fn aml_table_valid(table: &AmlTable) -> bool {
+    let address = table.address - SDT_HEADER_SIZE as usize;
+    let valid = unsafe {
+        map_table(address, table.length + SDT_HEADER_SIZE, |table| {
+            checksum_valid(table)
+        })
+    };
+    if !valid {
+        log::warn!("Skipping the corrupted AML table at {address:#x}");
+    }
+    valid
+}
+
+/// Returns true if the sum of all the bytes of the given structure is zero, which is how the ACPI
+/// structures are checksummed.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Find the sleep type values of the S5 state in the given DSDT.
+unsafe fn s5_sleep_type(dsdt: &AmlTable) -> Option<(u8, u8)> {
+    map_table(dsdt.address, dsdt.length, |aml| {
+        (0..aml.len().saturating_sub(4))
+            .filter(|&index| &aml[index..index + 4] == b"_S5_")
+            .find_map(|index| parse_s5(aml, index))
+    })
+}
+
+/// Map the ACPI table at the given physical address, with its header, and give its bytes to the
+/// given closure. The table is unmapped when the closure returns.
+unsafe fn map_table<T>(address: usize, len: u32, f: impl FnOnce(&[u8]) -> T) -> T {
+    let len = usize::try_from(len).unwrap();
+    let mapping = AcpiHandler::new().map_physical_region::<u8>(address, len);
+    f(core::slice::from_raw_parts(
+        mapping.virtual_start().as_ptr(),
+        len,
+    ))
+}
+
+/// A minimal parser of the `\_S5` object, used if the AML interpreter fails to evaluate it. The
+/// object is almost always defined as `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })`, at the
+/// given index of its name in the AML: the two first elements of the package are returned. Any
+/// other encoding, such as a method computing the package, is not supported.
+fn parse_s5(aml: &[u8], index: usize) -> Option<(u8, u8)> {
+    // The name is preceded by the Name operator, possibly with a root prefix
+    let name = match aml.get(index.checked_sub(1)?)? {
+        &AML_ROOT_PREFIX => index.checked_sub(2)?,
+        _ => index - 1,
+    };
+    if aml[name] != AML_NAME_OP {
+        return None;
+    }
+
+    let mut bytes = aml[index + 4..].iter().copied();
+    if bytes.next()? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // The two high bits of the package length give the number of bytes that follow it, and the
+    // number of elements comes next
+    let lead = bytes.next()?;
+    for _ in 0..=(lead >> 6) {
+        bytes.next()?;
+    }
+
+    let mut integer = || match bytes.next()? {
+        AML_ZERO_OP => Some(0),
+        AML_ONE_OP => Some(1),
+        AML_BYTE_PREFIX => bytes.next(),
+        _ => None,
+    };
+    Some((integer()?, integer()?))
+}
+
+/// Returns the interrupt topology described by the MADT, or `None` if [`setup`] has not been called
+/// or failed.
+#[must_use]
+pub fn topology() -> Option<&'static Topology> {
+    TOPOLOGY.get()
+}
+
+/// Returns the NUMA topology described by the SRAT and the SLIT, or `None` if there is no SRAT:
+/// the system then has a single proximity domain. The memory allocator and the scheduler use it to
+/// place the memory and the threads close to each other.
+#[must_use]
+pub fn numa_topology() -> Option<&'static NumaTopology> {
+    NUMA_TOPOLOGY.get()
+}
+
+/// Returns the power management registers described in the FADT, or `None` if there is no FADT.
+/// The shutdown and reboot code, the ACPI PM timer and the RTC use them instead of hard-coded
+/// addresses.
+#[must_use]
+pub fn fadt() -> Option<&'static FixedHardware> {
+    FIXED_HARDWARE.get()
+}
+
+/// Remap the registers of a memory-mapped device (LAPIC, IOAPIC, PCI BAR...) to a virtual address.
+/// The registers are mapped uncached, and the mapping is never removed.
+///
+/// # Errors
+/// If an error occurs, the registers are not remapped and `None` is returned. Otherwise, the
+/// virtual address of the registers is returned, wrapped in `Some`.
+#[must_use]
+pub unsafe fn remap_mmio(base: u64, len: usize) -> Option<Virtual> {
+    let aligned_base = base - (base % PAGE_SIZE as u64);
+    let offset = base - aligned_base;
+    let size = usize::try_from(offset).ok()? + len;
+    let size = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+    // The registers are mapped with the PCD and PWT bits, which select this entry of the PAT. A
+    // registers range cached because of the PAT or of the MTRRs is a bug, which would make the
+    // driver read stale values and delay its writes
+    let pat = Pat::read().0[MMIO_PAT_ENTRY];
+    match mtrr::effective_type(&Mtrrs::read(), aligned_base, size as u64, pat) {
+        Some(kind) if !kind.cacheable() => (),
+        kind => log::warn!(
+            "MMIO range {:#x}-{:#x} mapped with memory type {kind:?}",
+            aligned_base,
+            aligned_base + size as u64
+        ),
+    }
+
+    let flags = MapFlags::PRESENT
+        | MapFlags::WRITABLE
+        | MapFlags::NO_EXECUTE
+        | MapFlags::NO_CACHE
+        | MapFlags::WRITE_THROUGH;
+    let virt = vmm::allocate(size, AllocationFlags::NONE).ok()?.start();
+    for page in (0..size).step_by(PAGE_SIZE) {
+        let frame = Frame::from_u64(aligned_base + page as u64);
+        paging::map_current(virt + page as u64, frame, flags).ok()?;
+    }
+    Some(virt + offset)
+}
+
+/// Read little-endian integers at the given offset of a table, or `None` if the table is too short.
+fn read16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
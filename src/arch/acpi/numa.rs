@@ -0,0 +1,197 @@
+use alloc::vec::Vec;
+
+use super::{read32, read64};
+
+/// The offset of the first entry of the SRAT, after the SDT header and the reserved fields.
+const SRAT_ENTRIES_OFFSET: usize = 48;
+
+/// The offset of the number of localities in the SLIT, and of the distance matrix which follows.
+const SLIT_LOCALITIES_OFFSET: usize = 36;
+const SLIT_MATRIX_OFFSET: usize = 44;
+
+/// The types of the SRAT entries.
+const ENTRY_LOCAL_APIC_AFFINITY: u8 = 0;
+const ENTRY_MEMORY_AFFINITY: u8 = 1;
+const ENTRY_LOCAL_X2APIC_AFFINITY: u8 = 2;
+
+/// The flags of an affinity entry: the entry must be ignored if it is not enabled.
+const AFFINITY_ENABLED: u32 = 1 << 0;
+
+/// The flags of a memory affinity entry.
+const MEMORY_HOT_PLUGGABLE: u32 = 1 << 1;
+const MEMORY_NON_VOLATILE: u32 = 1 << 2;
+
+/// The relative distance from a domain to itself, and the distance assumed between two different
+/// domains when there is no SLIT.
+pub const LOCAL_DISTANCE: u8 = 10;
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// The NUMA topology described by the SRAT and the SLIT: the proximity domain of each processor
+/// and of each memory range, and the relative distances between the domains.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NumaTopology {
+    /// The enabled processors, in the order of the SRAT.
+    pub processors: Vec<ProcessorAffinity>,
+
+    /// The enabled memory ranges, in the order of the SRAT.
+    pub memory: Vec<MemoryAffinity>,
+
+    /// The number of localities of the SLIT, and its distance matrix in row-major order. Both are
+    /// empty if there is no SLIT.
+    localities: usize,
+    distances: Vec<u8>,
+}
+
+/// The proximity domain of a processor, identified by its APIC id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProcessorAffinity {
+    pub apic_id: u32,
+    pub domain: u32,
+}
+
+/// The proximity domain of a range of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryAffinity {
+    pub base: u64,
+    pub length: u64,
+    pub domain: u32,
+
+    /// Set if the range can be hot-plugged or removed, and if it is non-volatile memory.
+    pub hot_pluggable: bool,
+    pub non_volatile: bool,
+}
+
+impl NumaTopology {
+    /// Parse the given SRAT, and the given SLIT if any. The unknown, disabled or truncated entries
+    /// are ignored, as is a truncated SLIT.
+    #[must_use]
+    pub fn parse(srat: &[u8], slit: Option<&[u8]>) -> Self {
+        let mut topology = Self {
+            processors: Vec::new(),
+            memory: Vec::new(),
+            localities: 0,
+            distances: Vec::new(),
+        };
+
+        let mut offset = SRAT_ENTRIES_OFFSET;
+        while let (Some(&kind), Some(&len)) = (srat.get(offset), srat.get(offset + 1)) {
+            let len = usize::from(len);
+            let Some(entry) = srat.get(offset..offset + len).filter(|_| len >= 2) else {
+                log::warn!("Truncated SRAT entry at offset {offset}");
+                break;
+            };
+            if topology.parse_entry(kind, entry).is_none() {
+                log::debug!("Ignoring SRAT entry of type {kind} and length {len}");
+            }
+            offset += len;
+        }
+
+        if let Some(slit) = slit {
+            let localities = read64(slit, SLIT_LOCALITIES_OFFSET)
+                .and_then(|count| usize::try_from(count).ok())
+                .unwrap_or(0);
+            let matrix = localities
+                .checked_mul(localities)
+                .and_then(|size| slit.get(SLIT_MATRIX_OFFSET..SLIT_MATRIX_OFFSET + size));
+            if let Some(matrix) = matrix {
+                topology.localities = localities;
+                topology.distances = matrix.to_vec();
+            } else {
+                log::warn!("Truncated SLIT with {localities} localities, ignoring it");
+            }
+        }
+        topology
+    }
+
+    /// Returns the number of proximity domains, which are numbered from zero. A domain without
+    /// processors nor memory is counted if a higher domain exists.
+    #[must_use]
+    pub fn domains(&self) -> u32 {
+        self.processors
+            .iter()
+            .map(|processor| processor.domain)
+            .chain(self.memory.iter().map(|memory| memory.domain))
+            .max()
+            .map_or(0, |domain| domain + 1)
+    }
+
+    /// Returns the proximity domain of the processor with the given APIC id, or `None` if the SRAT
+    /// does not describe it.
+    #[must_use]
+    pub fn processor_domain(&self, apic_id: u32) -> Option<u32> {
+        self.processors
+            .iter()
+            .find(|processor| processor.apic_id == apic_id)
+            .map(|processor| processor.domain)
+    }
+
+    /// Returns the proximity domain of the memory at the given physical address, or `None` if the
+    /// SRAT does not describe it.
+    #[must_use]
+    pub fn memory_domain(&self, address: u64) -> Option<u32> {
+        self.memory
+            .iter()
+            .find(|memory| {
+                (memory.base..memory.base.saturating_add(memory.length)).contains(&address)
+            })
+            .map(|memory| memory.domain)
+    }
+
+    /// Returns the relative distance between two proximity domains, where [`LOCAL_DISTANCE`] is
+    /// the distance from a domain to itself. Without a SLIT, or for a domain that it does not
+    /// describe, two different domains are [`REMOTE_DISTANCE`] apart.
+    #[must_use]
+    pub fn distance(&self, from: u32, to: u32) -> u8 {
+        let (Ok(from), Ok(to)) = (usize::try_from(from), usize::try_from(to)) else {
+            return REMOTE_DISTANCE;
+        };
+        if from < self.localities && to < self.localities {
+            self.distances[from * self.localities + to]
+        } else if from == to {
+            LOCAL_DISTANCE
+        } else {
+            REMOTE_DISTANCE
+        }
+    }
+
+    /// Parse an entry of the SRAT. Returns `None` if the entry is unknown or too short. The
+    /// disabled entries are parsed but not recorded.
+    fn parse_entry(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            ENTRY_LOCAL_APIC_AFFINITY => {
+                // The proximity domain is split between the byte 2 (low bits) and the bytes 9 to
+                // 11 (high bits)
+                let high = entry.get(9..12)?;
+                let domain = u32::from_le_bytes([*entry.get(2)?, high[0], high[1], high[2]]);
+                let apic_id = u32::from(*entry.get(3)?);
+                if read32(entry, 4)? & AFFINITY_ENABLED != 0 {
+                    self.processors.push(ProcessorAffinity { apic_id, domain });
+                }
+            }
+            ENTRY_MEMORY_AFFINITY => {
+                let flags = read32(entry, 28)?;
+                let memory = MemoryAffinity {
+                    base: read64(entry, 8)?,
+                    length: read64(entry, 16)?,
+                    domain: read32(entry, 2)?,
+                    hot_pluggable: flags & MEMORY_HOT_PLUGGABLE != 0,
+                    non_volatile: flags & MEMORY_NON_VOLATILE != 0,
+                };
+                if flags & AFFINITY_ENABLED != 0 {
+                    self.memory.push(memory);
+                }
+            }
+            ENTRY_LOCAL_X2APIC_AFFINITY => {
+                let processor = ProcessorAffinity {
+                    apic_id: read32(entry, 8)?,
+                    domain: read32(entry, 4)?,
+                };
+                if read32(entry, 12)? & AFFINITY_ENABLED != 0 {
+                    self.processors.push(processor);
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
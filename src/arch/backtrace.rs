@@ -0,0 +1,233 @@
+//! Stack traces built by following the chain of frame pointers. The kernel is built with frame
+//! pointers, so each function saves the frame pointer of its caller at the start of its frame,
+//! right below its return address. The chain ends with a null frame pointer (see
+//! [`super::_start`]), or with a frame outside of the known stacks.
+//!
+//! A backtrace is taken when the stack may be corrupted, and the walk must not fault in the panic
+//! handler: a frame is only followed if it lies in one of the stacks known to the current CPU,
+//! which are its boot stack, the kernel stack of the running thread and its IST stacks. The walk
+//! can cross from an IST stack to the interrupted stack, but it never goes down a stack, so that a
+//! corrupted chain cannot loop.
+use core::{
+    fmt,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{cpu::State, paging::PAGE_SIZE};
+
+use crate::config::BOOT_STACK_SIZE;
+
+use super::tss::IstIndex;
+
+/// The maximum number of frames of a backtrace.
+pub const MAX_FRAMES: usize = 24;
+
+/// The number of entries of the Interrupt Stack Table.
+const IST_COUNT: usize = 7;
+
+/// The size of a frame record: the saved frame pointer followed by the return address.
+const FRAME_SIZE: u64 = 16;
+
+crate::per_cpu! {
+    /// The stacks each CPU can run on.
+    static STACKS: Stacks = Stacks::new();
+}
+
+/// The bounds of a stack. Atomics are used so that the bounds can be read from an NMI.
+struct Bounds {
+    bottom: AtomicU64,
+    top: AtomicU64,
+}
+
+struct Stacks {
+    /// The stack given by the bootloader, which is also the stack of the idle thread.
+    boot: Bounds,
+    /// The kernel stack of the running thread, or an empty range for the idle thread.
+    thread: Bounds,
+    /// The IST stacks, indexed by IST index minus one.
+    ist: [Bounds; IST_COUNT],
+}
+
+/// The return addresses of the frames of a stack, from the innermost one.
+#[derive(Debug, Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Bounds {
+    const fn new() -> Self {
+        Self {
+            bottom: AtomicU64::new(0),
+            top: AtomicU64::new(0),
+        }
+    }
+
+    fn set(&self, stack: Range<u64>) {
+        self.bottom.store(stack.start, Ordering::Relaxed);
+        self.top.store(stack.end, Ordering::Relaxed);
+    }
+
+    /// Returns true if a frame record at the given address lies entirely in the stack.
+    fn contains(&self, frame: u64) -> bool {
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        let top = self.top.load(Ordering::Relaxed);
+        frame >= bottom && frame.checked_add(FRAME_SIZE).is_some_and(|end| end <= top)
+    }
+}
+
+impl Stacks {
+    const fn new() -> Self {
+        Self {
+            boot: Bounds::new(),
+            thread: Bounds::new(),
+            ist: [const { Bounds::new() }; IST_COUNT],
+        }
+    }
+
+    /// Returns the stack containing a frame record at the given address, if it is a known stack.
+    fn find(&self, frame: u64) -> Option<&Bounds> {
+        [&self.thread, &self.boot]
+            .into_iter()
+            .chain(&self.ist)
+            .find(|stack| stack.contains(frame))
+    }
+}
+
+impl Backtrace {
+    /// Take the backtrace of the caller. The frame of this function is not included.
+    #[must_use]
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+
+        let mut backtrace = Self::empty();
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// Take the backtrace of the code interrupted with the given state: the interrupted
+    /// instruction followed by the return addresses of its callers. Only the interrupted
+    /// instruction is returned if the state was saved in user mode.
+    #[must_use]
+    pub fn from_state(state: &State) -> Self {
+        let mut backtrace = Self::empty();
+        backtrace.push(state.rip);
+        if state.cs & 3 != 3 {
+            backtrace.walk(state.rbp);
+        }
+        backtrace
+    }
+
+    #[must_use]
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    const fn empty() -> Self {
+        Self {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// Append a frame, and returns false if the backtrace is full.
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    /// Follow the chain of frame pointers from the given frame, until its end, a frame outside of
+    /// the known stacks or a full backtrace.
+    fn walk(&mut self, mut rbp: u64) {
+        // Before its thread local storage is allocated, an AP gets the stacks of the BSP, which
+        // do not contain its frames: the walk then stops at once
+        let stacks = STACKS.local();
+        let mut previous: Option<(&Bounds, u64)> = None;
+        while rbp.is_multiple_of(8) {
+            let Some(stack) = stacks.find(rbp) else {
+                break;
+            };
+            if previous.is_some_and(|(last, frame)| ptr::eq(last, stack) && rbp <= frame) {
+                break;
+            }
+
+            // SAFETY: The frame record lies in a stack of the current CPU
+            let (next, address) = unsafe {
+                let record = rbp as *const u64;
+                (record.read(), record.add(1).read())
+            };
+            if address == 0 || !self.push(address) {
+                break;
+            }
+            previous = Some((stack, rbp));
+            rbp = next;
+        }
+    }
+}
+
+impl FromIterator<u64> for Backtrace {
+    /// Build a backtrace from the given return addresses, for example saved by another CPU. The
+    /// addresses after [`MAX_FRAMES`] are dropped.
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> Self {
+        let mut backtrace = Self::empty();
+        for address in iter {
+            if !backtrace.push(address) {
+                break;
+            }
+        }
+        backtrace
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.len == 0 {
+            return write!(f, "  <no frame>");
+        }
+        for (index, address) in self.frames().iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  #{index:<2} {address:#018x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the top of the boot stack of the current CPU. This must be called by the entry point of
+/// the CPU or close to it, while the stack pointer is still in the last page of the stack given by
+/// the bootloader.
+#[must_use]
+pub fn boot_stack_top() -> u64 {
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    (rsp + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1)
+}
+
+/// Record the boot stack of the current CPU, given its top (see [`boot_stack_top`]).
+pub fn set_boot_stack(top: u64) {
+    STACKS.local().boot.set(top - BOOT_STACK_SIZE..top);
+}
+
+/// Record the kernel stack of the thread the current CPU switches to, or `None` for the idle
+/// thread, which runs on the boot stack.
+pub fn set_thread_stack(stack: Option<Range<u64>>) {
+    STACKS.local().thread.set(stack.unwrap_or(0..0));
+}
+
+/// Record an IST stack of the current CPU.
+pub fn set_ist_stack(index: IstIndex, stack: Range<u64>) {
+    STACKS.local().ist[usize::from(index.get() - 1)].set(stack);
+}
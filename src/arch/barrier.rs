@@ -0,0 +1,23 @@
+//! The memory barriers of the CPU. [`core::sync::atomic::fence`] is enough to order the accesses
+//! to normal memory between CPUs, but not the accesses that x86 does not order by itself: the
+//! write-combining and non-temporal stores, the `clflush` instructions, and the MSR writes that
+//! must follow a memory-mapped register write (such as the TSC-deadline MSR after the LVT timer).
+//! Each barrier is also a compiler barrier.
+
+/// Wait until all the previous loads and stores are globally visible before executing the next
+/// loads and stores.
+pub fn mfence() {
+    unsafe { core::arch::asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Wait until all the previous instructions have completed locally before executing the next ones.
+/// This orders the loads, and keeps instructions such as `rdtsc` from being executed early.
+pub fn lfence() {
+    unsafe { core::arch::asm!("lfence", options(nostack, preserves_flags)) };
+}
+
+/// Wait until all the previous stores are globally visible before executing the next stores. This
+/// is only needed after write-combining or non-temporal stores.
+pub fn sfence() {
+    unsafe { core::arch::asm!("sfence", options(nostack, preserves_flags)) };
+}
@@ -0,0 +1,138 @@
+//! The caches of the processor, and the instructions writing their lines back to the memory. The
+//! devices of a PC usually snoop the caches, but the DMA buffers shared with a device that does
+//! not must be flushed before the device reads them and after it writes them (see
+//! [`flush_range`]), and the writes to persistent memory are only durable once written back (see
+//! [`writeback_range`]).
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{
+    cpuid::{self, CacheDescriptor, CacheKind},
+    features::CpuFeatures,
+};
+
+/// The line size assumed if the processor does not report it.
+const DEFAULT_LINE_SIZE: usize = 64;
+
+/// The size of a cache line, in bytes, detected by [`setup`].
+static LINE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_LINE_SIZE);
+
+/// Detect the size of the cache lines and log the caches of the BSP. The line size is the one
+/// reported for CLFLUSH, or the line size of the first level data cache if the processor does not
+/// report it. Must be called by the BSP after [`super::features::setup`].
+pub fn setup() {
+    let line_size = cpuid::clflush_line_size()
+        .or_else(|| {
+            cpuid::caches()
+                .find(|cache| cache.level == 1 && cache.kind != CacheKind::Instruction)
+                .map(|cache| cache.line_size)
+        })
+        .map_or(DEFAULT_LINE_SIZE, |size| size as usize);
+    LINE_SIZE.store(line_size, Ordering::Relaxed);
+
+    for cache in cpuid::caches() {
+        log::debug!(
+            "L{} {:?} cache: {} KiB, {}-way, {} bytes lines, shared by {} threads",
+            cache.level,
+            cache.kind,
+            cache.size() / 1024,
+            cache.ways,
+            cache.line_size,
+            cache.shared_by
+        );
+    }
+    log::debug!("Cache line size: {line_size} bytes");
+}
+
+/// Returns the size of a cache line, in bytes. It is always a power of two.
+#[must_use]
+pub fn line_size() -> usize {
+    LINE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Returns the number of cache levels of the processor, or 0 if it does not describe its caches.
+#[must_use]
+pub fn levels() -> u32 {
+    cpuid::caches().map(|cache| cache.level).max().unwrap_or(0)
+}
+
+/// Returns the last level cache of the processor, usually shared by all its cores.
+#[must_use]
+pub fn last_level() -> Option<CacheDescriptor> {
+    cpuid::caches().max_by_key(|cache| cache.level)
+}
+
+/// Write back and invalidate the cache line containing the given address, in all the caches of
+/// the coherency domain. It is ordered with the other writes and `clflush` instructions.
+///
+/// # Safety
+/// This function is unsafe because the address must be mapped, and because the CPU must support
+/// CLFLUSH.
+pub unsafe fn clflush(address: *const u8) {
+    core::arch::asm!("clflush [{}]", in(reg) address, options(nostack, preserves_flags));
+}
+
+/// Write back and invalidate the cache line containing the given address, like [`clflush`], but
+/// only ordered with the fences and the writes to the same line, so that several lines can be
+/// flushed in parallel.
+///
+/// # Safety
+/// This function is unsafe because the address must be mapped, and because the CPU must support
+/// CLFLUSHOPT.
+pub unsafe fn clflushopt(address: *const u8) {
+    core::arch::asm!("clflushopt [{}]", in(reg) address, options(nostack, preserves_flags));
+}
+
+/// Write back the cache line containing the given address, which may be kept in the caches. It is
+/// ordered like [`clflushopt`].
+///
+/// # Safety
+/// This function is unsafe because the address must be mapped, and because the CPU must support
+/// CLWB.
+pub unsafe fn clwb(address: *const u8) {
+    core::arch::asm!("clwb [{}]", in(reg) address, options(nostack, preserves_flags));
+}
+
+/// Write back and invalidate the cache lines of the given range, with CLFLUSHOPT if all the CPUs
+/// support it, or CLFLUSH otherwise. The range is flushed once this function returns: the previous
+/// writes are in the memory, and the next reads will fetch the memory.
+///
+/// # Safety
+/// This function is unsafe because the whole range must be mapped.
+pub unsafe fn flush_range(address: *const u8, len: usize) {
+    if super::cpu_features().contains(CpuFeatures::CLFLUSHOPT) {
+        // CLFLUSHOPT is not ordered with the other memory accesses
+        super::barrier::mfence();
+        for_each_line(address, len, |line| clflushopt(line));
+        super::barrier::mfence();
+    } else {
+        for_each_line(address, len, |line| clflush(line));
+    }
+}
+
+/// Write back the cache lines of the given range to the memory, with CLWB if all the CPUs support
+/// it so that the lines stay cached, or with [`flush_range`] otherwise. The writes are durable once
+/// this function returns.
+///
+/// # Safety
+/// This function is unsafe because the whole range must be mapped.
+pub unsafe fn writeback_range(address: *const u8, len: usize) {
+    if super::cpu_features().contains(CpuFeatures::CLWB) {
+        for_each_line(address, len, |line| clwb(line));
+        super::barrier::sfence();
+    } else {
+        flush_range(address, len);
+    }
+}
+
+/// Call the given function with the address of each cache line overlapping the given range.
+fn for_each_line(address: *const u8, len: usize, mut f: impl FnMut(*const u8)) {
+    if len == 0 {
+        return;
+    }
+    let line_size = line_size();
+    let start = address as usize & !(line_size - 1);
+    let end = address as usize + len;
+    for line in (start..end).step_by(line_size) {
+        f(line as *const u8);
+    }
+}
@@ -0,0 +1,284 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{
+    sys::time::{clocksource, vdso, ClockSource},
+    Spinlock,
+};
+
+use super::{
+    port::ReadOnlyPort,
+    smp::{self, CallTarget},
+};
+
+/// The number of times each CPU reads its TSC during the synchronization check.
+const TSC_SYNC_LOOPS: u32 = 1000;
+
+/// The time stamp counter of the CPUs. It is the cheapest counter to read, but it can only be
+/// trusted if it runs at a constant rate in all power states and if the TSCs of all the CPUs are
+/// synchronized: otherwise, it is only used if there is no other clock source.
+struct Tsc {
+    frequency: u64,
+    invariant: bool,
+    synchronized: bool,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        super::tsc::rdtsc()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn rating(&self) -> u32 {
+        if self.invariant && self.synchronized {
+            300
+        } else {
+            50
+        }
+    }
+}
+
+/// The largest backward step of the TSC observed by [`tsc_warp`], and the CPUs involved.
+#[derive(Debug, Clone, Copy)]
+struct Warp {
+    cycles: u64,
+    from: u32,
+    to: u32,
+}
+
+/// The state shared by the CPUs during the TSC synchronization check.
+struct SyncState {
+    last: u64,
+    cpu: u32,
+    warp: Option<Warp>,
+}
+
+/// Check that the TSCs of the online CPUs are synchronized. All the CPUs read their TSC in turn,
+/// under a lock, so the values read must increase even if they are read by different CPUs. Returns
+/// the largest backward step observed, or `None` if the TSCs look synchronized.
+fn tsc_warp() -> Option<Warp> {
+    let cpus = smp::online_count();
+    if cpus < 2 {
+        return None;
+    }
+
+    let state = Spinlock::new(SyncState {
+        last: 0,
+        cpu: 0,
+        warp: None,
+    });
+    let arrived = AtomicU32::new(0);
+    smp::call_function(CallTarget::Mask(smp::online_mask()), &|| {
+        // Wait for all the CPUs, so that they really compete for the lock
+        arrived.fetch_add(1, Ordering::AcqRel);
+        while arrived.load(Ordering::Acquire) < cpus {
+            core::hint::spin_loop();
+        }
+
+        let cpu = smp::current_id();
+        for _ in 0..TSC_SYNC_LOOPS {
+            let mut state = state.lock();
+            // Do not let the TSC be read before the lock is taken
+            let now = super::tsc::rdtsc_ordered();
+            let cycles = state.last.saturating_sub(now);
+            if cycles > state.warp.map_or(0, |warp| warp.cycles) {
+                state.warp = Some(Warp {
+                    cycles,
+                    from: state.cpu,
+                    to: cpu,
+                });
+            }
+            state.last = now;
+            state.cpu = cpu;
+        }
+    });
+    state.into_inner().warp
+}
+
+/// The main counter of the HPET (see [`super::hpet`]). It is precise, but reading it is slow
+/// because it is a memory-mapped register, and even slower in a virtual machine.
+struct Hpet;
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        super::hpet::counter()
+    }
+
+    fn frequency(&self) -> u64 {
+        super::hpet::FEMTOSECONDS_PER_SECOND / super::hpet::period()
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn mask(&self) -> u64 {
+        super::hpet::counter_mask()
+    }
+}
+
+/// The channel 0 of the PIT, used as a free-running 16 bits counter once retired (see
+/// [`super::pit::retire`]). It wraps around every 55 ms and reading it requires several port
+/// accesses, so it is only a last resort.
+struct Pit;
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn read(&self) -> u64 {
+        // The PIT counts down, the clock source must count up
+        u64::from(0u16.wrapping_sub(super::pit::counter()))
+    }
+
+    fn frequency(&self) -> u64 {
+        super::pit::FREQUENCY
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn mask(&self) -> u64 {
+        u64::from(u16::MAX)
+    }
+}
+
+/// The ACPI power management timer (see [`super::acpi::PmTimer`]). It runs at the same rate in
+/// all the power states, but reading it requires a port access and its 24 bits variant wraps around
+/// every 4.7 s.
+struct AcpiPm {
+    port: ReadOnlyPort<u32>,
+    mask: u64,
+}
+
+impl ClockSource for AcpiPm {
+    fn name(&self) -> &'static str {
+        "acpi_pm"
+    }
+
+    fn read(&self) -> u64 {
+        u64::from(unsafe { self.port.read() }) & self.mask
+    }
+
+    fn frequency(&self) -> u64 {
+        super::acpi::PM_TIMER_FREQUENCY
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+}
+
+/// The kvmclock of KVM (see [`super::kvm`]), which counts nanoseconds. It is as cheap as the TSC
+/// and is corrected by the host, but it can only be shared by the CPUs if KVM marks it as stable.
+struct KvmClock;
+
+impl ClockSource for KvmClock {
+    fn name(&self) -> &'static str {
+        "kvm-clock"
+    }
+
+    fn read(&self) -> u64 {
+        super::kvm::nanoseconds()
+    }
+
+    fn frequency(&self) -> u64 {
+        1_000_000_000
+    }
+
+    fn rating(&self) -> u32 {
+        if super::kvm::clock_stable() {
+            350
+        } else {
+            150
+        }
+    }
+}
+
+static PIT: Pit = Pit;
+static KVM_CLOCK: KvmClock = KvmClock;
+static HPET: Hpet = Hpet;
+static TSC: sync::Once<Tsc> = sync::Once::new();
+static ACPI_PM: sync::Once<AcpiPm> = sync::Once::new();
+
+/// Register the clock sources available on this machine: the PIT is always present, the HPET and
+/// the ACPI PM timer if they are described in the ACPI tables, the kvmclock under KVM, and the TSC
+/// calibrated by the BSP. The TSC is only preferred to the other sources if it is invariant and
+/// synchronized across the CPUs, and the kvmclock is preferred to the TSC if it is stable. Must be
+/// called by the BSP once its timers are calibrated (see [`super::timer::enable`]) and the APs are
+/// started.
+///
+/// The time page is given the conversion factor of the TSC only if it can be trusted, so that the
+/// user space can interpolate the time between two clock ticks (see [`vdso::set_tsc_conversion`]).
+pub fn register() {
+    clocksource::register(&PIT);
+
+    if super::hpet::available() {
+        clocksource::register(&HPET);
+    }
+
+    if super::kvm::clock_available() {
+        clocksource::register(&KVM_CLOCK);
+    }
+
+    if let Some(timer) = super::acpi::fadt().and_then(|fadt| fadt.pm_timer) {
+        clocksource::register(ACPI_PM.call_once(|| AcpiPm {
+            port: ReadOnlyPort::new(timer.port),
+            mask: if timer.extended {
+                u64::from(u32::MAX)
+            } else {
+                (1 << 24) - 1
+            },
+        }));
+    }
+
+    // The TSC frequency is measured with the LAPIC timer, which is not used without ACPI
+    if !super::timer::calibrated() {
+        return;
+    }
+    let tsc = TSC.call_once(|| {
+        let invariant = super::cpuid::invariant_tsc();
+        if !invariant {
+            log::warn!("TSC is not invariant: it may drift with the frequency of the CPUs");
+        }
+
+        let warp = tsc_warp();
+        if let Some(warp) = warp {
+            log::error!(
+                "TSC is not synchronized: CPU {} read a TSC {} cycles behind CPU {}",
+                warp.to,
+                warp.cycles,
+                warp.from
+            );
+            log::error!("TSC marked as unstable, the HPET or the PIT will be used instead");
+        }
+
+        Tsc {
+            frequency: super::timer::calibration().tsc_hz,
+            invariant,
+            synchronized: warp.is_none(),
+        }
+    });
+    clocksource::register(tsc);
+
+    if tsc.invariant && tsc.synchronized {
+        let (mult, shift) = super::timer::calibration().tsc.mult_shift();
+        vdso::set_tsc_conversion(mult, shift);
+    }
+}
@@ -0,0 +1,137 @@
+use super::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+extern "C" {
+    fn __switch_context(prev: *mut u64, next: u64);
+    fn __thread_trampoline();
+    fn __enter_user(rip: u64, rsp: u64) -> !;
+}
+
+// The context switch only saves the callee-saved registers on the stack of the previous thread,
+// because the caller-saved registers are already saved by the compiler before calling the switch
+// function. The stack pointer is then saved in `prev` and the stack of the next thread is loaded.
+//
+// New threads start in the trampoline with the entry point in `r12` and the two arguments in `r13`
+// and `r14`: those registers are initialized by [`prepare_stack`]. As after any context switch,
+// the previous thread is first put back in the scheduler, with interrupts still disabled.
+core::arch::global_asm!(
+    r#"
+.global __switch_context
+__switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global __thread_trampoline
+__thread_trampoline:
+    call {finish_switch}
+    sti
+    mov rdi, r13
+    mov rsi, r14
+    call r12
+    ud2
+
+.global __enter_user
+__enter_user:
+    cli
+    push {user_ds}
+    push rsi
+    push 0x202
+    push {user_cs}
+    push rdi
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    swapgs
+    iretq
+"#,
+    user_ds = const USER_DATA_SELECTOR,
+    user_cs = const USER_CODE_SELECTOR,
+    finish_switch = sym crate::sched::finish_switch,
+);
+
+/// Switch from the current thread to the next one. The stack pointer of the current thread is
+/// saved in `prev`, and the execution will continue at the last [`switch`] call made by the next
+/// thread, or at its entry point if the thread has never been executed.
+///
+/// # Safety
+/// This function is unsafe because it switches the stack and the execution flow, and the caller
+/// must ensure that `next` is a stack pointer saved by a previous call to this function or
+/// initialized by [`prepare_stack`], and that the stack is still valid.
+pub unsafe fn switch(prev: *mut u64, next: u64) {
+    __switch_context(prev, next);
+}
+
+/// Prepare the stack of a new thread, so that the first [`switch`] to this thread will call
+/// `entry` with the two given arguments and with interrupts enabled. The entry point must never
+/// return. Returns the stack pointer to use for the first switch.
+///
+/// # Safety
+/// This function is unsafe because it writes to the given stack, and the caller must ensure that
+/// `top` is the (16 bytes aligned) top of a valid and unused stack.
+#[must_use]
+pub unsafe fn prepare_stack(
+    top: u64,
+    entry: unsafe extern "C" fn(u64, u64) -> !,
+    arg0: u64,
+    arg1: u64,
+) -> u64 {
+    // Registers popped by `__switch_context`, in this order: r15, r14, r13, r12, rbx, rbp and
+    // the return address.
+    let frame = (top - 7 * 8) as *mut u64;
+    frame.add(0).write(0);
+    frame.add(1).write(arg1);
+    frame.add(2).write(arg0);
+    frame.add(3).write(entry as usize as u64);
+    frame.add(4).write(0);
+    frame.add(5).write(0);
+    frame.add(6).write(__thread_trampoline as *const () as u64);
+    frame as u64
+}
+
+/// Jump to user mode at the given instruction pointer and with the given stack pointer. All
+/// general purpose registers are cleared before jumping to avoid leaking kernel data to the
+/// user, and interrupts are enabled in user mode.
+///
+/// The GS bases are exchanged with `swapgs` just before jumping, because the kernel and the user
+/// code each have their own (see [`super::interrupt::enter`]).
+///
+/// This function has the signature of a thread entry point (see [`prepare_stack`]), so it can
+/// directly be used to start an user thread.
+///
+/// # Safety
+/// This function is unsafe because the caller must ensure that the user address space is loaded
+/// and that the kernel stack for the current CPU is properly set (see [`set_kernel_stack`]).
+pub unsafe extern "C" fn enter_user(rip: u64, rsp: u64) -> ! {
+    __enter_user(rip, rsp);
+}
+
+/// Set the kernel stack used when the current CPU enters the kernel from user mode, either by
+/// an interrupt or by a system call.
+pub fn set_kernel_stack(top: u64) {
+    super::tss::set_kernel_stack(top);
+    super::smp::set_kernel_stack(top);
+}
@@ -0,0 +1,222 @@
+//! Typed access to the control registers, to the EFER register and to the XCR0 extended control
+//! register. Each register is a bitflags type with `read`, `write` and `update` functions, for
+//! example `Cr4::update(|cr4| cr4 | Cr4::SMAP)`. The bits unknown to the kernel are preserved by
+//! `update`, because they are kept in the value even if they have no name.
+use bitflags::bitflags;
+
+use super::msr;
+
+bitflags! {
+    /// The CR0 register, controlling the operating mode of the CPU.
+    pub struct Cr0 : u64 {
+        /// Protected mode.
+        const PE = 1 << 0;
+        /// Monitor the coprocessor: `wait` raises #NM when `TS` is set.
+        const MP = 1 << 1;
+        /// Emulate the x87 FPU: its instructions raise #NM.
+        const EM = 1 << 2;
+        /// Set on a task switch, to save the FPU state lazily.
+        const TS = 1 << 3;
+        const ET = 1 << 4;
+        /// Report the x87 errors with #MF rather than with an external interrupt.
+        const NE = 1 << 5;
+        /// Forbid the kernel to write to read-only pages.
+        const WP = 1 << 16;
+        /// Allow the alignment check when the `AC` flag is set in RFLAGS.
+        const AM = 1 << 18;
+        const NW = 1 << 29;
+        const CD = 1 << 30;
+        /// Paging.
+        const PG = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// The CR4 register, enabling architectural extensions.
+    pub struct Cr4 : u64 {
+        const VME = 1 << 0;
+        const PVI = 1 << 1;
+        /// Restrict `rdtsc` to the kernel.
+        const TSD = 1 << 2;
+        const DE = 1 << 3;
+        const PSE = 1 << 4;
+        const PAE = 1 << 5;
+        /// Machine check exceptions.
+        const MCE = 1 << 6;
+        /// Global pages.
+        const PGE = 1 << 7;
+        /// Performance counters readable with `rdpmc` in user mode.
+        const PCE = 1 << 8;
+        /// FXSAVE/FXRSTOR and the SSE instructions.
+        const OSFXSR = 1 << 9;
+        /// Report the SIMD floating point errors with #XM.
+        const OSXMMEXCPT = 1 << 10;
+        /// Forbid `sgdt`, `sidt`... in user mode.
+        const UMIP = 1 << 11;
+        const LA57 = 1 << 12;
+        const VMXE = 1 << 13;
+        const SMXE = 1 << 14;
+        /// The `rdfsbase`, `wrfsbase`... instructions.
+        const FSGSBASE = 1 << 16;
+        /// Process context identifiers.
+        const PCIDE = 1 << 17;
+        /// XSAVE and the XCR0 register.
+        const OSXSAVE = 1 << 18;
+        /// Forbid the kernel to execute user pages.
+        const SMEP = 1 << 20;
+        /// Forbid the kernel to access user pages unless the `AC` flag is set in RFLAGS.
+        const SMAP = 1 << 21;
+        const PKE = 1 << 22;
+    }
+}
+
+bitflags! {
+    /// The EFER register, enabling the long mode and its extensions.
+    pub struct Efer : u64 {
+        /// The `syscall` and `sysret` instructions.
+        const SCE = 1 << 0;
+        /// Long mode enabled.
+        const LME = 1 << 8;
+        /// Long mode active.
+        const LMA = 1 << 10;
+        /// The no-execute bit of the page table entries.
+        const NXE = 1 << 11;
+    }
+}
+
+bitflags! {
+    /// The XCR0 register, selecting the state components managed by XSAVE.
+    pub struct Xcr0 : u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREGS = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+    }
+}
+
+impl Cr0 {
+    #[must_use]
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe {
+            core::arch::asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags));
+        }
+        Self { bits: value }
+    }
+
+    /// # Safety
+    /// This function is unsafe because changing the operating mode of the CPU can break the memory
+    /// safety (e.g. disabling paging or the write protection).
+    pub unsafe fn write(self) {
+        core::arch::asm!("mov cr0, {}", in(reg) self.bits, options(nostack, preserves_flags));
+    }
+
+    /// Write the value returned by the given closure from the current value of the register.
+    ///
+    /// # Safety
+    /// See [`Cr0::write`].
+    pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+        f(Self::read()).write();
+    }
+}
+
+impl Cr4 {
+    #[must_use]
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe {
+            core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+        }
+        Self { bits: value }
+    }
+
+    /// # Safety
+    /// This function is unsafe because enabling an extension the CPU does not support raises a
+    /// general protection fault, and because disabling some of them (e.g. PAE) breaks the memory
+    /// safety.
+    pub unsafe fn write(self) {
+        core::arch::asm!("mov cr4, {}", in(reg) self.bits, options(nostack, preserves_flags));
+    }
+
+    /// Write the value returned by the given closure from the current value of the register.
+    ///
+    /// # Safety
+    /// See [`Cr4::write`].
+    pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+        f(Self::read()).write();
+    }
+}
+
+impl Efer {
+    #[must_use]
+    pub fn read() -> Self {
+        Self {
+            bits: unsafe { msr::read(msr::IA32_EFER) },
+        }
+    }
+
+    /// # Safety
+    /// This function is unsafe because leaving the long mode or enabling an unsupported extension
+    /// crashes the kernel.
+    pub unsafe fn write(self) {
+        msr::write(msr::IA32_EFER, self.bits);
+    }
+
+    /// Write the value returned by the given closure from the current value of the register.
+    ///
+    /// # Safety
+    /// See [`Efer::write`].
+    pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+        f(Self::read()).write();
+    }
+}
+
+impl Xcr0 {
+    /// Read the register. It can only be read once XSAVE is enabled with [`Cr4::OSXSAVE`].
+    #[must_use]
+    pub fn read() -> Self {
+        let (low, high): (u32, u32);
+        unsafe {
+            core::arch::asm!(
+                "xgetbv",
+                in("ecx") 0,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        Self {
+            bits: u64::from(high) << 32 | u64::from(low),
+        }
+    }
+
+    /// # Safety
+    /// This function is unsafe because the register can only be written once XSAVE is enabled
+    /// with [`Cr4::OSXSAVE`], with components supported by the CPU, and because changing the
+    /// components changes the size of the area saved by XSAVE.
+    #[allow(clippy::cast_possible_truncation)]
+    pub unsafe fn write(self) {
+        let low = self.bits as u32;
+        let high = (self.bits >> 32) as u32;
+        core::arch::asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") low,
+            in("edx") high,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+
+    /// Write the value returned by the given closure from the current value of the register.
+    ///
+    /// # Safety
+    /// See [`Xcr0::write`].
+    pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+        f(Self::read()).write();
+    }
+}
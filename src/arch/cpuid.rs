@@ -0,0 +1,279 @@
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+
+/// The leaves giving the maximum basic leaf and the maximum extended leaf.
+const LEAF_VENDOR: u32 = 0x00;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+
+/// The leaves decoded by this module.
+const LEAF_FEATURES: u32 = 0x01;
+const LEAF_CACHE_PARAMETERS: u32 = 0x04;
+const LEAF_MONITOR: u32 = 0x05;
+const LEAF_STRUCTURED_FEATURES: u32 = 0x07;
+const LEAF_TSC: u32 = 0x15;
+const LEAF_FREQUENCY: u32 = 0x16;
+const LEAF_EXTENDED_FEATURES: u32 = 0x8000_0001;
+const LEAF_BRAND: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+const LEAF_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const LEAF_AMD_CACHE_PARAMETERS: u32 = 0x8000_001D;
+
+/// Set in the EDX register of the leaf 0x80000007 if the TSC runs at a constant rate, whatever
+/// the frequency and the power state of the CPU.
+const INVARIANT_TSC: u32 = 1 << 8;
+
+/// Set in the ECX register of the leaf 0x80000001 on AMD processors that have the leaf
+/// 0x8000001D describing their caches.
+const AMD_TOPOLOGY_EXTENSIONS: u32 = 1 << 22;
+
+/// A register returned by CPUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// The vendor of the processor, from the string returned by the leaf 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other([u8; 12]),
+}
+
+/// The family, model and stepping of the processor, with the extended family and model already
+/// merged into them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Signature {
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// A cache of the processor, as described by the deterministic cache parameters leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheDescriptor {
+    pub level: u32,
+    pub kind: CacheKind,
+
+    /// The size of a line, in bytes.
+    pub line_size: u32,
+    pub ways: u32,
+    pub partitions: u32,
+    pub sets: u32,
+
+    /// The maximum number of logical processors sharing the cache.
+    pub shared_by: u32,
+}
+
+impl CacheDescriptor {
+    /// Returns the size of the cache, in bytes.
+    #[must_use]
+    pub const fn size(&self) -> u64 {
+        self.line_size as u64 * self.ways as u64 * self.partitions as u64 * self.sets as u64
+    }
+}
+
+/// Execute CPUID with the given leaf and sub-leaf. Returns `None` if the leaf is above the maximum
+/// basic or extended leaf of the processor: CPUID would then return the data of another leaf. The
+/// leaves outside these two ranges (e.g. the hypervisor leaves) are not checked.
+#[must_use]
+pub fn cpuid(leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+    let supported = match leaf {
+        0..=0x3FFF_FFFF => leaf <= max_leaf(),
+        LEAF_EXTENDED_MAX.. => leaf <= max_extended_leaf(),
+        _ => true,
+    };
+    supported.then(|| unsafe { __cpuid_count(leaf, subleaf) })
+}
+
+/// Returns the given register of the given leaf and sub-leaf, or 0 if the leaf is not supported.
+#[must_use]
+pub fn register(leaf: u32, subleaf: u32, register: Register) -> u32 {
+    cpuid(leaf, subleaf).map_or(0, |result| match register {
+        Register::Eax => result.eax,
+        Register::Ebx => result.ebx,
+        Register::Ecx => result.ecx,
+        Register::Edx => result.edx,
+    })
+}
+
+/// Returns the highest basic leaf supported by the processor.
+#[must_use]
+pub fn max_leaf() -> u32 {
+    unsafe { __cpuid_count(LEAF_VENDOR, 0) }.eax
+}
+
+/// Returns the highest extended leaf supported by the processor.
+#[must_use]
+pub fn max_extended_leaf() -> u32 {
+    unsafe { __cpuid_count(LEAF_EXTENDED_MAX, 0) }.eax
+}
+
+/// Returns the vendor of the processor.
+#[must_use]
+pub fn vendor() -> Vendor {
+    let result = unsafe { __cpuid_count(LEAF_VENDOR, 0) };
+    let mut name = [0; 12];
+    name[0..4].copy_from_slice(&result.ebx.to_le_bytes());
+    name[4..8].copy_from_slice(&result.edx.to_le_bytes());
+    name[8..12].copy_from_slice(&result.ecx.to_le_bytes());
+    match &name {
+        b"GenuineIntel" => Vendor::Intel,
+        b"AuthenticAMD" => Vendor::Amd,
+        _ => Vendor::Other(name),
+    }
+}
+
+/// Returns the family, model and stepping of the processor.
+#[must_use]
+pub fn signature() -> Signature {
+    let eax = register(LEAF_FEATURES, 0, Register::Eax);
+    let base_family = (eax >> 8) & 0xF;
+    let base_model = (eax >> 4) & 0xF;
+
+    // The extended family is only used by the family 15, and the extended model by the families 6
+    // and 15
+    let family = if base_family == 0xF {
+        base_family + ((eax >> 20) & 0xFF)
+    } else {
+        base_family
+    };
+    let model = if base_family == 0x6 || base_family == 0xF {
+        base_model | ((eax >> 12) & 0xF0)
+    } else {
+        base_model
+    };
+    Signature {
+        family,
+        model,
+        stepping: eax & 0xF,
+    }
+}
+
+/// Returns the brand string of the processor, without the padding spaces, or `None` if the
+/// processor does not have the brand leaves.
+#[must_use]
+pub fn brand(buffer: &mut [u8; 48]) -> Option<&str> {
+    for (chunk, &leaf) in buffer.chunks_exact_mut(16).zip(LEAF_BRAND.iter()) {
+        let result = cpuid(leaf, 0)?;
+        for (bytes, value) in chunk
+            .chunks_exact_mut(4)
+            .zip([result.eax, result.ebx, result.ecx, result.edx])
+        {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    let len = buffer.iter().position(|&byte| byte == 0).unwrap_or(48);
+    core::str::from_utf8(&buffer[..len]).ok().map(str::trim)
+}
+
+/// Returns the ECX and EDX registers of the leaf 1, which hold the basic feature flags.
+#[must_use]
+pub fn features() -> (u32, u32) {
+    (
+        register(LEAF_FEATURES, 0, Register::Ecx),
+        register(LEAF_FEATURES, 0, Register::Edx),
+    )
+}
+
+/// Returns the EBX, ECX and EDX registers of the first sub-leaf of the leaf 7, which hold the
+/// structured extended feature flags. They are all zeros if the leaf is not supported.
+#[must_use]
+pub fn structured_features() -> (u32, u32, u32) {
+    (
+        register(LEAF_STRUCTURED_FEATURES, 0, Register::Ebx),
+        register(LEAF_STRUCTURED_FEATURES, 0, Register::Ecx),
+        register(LEAF_STRUCTURED_FEATURES, 0, Register::Edx),
+    )
+}
+
+/// Returns the ECX and EDX registers of the leaf 0x80000001, which hold the extended feature
+/// flags. They are all zeros if the leaf is not supported.
+#[must_use]
+pub fn extended_features() -> (u32, u32) {
+    (
+        register(LEAF_EXTENDED_FEATURES, 0, Register::Ecx),
+        register(LEAF_EXTENDED_FEATURES, 0, Register::Edx),
+    )
+}
+
+/// Returns true if the TSC is invariant: it runs at a constant rate, whatever the frequency and
+/// the power state of the CPU.
+#[must_use]
+pub fn invariant_tsc() -> bool {
+    register(LEAF_POWER_MANAGEMENT, 0, Register::Edx) & INVARIANT_TSC != 0
+}
+
+/// Returns the frequency of the TSC in Hz as reported by the processor, or `None` if it does not
+/// report it. The leaf 0x15 gives the ratio between the TSC and the core crystal clock, and the
+/// frequency of the crystal on the recent processors only: on the older ones, the base frequency
+/// of the leaf 0x16 is used instead, which is the frequency of the TSC on Intel processors.
+#[must_use]
+pub fn tsc_frequency() -> Option<u64> {
+    let tsc = cpuid(LEAF_TSC, 0)?;
+    if tsc.eax == 0 || tsc.ebx == 0 {
+        return None;
+    }
+    if tsc.ecx != 0 {
+        return Some(u64::from(tsc.ecx) * u64::from(tsc.ebx) / u64::from(tsc.eax));
+    }
+
+    let base_mhz = register(LEAF_FREQUENCY, 0, Register::Eax) & 0xFFFF;
+    (base_mhz != 0).then(|| u64::from(base_mhz) * 1_000_000)
+}
+
+/// Returns the smallest size of the line monitored by MONITOR, in bytes, or `None` if the
+/// processor does not have the MONITOR leaf.
+#[must_use]
+pub fn monitor_line_size() -> Option<u32> {
+    cpuid(LEAF_MONITOR, 0).map(|result| result.ebx & 0xFFFF)
+}
+
+/// Returns the size of the line flushed by CLFLUSH, in bytes, or `None` if the processor does not
+/// report it.
+#[must_use]
+pub fn clflush_line_size() -> Option<u32> {
+    let size = ((register(LEAF_FEATURES, 0, Register::Ebx) >> 8) & 0xFF) * 8;
+    (size != 0).then_some(size)
+}
+
+/// Returns the caches of the processor, from the deterministic cache parameters leaf: the leaf 4
+/// on Intel processors, and the leaf 0x8000001D on AMD processors. Returns no cache if the
+/// processor does not have this leaf.
+pub fn caches() -> impl Iterator<Item = CacheDescriptor> {
+    let leaf = match vendor() {
+        Vendor::Amd if extended_features().0 & AMD_TOPOLOGY_EXTENSIONS != 0 => {
+            Some(LEAF_AMD_CACHE_PARAMETERS)
+        }
+        Vendor::Amd => None,
+        _ => Some(LEAF_CACHE_PARAMETERS),
+    };
+
+    (0..)
+        .map_while(move |subleaf| cpuid(leaf?, subleaf))
+        .map_while(|result| {
+            let kind = match result.eax & 0x1F {
+                1 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => return None,
+            };
+            Some(CacheDescriptor {
+                level: (result.eax >> 5) & 0x7,
+                kind,
+                line_size: (result.ebx & 0xFFF) + 1,
+                partitions: ((result.ebx >> 12) & 0x3FF) + 1,
+                ways: (result.ebx >> 22) + 1,
+                sets: result.ecx + 1,
+                shared_by: ((result.eax >> 14) & 0xFFF) + 1,
+            })
+        })
+}
@@ -0,0 +1,128 @@
+use core::ops::{BitAnd, BitOr, Not};
+
+use crate::config::MAX_CPU;
+
+// The masks are stored in a single word
+const _: () = assert!(MAX_CPU <= 64, "A CPU mask cannot hold more than 64 CPUs");
+
+/// A set of CPUs, one bit per CPU id. Only the ids lower than [`MAX_CPU`] can be in a mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    /// The mask without any CPU.
+    pub const EMPTY: Self = Self(0);
+
+    /// Create a mask from its bits, one bit per CPU id. The bits of the ids greater than or equal
+    /// to [`MAX_CPU`] are ignored.
+    #[must_use]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    /// Returns the mask containing all the possible CPU ids.
+    #[must_use]
+    pub const fn all() -> Self {
+        if MAX_CPU == 64 {
+            Self(u64::MAX)
+        } else {
+            Self((1 << MAX_CPU) - 1)
+        }
+    }
+
+    /// Returns the mask containing only the given CPU, or an empty mask if the id is too large.
+    #[must_use]
+    pub const fn single(cpu: u32) -> Self {
+        if (cpu as usize) < MAX_CPU {
+            Self(1 << cpu)
+        } else {
+            Self::EMPTY
+        }
+    }
+
+    /// Returns the bits of the mask, one bit per CPU id.
+    #[must_use]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    #[must_use]
+    pub const fn contains(self, cpu: u32) -> bool {
+        self.0 & Self::single(cpu).0 != 0
+    }
+
+    pub fn insert(&mut self, cpu: u32) {
+        self.0 |= Self::single(cpu).0;
+    }
+
+    pub fn remove(&mut self, cpu: u32) {
+        self.0 &= !Self::single(cpu).0;
+    }
+
+    /// Returns the number of CPUs in the mask.
+    #[must_use]
+    pub const fn count(self) -> u32 {
+        self.0.count_ones()
+    }
+
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the lowest CPU id of the mask, if any.
+    #[must_use]
+    pub const fn first(self) -> Option<u32> {
+        if self.0 == 0 {
+            None
+        } else {
+            Some(self.0.trailing_zeros())
+        }
+    }
+
+    /// Returns the ids of the CPUs of the mask, in ascending order.
+    pub fn iter(self) -> impl Iterator<Item = u32> + Clone {
+        let mut bits = self.0;
+        core::iter::from_fn(move || {
+            if bits == 0 {
+                return None;
+            }
+            let cpu = bits.trailing_zeros();
+            bits &= bits - 1;
+            Some(cpu)
+        })
+    }
+}
+
+impl BitAnd for CpuMask {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for CpuMask {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl Not for CpuMask {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0 & Self::all().0)
+    }
+}
+
+impl FromIterator<u32> for CpuMask {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        iter.into_iter().fold(Self::EMPTY, |mut mask, cpu| {
+            mask.insert(cpu);
+            mask
+        })
+    }
+}
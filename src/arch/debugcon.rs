@@ -0,0 +1,21 @@
+use super::port::Port;
+
+/// The I/O port of the debug console of QEMU and Bochs. QEMU must be started with
+/// `-debugcon <chardev>` for the output to go somewhere.
+const PORT: Port<u8> = Port::new(0xE9);
+
+/// Returns true if a debug console is present. Reading its port returns the port number itself,
+/// while a read from an unused port returns all ones.
+#[must_use]
+pub fn detect() -> bool {
+    unsafe { PORT.read() == 0xE9 }
+}
+
+/// Write the given string to the debug console. Unlike a serial port, there is no transmitter to
+/// wait for: each byte is consumed by the emulator as soon as it is written, so this is much
+/// faster than the serial output.
+pub fn write(s: &str) {
+    for byte in s.bytes() {
+        unsafe { PORT.write(byte) };
+    }
+}
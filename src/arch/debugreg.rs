@@ -0,0 +1,179 @@
+//! Hardware breakpoints and watchpoints, with the debug registers. Up to four addresses can be
+//! watched at the same time on all the CPUs: an access matching a watchpoint raises a debug
+//! exception, which logs the watchpoint and the instruction that triggered it, then resumes the
+//! execution. This is meant to find who corrupts a given memory location.
+use x86_64::cpu;
+
+use super::smp::{self, CallTarget};
+use crate::Spinlock;
+
+/// The number of hardware breakpoints.
+pub const BREAKPOINTS: usize = 4;
+
+/// The bits of DR6 set when the breakpoint with the same index was hit.
+const DR6_HIT: u64 = 0b1111;
+
+/// The bits of DR7: the global enable bit of a breakpoint, and the shift of its condition and
+/// length fields.
+const DR7_GLOBAL_ENABLE: u64 = 1 << 1;
+const DR7_FIELDS_SHIFT: u64 = 16;
+
+/// The resume flag of RFLAGS: the instruction breakpoints are ignored for the next instruction.
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// The breakpoints set on all the CPUs, indexed by their debug register.
+static TABLE: Spinlock<[Option<Breakpoint>; BREAKPOINTS]> = Spinlock::new([None; BREAKPOINTS]);
+
+/// The access that triggers a breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    /// The execution of the instruction at the address. The length must be [`Length::Byte`].
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+/// The size of the watched memory. The address must be aligned on this size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Length {
+    Byte = 0b00,
+    Word = 0b01,
+    Dword = 0b11,
+    Qword = 0b10,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Breakpoint {
+    pub address: u64,
+    pub condition: Condition,
+    pub length: Length,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DebugRegError {
+    /// All the debug registers are used.
+    NoFreeRegister,
+
+    /// The address is not aligned on the length, or an instruction breakpoint is not one byte
+    /// long.
+    InvalidBreakpoint,
+
+    /// The index is not the one of a breakpoint that is set.
+    NotSet,
+}
+
+impl Length {
+    const fn bytes(self) -> u64 {
+        match self {
+            Self::Byte => 1,
+            Self::Word => 2,
+            Self::Dword => 4,
+            Self::Qword => 8,
+        }
+    }
+}
+
+/// Set a breakpoint on all the online CPUs. Returns the index of the debug register used, to be
+/// given to [`clear`].
+///
+/// # Errors
+/// - [`DebugRegError::InvalidBreakpoint`]: The address is not aligned on the length, or an
+///   instruction breakpoint is not one byte long.
+/// - [`DebugRegError::NoFreeRegister`]: The four debug registers are already used.
+pub fn set(breakpoint: Breakpoint) -> Result<usize, DebugRegError> {
+    let aligned = breakpoint.address & (breakpoint.length.bytes() - 1) == 0;
+    let execute = breakpoint.condition == Condition::Execute;
+    if !aligned || (execute && breakpoint.length != Length::Byte) {
+        return Err(DebugRegError::InvalidBreakpoint);
+    }
+
+    let index = x86_64::irq::without(|| {
+        let mut table = TABLE.lock();
+        let index = table.iter().position(Option::is_none)?;
+        table[index] = Some(breakpoint);
+        Some(index)
+    })
+    .ok_or(DebugRegError::NoFreeRegister)?;
+
+    reload_all();
+    Ok(index)
+}
+
+/// Clear the breakpoint set in the given debug register on all the online CPUs.
+///
+/// # Errors
+/// Returns [`DebugRegError::NotSet`] if there is no breakpoint in the given debug register.
+pub fn clear(index: usize) -> Result<(), DebugRegError> {
+    x86_64::irq::without(|| TABLE.lock().get_mut(index).and_then(Option::take))
+        .ok_or(DebugRegError::NotSet)?;
+    reload_all();
+    Ok(())
+}
+
+/// Load the breakpoints into the debug registers of the current CPU. This is done by [`set`] and
+/// [`clear`] on the online CPUs, and must be done by the APs when they start.
+pub fn load() {
+    let table = x86_64::irq::without(|| *TABLE.lock());
+    let mut dr7 = 0;
+    for (index, breakpoint) in table.iter().enumerate() {
+        let Some(breakpoint) = breakpoint else {
+            continue;
+        };
+
+        unsafe { write_address(index, breakpoint.address) };
+        let fields = breakpoint.condition as u64 | (breakpoint.length as u64) << 2;
+        dr7 |= DR7_GLOBAL_ENABLE << (index * 2);
+        dr7 |= fields << (DR7_FIELDS_SHIFT + index as u64 * 4);
+    }
+    unsafe {
+        core::arch::asm!("mov dr7, {}", in(reg) dr7, options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Handle a debug exception: the breakpoints that were hit are logged and the execution resumes.
+/// Returns false if the exception was not raised by a breakpoint.
+pub fn handle(state: &mut cpu::State) -> bool {
+    let dr6: u64;
+    unsafe {
+        core::arch::asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags));
+    }
+    if dr6 & DR6_HIT == 0 {
+        return false;
+    }
+
+    // The table may be locked by the code that hit the breakpoint
+    let table = TABLE.try_lock().map(|table| *table);
+    for index in (0..BREAKPOINTS).filter(|index| dr6 & (1 << index) != 0) {
+        match table.and_then(|table| table[index]) {
+            Some(breakpoint) => log::warn!(
+                "Breakpoint {index} ({:?} of {} bytes at {:#018x}) hit at {:#018x}",
+                breakpoint.condition,
+                breakpoint.length.bytes(),
+                breakpoint.address,
+                state.rip
+            ),
+            None => log::warn!("Breakpoint {index} hit at {:#018x}", state.rip),
+        }
+    }
+
+    // The data breakpoints are reported after the access, but the instruction breakpoints before
+    // the execution of the instruction, which must not trigger it again when resumed
+    state.rflags |= RFLAGS_RF;
+    true
+}
+
+/// Load the breakpoints on all the online CPUs.
+fn reload_all() {
+    smp::call_function(CallTarget::Mask(smp::online_mask()), &load);
+}
+
+/// Write the address of the given breakpoint into its debug register.
+unsafe fn write_address(index: usize, address: u64) {
+    match index {
+        0 => core::arch::asm!("mov dr0, {}", in(reg) address, options(nomem, nostack)),
+        1 => core::arch::asm!("mov dr1, {}", in(reg) address, options(nomem, nostack)),
+        2 => core::arch::asm!("mov dr2, {}", in(reg) address, options(nomem, nostack)),
+        _ => core::arch::asm!("mov dr3, {}", in(reg) address, options(nomem, nostack)),
+    }
+}
@@ -1,11 +1,14 @@
+use crate::arch::backtrace::Backtrace;
 use crate::arch::paging;
+use crate::arch::vector::{Fault, PageFaultError, Registers};
+use crate::interrupt_handler;
+use crate::mm;
 use x86_64::address::Virtual;
 use x86_64::cpu;
 use x86_64::cpu::Privilege;
 use x86_64::idt::Descriptor;
 use x86_64::idt::DescriptorFlags;
-use x86_64::interrupt_handler;
-use x86_64::paging::PageFaultErrorCode;
+use x86_64::paging::{PageFaultErrorCode, PAGE_SIZE};
 
 pub fn setup() {
     register_exception_handler(0, divide_by_zero);
@@ -37,9 +40,127 @@ pub fn setup() {
     register_exception_handler(26, reserved_6);
     register_exception_handler(27, reserved_7);
     register_exception_handler(28, hypervisor_injection);
-    register_exception_handler(29, virtualization);
+    register_exception_handler(29, vmm_communication);
     register_exception_handler(30, security_exception);
     register_exception_handler(31, reserved_8);
+
+    // The critical exceptions use their own stack, because they may be caused by a kernel stack
+    // overflow and could not be handled on the faulty stack
+    super::idt::set_stack_index(2, super::tss::NMI_IST);
+    super::idt::set_stack_index(8, super::tss::DOUBLE_FAULT_IST);
+    super::idt::set_stack_index(18, super::tss::MACHINE_CHECK_IST);
+    enable_alignment_check();
+}
+
+/// Enable the alignment check on the current CPU when the kernel is built with the
+/// `alignment-check` feature, and do nothing otherwise. This must be called on each CPU, because
+/// the `AM` bit lives in the CR0 register that is not shared between cores.
+///
+/// This is a debugging aid for user programs only: the CPU raises an alignment check exception
+/// only for a misaligned access made at CPL 3 while the `AC` flag is set in RFLAGS, so misaligned
+/// accesses made by the kernel are never caught. The faulting user thread is killed (see
+/// [`alignment_check_handler`]).
+pub fn enable_alignment_check() {
+    #[cfg(feature = "alignment-check")]
+    unsafe {
+        super::control::Cr0::update(|cr0| cr0 | super::control::Cr0::AM);
+    }
+}
+
+/// Returns true if the given address is canonical, i.e. if the bits 48 to 63 are copies of the
+/// bit 47. Any memory access to a non-canonical address will trigger a general protection fault,
+/// or a stack segment fault if the access was done through the stack.
+#[must_use]
+pub const fn is_canonical(address: u64) -> bool {
+    matches!(address >> 47, 0 | 0x1_FFFF)
+}
+
+/// Try to guess why the given address is not canonical. This is only a heuristic, but in practice
+/// it is often right and can save a lot of time when debugging.
+#[must_use]
+fn non_canonical_cause(address: u64) -> &'static str {
+    if address >> 48 == 0 && address & (1 << 47) != 0 {
+        "missing sign extension: looks like a kernel address truncated to 48 bits"
+    } else if address >> 48 == 0xFFFF && address & (1 << 47) == 0 {
+        "wrong sign extension: looks like an user address with the upper bits set"
+    } else {
+        "wild pointer: uninitialized, corrupted or already freed value used as a pointer"
+    }
+}
+
+/// Search the saved registers for non-canonical addresses and log each one of them with the
+/// likely cause. A general protection fault with a null error code is almost always caused by
+/// a non-canonical address, and the register holding it is usually the faulty pointer.
+///
+/// Returns the number of non-canonical values found in the registers.
+fn report_non_canonical(state: &cpu::State) -> usize {
+    let registers = [
+        ("rax", state.rax),
+        ("rbx", state.rbx),
+        ("rcx", state.rcx),
+        ("rdx", state.rdx),
+        ("rsi", state.rsi),
+        ("rdi", state.rdi),
+        ("rbp", state.rbp),
+        ("rsp", state.rsp),
+        ("r8", state.r8),
+        ("r9", state.r9),
+        ("r10", state.r10),
+        ("r11", state.r11),
+        ("r12", state.r12),
+        ("r13", state.r13),
+        ("r14", state.r14),
+        ("r15", state.r15),
+    ];
+
+    let mut count = 0;
+    for (name, value) in registers.iter().filter(|(_, value)| !is_canonical(*value)) {
+        log::error!(
+            "Non-canonical value in {name}: {value:#018x} ({})",
+            non_canonical_cause(*value)
+        );
+        count += 1;
+    }
+    count
+}
+
+/// Log the first bytes of the instruction that caused the fault, so that the faulting access can
+/// be decoded with a disassembler. Nothing is logged if the instruction pointer is not a canonical
+/// kernel address, because reading it could trigger another fault. The read stops at the end of
+/// the page containing the instruction pointer, because the next page may not be mapped.
+fn report_faulting_instruction(state: &cpu::State) {
+    if is_canonical(state.rip) && state.rip >= crate::mm::KERNEL_BASE {
+        let page_offset = usize::try_from(state.rip % PAGE_SIZE as u64).unwrap();
+        let len = core::cmp::min(8, PAGE_SIZE - page_offset);
+        let bytes = unsafe { core::slice::from_raw_parts(state.rip as *const u8, len) };
+        log::error!(
+            "Faulting instruction at {:#018x}: {:02x?}",
+            state.rip,
+            bytes
+        );
+    }
+}
+
+/// Terminate the current thread if the exception was raised by the user code, which must not bring
+/// down the kernel, and handle it as an unexpected kernel exception otherwise.
+fn user_fault(state: &cpu::State) -> ! {
+    if state.cs & 3 == 3 {
+        log::warn!(
+            "Thread {} killed: {}",
+            crate::sched::current_tid().as_u64(),
+            Fault(state)
+        );
+        crate::sched::exit();
+    }
+    unhandled(state);
+}
+
+/// Log the registers saved when the given exception was raised, and panic with the decoded
+/// description of the exception (see [`Fault`]).
+fn unhandled(state: &cpu::State) -> ! {
+    log::error!("Registers:\n{}", Registers(state));
+    log::error!("Backtrace:\n{}", Backtrace::from_state(state));
+    panic!("{}", Fault(state));
 }
 
 #[allow(clippy::fn_to_numeric_cast)]
@@ -56,152 +177,218 @@ fn register_exception_handler(index: u8, handler: unsafe extern "C" fn()) {
     idt.set_descriptor(index, descriptor);
 }
 
-pub extern "C" fn divide_by_zero_handler(_state: &cpu::State) {
-    panic!("Divide by zero exception");
+pub extern "C" fn divide_by_zero_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn debug_handler(_state: &cpu::State) {
-    panic!("Debug exception");
+pub extern "C" fn debug_handler(state: &mut cpu::State) {
+    // The hardware breakpoints are only reported, so that all the accesses to the watched memory
+    // can be traced
+    let rip = state.rip;
+    if !super::debugreg::handle(state) {
+        assert!(state.cs & 3 == 3, "Debug exception at {rip:#018x}");
+        user_fault(state);
+    }
 }
 
-pub extern "C" fn non_maskable_interrupt_handler(_state: &cpu::State) {
-    // Just freeze the CPU. This is used by the panic function to halt other core.
-    // This is a temporary solution, but it works.
-    x86_64::cpu::freeze();
+pub extern "C" fn non_maskable_interrupt_handler(state: &cpu::State) {
+    // The NMI is only used by the panic function to halt the other cores: the state of the core is
+    // saved for the panicking core before it is frozen.
+    crate::glue::halted_by_panic(state);
 }
 
-pub extern "C" fn breakpoint_handler(_state: &cpu::State) {
-    panic!("Breakpoint exception");
+pub extern "C" fn breakpoint_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn overflow_handler(_state: &cpu::State) {
-    panic!("Overflow exception");
+pub extern "C" fn overflow_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn bound_range_exceeded_handler(_state: &cpu::State) {
-    panic!("Bound range exceeded exception");
+pub extern "C" fn bound_range_exceeded_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn invalid_opcode_handler(_state: &cpu::State) {
-    panic!("Invalid opcode exception");
+pub extern "C" fn invalid_opcode_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn device_not_available_handler(_state: &cpu::State) {
-    panic!("Device not available exception");
+pub extern "C" fn device_not_available_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn double_fault_handler(_state: &cpu::State) {
-    panic!("Double fault");
+pub extern "C" fn double_fault_handler(state: &cpu::State) {
+    log::error!("Registers:\n{}", Registers(state));
+    log::error!("Backtrace:\n{}", Backtrace::from_state(state));
+    panic!(
+        "Double fault at {:#018x} with the stack pointer at {:#018x} (kernel stack overflow?)",
+        state.rip, state.rsp
+    );
 }
 
-pub extern "C" fn coprocessor_segment_overrun_handler(_state: &cpu::State) {
-    panic!("Coprocessor segment overrun exception");
+pub extern "C" fn coprocessor_segment_overrun_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-pub extern "C" fn invalid_tss_handler(_state: &cpu::State) {
-    panic!("Invalid TSS exception");
+pub extern "C" fn invalid_tss_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn segment_not_present_handler(_state: &cpu::State) {
-    panic!("Segment not present exception");
+pub extern "C" fn segment_not_present_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn stack_segment_fault_handler(_state: &cpu::State) {
-    panic!("Stack segment fault exception");
+pub extern "C" fn stack_segment_fault_handler(state: &cpu::State) {
+    if state.cs & 3 == 3 {
+        user_fault(state);
+    }
+    if state.code == 0 && report_non_canonical(state) > 0 {
+        report_faulting_instruction(state);
+        panic!("Stack segment fault caused by a non-canonical stack address");
+    }
+    unhandled(state);
 }
 
-pub extern "C" fn general_protection_fault_handler(state: &cpu::State) {
-    panic!(
-        "General protection fault (error code: 0x{:02x})",
-        state.code
-    );
+pub extern "C" fn general_protection_fault_handler(state: &mut cpu::State) {
+    // A fault raised by an instruction of the exception table (e.g. an access to a missing MSR,
+    // see [`super::msr::try_read`]) resumes the execution at its fixup code
+    if mm::user::fixup(state) {
+        return;
+    }
+    if state.cs & 3 == 3 {
+        user_fault(state);
+    }
+
+    // A null error code means that the fault was not caused by a selector
+    if state.code == 0 {
+        report_faulting_instruction(state);
+        assert!(
+            report_non_canonical(state) == 0,
+            "General protection fault caused by a non-canonical address"
+        );
+    }
+    unhandled(state);
 }
 
-pub extern "C" fn page_fault_handler(state: &cpu::State) {
+pub extern "C" fn page_fault_handler(state: &mut cpu::State) {
     let code = PageFaultErrorCode::from_bits_truncate(state.code);
     let addr = Virtual::new(x86_64::cpu::cr2::read());
 
     if let Err(reason) = paging::page_fault(code, addr) {
+        // If the fault was caused by an user memory access, resume the execution at the fixup
+        // code instead of panicking.
+        if mm::user::fixup(state) {
+            return;
+        }
+
+        // If the fault was caused by the user code, only the faulting thread is terminated
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            log::warn!(
+                "Thread {} killed: page fault ({}) at {:016x}: {:?}",
+                crate::sched::current_tid().as_u64(),
+                PageFaultError(code),
+                addr.as_u64(),
+                reason
+            );
+            crate::sched::exit();
+        }
+        log::error!("Registers:\n{}", Registers(state));
+        log::error!("Backtrace:\n{}", Backtrace::from_state(state));
         panic!(
-            "Unrecoverable page fault ({:?}) at {:016x}: {:?}",
-            code,
+            "Unrecoverable page fault ({}) at {:016x}: {:?}",
+            PageFaultError(code),
             addr.as_u64(),
             reason
         );
     }
 }
 
-pub extern "C" fn reserved_handler(_state: &cpu::State) {
-    panic!("Reserved exception");
+pub extern "C" fn reserved_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-pub extern "C" fn x87_floating_point_handler(_state: &cpu::State) {
-    panic!("x87 floating point exception");
+pub extern "C" fn x87_floating_point_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn alignment_check_handler(_state: &cpu::State) {
-    panic!("Alignment check exception");
+/// The alignment check exception is only raised at CPL 3 (see [`enable_alignment_check`]), so
+/// only the faulting user thread is terminated.
+pub extern "C" fn alignment_check_handler(state: &cpu::State) {
+    if state.cs & 3 == 3 {
+        log::warn!(
+            "Thread {} killed: alignment check exception at {:#018x}",
+            crate::sched::current_tid().as_u64(),
+            state.rip
+        );
+        crate::sched::exit();
+    }
+    report_faulting_instruction(state);
+    panic!(
+        "Alignment check exception: misaligned access at {:#018x} (CPL {})",
+        state.rip,
+        state.cs & 0b11
+    );
 }
 
-pub extern "C" fn machine_check_handler(_state: &cpu::State) {
-    panic!("Machine check exception");
+pub extern "C" fn machine_check_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-pub extern "C" fn simd_floating_point_handler(_state: &cpu::State) {
-    panic!("SIMD floating point exception");
+pub extern "C" fn simd_floating_point_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn virtualization_handler(_state: &cpu::State) {
-    panic!("Virtualization exception");
+pub extern "C" fn virtualization_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-pub extern "C" fn control_protection_handler(_state: &cpu::State) {
-    panic!("Control protection exception");
+pub extern "C" fn control_protection_handler(state: &cpu::State) {
+    user_fault(state);
 }
 
-pub extern "C" fn hypervisor_injection_handler(_state: &cpu::State) {
-    panic!("Hypervisor injection exception");
+pub extern "C" fn hypervisor_injection_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-pub extern "C" fn vmm_communication_handler(_state: &cpu::State) {
-    panic!("Hypervisor injection exception");
+pub extern "C" fn vmm_communication_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-pub extern "C" fn security_exception_handler(_state: &cpu::State) {
-    panic!("Security exception");
+pub extern "C" fn security_exception_handler(state: &cpu::State) {
+    unhandled(state);
 }
 
-interrupt_handler!(0, divide_by_zero, divide_by_zero_handler, 0);
-interrupt_handler!(1, debug, debug_handler, 0);
-interrupt_handler!(2, non_maskable_interrupt, non_maskable_interrupt_handler, 0);
-interrupt_handler!(3, breakpoint, breakpoint_handler, 0);
-interrupt_handler!(4, overflow, overflow_handler, 0);
-interrupt_handler!(5, bound_range_exceeded, bound_range_exceeded_handler, 0);
-interrupt_handler!(6, invalid_opcode, invalid_opcode_handler, 0);
-interrupt_handler!(7, device_not_available, device_not_available_handler, 0);
+interrupt_handler!(0, divide_by_zero, divide_by_zero_handler);
+interrupt_handler!(1, debug, debug_handler);
+interrupt_handler!(2, non_maskable_interrupt, non_maskable_interrupt_handler);
+interrupt_handler!(3, breakpoint, breakpoint_handler);
+interrupt_handler!(4, overflow, overflow_handler);
+interrupt_handler!(5, bound_range_exceeded, bound_range_exceeded_handler);
+interrupt_handler!(6, invalid_opcode, invalid_opcode_handler);
+interrupt_handler!(7, device_not_available, device_not_available_handler);
 interrupt_handler!(8, double_fault, double_fault_handler);
 #[rustfmt::skip]
-interrupt_handler!(9,coprocessor_segment_overrun, coprocessor_segment_overrun_handler, 0);
+interrupt_handler!(9,coprocessor_segment_overrun, coprocessor_segment_overrun_handler);
 interrupt_handler!(10, invalid_tss, invalid_tss_handler);
 interrupt_handler!(11, segment_not_present, segment_not_present_handler);
 interrupt_handler!(12, stack_segment_fault, stack_segment_fault_handler);
 #[rustfmt::skip]
 interrupt_handler!(13,general_protection_fault, general_protection_fault_handler);
 interrupt_handler!(14, page_fault, page_fault_handler);
-interrupt_handler!(15, reserved_1, reserved_handler, 0);
-interrupt_handler!(16, x87_floating_point, x87_floating_point_handler, 0);
+interrupt_handler!(15, reserved_1, reserved_handler);
+interrupt_handler!(16, x87_floating_point, x87_floating_point_handler);
 interrupt_handler!(17, alignment_check, alignment_check_handler);
-interrupt_handler!(18, machine_check, machine_check_handler, 0);
-interrupt_handler!(19, simd, simd_floating_point_handler, 0);
-interrupt_handler!(20, virtualization, virtualization_handler, 0);
+interrupt_handler!(18, machine_check, machine_check_handler);
+interrupt_handler!(19, simd, simd_floating_point_handler);
+interrupt_handler!(20, virtualization, virtualization_handler);
 interrupt_handler!(21, control_protection, control_protection_handler);
-interrupt_handler!(22, reserved_2, reserved_handler, 0);
-interrupt_handler!(23, reserved_3, reserved_handler, 0);
-interrupt_handler!(24, reserved_4, reserved_handler, 0);
-interrupt_handler!(25, reserved_5, reserved_handler, 0);
-interrupt_handler!(26, reserved_6, reserved_handler, 0);
-interrupt_handler!(27, reserved_7, reserved_handler, 0);
-interrupt_handler!(28, hypervisor_injection, hypervisor_injection_handler, 0);
+interrupt_handler!(22, reserved_2, reserved_handler);
+interrupt_handler!(23, reserved_3, reserved_handler);
+interrupt_handler!(24, reserved_4, reserved_handler);
+interrupt_handler!(25, reserved_5, reserved_handler);
+interrupt_handler!(26, reserved_6, reserved_handler);
+interrupt_handler!(27, reserved_7, reserved_handler);
+interrupt_handler!(28, hypervisor_injection, hypervisor_injection_handler);
 interrupt_handler!(29, vmm_communication, vmm_communication_handler);
 interrupt_handler!(30, security_exception, security_exception_handler);
-interrupt_handler!(31, reserved_8, reserved_handler, 0);
+interrupt_handler!(31, reserved_8, reserved_handler);
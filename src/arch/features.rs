@@ -0,0 +1,220 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bitflags::bitflags;
+
+use super::cpuid::{self, Register};
+
+/// The CPUID leaves scanned for features.
+const CPUID_FEATURES: u32 = 0x01;
+const CPUID_STRUCTURED_FEATURES: u32 = 0x07;
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+bitflags! {
+    /// The CPU features the kernel may rely on, as reported by CPUID.
+    pub struct CpuFeatures : u64 {
+        const NONE = 0;
+        const FPU = 1 << 0;
+        const TSC = 1 << 1;
+        const APIC = 1 << 2;
+        const FXSR = 1 << 3;
+        const SSE = 1 << 4;
+        const SSE2 = 1 << 5;
+        const SSE3 = 1 << 6;
+        const MONITOR_MWAIT = 1 << 7;
+        const X2APIC = 1 << 8;
+        const TSC_DEADLINE = 1 << 9;
+        const XSAVE = 1 << 10;
+        const AVX = 1 << 11;
+        const RDRAND = 1 << 12;
+        const HYPERVISOR = 1 << 13;
+        const FSGSBASE = 1 << 14;
+        const SMEP = 1 << 15;
+        const SMAP = 1 << 16;
+        const INVPCID = 1 << 17;
+        const PCID = 1 << 18;
+        const NX = 1 << 19;
+        const PDPE1GB = 1 << 20;
+        const RDTSCP = 1 << 21;
+        const INVARIANT_TSC = 1 << 22;
+        const CLFLUSH = 1 << 23;
+        const CLFLUSHOPT = 1 << 24;
+        const CLWB = 1 << 25;
+
+        /// The features the kernel cannot run without, whatever the configuration.
+        const BASELINE = Self::FPU.bits | Self::TSC.bits | Self::APIC.bits | Self::FXSR.bits
+            | Self::SSE.bits | Self::SSE2.bits | Self::NX.bits;
+    }
+}
+
+/// The features supported by all the CPUs started so far. It starts with the features of the BSP,
+/// and the features missing on an AP are removed from it when the AP starts.
+static COMMON: AtomicU64 = AtomicU64::new(0);
+
+/// The features the kernel already relies on: an AP without one of them cannot be used.
+static REQUIRED: AtomicU64 = AtomicU64::new(CpuFeatures::BASELINE.bits);
+
+/// The bits of the registers returned by CPUID for each feature: the leaf, the register and the
+/// bit.
+const FEATURE_BITS: [(CpuFeatures, u32, Register, u32); 26] = [
+    (CpuFeatures::FPU, CPUID_FEATURES, Register::Edx, 0),
+    (CpuFeatures::TSC, CPUID_FEATURES, Register::Edx, 4),
+    (CpuFeatures::APIC, CPUID_FEATURES, Register::Edx, 9),
+    (CpuFeatures::CLFLUSH, CPUID_FEATURES, Register::Edx, 19),
+    (CpuFeatures::FXSR, CPUID_FEATURES, Register::Edx, 24),
+    (CpuFeatures::SSE, CPUID_FEATURES, Register::Edx, 25),
+    (CpuFeatures::SSE2, CPUID_FEATURES, Register::Edx, 26),
+    (CpuFeatures::SSE3, CPUID_FEATURES, Register::Ecx, 0),
+    (CpuFeatures::MONITOR_MWAIT, CPUID_FEATURES, Register::Ecx, 3),
+    (CpuFeatures::PCID, CPUID_FEATURES, Register::Ecx, 17),
+    (CpuFeatures::X2APIC, CPUID_FEATURES, Register::Ecx, 21),
+    (CpuFeatures::TSC_DEADLINE, CPUID_FEATURES, Register::Ecx, 24),
+    (CpuFeatures::XSAVE, CPUID_FEATURES, Register::Ecx, 26),
+    (CpuFeatures::AVX, CPUID_FEATURES, Register::Ecx, 28),
+    (CpuFeatures::RDRAND, CPUID_FEATURES, Register::Ecx, 30),
+    (CpuFeatures::HYPERVISOR, CPUID_FEATURES, Register::Ecx, 31),
+    (
+        CpuFeatures::FSGSBASE,
+        CPUID_STRUCTURED_FEATURES,
+        Register::Ebx,
+        0,
+    ),
+    (
+        CpuFeatures::SMEP,
+        CPUID_STRUCTURED_FEATURES,
+        Register::Ebx,
+        7,
+    ),
+    (
+        CpuFeatures::INVPCID,
+        CPUID_STRUCTURED_FEATURES,
+        Register::Ebx,
+        10,
+    ),
+    (
+        CpuFeatures::SMAP,
+        CPUID_STRUCTURED_FEATURES,
+        Register::Ebx,
+        20,
+    ),
+    (
+        CpuFeatures::CLFLUSHOPT,
+        CPUID_STRUCTURED_FEATURES,
+        Register::Ebx,
+        23,
+    ),
+    (
+        CpuFeatures::CLWB,
+        CPUID_STRUCTURED_FEATURES,
+        Register::Ebx,
+        24,
+    ),
+    (CpuFeatures::NX, CPUID_EXTENDED_FEATURES, Register::Edx, 20),
+    (
+        CpuFeatures::PDPE1GB,
+        CPUID_EXTENDED_FEATURES,
+        Register::Edx,
+        26,
+    ),
+    (
+        CpuFeatures::RDTSCP,
+        CPUID_EXTENDED_FEATURES,
+        Register::Edx,
+        27,
+    ),
+    (
+        CpuFeatures::INVARIANT_TSC,
+        CPUID_POWER_MANAGEMENT,
+        Register::Edx,
+        8,
+    ),
+];
+
+/// Scan the features of the current CPU with CPUID. The features of the leaves the CPU does not
+/// have are not supported.
+#[must_use]
+pub fn detect() -> CpuFeatures {
+    FEATURE_BITS
+        .iter()
+        .filter(|&&(_, leaf, register, bit)| cpuid::register(leaf, 0, register) & (1 << bit) != 0)
+        .fold(CpuFeatures::NONE, |features, &(feature, ..)| {
+            features | feature
+        })
+}
+
+/// Record the features of the BSP as the initial common feature mask. This must be called by the
+/// BSP before any AP is started.
+///
+/// # Panics
+/// Panics if the BSP lacks a feature of [`CpuFeatures::BASELINE`].
+pub fn setup() {
+    let features = detect();
+    let missing = CpuFeatures::BASELINE - features;
+    assert!(
+        missing.is_empty(),
+        "CPU lacks required features: {missing:?}"
+    );
+    COMMON.store(features.bits(), Ordering::Release);
+    report(features);
+}
+
+/// Check the features of the current AP against the features of the CPUs started before it. The
+/// features it lacks are removed from the common feature mask, so that the rest of the kernel
+/// stops using them.
+///
+/// # Panics
+/// Panics if the AP lacks a feature the kernel already relies on (see [`require`]): the AP cannot
+/// be used, and it cannot be ignored either because it has been started.
+pub fn ap_check(cpu: u32) {
+    let features = detect();
+    let required = CpuFeatures::from_bits_truncate(REQUIRED.load(Ordering::Acquire));
+    let missing = required - features;
+    assert!(
+        missing.is_empty(),
+        "CPU {cpu} lacks features the kernel relies on: {missing:?}"
+    );
+
+    let previous =
+        CpuFeatures::from_bits_truncate(COMMON.fetch_and(features.bits(), Ordering::AcqRel));
+    let disabled = previous - features;
+    if !disabled.is_empty() {
+        log::warn!("CPU {cpu} lacks some features, they are disabled on all CPUs: {disabled:?}");
+    }
+}
+
+/// Record that the kernel relies on the given features from now on, because the BSP has started to
+/// use them in a way that cannot be undone: the APs lacking them are refused (see [`ap_check`]).
+/// Returns false if one of the features is not supported by all the CPUs started so far, in which
+/// case the caller must not use them.
+pub fn require(features: CpuFeatures) -> bool {
+    if !cpu_features().contains(features) {
+        return false;
+    }
+    REQUIRED.fetch_or(features.bits(), Ordering::AcqRel);
+    true
+}
+
+/// Returns the features supported by all the CPUs started so far. Only those features should be
+/// used by the kernel, because a thread can migrate to any CPU.
+#[must_use]
+pub fn cpu_features() -> CpuFeatures {
+    CpuFeatures::from_bits_truncate(COMMON.load(Ordering::Acquire))
+}
+
+/// Log the identity and the features of the BSP.
+fn report(features: CpuFeatures) {
+    let mut buffer = [0; 48];
+    let signature = cpuid::signature();
+    log::info!(
+        "CPU: {} ({:?}, family {:#x}, model {:#x}, stepping {})",
+        cpuid::brand(&mut buffer).unwrap_or("unknown model"),
+        cpuid::vendor(),
+        signature.family,
+        signature.model,
+        signature.stepping
+    );
+    if let Some(hz) = cpuid::tsc_frequency() {
+        log::info!("CPU: TSC frequency reported at {} MHz", hz / 1_000_000);
+    }
+    log::debug!("CPU features: {features:?}");
+}
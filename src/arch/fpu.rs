@@ -0,0 +1,186 @@
+use core::{
+    alloc::Layout,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::{
+    control::{Cr0, Cr4, Xcr0},
+    cpuid::{self, Register},
+    features::{self, CpuFeatures},
+};
+
+/// The CPUID leaf describing the state components saved by XSAVE.
+const CPUID_XSAVE: u32 = 0x0D;
+
+/// The size of the legacy area saved by FXSAVE.
+const FXSAVE_SIZE: usize = 512;
+
+/// The alignment of the area saved by XSAVE (FXSAVE only needs 16 bytes).
+const AREA_ALIGN: usize = 64;
+
+/// The initial values of the x87 control word and of the MXCSR register: all the exceptions are
+/// masked, and the rounding is to the nearest. They are stored at these offsets of the area.
+const FCW_INIT: u16 = 0x037F;
+const FCW_OFFSET: usize = 0;
+const MXCSR_INIT: u32 = 0x1F80;
+const MXCSR_OFFSET: usize = 24;
+
+/// Set if the state is saved with XSAVE rather than with FXSAVE.
+static XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// The state components enabled in XCR0 on all the CPUs, chosen by the BSP.
+static XCR0: AtomicU64 = AtomicU64::new(0);
+
+/// The size of the area needed to save the state, or 0 until [`setup`] is called by the BSP.
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The x87, SSE and AVX registers of a user thread, saved while the thread does not run. The
+/// kernel itself is built without floating point and SIMD instructions, so the kernel threads do
+/// not need to save or restore these registers.
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// Create a new state with the default values of the registers: all the registers are zeroed
+    /// and the floating point exceptions are masked.
+    ///
+    /// # Panics
+    /// Panics if the FPU was not initialized with [`setup`].
+    #[must_use]
+    pub fn new() -> Self {
+        let layout = layout();
+        let area = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        let Some(area) = NonNull::new(area) else {
+            alloc::alloc::handle_alloc_error(layout);
+        };
+
+        // The header of the XSAVE area is zeroed, so XRSTOR loads the initial state of all the
+        // components except MXCSR, which is always loaded from the legacy area
+        unsafe {
+            area.as_ptr()
+                .add(FCW_OFFSET)
+                .cast::<u16>()
+                .write_unaligned(FCW_INIT);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write_unaligned(MXCSR_INIT);
+        }
+        Self { area }
+    }
+
+    /// Save the registers of the current CPU into this state.
+    pub fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                core::arch::asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Load the registers of the current CPU from this state.
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                core::arch::asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                core::arch::asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.area.as_ptr(), layout()) };
+    }
+}
+
+/// Enable the FPU, SSE and, if supported by all the CPUs, XSAVE and AVX on the current CPU. The
+/// BSP chooses the state components and the size of the area needed to save them, which the APs
+/// then use as well. This must be called on each CPU after its features are checked (see
+/// [`features::ap_check`]), because the CR0, CR4 and XCR0 registers are not shared between cores.
+pub fn setup() {
+    let bsp = AREA_SIZE.load(Ordering::Acquire) == 0;
+    if bsp && features::require(CpuFeatures::XSAVE) {
+        // The x87 and SSE states are mandatory, the AVX state is enabled if all the CPUs have it
+        let supported = u64::from(cpuid::register(CPUID_XSAVE, 0, Register::Eax));
+        let mut xcr0 = Xcr0::X87 | Xcr0::SSE;
+        if supported & Xcr0::AVX.bits() != 0 && features::require(CpuFeatures::AVX) {
+            xcr0 |= Xcr0::AVX;
+        }
+        XCR0.store(xcr0.bits(), Ordering::Relaxed);
+        XSAVE.store(true, Ordering::Relaxed);
+    }
+
+    let xsave = XSAVE.load(Ordering::Relaxed);
+    unsafe {
+        Cr0::update(|cr0| (cr0 - Cr0::EM) | Cr0::MP | Cr0::NE);
+        Cr4::update(|cr4| {
+            let cr4 = cr4 | Cr4::OSFXSR | Cr4::OSXMMEXCPT;
+            if xsave {
+                cr4 | Cr4::OSXSAVE
+            } else {
+                cr4
+            }
+        });
+        if xsave {
+            Xcr0::from_bits_truncate(XCR0.load(Ordering::Relaxed)).write();
+        }
+    }
+
+    if bsp {
+        // The leaf gives the size needed for the components currently enabled in XCR0
+        let size = if XSAVE.load(Ordering::Relaxed) {
+            cpuid::register(CPUID_XSAVE, 0, Register::Ebx) as usize
+        } else {
+            FXSAVE_SIZE
+        };
+        AREA_SIZE.store(size, Ordering::Release);
+        log::info!(
+            "FPU: state saved with {} ({size} bytes, XCR0 = {:#x})",
+            if XSAVE.load(Ordering::Relaxed) {
+                "XSAVE"
+            } else {
+                "FXSAVE"
+            },
+            XCR0.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Returns the layout of the area needed to save the state.
+fn layout() -> Layout {
+    let size = AREA_SIZE.load(Ordering::Acquire);
+    assert!(
+        size != 0,
+        "FPU state allocated before the FPU is initialized"
+    );
+    Layout::from_size_align(size, AREA_ALIGN).unwrap()
+}
@@ -1,35 +1,64 @@
+use sync::Once;
 use x86_64::gdt;
 use x86_64::segment;
 
-use crate::{config, Spinlock};
+/// The number of entries of the GDT of each CPU: the null descriptor, the kernel and user
+/// segments, and the TSS of the CPU, whose descriptor takes two entries.
+const ENTRIES: usize = 8;
 
-pub static GDT: Spinlock<gdt::Table<{ 6 + config::MAX_CPU * 2 }>> =
-    Spinlock::new(gdt::Table::new());
+/// The index of the TSS descriptor in the GDT of each CPU.
+pub const TSS_INDEX: u16 = 6;
 
-/// Onlu used during the boot process, this function setup the GDT for the BSP and load it into the
-/// CPU. This allow to have early interrupts and exceptions handling, when we not yet have a
-/// TLS.
+crate::per_cpu! {
+    /// The GDT of each CPU. The GDTs only differ by their TSS descriptor, and are never modified
+    /// once built: they can therefore be reloaded without taking any lock.
+    static GDT: Once<gdt::Table<ENTRIES>> = Once::new();
+}
+
+/// The selector of the user data segment, with the RPL set to 3.
+pub const USER_DATA_SELECTOR: u16 = (4 << 3) | 3;
+
+/// The selector of the user code segment used when returning to user mode, with the RPL set to
+/// 3. The `sysret` instruction requires the user code segment to be located just after the user
+/// data segment in the GDT, so the user code segment is duplicated in the 6th entry of the GDT.
+pub const USER_CODE_SELECTOR: u16 = (5 << 3) | 3;
+
+/// Build the GDT of the current CPU, with the descriptor of its TSS, and load it into the CPU. The
+/// BSP calls this function during the boot process, before it has a TLS, to have early interrupts
+/// and exceptions handling. The APs call it once their TLS is allocated, so that they do not share
+/// the GDT of the BSP, and therefore its TSS descriptor. The TSS itself is loaded later by
+/// [`super::tss::install`].
 pub fn setup() {
-    let mut gdt = GDT.lock();
-    gdt.set_descriptor(0, &gdt::Descriptor::NULL);
-    gdt.set_descriptor(1, &gdt::Descriptor::KERNEL_CODE64);
-    gdt.set_descriptor(2, &gdt::Descriptor::KERNEL_DATA);
-    gdt.set_descriptor(3, &gdt::Descriptor::USER_CODE64);
-    gdt.set_descriptor(4, &gdt::Descriptor::USER_DATA);
+    let gdt = GDT.local().call_once(|| {
+        let mut gdt = gdt::Table::new();
+        gdt.set_descriptor(0, &gdt::Descriptor::NULL);
+        gdt.set_descriptor(1, &gdt::Descriptor::KERNEL_CODE64);
+        gdt.set_descriptor(2, &gdt::Descriptor::KERNEL_DATA);
+        gdt.set_descriptor(3, &gdt::Descriptor::USER_CODE64);
+        gdt.set_descriptor(4, &gdt::Descriptor::USER_DATA);
+        gdt.set_descriptor(5, &gdt::Descriptor::USER_CODE64);
+        gdt.set_descriptor(usize::from(TSS_INDEX), &super::tss::descriptor());
+        gdt
+    });
     gdt.flush();
-    unsafe {
-        segment::reload(
-            &segment::Selector::KERNEL_CODE64,
-            &segment::Selector::KERNEL_DATA,
-        );
-        core::arch::asm!("mov fs, {0:e}", in(reg) 0);
-        core::arch::asm!("mov gs, {0:e}", in(reg) 0);
-    }
+    reload_segments();
 }
 
-/// Reload the GDT and all the segment registers, and load 0 in the FS and GS registers
+/// Reload the GDT of the current CPU and all the segment registers, and load 0 in the FS and GS
+/// registers. An AP calls this function before its TLS is allocated, and then loads the GDT of the
+/// BSP, which is fine as long as it does not load the TSS described by it.
+///
+/// # Panics
+/// Panics if the GDT of the current CPU was not built yet (see [`setup`]).
 pub fn reload() {
-    GDT.lock().flush();
+    GDT.local()
+        .get()
+        .expect("GDT reloaded before being built")
+        .flush();
+    reload_segments();
+}
+
+fn reload_segments() {
     unsafe {
         segment::reload(
             &segment::Selector::KERNEL_CODE64,
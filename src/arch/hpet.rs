@@ -0,0 +1,343 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{address::Virtual, cpu::State};
+
+use crate::{interrupt_handler, Spinlock};
+
+use super::{
+    acpi::{HpetTable, HPET_VECTOR},
+    ioapic::{self, Polarity, TriggerMode},
+    mmio::Mmio,
+};
+
+/// The offsets of the HPET registers used by the kernel.
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+/// The offsets of the configuration and comparator registers of the first timer. The registers of
+/// the timer `n` are at these offsets plus `n * TIMER_STRIDE`.
+const TIMER_CONFIGURATION: u64 = 0x100;
+const TIMER_COMPARATOR: u64 = 0x108;
+const TIMER_STRIDE: u64 = 0x20;
+
+/// The bit of the capabilities register set if the main counter is 64 bits wide.
+const CAPABILITIES_64_BITS: u64 = 1 << 13;
+
+/// The bits of the capabilities register holding the index of the last timer.
+const CAPABILITIES_LAST_TIMER_SHIFT: u64 = 8;
+const CAPABILITIES_LAST_TIMER_MASK: u64 = 0x1F;
+
+/// The bits of the configuration register of a timer.
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_64_BITS_CAPABLE: u64 = 1 << 5;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+/// The high half of the configuration register of a timer is the set of the IOAPIC inputs the
+/// timer can be routed to.
+const TIMER_ROUTING_CAPABILITIES_SHIFT: u64 = 32;
+
+/// The minimum delay of a one-shot event, in counter ticks. A comparator is only compared for
+/// equality with the main counter, so an event programmed too close to the current time may be
+/// missed if the counter has passed the comparator before it was written.
+const MIN_EVENT_TICKS: u64 = 16;
+
+/// The size of the HPET register block.
+const REGISTERS_SIZE: usize = 0x400;
+
+/// The bits of the configuration register starting the main counter, and routing the two first
+/// timers to the IRQ 0 and 8 instead of their own routes (legacy replacement).
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// The longest counter period allowed by the specification, in femtoseconds (100 ns).
+const MAX_PERIOD: u64 = 100_000_000;
+
+pub const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The virtual address of the HPET registers, or 0 if there is no usable HPET.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// The period of the main counter, in femtoseconds.
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// The number of timers of the HPET.
+static TIMERS: AtomicU64 = AtomicU64::new(0);
+
+/// The timer used for the one-shot events, if one could be routed to the IOAPIC.
+static EVENT_TIMER: Spinlock<Option<EventTimer>> = Spinlock::new(None);
+
+/// The function called when the pending one-shot event fires.
+static EVENT_HANDLER: Spinlock<Option<EventHandler>> = Spinlock::new(None);
+
+/// A function called from the interrupt handler when a one-shot event fires, with interrupts
+/// disabled.
+pub type EventHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HpetError {
+    /// There is no HPET, or none of its timers can raise an interrupt through the IOAPIC.
+    Unavailable,
+
+    /// The delay cannot be represented by the comparator of the timer.
+    TooLong,
+
+    /// The main counter passed the comparator before it was written: the event will not fire.
+    Expired,
+}
+
+/// A timer of the HPET, and the global system interrupt it is routed to.
+#[derive(Debug, Clone, Copy)]
+struct EventTimer {
+    index: u64,
+    gsi: u32,
+    wide: bool,
+}
+
+/// Map the HPET described by the HPET table and start its main counter. The HPET is only used as
+/// a reference clock: its comparators are left disabled until [`setup_events`] routes one of them.
+/// The legacy replacement routing is disabled, so that each timer uses its own route.
+pub fn setup(table: &HpetTable) {
+    let Some(base) = (unsafe { super::acpi::remap_mmio(table.base_address, REGISTERS_SIZE) })
+    else {
+        log::warn!("Failed to map the HPET registers");
+        return;
+    };
+
+    let period = read(base, CAPABILITIES) >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        log::warn!("HPET ignored: invalid counter period ({period} fs)");
+        return;
+    }
+
+    // The table and the capabilities register should agree, but the registers are trusted
+    let capabilities = read(base, CAPABILITIES);
+    let timers =
+        ((capabilities >> CAPABILITIES_LAST_TIMER_SHIFT) & CAPABILITIES_LAST_TIMER_MASK) + 1;
+    if timers != u64::from(table.comparators) {
+        log::warn!(
+            "HPET: the HPET table describes {} timers, but the HPET has {timers}",
+            table.comparators
+        );
+    }
+
+    let configuration = read(base, CONFIGURATION) & !CONFIGURATION_LEGACY_REPLACEMENT;
+    write(base, CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+    PERIOD.store(period, Ordering::Relaxed);
+    TIMERS.store(timers, Ordering::Relaxed);
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    log::debug!(
+        "HPET {}: {} Hz, {timers} timers{}",
+        table.number,
+        FEMTOSECONDS_PER_SECOND / period,
+        if table.legacy_replacement {
+            ", legacy replacement capable"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Returns true if an HPET is present and its main counter is running.
+#[must_use]
+pub fn available() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the period of the main counter in femtoseconds, or 0 if there is no HPET.
+#[must_use]
+pub fn period() -> u64 {
+    PERIOD.load(Ordering::Relaxed)
+}
+
+/// Returns the mask of the significant bits of the main counter, which is either 32 or 64 bits
+/// wide, or 0 if there is no HPET.
+#[must_use]
+pub fn counter_mask() -> u64 {
+    if !available() {
+        return 0;
+    }
+    let capabilities = read(Virtual::new(BASE.load(Ordering::Relaxed)), CAPABILITIES);
+    if capabilities & CAPABILITIES_64_BITS != 0 {
+        u64::MAX
+    } else {
+        u64::from(u32::MAX)
+    }
+}
+
+/// Returns the current value of the main counter.
+///
+/// # Panics
+/// Panics if there is no HPET (see [`available`]).
+#[must_use]
+pub fn counter() -> u64 {
+    assert!(available(), "No HPET available");
+    read(Virtual::new(BASE.load(Ordering::Relaxed)), MAIN_COUNTER)
+}
+
+fn read(base: Virtual, register: u64) -> u64 {
+    unsafe { Mmio::<u64>::new(base + register) }.read()
+}
+
+fn write(base: Virtual, register: u64, value: u64) {
+    unsafe { Mmio::<u64>::new(base + register) }.write(value);
+}
+
+/// Route a timer of the HPET to the IOAPIC, so that it can raise one-shot events (see [`arm`]).
+/// This must be called after [`setup`] and after the IOAPICs are initialized. Each timer can only
+/// be routed to the global system interrupts allowed by its routing capabilities, and handled by
+/// an IOAPIC. A timer routed to a global system interrupt that is not used by the legacy ISA IRQs
+/// is preferred, so that the events do not share a line with a device.
+pub fn setup_events() {
+    if !available() || !ioapic::enabled() {
+        return;
+    }
+
+    let base = Virtual::new(BASE.load(Ordering::Relaxed));
+    let candidates = (0..TIMERS.load(Ordering::Relaxed)).filter_map(|index| {
+        let configuration = read(base, timer_register(TIMER_CONFIGURATION, index));
+        let routes = configuration >> TIMER_ROUTING_CAPABILITIES_SHIFT;
+        let routable = |gsi: &u32| routes & (1 << gsi) != 0 && ioapic::handles(*gsi);
+        let gsi = (u32::from(ioapic::ISA_IRQ_COUNT)..32)
+            .rev()
+            .find(routable)
+            .or_else(|| (0..u32::from(ioapic::ISA_IRQ_COUNT)).rev().find(routable))?;
+        Some(EventTimer {
+            index,
+            gsi,
+            wide: configuration & TIMER_64_BITS_CAPABLE != 0,
+        })
+    });
+
+    let mut fallback = None;
+    let mut timer = None;
+    for candidate in candidates {
+        if candidate.gsi >= u32::from(ioapic::ISA_IRQ_COUNT) {
+            timer = Some(candidate);
+            break;
+        }
+        fallback = fallback.or(Some(candidate));
+    }
+    let Some(timer) = timer.or(fallback) else {
+        log::info!("HPET: no timer can be routed to the IOAPIC");
+        return;
+    };
+
+    let bsp = super::smp::get_cpu_info().lapic_id;
+    let routed = ioapic::program(
+        timer.gsi,
+        HPET_VECTOR,
+        bsp,
+        TriggerMode::Edge,
+        Polarity::ActiveHigh,
+        false,
+    );
+    if !routed {
+        log::warn!("HPET: no IOAPIC handles GSI {}", timer.gsi);
+        return;
+    }
+
+    // Disable the timer until an event is armed. The timer is edge triggered and one-shot.
+    let register = timer_register(TIMER_CONFIGURATION, timer.index);
+    let configuration = read(base, register)
+        & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE | TIMER_ROUTE_MASK);
+    write(
+        base,
+        register,
+        configuration | u64::from(timer.gsi) << TIMER_ROUTE_SHIFT,
+    );
+
+    log::debug!("HPET: timer {} routed to GSI {}", timer.index, timer.gsi);
+    x86_64::irq::without(|| *EVENT_TIMER.lock() = Some(timer));
+}
+
+/// Returns true if one-shot events can be armed with [`arm`].
+#[must_use]
+pub fn events_available() -> bool {
+    x86_64::irq::without(|| EVENT_TIMER.lock().is_some())
+}
+
+/// Arm a one-shot event that calls the given handler in about `nanoseconds` nanoseconds. Only one
+/// event can be pending at a time: arming a new event replaces the previous one. The handler is
+/// called on the BSP, with interrupts disabled.
+///
+/// # Errors
+/// - [`HpetError::Unavailable`]: there is no timer usable for one-shot events.
+/// - [`HpetError::TooLong`]: the delay is too long for the comparator of the timer.
+/// - [`HpetError::Expired`]: the delay is too short, and the event would never fire.
+pub fn arm(nanoseconds: u64, handler: EventHandler) -> Result<(), HpetError> {
+    let timer = x86_64::irq::without(|| *EVENT_TIMER.lock()).ok_or(HpetError::Unavailable)?;
+    let ticks = u128::from(nanoseconds) * 1_000_000 / u128::from(period());
+    let ticks = u64::try_from(ticks)
+        .ok()
+        .filter(|&ticks| timer.wide || u32::try_from(ticks).is_ok())
+        .ok_or(HpetError::TooLong)?
+        .max(MIN_EVENT_TICKS);
+
+    let base = Virtual::new(BASE.load(Ordering::Relaxed));
+    let configuration = timer_register(TIMER_CONFIGURATION, timer.index);
+    x86_64::irq::without(|| {
+        *EVENT_HANDLER.lock() = Some(handler);
+        let start = read(base, MAIN_COUNTER);
+        write(
+            base,
+            timer_register(TIMER_COMPARATOR, timer.index),
+            start.wrapping_add(ticks),
+        );
+        write(
+            base,
+            configuration,
+            read(base, configuration) | TIMER_INTERRUPT_ENABLE,
+        );
+
+        // With a 32 bits comparator, only the low half of the counter is compared
+        let elapsed = read(base, MAIN_COUNTER).wrapping_sub(start);
+        let elapsed = if timer.wide {
+            elapsed
+        } else {
+            elapsed & u64::from(u32::MAX)
+        };
+        if elapsed >= ticks {
+            disarm_timer(base, timer);
+            return Err(HpetError::Expired);
+        }
+        Ok(())
+    })
+}
+
+/// Cancel the pending one-shot event, if any.
+pub fn disarm() {
+    x86_64::irq::without(|| {
+        if let Some(timer) = *EVENT_TIMER.lock() {
+            disarm_timer(Virtual::new(BASE.load(Ordering::Relaxed)), timer);
+        }
+    });
+}
+
+fn disarm_timer(base: Virtual, timer: EventTimer) {
+    let register = timer_register(TIMER_CONFIGURATION, timer.index);
+    write(
+        base,
+        register,
+        read(base, register) & !TIMER_INTERRUPT_ENABLE,
+    );
+    *EVENT_HANDLER.lock() = None;
+}
+
+fn timer_register(register: u64, index: u64) -> u64 {
+    register + index * TIMER_STRIDE
+}
+
+/// Handler for the interrupts of the HPET timer used for the one-shot events. The handler of the
+/// event is removed before being called, so it can arm a new event.
+pub extern "C" fn event_handler(_state: &State) {
+    if let Some(handler) = EVENT_HANDLER.lock().take() {
+        handler();
+    }
+    super::lapic::send_eoi();
+}
+
+interrupt_handler!(HPET_VECTOR, event, event_handler);
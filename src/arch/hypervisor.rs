@@ -0,0 +1,103 @@
+//! Detection of the hypervisor the kernel runs under, if any. Hypervisors set the hypervisor bit of
+//! CPUID and describe themselves in the leaves from 0x40000000, which some of them also use to
+//! report the frequencies of the TSC and of the LAPIC timer, sparing a slow and, under emulation,
+//! inaccurate calibration (see [`super::timer`]).
+use sync::Once;
+
+use super::{cpuid, features::CpuFeatures};
+
+/// The leaf giving the highest hypervisor leaf and the signature of the hypervisor.
+const LEAF_HYPERVISOR: u32 = 0x4000_0000;
+
+/// The leaf giving the frequencies of the TSC and of the LAPIC bus, in kHz.
+const LEAF_TIMING: u32 = 0x4000_0010;
+
+/// The hypervisor detected at boot.
+static HYPERVISOR: Once<Option<Hypervisor>> = Once::new();
+
+/// A hypervisor, identified by the signature returned by the leaf 0x40000000.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hypervisor {
+    Kvm,
+    /// The emulator of QEMU, without hardware acceleration.
+    Tcg,
+    VMware,
+    HyperV,
+    Xen,
+    Other([u8; 12]),
+}
+
+/// The frequencies reported by the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timing {
+    /// The frequency of the TSC, in Hz.
+    pub tsc_hz: u64,
+
+    /// The frequency of the LAPIC bus, before the divider of the LAPIC timer, in Hz.
+    pub lapic_hz: u64,
+}
+
+/// Detect the hypervisor and log it, then set up the paravirtual features of KVM if the kernel runs
+/// under it (see [`super::kvm::setup`]). Must be called by the BSP once the frame allocator is
+/// initialized.
+pub fn setup() {
+    match hypervisor() {
+        Some(hypervisor) => log::info!("Running under {hypervisor:?}"),
+        None => log::debug!("No hypervisor detected"),
+    }
+    if hypervisor() == Some(Hypervisor::Kvm) {
+        super::kvm::setup();
+    }
+}
+
+/// Returns the hypervisor the kernel runs under, or `None` on bare metal.
+#[must_use]
+pub fn hypervisor() -> Option<Hypervisor> {
+    *HYPERVISOR.call_once(detect)
+}
+
+/// Returns the highest hypervisor leaf, or 0 on bare metal.
+#[must_use]
+pub fn max_leaf() -> u32 {
+    if hypervisor().is_none() {
+        return 0;
+    }
+    cpuid::register(LEAF_HYPERVISOR, 0, cpuid::Register::Eax)
+}
+
+/// Returns the frequencies of the TSC and of the LAPIC bus reported by the hypervisor, or `None` if
+/// it does not report them.
+#[must_use]
+pub fn timing() -> Option<Timing> {
+    if max_leaf() < LEAF_TIMING {
+        return None;
+    }
+    let result = cpuid::cpuid(LEAF_TIMING, 0)?;
+    if result.eax == 0 || result.ebx == 0 {
+        return None;
+    }
+    Some(Timing {
+        tsc_hz: u64::from(result.eax) * 1000,
+        lapic_hz: u64::from(result.ebx) * 1000,
+    })
+}
+
+fn detect() -> Option<Hypervisor> {
+    if !super::cpu_features().contains(CpuFeatures::HYPERVISOR) {
+        return None;
+    }
+
+    let result = cpuid::cpuid(LEAF_HYPERVISOR, 0)?;
+    let mut name = [0; 12];
+    name[0..4].copy_from_slice(&result.ebx.to_le_bytes());
+    name[4..8].copy_from_slice(&result.ecx.to_le_bytes());
+    name[8..12].copy_from_slice(&result.edx.to_le_bytes());
+    Some(match &name {
+        b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+        b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+        b"VMwareVMware" => Hypervisor::VMware,
+        b"Microsoft Hv" => Hypervisor::HyperV,
+        b"XenVMMXenVMM" => Hypervisor::Xen,
+        _ => Hypervisor::Other(name),
+    })
+}
@@ -0,0 +1,68 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{cpuid, features::CpuFeatures};
+
+/// The MWAIT hint entering the C1 state, the shallowest one: its wake latency is close to the one
+/// of `hlt`, but the CPU is also woken up by a write to the monitored cache line.
+const MWAIT_HINT_C1: u32 = 0x00;
+
+/// Set if the idle CPUs wait with MWAIT rather than with `hlt`.
+static MWAIT: AtomicBool = AtomicBool::new(false);
+
+/// Detect whether the idle CPUs can wait with MONITOR/MWAIT.
+pub fn setup() {
+    let line = cpuid::monitor_line_size()
+        .filter(|_| super::cpu_features().contains(CpuFeatures::MONITOR_MWAIT));
+    let Some(line) = line else {
+        log::info!("MWAIT not supported, idle CPUs use hlt");
+        return;
+    };
+
+    MWAIT.store(true, Ordering::Relaxed);
+    log::info!("Idle CPUs use MWAIT, with a monitor line of {line} bytes");
+}
+
+/// Returns true if the idle CPUs wait with MWAIT, and can therefore be woken up by writing to the
+/// flag given to [`wait`] rather than by an interrupt. MWAIT is no longer used once an AP without
+/// it has started (see [`super::features::ap_check`]).
+#[must_use]
+pub fn mwait() -> bool {
+    MWAIT.load(Ordering::Relaxed) && super::cpu_features().contains(CpuFeatures::MONITOR_MWAIT)
+}
+
+/// Halt the current CPU until the next interrupt or, when MWAIT is supported, until another CPU
+/// writes to the cache line of the given flag. Returns immediately if the flag is already set.
+/// Interrupts are enabled when this function returns.
+///
+/// The flag should be alone in its cache line, otherwise the CPU is also woken up by the writes
+/// to its neighbours.
+pub fn wait(flag: &AtomicBool) {
+    // The interrupts are disabled until the CPU halts, and enabled by the instruction just before
+    // the halt, whose effect is delayed by one instruction. An interrupt raised after the flag is
+    // checked therefore wakes up the CPU instead of being handled before it halts.
+    unsafe {
+        core::arch::asm!("cli", options(nomem, nostack));
+        if mwait() {
+            core::arch::asm!(
+                "monitor",
+                in("rax") flag.as_ptr(),
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack, preserves_flags)
+            );
+        }
+        if flag.load(Ordering::Acquire) {
+            core::arch::asm!("sti", options(nomem, nostack));
+        } else if mwait() {
+            core::arch::asm!(
+                "sti",
+                "mwait",
+                in("eax") MWAIT_HINT_C1,
+                in("ecx") 0,
+                options(nostack)
+            );
+        } else {
+            core::arch::asm!("sti", "hlt", options(nomem, nostack));
+        }
+    }
+}
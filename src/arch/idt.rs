@@ -1,18 +1,22 @@
-use crate::arch::acpi::{CLOCK_TICK_VECTOR, TLB_SHOOTDOWN_VECTOR};
+use crate::arch::acpi::{
+    CALL_FUNCTION_VECTOR, CLOCK_TICK_VECTOR, HPET_VECTOR, LAPIC_ERROR_VECTOR, PMU_VECTOR,
+    RESCHEDULE_VECTOR, SPURIOUS_VECTOR,
+};
 use x86_64::cpu::{Privilege, State};
+use x86_64::idt;
 use x86_64::idt::{Descriptor, DescriptorFlags};
-use x86_64::interrupt_handler;
-use x86_64::{idt, lapic};
 
-use crate::Spinlock;
+use crate::{interrupt_handler, Spinlock};
 
-use super::paging;
+use super::{smp, tss::IstIndex};
 
 pub static IDT: Spinlock<idt::Table> = Spinlock::new(idt::Table::new());
 
 /// Initializes the IDT and load it. This function must be called before enabling interrupts and
 /// install a default handler for all interrupts (see [`unknown_interrupt_handler`]).
 /// Each interrupt handler must be generated with the [`interrupt_handler!`] macro.
+/// Drivers should not modify the IDT to handle legacy IRQs, but register their handler with
+/// [`super::irq::request_irq`] instead.
 pub fn setup() {
     let mut idt = IDT.lock();
     let flags = DescriptorFlags::new()
@@ -31,12 +35,12 @@ pub fn setup() {
         );
     }
 
-    // Set the TLB shootdown handler
+    // Set the cross-CPU call handler
     let descriptor = Descriptor::new()
-        .set_handler_addr(tlb_shootdown as usize as u64)
+        .set_handler_addr(call_function as usize as u64)
         .set_options(flags)
         .build();
-    idt.set_descriptor(TLB_SHOOTDOWN_VECTOR, descriptor);
+    idt.set_descriptor(CALL_FUNCTION_VECTOR, descriptor);
 
     // Set the clock tick handler
     let descriptor = Descriptor::new()
@@ -45,40 +49,116 @@ pub fn setup() {
         .build();
     idt.set_descriptor(CLOCK_TICK_VECTOR, descriptor);
 
+    // Set the reschedule handler
+    let descriptor = Descriptor::new()
+        .set_handler_addr(reschedule as *const () as u64)
+        .set_options(flags)
+        .build();
+    idt.set_descriptor(RESCHEDULE_VECTOR, descriptor);
+
+    // Set the handler of the HPET one-shot events
+    let descriptor = Descriptor::new()
+        .set_handler_addr(super::hpet::event as *const () as u64)
+        .set_options(flags)
+        .build();
+    idt.set_descriptor(HPET_VECTOR, descriptor);
+
+    // Set the handler of the overflow interrupts of the performance counters
+    let descriptor = Descriptor::new()
+        .set_handler_addr(super::pmu::overflow as *const () as u64)
+        .set_options(flags)
+        .build();
+    idt.set_descriptor(PMU_VECTOR, descriptor);
+
+    // Set the LAPIC error interrupt handler
+    let descriptor = Descriptor::new()
+        .set_handler_addr(super::lapic::error as *const () as u64)
+        .set_options(flags)
+        .build();
+    idt.set_descriptor(LAPIC_ERROR_VECTOR, descriptor);
+
+    // Set the LAPIC spurious interrupt handler
+    let descriptor = Descriptor::new()
+        .set_handler_addr(super::spurious::spurious_interrupt as *const () as u64)
+        .set_options(flags)
+        .build();
+    idt.set_descriptor(SPURIOUS_VECTOR, descriptor);
+
     idt.load();
 }
 
+/// Make the given vector switch to the stack with the given index in the Interrupt Stack Table of
+/// the TSS (see [`super::tss`]) when it is raised. [`Descriptor`] cannot set the IST index, so it
+/// is patched directly in the IDT loaded on the current CPU, which is shared by all the CPUs.
+pub fn set_stack_index(vector: u8, ist: IstIndex) {
+    patch_stack_index(vector, ist.get());
+}
+
+/// Make the given vector stay on the current stack when it is raised in kernel mode, which is the
+/// default.
+pub fn clear_stack_index(vector: u8) {
+    patch_stack_index(vector, 0);
+}
+
+/// Write the IST index of the given vector in the IDT loaded on the current CPU.
+fn patch_stack_index(vector: u8, ist: u8) {
+    let _idt = IDT.lock();
+    let mut pointer = [0u8; 10];
+    unsafe {
+        core::arch::asm!(
+            "sidt [{}]",
+            in(reg) pointer.as_mut_ptr(),
+            options(nostack, preserves_flags)
+        );
+        // The IST index is stored in the low 3 bits of the byte 4 of the gate descriptor, and the
+        // other bits of this byte are reserved
+        let base = u64::from_le_bytes(pointer[2..].try_into().unwrap());
+        let entry = (base + u64::from(vector) * 16 + 4) as *mut u8;
+        entry.write_volatile(ist);
+    }
+}
+
 /// Reload the current IDT into the current CPU.
 pub fn reload() {
     IDT.lock().load();
 }
 
 /// Default handler for all interrupts. This function is called when an interrupt occurs but no
-/// handler is installed for it. Currently, this function only panics but it should not panic in the
-/// future, only a debug message should be printed and eventually count the number of times the
-/// interrupt occurred.
-pub extern "C" fn unknown_interrupt_handler(_state: State) {
-    panic!("Unknown interrupt");
+/// handler is installed for it. Spurious interrupts have their own handlers (see
+/// [`super::spurious`]), so an unknown interrupt is a bug and the kernel panics.
+pub extern "C" fn unknown_interrupt_handler(state: &State) {
+    panic!("Unknown interrupt {}", state.number);
 }
 
-/// Handler for the TLB shootdown interrupt. This interrupt is triggered when a TLB entry must be
-/// invalidated. This function will invalidate all TLB entries on the current CPU by simplicity,
-/// but it should be improved in the future to avoid unnecessary invalidations (and performance
-/// penalties)
-pub extern "C" fn tlb_shootdown_handler(_state: State) {
-    paging::tlb::flush_all();
-    lapic::send_eoi();
+/// Handler for the cross-CPU call interrupt, sent by [`smp::call_function`] and
+/// [`smp::call_function_async`] to execute the calls queued for the current CPU.
+pub extern "C" fn call_function_handler(_state: &State) {
+    smp::handle_calls();
+    super::lapic::send_eoi();
 }
 
-pub extern "C" fn clock_tick_handler(_state: State) {
-    lapic::send_eoi();
+/// Handler for the clock tick, raised by the LAPIC timer of each CPU (see [`super::timer`]). The
+/// EOI is sent before preempting the current thread, for the same reason as in
+/// [`super::irq::irq_handler`], and threads running in kernel mode are never preempted here.
+pub extern "C" fn clock_tick_handler(state: &State) {
+    let reschedule = super::timer::tick();
+    super::lapic::send_eoi();
+    if reschedule && state.cs & 3 == 3 {
+        crate::sched::preempt();
+    }
+}
+
+/// Handler for the reschedule interrupt, sent by [`smp::send_reschedule`] to an idle CPU when a
+/// thread is made ready, so that it runs the thread without waiting for its next clock tick. The
+/// interrupt only wakes up the CPU: its idle loop then runs the scheduler.
+pub extern "C" fn reschedule_handler(state: &State) {
+    super::lapic::send_eoi();
+    if crate::sched::need_resched() && state.cs & 3 == 3 {
+        crate::sched::preempt();
+    }
 }
 
-interrupt_handler!(-1, unknown_interrupt, unknown_interrupt_handler, 0);
-interrupt_handler!(
-    TLB_SHOOTDOWN_VECTOR,
-    tlb_shootdown,
-    tlb_shootdown_handler,
-    0
-);
-interrupt_handler!(CLOCK_TICK_VECTOR, clock_tick, clock_tick_handler, 0);
+interrupt_handler!(-1, unknown_interrupt, unknown_interrupt_handler);
+interrupt_handler!(CALL_FUNCTION_VECTOR, call_function, call_function_handler);
+interrupt_handler!(CLOCK_TICK_VECTOR, clock_tick, clock_tick_handler);
+interrupt_handler!(RESCHEDULE_VECTOR, reschedule, reschedule_handler);
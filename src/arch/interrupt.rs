@@ -0,0 +1,253 @@
+use core::{mem::offset_of, sync::atomic::Ordering};
+
+use x86_64::cpu::State;
+
+use super::smp;
+
+/// Generate the entry stub of an interrupt vector, named `$name`, which calls `$handler` with the
+/// interrupted [`State`] and the vector number. The handler can have any of these signatures:
+/// `extern "C" fn(&State)`, `extern "C" fn(&mut State)` or `extern "C" fn(&mut State, u8)`. The
+/// registers and the interrupted context are restored from the state when the handler returns.
+///
+/// The CPU pushes an error code for some exceptions only (see [`has_error_code`]): the stubs of
+/// the other vectors push a zero in its place, so that the state has the same layout for all the
+/// vectors.
+///
+/// ```ignore
+/// interrupt_handler!(CLOCK_TICK_VECTOR, clock_tick, clock_tick_handler);
+/// ```
+#[macro_export]
+macro_rules! interrupt_handler {
+    ($vector:expr, $name:ident, $handler:path) => {
+        extern "C" {
+            pub fn $name();
+        }
+
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            ".if !{code}",
+            "    push 0",
+            ".endif",
+            "    push {vector}",
+            "    push rax",
+            "    lea rax, [rip + {handler}]",
+            "    jmp __interrupt_common",
+            vector = const $vector,
+            code = const $crate::arch::interrupt::has_error_code($vector as i64) as u8,
+            handler = sym $handler,
+        );
+    };
+}
+
+/// Generate the entry stubs of `$count` contiguous vectors starting at `$first`, which all call
+/// `$handler` as [`interrupt_handler!`] does, with the number of the vector raised. The addresses
+/// of the stubs are stored in order in the `$table` array, to be installed in the IDT. The vectors
+/// must be above the CPU exceptions, so that none of them has an error code.
+///
+/// ```ignore
+/// interrupt_table!(config::IRQ_BASE, IRQ_COUNT, IRQ_STUBS, irq_handler);
+/// ```
+#[macro_export]
+macro_rules! interrupt_table {
+    ($first:expr, $count:expr, $table:ident, $handler:path) => {
+        const _: () = assert!($first as usize >= 32, "Interrupt tables cannot hold exceptions");
+
+        extern "C" {
+            pub static $table: [unsafe extern "C" fn(); $count as usize];
+        }
+
+        // Each stub is padded to the same size, so the table can be filled with a loop
+        core::arch::global_asm!(
+            ".pushsection .text",
+            ".balign {stub_size}",
+            concat!(stringify!($table), "_stubs:"),
+            ".set .Lvector, {first}",
+            ".rept {count}",
+            ".balign {stub_size}",
+            "    push 0",
+            "    push .Lvector",
+            "    push rax",
+            "    lea rax, [rip + {handler}]",
+            "    jmp __interrupt_common",
+            ".set .Lvector, .Lvector + 1",
+            ".endr",
+            ".popsection",
+            "",
+            ".pushsection .rodata",
+            ".balign 8",
+            concat!(".global ", stringify!($table)),
+            concat!(stringify!($table), ":"),
+            ".set .Lindex, 0",
+            ".rept {count}",
+            concat!(".quad ", stringify!($table), "_stubs + .Lindex * {stub_size}"),
+            ".set .Lindex, .Lindex + 1",
+            ".endr",
+            ".popsection",
+            first = const $first,
+            count = const $count,
+            stub_size = const $crate::arch::interrupt::STUB_SIZE,
+            handler = sym $handler,
+        );
+    };
+}
+
+/// The size reserved for each stub of an [`interrupt_table!`].
+pub const STUB_SIZE: usize = 32;
+
+// The stubs save the registers below the error code and the vector, so the saved `rax` must be the
+// last register of the state. The CPU aligns the stack on 16 bytes before pushing its 40 bytes
+// frame, so the state must be 8 bytes off that alignment for the stack to be aligned when the
+// handler is called.
+const _: () = assert!(offset_of!(State, rax) + 8 == offset_of!(State, number));
+const _: () = assert!(offset_of!(State, number) + 8 == offset_of!(State, code));
+const _: () = assert!(offset_of!(State, code) + 8 == offset_of!(State, rip));
+const _: () = assert!(offset_of!(State, rip) % 16 == 8);
+
+// The stubs push the error code (a zero for the vectors without one), the vector and `rax`, and
+// jump here with the address of the handler in `rax`. The other registers are saved below to
+// complete the state, whose address is passed to the handler with the vector.
+//
+// The GS base is switched here when the interrupt arrived from user mode, and the handler is
+// surrounded by `enter` and `leave`, so that the handlers do not have to do it themselves. The
+// address of the handler is kept in `rbx`, which is preserved by these calls.
+core::arch::global_asm!(
+    r#"
+.global __interrupt_common
+__interrupt_common:
+    sub rsp, {rax}
+    mov [rsp + {rbx}], rbx
+    mov [rsp + {rcx}], rcx
+    mov [rsp + {rdx}], rdx
+    mov [rsp + {rsi}], rsi
+    mov [rsp + {rdi}], rdi
+    mov [rsp + {rbp}], rbp
+    mov [rsp + {r8}], r8
+    mov [rsp + {r9}], r9
+    mov [rsp + {r10}], r10
+    mov [rsp + {r11}], r11
+    mov [rsp + {r12}], r12
+    mov [rsp + {r13}], r13
+    mov [rsp + {r14}], r14
+    mov [rsp + {r15}], r15
+    mov rbx, rax
+    cld
+    test byte ptr [rsp + {cs}], 3
+    jz 1f
+    swapgs
+1:
+    call {enter}
+    mov rdi, rsp
+    mov rsi, [rsp + {number}]
+    call rbx
+    call {leave}
+    test byte ptr [rsp + {cs}], 3
+    jz 2f
+    swapgs
+2:
+    mov rax, [rsp + {rax}]
+    mov rbx, [rsp + {rbx}]
+    mov rcx, [rsp + {rcx}]
+    mov rdx, [rsp + {rdx}]
+    mov rsi, [rsp + {rsi}]
+    mov rdi, [rsp + {rdi}]
+    mov rbp, [rsp + {rbp}]
+    mov r8, [rsp + {r8}]
+    mov r9, [rsp + {r9}]
+    mov r10, [rsp + {r10}]
+    mov r11, [rsp + {r11}]
+    mov r12, [rsp + {r12}]
+    mov r13, [rsp + {r13}]
+    mov r14, [rsp + {r14}]
+    mov r15, [rsp + {r15}]
+    add rsp, {rip}
+    iretq
+"#,
+    rax = const offset_of!(State, rax),
+    rbx = const offset_of!(State, rbx),
+    rcx = const offset_of!(State, rcx),
+    rdx = const offset_of!(State, rdx),
+    rsi = const offset_of!(State, rsi),
+    rdi = const offset_of!(State, rdi),
+    rbp = const offset_of!(State, rbp),
+    r8 = const offset_of!(State, r8),
+    r9 = const offset_of!(State, r9),
+    r10 = const offset_of!(State, r10),
+    r11 = const offset_of!(State, r11),
+    r12 = const offset_of!(State, r12),
+    r13 = const offset_of!(State, r13),
+    r14 = const offset_of!(State, r14),
+    r15 = const offset_of!(State, r15),
+    number = const offset_of!(State, number),
+    rip = const offset_of!(State, rip),
+    cs = const offset_of!(State, cs),
+    enter = sym enter,
+    leave = sym leave,
+);
+
+/// Returns true if the CPU pushes an error code when it raises the given vector: the double fault,
+/// the invalid TSS, segment not present, stack segment, general protection and page faults, the
+/// alignment check, the control protection exception, and the VMM communication and security
+/// exceptions.
+#[must_use]
+pub const fn has_error_code(vector: i64) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Called by `__interrupt_common` before each interrupt handler, once the GS base has been
+/// switched: if the interrupt arrived from user mode, `swapgs` is executed first so that the GS
+/// base is the thread local information of the current CPU (see [`smp::ThreadLocalInfo`]). The
+/// kernel always runs with this GS base, and user code always runs with its own: the system call
+/// entry and the return to user mode (see [`super::context`]) follow the same rule.
+///
+/// This increments the interrupt nesting depth of the current CPU, which is decremented by
+/// [`leave`] after the handler returns.
+extern "C" fn enter() {
+    if let Some(info) = smp::try_cpu_info() {
+        info.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Called by `__interrupt_common` after each interrupt handler, before the GS base of user code is
+/// restored. See [`enter`] for more details.
+extern "C" fn leave() {
+    if let Some(info) = smp::try_cpu_info() {
+        info.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of interrupt handlers currently executing on the current CPU.
+#[must_use]
+pub fn depth() -> u32 {
+    smp::try_cpu_info().map_or(0, |info| info.interrupt_depth.load(Ordering::Relaxed))
+}
+
+/// Returns true if the current CPU is executing an interrupt handler.
+#[must_use]
+pub fn in_interrupt() -> bool {
+    depth() > 0
+}
+
+/// Returns true if the current CPU is executing an interrupt handler that interrupted another
+/// interrupt handler. The scheduler must not be called from a nested interrupt, because the
+/// interrupted handler would be resumed in the context of another thread.
+#[must_use]
+pub fn nested() -> bool {
+    depth() > 1
+}
+
+/// Reset the interrupt depth of the current CPU and returns its previous value. The depth belongs
+/// to the thread running on the CPU: it is saved by the scheduler before a context switch and
+/// restored with [`restore_depth`] when the thread is resumed, while new threads start with a
+/// depth of 0.
+#[must_use]
+pub fn take_depth() -> u32 {
+    smp::try_cpu_info().map_or(0, |info| info.interrupt_depth.swap(0, Ordering::Relaxed))
+}
+
+/// Restore the interrupt depth saved with [`take_depth`].
+pub fn restore_depth(depth: u32) {
+    if let Some(info) = smp::try_cpu_info() {
+        info.interrupt_depth.store(depth, Ordering::Relaxed);
+    }
+}
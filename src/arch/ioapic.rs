@@ -0,0 +1,323 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+use log::{info, warn};
+use x86_64::{address::Virtual, paging::PAGE_SIZE};
+
+use crate::{config, Spinlock};
+
+use super::{acpi::Topology, mmio::Mmio};
+
+/// The register used to select the register accessed through the window register.
+const IOREGSEL: u64 = 0x00;
+
+/// The window register, used to read or write the selected register.
+const IOWIN: u64 = 0x10;
+
+/// The version register, which also contains the number of redirection entries.
+const IOAPICVER: u32 = 0x01;
+
+/// The first redirection table register. Each entry uses two 32 bits registers.
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_POLARITY_LOW: u64 = 1 << 13;
+const REDIRECTION_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION: u64 = 0xFF << 56;
+
+/// The number of legacy ISA IRQs.
+pub const ISA_IRQ_COUNT: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// How a legacy ISA IRQ is connected to the IOAPICs. Without an interrupt source override in
+/// the MADT, an ISA IRQ is identity mapped to the global system interrupt with the same number,
+/// and is active high and edge triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub trigger: TriggerMode,
+    pub polarity: Polarity,
+}
+
+impl IsaRoute {
+    const fn identity(irq: u8) -> Self {
+        Self {
+            gsi: irq as u32,
+            trigger: TriggerMode::Edge,
+            polarity: Polarity::ActiveHigh,
+        }
+    }
+}
+
+/// An IOAPIC, which handles the global system interrupts from `gsi_base` to
+/// `gsi_base + count - 1`.
+#[derive(Debug)]
+struct IoApic {
+    id: u8,
+    base: Virtual,
+    gsi_base: u32,
+    count: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.select(register);
+        unsafe { Mmio::<u32>::new(self.base + IOWIN) }.read()
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.select(register);
+        unsafe { Mmio::<u32>::new(self.base + IOWIN) }.write(value);
+    }
+
+    /// Select the register accessed through the IOWIN window.
+    fn select(&self, register: u32) {
+        unsafe { Mmio::<u32>::new(self.base + IOREGSEL) }.write(register);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.count
+    }
+
+    fn read_redirection(&self, gsi: u32) -> u64 {
+        let register = IOREDTBL + (gsi - self.gsi_base) * 2;
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOREDTBL + (gsi - self.gsi_base) * 2;
+        // Write the high part first, so the entry is never unmasked with a wrong destination
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+static IOAPICS: Spinlock<Vec<IoApic>> = Spinlock::new(Vec::new());
+static ISA_ROUTES: Spinlock<[Option<IsaRoute>; ISA_IRQ_COUNT as usize]> =
+    Spinlock::new([None; ISA_IRQ_COUNT as usize]);
+
+/// Set when the legacy IRQs are routed through the IOAPICs instead of the 8259 PIC.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Initialize all the IOAPICs described in the MADT, and route the legacy ISA IRQs through them
+/// to the BSP, with the vectors starting at [`config::IRQ_BASE`]. The IRQs without handler (see
+/// [`super::irq::request_irq`]) are masked, and the 8259 PIC is disabled. The interrupt source
+/// overrides of the MADT are honored (see [`isa_routes`]), and `sci` is the ISA IRQ used by the
+/// ACPI System Control Interrupt, if any.
+///
+/// If the MADT does not describe any IOAPIC, the 8259 PIC is kept.
+pub fn setup(topology: &Topology, sci: Option<u8>) {
+    if topology.io_apics.is_empty() {
+        warn!("No IOAPIC found, using the 8259 PIC for legacy IRQs");
+        return;
+    }
+
+    x86_64::irq::without(|| {
+        let mut ioapics = IOAPICS.lock();
+        for ioapic in &topology.io_apics {
+            let base = unsafe { super::acpi::remap_mmio(u64::from(ioapic.address), PAGE_SIZE) }
+                .expect("Failed to map the IOAPIC registers");
+            let mut ioapic = IoApic {
+                id: ioapic.id,
+                base,
+                gsi_base: ioapic.gsi_base,
+                count: 0,
+            };
+            ioapic.count = ((ioapic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+
+            // Mask all entries until a driver routes them
+            for gsi in ioapic.gsi_base..ioapic.gsi_base + ioapic.count {
+                ioapic.write_redirection(gsi, REDIRECTION_MASKED);
+            }
+            info!(
+                "IOAPIC {} handles GSI {} to {}",
+                ioapic.id,
+                ioapic.gsi_base,
+                ioapic.gsi_base + ioapic.count - 1
+            );
+            ioapics.push(ioapic);
+        }
+
+        let mut routes = ISA_ROUTES.lock();
+        *routes = isa_routes(topology, sci);
+    });
+
+    let bsp = super::smp::get_cpu_info().lapic_id;
+    for irq in 0..ISA_IRQ_COUNT {
+        let Some(route) = isa_route(irq) else {
+            log::debug!("IRQ {irq} is not connected: its GSI is used by another ISA IRQ");
+            continue;
+        };
+        let vector = config::IRQ_BASE + irq;
+        let masked = !super::irq::requested(irq);
+        if !program(
+            route.gsi,
+            vector,
+            bsp,
+            route.trigger,
+            route.polarity,
+            masked,
+        ) {
+            warn!("No IOAPIC handles GSI {} (IRQ {})", route.gsi, irq);
+        }
+    }
+
+    super::pic::disable_all();
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if the legacy IRQs are routed through the IOAPICs.
+#[must_use]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns true if an IOAPIC handles the given global system interrupt.
+#[must_use]
+pub fn handles(gsi: u32) -> bool {
+    with_ioapic(gsi, |_| ()).is_some()
+}
+
+/// Returns how the given legacy ISA IRQ is connected to the IOAPICs, or `None` if the IRQ is not
+/// connected, because its GSI is used by another ISA IRQ (see [`isa_routes`]) or the IOAPICs are
+/// not enabled.
+#[must_use]
+pub fn isa_route(irq: u8) -> Option<IsaRoute> {
+    x86_64::irq::without(|| ISA_ROUTES.lock().get(usize::from(irq)).copied().flatten())
+}
+
+/// Compute how each legacy ISA IRQ is connected to the IOAPICs from the interrupt source overrides
+/// of the MADT. An override can change the GSI of an ISA IRQ (the PIT or the HPET in legacy mode
+/// are often connected to the GSI 2, for example), and its polarity and trigger mode. A polarity or
+/// a trigger mode that "conforms to the bus" is active high and edge triggered for the ISA bus.
+///
+/// An ISA IRQ whose GSI is taken by the override of another IRQ is left disconnected, otherwise
+/// both would be programmed in the same redirection entry. The ACPI SCI is active low and level
+/// triggered, unless an override says otherwise.
+fn isa_routes(topology: &Topology, sci: Option<u8>) -> [Option<IsaRoute>; ISA_IRQ_COUNT as usize] {
+    let mut routes = [None; ISA_IRQ_COUNT as usize];
+    for (irq, route) in (0..ISA_IRQ_COUNT).zip(routes.iter_mut()) {
+        let mut identity = IsaRoute::identity(irq);
+        if sci == Some(irq) {
+            identity.trigger = TriggerMode::Level;
+            identity.polarity = Polarity::ActiveLow;
+        }
+        *route = Some(identity);
+    }
+
+    // Only the overrides of the ISA bus are meaningful for the legacy IRQs
+    let overrides = || topology.overrides.iter().filter(|isa| isa.bus == 0);
+    for isa_override in overrides() {
+        let source = usize::from(isa_override.source);
+        let Some(route) = routes.get_mut(source) else {
+            warn!("Ignoring override of invalid ISA IRQ {source}");
+            continue;
+        };
+
+        let mut overridden = route.unwrap_or(IsaRoute::identity(isa_override.source));
+        overridden.gsi = isa_override.gsi;
+        if let Some(polarity) = isa_override.polarity {
+            overridden.polarity = polarity;
+        }
+        if let Some(trigger) = isa_override.trigger {
+            overridden.trigger = trigger;
+        }
+        *route = Some(overridden);
+        info!(
+            "IRQ {} routed to GSI {} ({:?}, {:?})",
+            source, overridden.gsi, overridden.trigger, overridden.polarity
+        );
+    }
+
+    // Disconnect the identity mapped IRQs whose GSI is the target of an override
+    for irq in 0..ISA_IRQ_COUNT {
+        let overridden = overrides()
+            .any(|isa_override| isa_override.source != irq && isa_override.gsi == u32::from(irq));
+        let identity = !overrides().any(|isa_override| isa_override.source == irq);
+        if overridden && identity {
+            routes[usize::from(irq)] = None;
+        }
+    }
+    routes
+}
+
+/// Program the redirection entry of the given global system interrupt to deliver the given vector
+/// to the LAPIC with the given identifier. Returns false if no IOAPIC handles the interrupt.
+#[must_use]
+pub fn program(
+    gsi: u32,
+    vector: u8,
+    destination: u32,
+    trigger: TriggerMode,
+    polarity: Polarity,
+    masked: bool,
+) -> bool {
+    let mut entry = u64::from(vector) | u64::from(destination & 0xFF) << 56;
+    if trigger == TriggerMode::Level {
+        entry |= REDIRECTION_TRIGGER_LEVEL;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_POLARITY_LOW;
+    }
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    with_ioapic(gsi, |ioapic| ioapic.write_redirection(gsi, entry)).is_some()
+}
+
+/// Mask the given legacy ISA IRQ. Does nothing if the IRQ is not connected.
+pub fn mask(irq: u8) {
+    let Some(IsaRoute { gsi, .. }) = isa_route(irq) else {
+        return;
+    };
+    with_ioapic(gsi, |ioapic| {
+        let entry = ioapic.read_redirection(gsi);
+        ioapic.write_redirection(gsi, entry | REDIRECTION_MASKED);
+    });
+}
+
+/// Unmask the given legacy ISA IRQ. Does nothing if the IRQ is not connected.
+pub fn unmask(irq: u8) {
+    let Some(IsaRoute { gsi, .. }) = isa_route(irq) else {
+        return;
+    };
+    with_ioapic(gsi, |ioapic| {
+        let entry = ioapic.read_redirection(gsi);
+        ioapic.write_redirection(gsi, entry & !REDIRECTION_MASKED);
+    });
+}
+
+/// Deliver the given legacy ISA IRQ to the LAPIC with the given identifier, keeping the rest of
+/// its redirection entry. Returns false if the IRQ is not connected.
+#[must_use]
+pub fn set_destination(irq: u8, destination: u32) -> bool {
+    let Some(IsaRoute { gsi, .. }) = isa_route(irq) else {
+        return false;
+    };
+    with_ioapic(gsi, |ioapic| {
+        let entry = ioapic.read_redirection(gsi) & !REDIRECTION_DESTINATION;
+        ioapic.write_redirection(gsi, entry | u64::from(destination & 0xFF) << 56);
+    })
+    .is_some()
+}
+
+/// Execute the given closure with the IOAPIC handling the given global system interrupt, or
+/// returns `None` if no IOAPIC handles it.
+fn with_ioapic<T>(gsi: u32, f: impl FnOnce(&IoApic) -> T) -> Option<T> {
+    x86_64::irq::without(|| {
+        let ioapics = IOAPICS.lock();
+        ioapics.iter().find(|ioapic| ioapic.handles(gsi)).map(f)
+    })
+}
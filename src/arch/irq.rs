@@ -1,68 +1,484 @@
-use crate::config;
-use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{
+    arch::percpu::PerCpuCounter,
+    config,
+    sched::{self, thread::Thread, thread::Tid},
+    Spinlock,
+};
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use sync::Once;
 use x86_64::{
     cpu::{self, Privilege},
     idt::{Descriptor, DescriptorFlags},
-    interrupt_handler,
-    lapic::{self, IpiDestination, IpiPriority},
-    pic,
 };
 
-use super::acpi::CLOCK_TICK_VECTOR;
+/// The number of legacy IRQ lines, mapped to the vectors starting at [`config::IRQ_BASE`].
+pub const IRQ_COUNT: u8 = 16;
+
+/// The maximum number of handlers that can share the same IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 8;
+
+/// The id of the CPU each legacy IRQ line is delivered to. All the lines are delivered to the BSP
+/// until [`set_affinity`] is called.
+static AFFINITY: [AtomicU32; IRQ_COUNT as usize] =
+    [const { AtomicU32::new(0) }; IRQ_COUNT as usize];
+
+/// The interrupt flag of the RFLAGS register.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// The number of interrupts raised on each IRQ line, spurious IRQs excluded.
+static COUNTS: [PerCpuCounter; IRQ_COUNT as usize] =
+    [const { PerCpuCounter::new() }; IRQ_COUNT as usize];
+
+/// The handlers registered for each IRQ line.
+static ACTIONS: [Spinlock<[Option<Action>; MAX_SHARED_HANDLERS]>; IRQ_COUNT as usize] =
+    [const { Spinlock::new([const { None }; MAX_SHARED_HANDLERS]) }; IRQ_COUNT as usize];
+
+/// An IRQ handler. It is called with interrupts disabled and the line number of the IRQ, and must
+/// return whether the interrupt was raised by its device.
+pub type Handler = fn(line: u8) -> IrqReturn;
+
+/// The threaded part of an IRQ handler, executed in a dedicated kernel thread with interrupts
+/// enabled when the hard handler returns [`IrqReturn::WakeThread`].
+pub type ThreadHandler = fn(line: u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IrqReturn {
+    /// The interrupt was not raised by the device of this handler.
+    None,
+
+    /// The interrupt was handled.
+    Handled,
+
+    /// The interrupt was handled, and the current thread should be preempted if possible once the
+    /// end of interrupt has been sent.
+    Reschedule,
+
+    /// The device was acknowledged, and the rest of the work must be done by the threaded handler
+    /// registered with [`request_threaded_irq`].
+    WakeThread,
+}
+
+bitflags! {
+    pub struct IrqFlags : u32 {
+        const NONE = 0;
+
+        /// The line can be shared with other handlers that also set this flag.
+        const SHARED = 1 << 0;
+
+        /// The line is masked when the hard handler wakes the threaded handler, and unmasked once
+        /// the threaded handler has run. This is required for level-triggered devices that cannot
+        /// be silenced by the hard handler. Such a line cannot be shared.
+        const ONESHOT = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IrqError {
+    /// The line number is not a valid legacy IRQ line.
+    InvalidLine,
+
+    /// The line is already used by a handler, and the line cannot be shared with it.
+    Busy,
+
+    /// Too many handlers share the line.
+    TooManyHandlers,
+
+    /// No handler with the given name is registered on the line.
+    NotFound,
+
+    /// The flags are not valid for this handler: a line cannot be both shared and oneshot, and
+    /// only a threaded handler can be oneshot.
+    InvalidFlags,
+
+    /// The CPU is not online.
+    InvalidCpu,
 
-static TICKS: AtomicU64 = AtomicU64::new(0);
+    /// The line is not routed through an IOAPIC: the 8259 PIC only delivers it to the BSP.
+    NotRouted,
+}
+
+#[derive(Clone)]
+struct Action {
+    handler: Handler,
+    flags: IrqFlags,
+    name: &'static str,
+    thread: Option<Arc<IrqThread>>,
+}
+
+/// The state shared between a threaded IRQ handler and its kernel thread.
+struct IrqThread {
+    handler: ThreadHandler,
+    line: u8,
+    oneshot: bool,
+    tid: Once<Tid>,
+
+    /// Set by the hard handler when the threaded handler must run.
+    pending: AtomicBool,
+
+    /// Set when the handler is removed, to terminate the thread.
+    stop: AtomicBool,
+}
 
-/// Setup the IRQs handlers
-#[allow(clippy::fn_to_numeric_cast)]
+/// Setup the IRQs handlers. Each legacy IRQ vector is connected to the dispatcher, which calls
+/// the handlers registered with [`request_irq`] for its line.
 pub fn setup() {
     let flags = DescriptorFlags::new()
         .set_privilege_level(Privilege::KERNEL)
         .present(true)
         .build();
     let mut idt = super::idt::IDT.lock();
-
-    // Set default handlers
-    for i in 0..16 {
+    let stubs = unsafe { &IRQ_STUBS };
+    for (line, stub) in (0..IRQ_COUNT).zip(stubs) {
         idt.set_descriptor(
-            crate::config::IRQ_BASE + i,
+            config::IRQ_BASE + line,
             Descriptor::new()
-                .set_handler_addr(ignore_irq as u64)
+                .set_handler_addr(*stub as *const () as u64)
                 .set_options(flags)
                 .build(),
         );
     }
+}
+
+/// Register a handler for the given legacy IRQ line, and unmask the line if it was not used
+/// before. A line can only be shared if all its handlers are registered with
+/// [`IrqFlags::SHARED`]: the handlers are then called in the order of their registration each time
+/// the line is raised.
+///
+/// # Errors
+/// - [`IrqError::InvalidLine`]: The line is not a valid legacy IRQ line.
+/// - [`IrqError::InvalidFlags`]: [`IrqFlags::ONESHOT`] is set, but there is no threaded handler.
+/// - [`IrqError::Busy`]: The line is already used, and either this handler or the existing ones
+///   do not allow sharing it.
+/// - [`IrqError::TooManyHandlers`]: [`MAX_SHARED_HANDLERS`] handlers already share the line.
+pub fn request_irq(
+    line: u8,
+    handler: Handler,
+    flags: IrqFlags,
+    name: &'static str,
+) -> Result<(), IrqError> {
+    if flags.contains(IrqFlags::ONESHOT) {
+        return Err(IrqError::InvalidFlags);
+    }
+    register(line, handler, None, flags, name)
+}
+
+/// Register a threaded handler for the given legacy IRQ line. The hard handler runs in interrupt
+/// context and should only acknowledge the device and return [`IrqReturn::WakeThread`]: the thread
+/// handler is then executed by a dedicated kernel thread, with interrupts enabled. This keeps the
+/// interrupt latency low for slow devices. The sharing rules are the same as for [`request_irq`].
+///
+/// # Errors
+/// - [`IrqError::InvalidLine`]: The line is not a valid legacy IRQ line.
+/// - [`IrqError::InvalidFlags`]: Both [`IrqFlags::SHARED`] and [`IrqFlags::ONESHOT`] are set.
+/// - [`IrqError::Busy`]: The line is already used, and either this handler or the existing ones
+///   do not allow sharing it.
+/// - [`IrqError::TooManyHandlers`]: [`MAX_SHARED_HANDLERS`] handlers already share the line.
+pub fn request_threaded_irq(
+    line: u8,
+    handler: Handler,
+    thread: ThreadHandler,
+    flags: IrqFlags,
+    name: &'static str,
+) -> Result<(), IrqError> {
+    if flags.contains(IrqFlags::SHARED | IrqFlags::ONESHOT) {
+        return Err(IrqError::InvalidFlags);
+    }
+    register(line, handler, Some(thread), flags, name)
+}
+
+fn register(
+    line: u8,
+    handler: Handler,
+    thread: Option<ThreadHandler>,
+    flags: IrqFlags,
+    name: &'static str,
+) -> Result<(), IrqError> {
+    let actions = ACTIONS
+        .get(usize::from(line))
+        .ok_or(IrqError::InvalidLine)?;
+
+    // The kernel thread is created before taking the lock, but only started once the handler is
+    // registered. It owns a reference to the shared state, passed as its argument.
+    let thread = thread.map(|handler| {
+        let thread = Arc::new(IrqThread {
+            handler,
+            line,
+            oneshot: flags.contains(IrqFlags::ONESHOT),
+            tid: Once::new(),
+            pending: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+        });
+        let kthread = Thread::kernel(irq_thread, Arc::into_raw(Arc::clone(&thread)) as u64, 0);
+        thread.tid.call_once(|| kthread.tid());
+        (kthread, thread)
+    });
+
+    let first = x86_64::irq::without(|| {
+        let mut actions = actions.lock();
+        let shareable = actions
+            .iter()
+            .flatten()
+            .all(|action| action.flags.contains(IrqFlags::SHARED));
+        let used = actions.iter().any(Option::is_some);
+        if used && !(shareable && flags.contains(IrqFlags::SHARED)) {
+            return Err(IrqError::Busy);
+        }
+
+        let slot = actions
+            .iter_mut()
+            .find(|action| action.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(Action {
+            handler,
+            flags,
+            name,
+            thread: thread.as_ref().map(|(_, thread)| Arc::clone(thread)),
+        });
+        Ok(!used)
+    });
+
+    let first = match (first, thread) {
+        (Ok(first), Some((kthread, _))) => {
+            sched::spawn(kthread);
+            first
+        }
+        (Ok(first), None) => first,
+        (Err(error), Some((_, thread))) => {
+            // Release the reference owned by the thread that will never run
+            unsafe { drop(Arc::from_raw(Arc::as_ptr(&thread))) };
+            return Err(error);
+        }
+        (Err(error), None) => return Err(error),
+    };
+
+    if first {
+        unmask(line);
+    }
+    log::debug!("IRQ {line} requested by {name}");
+    Ok(())
+}
+
+/// Remove the handler registered with the given name on the given legacy IRQ line. The line is
+/// masked when its last handler is removed.
+///
+/// # Errors
+/// - [`IrqError::InvalidLine`]: The line is not a valid legacy IRQ line.
+/// - [`IrqError::NotFound`]: No handler with this name is registered on the line.
+pub fn free_irq(line: u8, name: &str) -> Result<(), IrqError> {
+    let actions = ACTIONS
+        .get(usize::from(line))
+        .ok_or(IrqError::InvalidLine)?;
+
+    let (last, action) = x86_64::irq::without(|| {
+        let mut actions = actions.lock();
+        let index = actions
+            .iter()
+            .position(|action| action.as_ref().is_some_and(|action| action.name == name))
+            .ok_or(IrqError::NotFound)?;
+
+        // Keep the handlers in the order of their registration
+        actions[index..].rotate_left(1);
+        let action = actions[MAX_SHARED_HANDLERS - 1].take().unwrap();
+        Ok((actions.iter().all(Option::is_none), action))
+    })?;
+
+    if let Some(thread) = action.thread {
+        thread.stop.store(true, Ordering::Release);
+        if let Some(&tid) = thread.tid.get() {
+            sched::wake(tid);
+        }
+    }
+
+    if last {
+        mask(line);
+    }
+    log::debug!("IRQ {line} freed by {name}");
+    Ok(())
+}
+
+/// Returns true if at least one handler is registered on the given legacy IRQ line.
+#[must_use]
+pub fn requested(line: u8) -> bool {
+    ACTIONS
+        .get(usize::from(line))
+        .is_some_and(|actions| x86_64::irq::without(|| actions.lock().iter().any(Option::is_some)))
+}
 
-    // Set the clock tick handler
-    idt.set_descriptor(
-        crate::config::IRQ_BASE,
-        Descriptor::new()
-            .set_handler_addr(pit_tick as u64)
-            .set_options(flags)
-            .build(),
-    );
+/// Returns the number of interrupts raised on the given legacy IRQ line since the boot, on all the
+/// CPUs or only on the given one. Spurious IRQs are not counted.
+#[must_use]
+pub fn count(line: u8, cpu: Option<u32>) -> u64 {
+    COUNTS
+        .get(usize::from(line))
+        .map_or(0, |counter| match cpu {
+            Some(cpu) => counter.cpu(cpu),
+            None => counter.sum(),
+        })
 }
 
-/// This function is called when the clock tick interrupt is triggered. It will increment the
-/// number of ticks and send an EOI to the PIC.
+/// Deliver the given legacy IRQ line to the CPU with the given id, for example to spread the
+/// interrupts of the devices across the CPUs. An interrupt already delivered to the previous CPU
+/// is still handled there.
 ///
-/// Currently, due to limitations of the kernel, this function is called by the PIT with using the
-/// PIC. Because of this, only the BSP can handle IRQs, and interact with the PIC/PIT is slow.
-/// This will be fixed in the future, when the kernel will be more advanced.
-pub extern "C" fn pit_tick_handler(state: &cpu::State) {
-    TICKS.fetch_add(1, Ordering::Relaxed);
+/// # Errors
+/// - [`IrqError::InvalidLine`]: The line is not a valid legacy IRQ line.
+/// - [`IrqError::InvalidCpu`]: The CPU is not online.
+/// - [`IrqError::NotRouted`]: The line is not routed through an IOAPIC.
+pub fn set_affinity(line: u8, cpu: u32) -> Result<(), IrqError> {
+    let affinity = AFFINITY
+        .get(usize::from(line))
+        .ok_or(IrqError::InvalidLine)?;
+    let lapic = super::smp::lapic_id(cpu).ok_or(IrqError::InvalidCpu)?;
+    if !super::ioapic::enabled() || !super::ioapic::set_destination(line, lapic) {
+        return Err(IrqError::NotRouted);
+    }
+    affinity.store(cpu, Ordering::Relaxed);
+    log::debug!("IRQ {line} delivered to CPU {cpu}");
+    Ok(())
+}
+
+/// Returns the id of the CPU the given legacy IRQ line is delivered to, or `None` if the line is
+/// not a valid legacy IRQ line.
+#[must_use]
+pub fn affinity(line: u8) -> Option<u32> {
+    AFFINITY
+        .get(usize::from(line))
+        .map(|affinity| affinity.load(Ordering::Relaxed))
+}
+
+/// Deliver all the legacy IRQ lines delivered to the given CPU to the `target` CPU instead. This
+/// must be done before a CPU is taken offline, while both CPUs are still online.
+///
+/// # Errors
+/// - [`IrqError::InvalidCpu`]: The target CPU is not online.
+/// - [`IrqError::NotRouted`]: A line is not routed through an IOAPIC.
+pub fn evacuate(cpu: u32, target: u32) -> Result<(), IrqError> {
+    for line in (0..IRQ_COUNT).filter(|&line| affinity(line) == Some(cpu)) {
+        set_affinity(line, target)?;
+    }
+    Ok(())
+}
+
+/// Returns true if the interrupts are enabled on the current CPU.
+#[must_use]
+pub fn enabled() -> bool {
+    let flags: u64;
     unsafe {
-        lapic::send_ipi(
-            IpiDestination::OtherCores,
-            IpiPriority::Normal,
-            CLOCK_TICK_VECTOR,
-        );
-        pic::send_eoi(u8::try_from(state.number).unwrap());
+        core::arch::asm!("pushfq", "pop {}", out(reg) flags, options(preserves_flags));
+    }
+    flags & RFLAGS_IF != 0
+}
+
+/// Send an end of interrupt for the legacy IRQ that triggered the given vector, to the LAPIC if the
+/// legacy IRQs are routed through the IOAPICs, or to the 8259 PIC otherwise.
+pub fn send_eoi(vector: u8) {
+    if super::ioapic::enabled() {
+        super::lapic::send_eoi();
+    } else {
+        super::pic::send_eoi(vector - config::IRQ_BASE);
+    }
+}
+
+/// Called for all legacy IRQs. The handlers of the line are copied before being called, so that
+/// the lock is not held if a handler is removed or if the current thread is preempted. When a
+/// hard handler asks for its threaded handler, the handler thread is woken up and the current
+/// thread is preempted if possible, so that the handler thread runs as soon as possible. The EOI is
+/// sent before preempting the current thread, because the scheduler may switch to another thread
+/// and not return here before a long time.
+///
+/// Spurious IRQs of the 8259 PICs are counted and ignored (see [`super::spurious::pic_spurious`]).
+///
+/// Threads running in kernel mode are never preempted, because the kernel is not yet ready for it.
+pub extern "C" fn irq_handler(state: &cpu::State, vector: u8) {
+    let line = vector - config::IRQ_BASE;
+    if !super::ioapic::enabled() && super::spurious::pic_spurious(line) {
+        return;
+    }
+    COUNTS[usize::from(line)].add(1);
+    let actions = ACTIONS[usize::from(line)].lock().clone();
+
+    let mut handled = false;
+    let mut reschedule = false;
+    for action in actions.iter().flatten() {
+        match (action.handler)(line) {
+            IrqReturn::None => (),
+            IrqReturn::Handled => handled = true,
+            IrqReturn::Reschedule => {
+                handled = true;
+                reschedule = true;
+            }
+            IrqReturn::WakeThread => {
+                handled = true;
+                reschedule = true;
+                match &action.thread {
+                    Some(thread) => wake_thread(thread),
+                    None => log::warn!("IRQ {line}: {} has no threaded handler", action.name),
+                }
+            }
+        }
+    }
+    if !handled {
+        log::trace!("Unhandled IRQ {line}");
+    }
+
+    send_eoi(vector);
+    if reschedule && state.cs & 3 == 3 {
+        crate::sched::preempt();
+    }
+}
+
+/// Mask the given legacy IRQ line in the IOAPIC if the legacy IRQs are routed through it, or in
+/// the 8259 PIC otherwise.
+fn mask(line: u8) {
+    if super::ioapic::enabled() {
+        super::ioapic::mask(line);
+    } else {
+        super::pic::mask(line);
+    }
+}
+
+fn unmask(line: u8) {
+    if super::ioapic::enabled() {
+        super::ioapic::unmask(line);
+    } else {
+        super::pic::unmask(line);
+    }
+}
+
+/// Wake up the thread of a threaded handler, masking the line first if the handler is oneshot.
+fn wake_thread(thread: &IrqThread) {
+    if thread.oneshot {
+        mask(thread.line);
+    }
+    thread.pending.store(true, Ordering::Release);
+    if let Some(&tid) = thread.tid.get() {
+        sched::wake(tid);
     }
 }
 
-/// Ignore an IRQ. This is useful to avoid the kernel to crash when an IRQ is triggered (for
-/// example, when the keyboard is used), but should not be used in the future.
-pub extern "C" fn ignore_irq_handler(_: &cpu::State) {}
+/// The entry point of the thread of a threaded IRQ handler, which receives a reference to the
+/// shared state of the handler as its argument. The thread runs the threaded handler each time the
+/// hard handler asks for it, and exits once the handler is removed with [`free_irq`].
+unsafe extern "C" fn irq_thread(arg: u64, _: u64) -> ! {
+    let thread = Arc::from_raw(arg as *const IrqThread);
+    loop {
+        if thread.pending.swap(false, Ordering::Acquire) {
+            (thread.handler)(thread.line);
+            if thread.oneshot && requested(thread.line) {
+                unmask(thread.line);
+            }
+            continue;
+        }
+        if thread.stop.load(Ordering::Acquire) {
+            break;
+        }
+        sched::block();
+    }
+
+    drop(thread);
+    sched::exit();
+}
 
-interrupt_handler!(config::IRQ_BASE, pit_tick, pit_tick_handler, 0);
-interrupt_handler!(0xFF, ignore_irq, ignore_irq_handler, 0);
+crate::interrupt_table!(config::IRQ_BASE, IRQ_COUNT, IRQ_STUBS, irq_handler);
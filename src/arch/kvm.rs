@@ -0,0 +1,334 @@
+//! The paravirtual features of KVM, advertised in the leaf 0x40000001 of CPUID:
+//!
+//! - the kvmclock, a per-CPU structure that KVM keeps up to date with the system time of the host
+//!   and the parameters to extrapolate it from the TSC. It gives the time in nanoseconds without
+//!   any calibration, and without the exits caused by reading an emulated timer.
+//! - the paravirtual EOI: KVM tells whether the EOI of the interrupt being handled can be skipped,
+//!   saving an exit per interrupt.
+//! - the paravirtual IPI: an IPI is sent with a hypercall instead of a write to the interrupt
+//!   command register of the LAPIC, which is emulated in xAPIC mode.
+//!
+//! The kvmclocks and the EOI words of all the CPUs are packed in a single frame, one
+//! [`Area`] per CPU, registered by each CPU to KVM with its own MSRs (see [`enable`]).
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use bitflags::bitflags;
+use x86_64::{address::Physical, paging::PAGE_SIZE};
+
+use crate::{
+    arch::address::phys_to_virt,
+    config::MAX_CPU,
+    mm::{
+        frame::{AllocationFlags, Allocator},
+        FRAME_ALLOCATOR,
+    },
+};
+
+use super::{
+    cpuid::{self, Register, Vendor},
+    mmio::VolatileCell,
+    msr::{self, MSR_KVM_PV_EOI_EN, MSR_KVM_SYSTEM_TIME_NEW},
+};
+
+/// The leaf giving the paravirtual features of KVM.
+const LEAF_FEATURES: u32 = 0x4000_0001;
+
+/// The enable bit of the kvmclock and paravirtual EOI MSRs.
+const MSR_ENABLE: u64 = 1 << 0;
+
+/// Set in the flags of a kvmclock if the TSCs of all the CPUs are synchronized, so that the clocks
+/// of all the CPUs can be mixed. Only meaningful with [`KvmFeatures::CLOCKSOURCE_STABLE`].
+const PVCLOCK_TSC_STABLE: u8 = 1 << 0;
+
+/// Set by KVM in the EOI word of a CPU when the EOI of the current interrupt can be skipped.
+const PV_EOI_PENDING: u32 = 1 << 0;
+
+/// The hypercall sending an IPI to a set of APIC ids.
+const KVM_HC_SEND_IPI: u64 = 10;
+
+/// The fixed delivery mode in the ICR passed to [`KVM_HC_SEND_IPI`].
+const ICR_DELIVERY_FIXED: u64 = 0;
+
+bitflags! {
+    /// The paravirtual features of KVM, from the EAX register of the leaf 0x40000001.
+    pub struct KvmFeatures : u32 {
+        /// The kvmclock is registered with [`MSR_KVM_SYSTEM_TIME_NEW`].
+        const CLOCKSOURCE2 = 1 << 3;
+        const PV_EOI = 1 << 6;
+        const PV_SEND_IPI = 1 << 11;
+        /// The [`PVCLOCK_TSC_STABLE`] flag of the kvmclock can be trusted.
+        const CLOCKSOURCE_STABLE = 1 << 24;
+    }
+}
+
+/// The features advertised by KVM, and used by the kernel.
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+
+/// The physical address of the frame holding the [`Area`] of each CPU, or 0 if it is not allocated.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Set if the hypercalls use `vmmcall` (AMD) rather than `vmcall` (Intel).
+static VMMCALL: AtomicBool = AtomicBool::new(false);
+
+/// The kvmclock of a CPU, written by KVM. The version is odd while KVM updates the structure, and
+/// changes at each update.
+#[repr(C)]
+struct PvClock {
+    version: VolatileCell<u32>,
+    _pad0: u32,
+    tsc_timestamp: VolatileCell<u64>,
+    system_time: VolatileCell<u64>,
+    tsc_to_system_mul: VolatileCell<u32>,
+    tsc_shift: VolatileCell<i8>,
+    flags: VolatileCell<u8>,
+    _pad1: [u8; 2],
+}
+
+/// The structures a CPU shares with KVM. They are aligned on a cache line so that the updates of
+/// KVM for one CPU do not slow down the others.
+#[repr(C, align(64))]
+struct Area {
+    clock: PvClock,
+    eoi: AtomicU32,
+}
+
+const _: () = assert!(size_of::<PvClock>() == 32);
+const _: () = assert!(size_of::<Area>() * MAX_CPU <= PAGE_SIZE);
+
+/// A consistent copy of a kvmclock.
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    version: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    mul: u32,
+    shift: i8,
+    flags: u8,
+}
+
+impl Snapshot {
+    /// Returns the system time at the given TSC value, in nanoseconds.
+    #[allow(clippy::cast_possible_truncation)]
+    fn nanoseconds(&self, tsc: u64) -> u64 {
+        let delta = tsc.wrapping_sub(self.tsc_timestamp);
+        let delta = if self.shift >= 0 {
+            delta << self.shift
+        } else {
+            delta >> -self.shift
+        };
+        let scaled = (u128::from(delta) * u128::from(self.mul)) >> 32;
+        self.system_time.wrapping_add(scaled as u64)
+    }
+}
+
+/// Read the features of KVM and allocate the frame shared with it, then enable the paravirtual
+/// features on the BSP. Called by [`super::hypervisor::setup`] when the kernel runs under KVM.
+///
+/// # Panics
+/// Panics if the frame shared with KVM cannot be allocated.
+pub fn setup() {
+    let available =
+        KvmFeatures::from_bits_truncate(cpuid::register(LEAF_FEATURES, 0, Register::Eax));
+    FEATURES.store(available.bits, Ordering::Relaxed);
+    VMMCALL.store(cpuid::vendor() == Vendor::Amd, Ordering::Relaxed);
+    log::debug!("KVM features: {available:?}");
+
+    if available.intersects(KvmFeatures::CLOCKSOURCE2 | KvmFeatures::PV_EOI) {
+        let flags = AllocationFlags::KERNEL | AllocationFlags::ZEROED;
+        let frame = x86_64::irq::without(|| unsafe { FRAME_ALLOCATOR.lock().allocate(flags) })
+            .expect("Failed to allocate the KVM shared frame");
+        BASE.store(frame.start().as_u64(), Ordering::Relaxed);
+    }
+
+    enable();
+    if clock_available() {
+        log::info!(
+            "kvmclock: TSC at {} Hz{}",
+            tsc_frequency(),
+            if clock_stable() { ", stable" } else { "" }
+        );
+    }
+}
+
+/// Register the kvmclock and the EOI word of the current CPU to KVM. This must be called by each AP
+/// when it starts, before its timers are calibrated. Does nothing if the kernel does not run under
+/// KVM.
+pub fn enable() {
+    let base = BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return;
+    }
+
+    let cpu = super::smp::try_current_id().unwrap_or(0);
+    let area = base + u64::from(cpu) * size_of::<Area>() as u64;
+    unsafe {
+        if features().contains(KvmFeatures::CLOCKSOURCE2) {
+            msr::write(MSR_KVM_SYSTEM_TIME_NEW, area | MSR_ENABLE);
+        }
+        if features().contains(KvmFeatures::PV_EOI) {
+            let eoi = area + core::mem::offset_of!(Area, eoi) as u64;
+            msr::write(MSR_KVM_PV_EOI_EN, eoi | MSR_ENABLE);
+        }
+    }
+}
+
+/// Returns the paravirtual features of KVM, or an empty set if the kernel does not run under KVM.
+#[must_use]
+pub fn features() -> KvmFeatures {
+    KvmFeatures::from_bits_truncate(FEATURES.load(Ordering::Relaxed))
+}
+
+/// Returns true if the kvmclock can be read (see [`nanoseconds`]).
+#[must_use]
+pub fn clock_available() -> bool {
+    features().contains(KvmFeatures::CLOCKSOURCE2) && BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Returns true if the kvmclocks of all the CPUs are synchronized, so that the time read on a CPU
+/// never goes backward when read on another one.
+#[must_use]
+pub fn clock_stable() -> bool {
+    features().contains(KvmFeatures::CLOCKSOURCE_STABLE)
+        && clock_available()
+        && snapshot(area(0)).flags & PVCLOCK_TSC_STABLE != 0
+}
+
+/// Returns the system time of the host given by the kvmclock of the current CPU, in nanoseconds.
+///
+/// # Panics
+/// Panics if the kvmclock is not available (see [`clock_available`]).
+#[must_use]
+pub fn nanoseconds() -> u64 {
+    assert!(clock_available(), "kvmclock not available");
+    x86_64::irq::without(|| {
+        let cpu = super::smp::try_current_id().unwrap_or(0);
+        let area = area(cpu);
+        loop {
+            // The TSC must be read with the same version of the clock as its parameters
+            let snapshot = snapshot(area);
+            let tsc = super::tsc::rdtsc_ordered();
+            if area.clock.version.get() == snapshot.version {
+                return snapshot.nanoseconds(tsc);
+            }
+        }
+    })
+}
+
+/// Returns the frequency of the TSC derived from the scale of the kvmclock of the BSP, in Hz.
+///
+/// # Panics
+/// Panics if the kvmclock is not available (see [`clock_available`]).
+#[must_use]
+pub fn tsc_frequency() -> u64 {
+    assert!(clock_available(), "kvmclock not available");
+    let snapshot = snapshot(area(0));
+    let hz = (1_000_000_000u128 << 32) / u128::from(snapshot.mul.max(1));
+    let hz = if snapshot.shift >= 0 {
+        hz >> snapshot.shift
+    } else {
+        hz << -snapshot.shift
+    };
+    u64::try_from(hz).unwrap_or(u64::MAX)
+}
+
+/// Consume the paravirtual EOI of the current CPU: returns true if KVM allowed the EOI of the
+/// interrupt being handled to be skipped, in which case it must not be sent to the LAPIC. Must be
+/// called with interrupts disabled.
+#[must_use]
+pub fn skip_eoi() -> bool {
+    if !features().contains(KvmFeatures::PV_EOI) || BASE.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let Some(cpu) = super::smp::try_current_id() else {
+        return false;
+    };
+    area(cpu).eoi.fetch_and(!PV_EOI_PENDING, Ordering::AcqRel) & PV_EOI_PENDING != 0
+}
+
+/// Returns true if the IPIs can be sent with a hypercall (see [`send_ipi`]).
+#[must_use]
+pub fn pv_send_ipi() -> bool {
+    features().contains(KvmFeatures::PV_SEND_IPI)
+}
+
+/// Send a fixed IPI raising the given vector to the CPU with the given APIC id, with a hypercall.
+/// Returns false if KVM rejected the hypercall, in which case the IPI must be sent to the LAPIC.
+///
+/// # Safety
+/// This function is unsafe because the destination must be ready to handle the interrupt, and
+/// because the paravirtual IPIs must be supported (see [`pv_send_ipi`]).
+#[must_use]
+pub unsafe fn send_ipi(apic_id: u32, vector: u8) -> bool {
+    // The destinations are a bitmap of 128 APIC ids starting from the lowest one
+    let icr = ICR_DELIVERY_FIXED | u64::from(vector);
+    let sent = hypercall(KVM_HC_SEND_IPI, [1, 0, u64::from(apic_id), icr]);
+    sent > 0
+}
+
+/// Execute the given hypercall with its 4 arguments, and return its result. The arguments are
+/// passed in RBX, RCX, RDX and RSI, but RBX is reserved by LLVM, so the first one is swapped into
+/// it around the call.
+unsafe fn hypercall(number: u64, arguments: [u64; 4]) -> i64 {
+    let result: i64;
+    if VMMCALL.load(Ordering::Relaxed) {
+        core::arch::asm!(
+            "xchg {a0}, rbx",
+            "vmmcall",
+            "xchg {a0}, rbx",
+            a0 = inout(reg) arguments[0] => _,
+            inlateout("rax") number => result,
+            in("rcx") arguments[1],
+            in("rdx") arguments[2],
+            in("rsi") arguments[3],
+            options(nostack)
+        );
+    } else {
+        core::arch::asm!(
+            "xchg {a0}, rbx",
+            "vmcall",
+            "xchg {a0}, rbx",
+            a0 = inout(reg) arguments[0] => _,
+            inlateout("rax") number => result,
+            in("rcx") arguments[1],
+            in("rdx") arguments[2],
+            in("rsi") arguments[3],
+            options(nostack)
+        );
+    }
+    result
+}
+
+/// Returns the area of the given CPU in the frame shared with KVM.
+fn area(cpu: u32) -> &'static Area {
+    let base = BASE.load(Ordering::Relaxed);
+    let address = phys_to_virt(Physical::new(
+        base + u64::from(cpu) * size_of::<Area>() as u64,
+    ));
+    unsafe { &*address.as_ptr::<Area>() }
+}
+
+/// Copy the given kvmclock, retrying while KVM is updating it.
+fn snapshot(area: &Area) -> Snapshot {
+    let clock = &area.clock;
+    loop {
+        let version = clock.version.get();
+        if version & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let snapshot = Snapshot {
+            version,
+            tsc_timestamp: clock.tsc_timestamp.get(),
+            system_time: clock.system_time.get(),
+            mul: clock.tsc_to_system_mul.get(),
+            shift: clock.tsc_shift.get(),
+            flags: clock.flags.get(),
+        };
+        if clock.version.get() == version {
+            return snapshot;
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! The local APIC of each CPU. Its registers are accessed through their memory mapping in xAPIC
+//! mode, or through MSRs in x2APIC mode, which is used when all the CPUs support it: it needs no
+//! mapping, sends an IPI with a single write, and has 32-bit APIC ids, so that machines with more
+//! than 255 CPUs can address all of them. The registers are named by their offset in the xAPIC
+//! mapping whatever the mode.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use bitflags::bitflags;
+use x86_64::{address::Virtual, cpu::State, paging::PAGE_SIZE};
+
+use crate::interrupt_handler;
+
+use super::{
+    acpi::LAPIC_ERROR_VECTOR,
+    features::{self, CpuFeatures},
+    mmio::Mmio,
+    msr::{self, ApicBase},
+    percpu::PerCpuCounter,
+};
+
+/// The offsets of the LAPIC registers in the xAPIC mapping.
+pub const ID: u32 = 0x20;
+pub const EOI: u32 = 0xB0;
+pub const SPURIOUS_VECTOR_REGISTER: u32 = 0xF0;
+pub const ERROR_STATUS: u32 = 0x280;
+pub const INTERRUPT_COMMAND: u32 = 0x300;
+pub const INTERRUPT_COMMAND_HIGH: u32 = 0x310;
+pub const LVT_TIMER: u32 = 0x320;
+pub const LVT_THERMAL: u32 = 0x330;
+pub const LVT_PERFORMANCE_COUNTER: u32 = 0x340;
+pub const LVT_LINT0: u32 = 0x350;
+pub const LVT_LINT1: u32 = 0x360;
+pub const LVT_ERROR: u32 = 0x370;
+pub const INITIAL_COUNT: u32 = 0x380;
+pub const CURRENT_COUNT: u32 = 0x390;
+pub const DIVIDE_CONFIGURATION: u32 = 0x3E0;
+
+/// The MSR of the first register in x2APIC mode. The MSR of a register is this one plus its xAPIC
+/// offset divided by 16.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// The bit of the spurious interrupt vector register enabling the LAPIC.
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+
+/// The bits of an LVT entry: the delivery mode, the mask, and the timer mode.
+const LVT_DELIVERY_MODE_SHIFT: u32 = 8;
+const LVT_DELIVERY_MODE_MASK: u32 = 0b111 << LVT_DELIVERY_MODE_SHIFT;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const LVT_TIMER_MODE_MASK: u32 = 0b11 << LVT_TIMER_MODE_SHIFT;
+
+/// The bits of the interrupt command register: the delivery status (xAPIC only), the level, and
+/// the shift of the destination shorthand.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_SHIFT: u32 = 18;
+
+/// Set when the LAPICs are in x2APIC mode.
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// The virtual address of the LAPIC registers in xAPIC mode, or 0 if they are not mapped.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+static ERRORS: PerCpuCounter = PerCpuCounter::new();
+
+/// The CPUs an IPI is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpiDestination {
+    /// The CPU with the given APIC id. It must fit in 8 bits in xAPIC mode.
+    Core(u32),
+    Myself,
+    All,
+    OtherCores,
+}
+
+/// How an IPI is delivered to its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeliveryMode {
+    /// Raise the given vector.
+    Fixed = 0b000,
+    /// Raise a non-maskable interrupt, whatever the vector.
+    Nmi = 0b100,
+    Init = 0b101,
+    /// Start an AP at the page given by the vector.
+    Startup = 0b110,
+    /// Let the 8259 PIC provide the vector. This is only valid in the LINT0 entry of the LVT.
+    ExtInt = 0b111,
+}
+
+/// An entry of the Local Vector Table, which selects how an interrupt source of the LAPIC is
+/// delivered to its CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum Lvt {
+    Timer = LVT_TIMER,
+    /// The thermal sensor, which raises its interrupt when the temperature crosses a threshold.
+    Thermal = LVT_THERMAL,
+    PerformanceCounter = LVT_PERFORMANCE_COUNTER,
+    /// The LINT0 pin, usually wired to the 8259 PIC.
+    Lint0 = LVT_LINT0,
+    /// The LINT1 pin, usually wired to the NMI line of the chipset.
+    Lint1 = LVT_LINT1,
+    /// The errors detected by the LAPIC (see [`ErrorStatus`]).
+    Error = LVT_ERROR,
+}
+
+/// The value of an LVT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LvtEntry(u32);
+
+/// The mode of the LAPIC timer, selected by its LVT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerMode {
+    /// Count down once from the initial count.
+    OneShot = 0b00,
+    /// Count down from the initial count, and start again when zero is reached.
+    Periodic = 0b01,
+    /// Fire when the TSC reaches the value written to the `IA32_TSC_DEADLINE` MSR.
+    TscDeadline = 0b10,
+}
+
+/// The value by which the bus clock is divided to drive the LAPIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+bitflags! {
+    /// The errors detected by the LAPIC, read from its error status register.
+    pub struct ErrorStatus : u32 {
+        /// A message sent on the APIC bus had a wrong checksum (P6 and Pentium only).
+        const SEND_CHECKSUM = 1 << 0;
+        const RECEIVE_CHECKSUM = 1 << 1;
+        /// A message sent on the APIC bus was not accepted by any APIC (P6 and Pentium only).
+        const SEND_ACCEPT = 1 << 2;
+        const RECEIVE_ACCEPT = 1 << 3;
+        /// A lowest priority IPI was sent, but the CPU does not support it.
+        const REDIRECTABLE_IPI = 1 << 4;
+        /// An IPI was sent with a vector below 16.
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        /// An interrupt was received, or set in an LVT entry, with a vector below 16.
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+        /// A register that does not exist was accessed (xAPIC mode only).
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
+}
+
+impl LvtEntry {
+    /// A masked entry: the interrupt source is ignored.
+    pub const MASKED: Self = Self(LVT_MASKED);
+
+    /// An unmasked entry raising the given vector.
+    #[must_use]
+    pub const fn fixed(vector: u8) -> Self {
+        Self(vector as u32)
+    }
+
+    /// Select how the interrupt is delivered. The vector is ignored by the [`DeliveryMode::Nmi`]
+    /// and [`DeliveryMode::ExtInt`] modes.
+    #[must_use]
+    pub const fn with_delivery_mode(self, mode: DeliveryMode) -> Self {
+        Self(self.0 & !LVT_DELIVERY_MODE_MASK | (mode as u32) << LVT_DELIVERY_MODE_SHIFT)
+    }
+
+    /// Select the mode of the timer. This is only valid in the timer entry.
+    #[must_use]
+    pub const fn with_timer_mode(self, mode: TimerMode) -> Self {
+        Self(self.0 & !LVT_TIMER_MODE_MASK | (mode as u32) << LVT_TIMER_MODE_SHIFT)
+    }
+
+    #[must_use]
+    pub const fn masked(self) -> Self {
+        Self(self.0 | LVT_MASKED)
+    }
+
+    #[must_use]
+    pub const fn is_masked(self) -> bool {
+        self.0 & LVT_MASKED != 0
+    }
+
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn vector(self) -> u8 {
+        self.0 as u8
+    }
+
+    #[must_use]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+/// Select the mode of the LAPICs and enable the LAPIC of the current CPU, which must be the BSP.
+/// The x2APIC mode is used if all the CPUs started so far support it, and is then required on the
+/// APs. Otherwise, the registers at the given physical address are mapped.
+///
+/// # Panics
+/// Panics if the LAPIC registers cannot be mapped.
+pub fn setup(physical: u64) {
+    // The firmware may already have switched to the x2APIC mode, which cannot be left without
+    // disabling the LAPIC
+    let x2apic = features::require(CpuFeatures::X2APIC) || ApicBase::read().x2apic;
+    X2APIC.store(x2apic, Ordering::Relaxed);
+    if !x2apic {
+        let base = unsafe { super::acpi::remap_mmio(physical, PAGE_SIZE) }
+            .expect("Failed to map the LAPIC registers");
+        BASE.store(base.as_u64(), Ordering::Relaxed);
+    }
+
+    enable();
+    log::info!("LAPIC in {} mode", if x2apic { "x2APIC" } else { "xAPIC" });
+}
+
+/// Enable the LAPIC of the current CPU in the mode selected by the BSP. This must be called by each
+/// AP when it starts, before any other LAPIC access. The errors detected by the LAPIC are then
+/// raised on the [`LAPIC_ERROR_VECTOR`], and the errors latched before are discarded.
+pub fn enable() {
+    unsafe {
+        let mut base = ApicBase::read();
+        base.enabled = true;
+        base.x2apic = x2apic();
+        base.write();
+    }
+    write(
+        SPURIOUS_VECTOR_REGISTER,
+        read(SPURIOUS_VECTOR_REGISTER) | SVR_APIC_ENABLE,
+    );
+
+    set_lvt(Lvt::Error, LvtEntry::fixed(LAPIC_ERROR_VECTOR));
+    let _ = error_status();
+}
+
+/// Returns true if the LAPICs can be used: they are initialized by [`setup`].
+#[must_use]
+pub fn initialized() -> bool {
+    x2apic() || BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Returns true if the LAPICs are in x2APIC mode.
+#[must_use]
+pub fn x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+/// Returns the APIC id of the current CPU.
+#[must_use]
+pub fn id() -> u32 {
+    if x2apic() {
+        read(ID)
+    } else {
+        read(ID) >> 24
+    }
+}
+
+/// Signal the end of the interrupt being handled to the LAPIC of the current CPU. Under KVM, the
+/// EOI is skipped when the hypervisor allows it (see [`super::kvm::skip_eoi`]).
+pub fn send_eoi() {
+    if super::kvm::skip_eoi() {
+        return;
+    }
+    write(EOI, 0);
+}
+
+/// Select the vector raised by the spurious interrupts of the LAPIC of the current CPU. The low 4
+/// bits of the vector are hardwired to 1 on some CPUs.
+pub fn set_spurious_vector(vector: u8) {
+    let svr = read(SPURIOUS_VECTOR_REGISTER);
+    write(SPURIOUS_VECTOR_REGISTER, (svr & !0xFF) | u32::from(vector));
+}
+
+/// Returns the given LVT entry of the LAPIC of the current CPU.
+#[must_use]
+pub fn lvt(lvt: Lvt) -> LvtEntry {
+    LvtEntry(read(lvt as u32))
+}
+
+/// Write the given LVT entry of the LAPIC of the current CPU. The thermal and performance counter
+/// entries do not exist on all CPUs.
+pub fn set_lvt(lvt: Lvt, entry: LvtEntry) {
+    write(lvt as u32, entry.bits());
+}
+
+/// Returns the errors detected by the LAPIC of the current CPU since the last call, and clears
+/// them. The register must be written before being read, to latch the errors detected since the
+/// previous write.
+#[must_use]
+pub fn error_status() -> ErrorStatus {
+    write(ERROR_STATUS, 0);
+    ErrorStatus::from_bits_truncate(read(ERROR_STATUS))
+}
+
+/// Returns the number of error interrupts raised by the LAPICs since the boot.
+#[must_use]
+pub fn error_count() -> u64 {
+    ERRORS.sum()
+}
+
+pub fn set_timer_divide(divide: TimerDivide) {
+    write(DIVIDE_CONFIGURATION, divide as u32);
+}
+
+/// Start the LAPIC timer of the current CPU from the given count, or stop it if the count is zero.
+/// This has no effect in TSC-deadline mode.
+pub fn set_initial_count(count: u32) {
+    write(INITIAL_COUNT, count);
+}
+
+#[must_use]
+pub fn initial_count() -> u32 {
+    read(INITIAL_COUNT)
+}
+
+/// Returns the count of the LAPIC timer of the current CPU, which reaches zero when it fires.
+#[must_use]
+pub fn current_count() -> u32 {
+    read(CURRENT_COUNT)
+}
+
+/// Send an IPI to the given CPUs. Under KVM, a fixed IPI to a single CPU is sent with a hypercall
+/// when the hypervisor supports it (see [`super::kvm::send_ipi`]).
+///
+/// # Panics
+/// Panics if the APIC id of the destination does not fit in 8 bits in xAPIC mode.
+///
+/// # Safety
+/// This function is unsafe because the INIT and startup IPIs reset the destination CPUs, and
+/// because the destination must be ready to handle the interrupt.
+pub unsafe fn send_ipi(destination: IpiDestination, mode: DeliveryMode, vector: u8) {
+    if let (IpiDestination::Core(id), DeliveryMode::Fixed) = (destination, mode) {
+        if super::kvm::pv_send_ipi() && super::kvm::send_ipi(id, vector) {
+            return;
+        }
+    }
+
+    let (id, shorthand) = match destination {
+        IpiDestination::Core(id) => (id, 0b00),
+        IpiDestination::Myself => (0, 0b01),
+        IpiDestination::All => (0, 0b10),
+        IpiDestination::OtherCores => (0, 0b11),
+    };
+    let command = u32::from(vector)
+        | (mode as u32) << 8
+        | ICR_LEVEL_ASSERT
+        | shorthand << ICR_SHORTHAND_SHIFT;
+
+    if x2apic() {
+        // The whole command is a single MSR, so the IPI is sent with one write
+        let msr = X2APIC_MSR_BASE + (INTERRUPT_COMMAND >> 4);
+        msr::write(msr, u64::from(id) << 32 | u64::from(command));
+    } else {
+        let id = u8::try_from(id).expect("APIC id should fit in u8 in xAPIC mode");
+        while read(INTERRUPT_COMMAND) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        write(INTERRUPT_COMMAND_HIGH, u32::from(id) << 24);
+        write(INTERRUPT_COMMAND, command);
+    }
+}
+
+/// Read a register of the LAPIC of the current CPU.
+#[must_use]
+pub fn read(register: u32) -> u32 {
+    if x2apic() {
+        #[allow(clippy::cast_possible_truncation)]
+        let value = unsafe { msr::read(X2APIC_MSR_BASE + (register >> 4)) } as u32;
+        value
+    } else {
+        register_mmio(register).read()
+    }
+}
+
+/// Write a register of the LAPIC of the current CPU.
+pub fn write(register: u32, value: u32) {
+    if x2apic() {
+        unsafe { msr::write(X2APIC_MSR_BASE + (register >> 4), u64::from(value)) };
+    } else {
+        register_mmio(register).write(value);
+    }
+}
+
+/// Returns the memory-mapped register in xAPIC mode.
+fn register_mmio(register: u32) -> Mmio<u32> {
+    let address = Virtual::new(BASE.load(Ordering::Relaxed) + u64::from(register));
+    unsafe { Mmio::new(address) }
+}
+
+/// Handler for the error interrupt of the LAPIC. The errors usually come from a bad vector in an
+/// IPI or an LVT entry, so they are logged and otherwise ignored.
+pub extern "C" fn error_interrupt_handler(_state: &State) {
+    let status = error_status();
+    ERRORS.add(1);
+    log::warn!(
+        "LAPIC error on CPU {}: {:?}",
+        super::smp::current_id(),
+        status
+    );
+    send_eoi();
+}
+
+interrupt_handler!(LAPIC_ERROR_VECTOR, error, error_interrupt_handler);
@@ -0,0 +1,105 @@
+//! Access to the memory-mapped registers of the devices. The accesses are volatile, so the compiler
+//! neither removes nor merges them, and they are not reordered with each other. They are also
+//! ordered with the surrounding accesses to normal memory: the memory written before a register
+//! write (for example a DMA descriptor) is written before the register, and the memory read after
+//! a register read is read after it. x86 does not reorder the uncacheable accesses used for the
+//! device registers with the other memory accesses, so only the compiler must be constrained.
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use x86_64::address::Virtual;
+
+/// A memory-mapped register of type `T`, which must be `u8`, `u16`, `u32` or `u64` so that it is
+/// accessed with a single instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Mmio<T: Copy> {
+    address: u64,
+    _value: PhantomData<T>,
+}
+
+/// A value in memory shared with a device or a hypervisor, which can change at any time. Unlike
+/// [`Mmio`], a cell is placed in a structure describing the layout of the shared memory.
+#[repr(transparent)]
+pub struct VolatileCell<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// Create a register at the given virtual address.
+    ///
+    /// # Safety
+    /// This function is unsafe because the address must be mapped to the register as uncacheable
+    /// memory (see [`super::acpi::remap_mmio`]) and aligned on the size of `T` while the register is
+    /// used, and because accessing a register can have side effects on the device.
+    #[must_use]
+    pub const unsafe fn new(address: Virtual) -> Self {
+        Self {
+            address: address.as_u64(),
+            _value: PhantomData,
+        }
+    }
+
+    /// Returns the register at the given offset in bytes from this one.
+    ///
+    /// # Safety
+    /// See [`Mmio::new`].
+    #[must_use]
+    pub const unsafe fn offset<U: Copy>(self, offset: u64) -> Mmio<U> {
+        Mmio {
+            address: self.address + offset,
+            _value: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub fn address(self) -> Virtual {
+        Virtual::new(self.address)
+    }
+
+    /// Read the register. The memory reads that follow are not moved before it.
+    #[must_use]
+    pub fn read(self) -> T {
+        let value = unsafe { (self.address as *const T).read_volatile() };
+        compiler_fence(Ordering::Acquire);
+        value
+    }
+
+    /// Write the register. The memory writes that precede are not moved after it.
+    pub fn write(self, value: T) {
+        compiler_fence(Ordering::Release);
+        unsafe { (self.address as *mut T).write_volatile(value) };
+    }
+
+    /// Read the register, and write the value returned by `f`.
+    pub fn update<F: FnOnce(T) -> T>(self, f: F) {
+        self.write(f(self.read()));
+    }
+}
+
+impl<T: Copy> VolatileCell<T> {
+    #[must_use]
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Read the value. The memory reads that follow are not moved before it.
+    #[must_use]
+    pub fn get(&self) -> T {
+        let value = unsafe { self.value.get().read_volatile() };
+        compiler_fence(Ordering::Acquire);
+        value
+    }
+
+    /// Write the value. The memory writes that precede are not moved after it.
+    pub fn set(&self, value: T) {
+        compiler_fence(Ordering::Release);
+        unsafe { self.value.get().write_volatile(value) };
+    }
+}
+
+unsafe impl<T: Copy + Send> Sync for VolatileCell<T> {}
@@ -1,26 +1,58 @@
 use limine::LimineSmpInfo;
-use x86_64::{pic, pit::Pit};
 
-use crate::{
-    config::{self, KERNEL_HZ},
-    Spinlock,
-};
+use crate::config;
 
 pub mod acpi;
 pub mod address;
+pub mod backtrace;
+pub mod barrier;
+pub mod cache;
+pub mod clocksource;
+pub mod context;
+pub mod control;
+pub mod cpuid;
+pub mod cpumask;
+pub mod debugcon;
+pub mod debugreg;
 pub mod exception;
+pub mod features;
+pub mod fpu;
 pub mod gdt;
+pub mod hpet;
+pub mod hypervisor;
+pub mod idle;
 pub mod idt;
+pub mod interrupt;
+pub mod ioapic;
 pub mod irq;
+pub mod kvm;
+pub mod lapic;
+pub mod mmio;
+pub mod msi;
+pub mod msr;
+pub mod mtrr;
 pub mod paging;
+pub mod percpu;
+pub mod pic;
+pub mod pit;
+pub mod pmu;
+pub mod port;
+pub mod sci;
 pub mod smp;
+pub mod spurious;
+pub mod syscall;
+pub mod timer;
+pub mod topology;
+pub mod tsc;
 pub mod tss;
+pub mod vector;
 
-pub static PIT: Spinlock<Pit> = Spinlock::new(Pit::new(KERNEL_HZ));
+pub use features::cpu_features;
 
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
     core::arch::asm!("xor rbp, rbp"); // Clear the base pointer (useful for backtraces)
+    backtrace::set_boot_stack(backtrace::boot_stack_top());
     #[cfg(feature = "log")]
     crate::log::init();
     crate::start();
@@ -40,10 +72,17 @@ pub extern "C" fn _ap_start(info: *const LimineSmpInfo) -> ! {
 
 /// Initialize the BSP
 pub fn init_bsp() {
-    PIT.lock().setup();
+    features::setup();
+    cache::setup();
+    fpu::setup();
+    mtrr::report();
     smp::bsp_setup();
+    hypervisor::setup();
+    idle::setup();
     paging::setup();
-    tss::install(0);
+    tss::install();
+    crate::mm::user::setup();
+    syscall::setup();
     unsafe {
         pic::remap(config::IRQ_BASE);
     }
@@ -0,0 +1,152 @@
+use x86_64::{
+    cpu::{self, Privilege},
+    idt::{Descriptor, DescriptorFlags},
+};
+
+use crate::Spinlock;
+
+/// The first vector used for message signaled interrupts, right after the legacy IRQs.
+pub const MSI_BASE: u8 = 0x30;
+
+/// The number of vectors available for message signaled interrupts.
+pub const MSI_VECTOR_COUNT: u8 = 32;
+
+/// The base of the MSI address: writes to this range are delivered to a LAPIC instead of the
+/// memory.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/// The vector bits of the MSI data.
+const MSI_DATA_VECTOR: u32 = 0xFF;
+
+/// The level bit of the MSI data, which must be set for level-triggered interrupts.
+const MSI_DATA_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// The trigger mode bit of the MSI data.
+const MSI_DATA_TRIGGER_LEVEL: u32 = 1 << 15;
+
+/// A message signaled interrupt handler, called with interrupts disabled and with the vector of
+/// the interrupt.
+pub type Handler = fn(vector: u8);
+
+#[derive(Debug, Clone, Copy)]
+struct Action {
+    handler: Handler,
+    name: &'static str,
+}
+
+/// The handlers of the allocated vectors, indexed by vector number minus [`MSI_BASE`]. A vector
+/// is free if it has no handler.
+static ACTIONS: Spinlock<[Option<Action>; MSI_VECTOR_COUNT as usize]> =
+    Spinlock::new([None; MSI_VECTOR_COUNT as usize]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// The address and data that a device must write to raise a message signaled interrupt. The
+/// interrupt is delivered in fixed mode and physical destination mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Message {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl Message {
+    /// Compose the message that delivers the given vector to the LAPIC with the given identifier.
+    #[must_use]
+    pub fn new(vector: u8, destination: u32, trigger: TriggerMode) -> Self {
+        let address = MSI_ADDRESS_BASE | u64::from(destination & 0xFF) << 12;
+        let mut data = u32::from(vector);
+        if trigger == TriggerMode::Level {
+            data |= MSI_DATA_TRIGGER_LEVEL | MSI_DATA_LEVEL_ASSERT;
+        }
+        Self { address, data }
+    }
+
+    /// Returns the identifier of the LAPIC the message is delivered to.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn destination(&self) -> u32 {
+        ((self.address >> 12) & 0xFF) as u32
+    }
+
+    /// Returns the vector raised by the message.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn vector(&self) -> u8 {
+        (self.data & MSI_DATA_VECTOR) as u8
+    }
+
+    /// Returns the same message, delivered to the LAPIC with the given identifier.
+    #[must_use]
+    pub fn with_destination(self, destination: u32) -> Self {
+        let address = self.address & !(0xFF << 12) | u64::from(destination & 0xFF) << 12;
+        Self { address, ..self }
+    }
+}
+
+/// Install the MSI dispatcher on all the MSI vectors in the IDT.
+pub fn setup() {
+    let flags = DescriptorFlags::new()
+        .set_privilege_level(Privilege::KERNEL)
+        .present(true)
+        .build();
+    let mut idt = super::idt::IDT.lock();
+    let stubs = unsafe { &MSI_STUBS };
+    for (vector, stub) in (MSI_BASE..MSI_BASE + MSI_VECTOR_COUNT).zip(stubs) {
+        idt.set_descriptor(
+            vector,
+            Descriptor::new()
+                .set_handler_addr(*stub as *const () as u64)
+                .set_options(flags)
+                .build(),
+        );
+    }
+}
+
+/// Allocate a free MSI vector and install the given handler on it. Returns `None` if all the MSI
+/// vectors are already allocated.
+#[must_use]
+pub fn allocate(handler: Handler, name: &'static str) -> Option<u8> {
+    x86_64::irq::without(|| {
+        let mut actions = ACTIONS.lock();
+        let index = actions.iter().position(Option::is_none)?;
+        actions[index] = Some(Action { handler, name });
+
+        let vector = MSI_BASE + u8::try_from(index).unwrap();
+        log::debug!("MSI vector {vector:#x} allocated by {name}");
+        Some(vector)
+    })
+}
+
+/// Free the given MSI vector. The device must no longer use it.
+///
+/// # Panics
+/// Panics if the vector is not an allocated MSI vector.
+pub fn free(vector: u8) {
+    let index = vector
+        .checked_sub(MSI_BASE)
+        .filter(|&index| index < MSI_VECTOR_COUNT)
+        .expect("Not an MSI vector");
+    x86_64::irq::without(|| {
+        let action = ACTIONS.lock()[usize::from(index)]
+            .take()
+            .expect("Freeing an MSI vector that is not allocated");
+        log::debug!("MSI vector {vector:#x} freed by {}", action.name);
+    });
+}
+
+/// Called for all MSI vectors. The handler is copied before being called so that the lock is not
+/// held while it runs. Message signaled interrupts are always acknowledged to the LAPIC.
+pub extern "C" fn msi_handler(_state: &cpu::State, vector: u8) {
+    let action = ACTIONS.lock()[usize::from(vector - MSI_BASE)];
+    match action {
+        Some(action) => (action.handler)(vector),
+        None => log::warn!("Spurious MSI on vector {vector:#x}"),
+    }
+    super::lapic::send_eoi();
+}
+
+crate::interrupt_table!(MSI_BASE, MSI_VECTOR_COUNT, MSI_STUBS, msi_handler);
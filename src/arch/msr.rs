@@ -0,0 +1,474 @@
+//! A catalogue of the model specific registers used by the kernel, with typed wrappers so that the
+//! callers do not have to know their address and the layout of their bits. The registers that may
+//! be missing on some CPUs are read with [`try_read`], which returns `None` instead of crashing on
+//! the general protection fault raised by a missing MSR.
+use bitflags::bitflags;
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_SPEC_CTRL: u32 = 0x48;
+pub const IA32_PMC0: u32 = 0xC1;
+pub const IA32_MTRRCAP: u32 = 0xFE;
+pub const MSR_PLATFORM_INFO: u32 = 0xCE;
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+pub const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+pub const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+pub const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+pub const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+pub const IA32_FIXED_CTR0: u32 = 0x309;
+pub const IA32_FIXED_CTR_CTRL: u32 = 0x38D;
+pub const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+pub const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4B56_4D01;
+pub const MSR_KVM_PV_EOI_EN: u32 = 0x4B56_4D04;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_STAR: u32 = 0xC000_0081;
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+pub const IA32_FMASK: u32 = 0xC000_0084;
+
+extern "C" {
+    /// Read the given MSR into `value`. Returns 0 on success, or 1 if the read raised a general
+    /// protection fault.
+    fn __rdmsr_safe(msr: u32, value: *mut u64) -> u32;
+
+    /// Write the given value to the given MSR. Returns 0 on success, or 1 if the write raised a
+    /// general protection fault.
+    fn __wrmsr_safe(msr: u32, value: u64) -> u32;
+}
+
+// As for the user memory accesses, the faulting instructions are registered in the exception
+// table: the general protection fault handler then resumes the execution at the fixup code, which
+// returns an error.
+core::arch::global_asm!(
+    r#"
+.global __rdmsr_safe
+__rdmsr_safe:
+    mov ecx, edi
+.Lrdmsr_safe_insn:
+    rdmsr
+    mov [rsi], eax
+    mov [rsi + 4], edx
+    xor eax, eax
+    ret
+.Lrdmsr_safe_fixup:
+    mov eax, 1
+    ret
+
+.global __wrmsr_safe
+__wrmsr_safe:
+    mov ecx, edi
+    mov eax, esi
+    mov rdx, rsi
+    shr rdx, 32
+.Lwrmsr_safe_insn:
+    wrmsr
+    xor eax, eax
+    ret
+.Lwrmsr_safe_fixup:
+    mov eax, 1
+    ret
+
+.pushsection .ex_table, "a"
+.balign 8
+.quad .Lrdmsr_safe_insn, .Lrdmsr_safe_fixup
+.quad .Lwrmsr_safe_insn, .Lwrmsr_safe_fixup
+.popsection
+"#
+);
+
+/// Read the given MSR.
+///
+/// # Safety
+/// This function is unsafe because reading a MSR that does not exist raises a general protection
+/// fault, and because reading some MSRs has side effects.
+#[must_use]
+pub unsafe fn read(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    core::arch::asm!(
+        "rdmsr",
+        in("ecx") msr,
+        out("eax") low,
+        out("edx") high,
+        options(nomem, nostack, preserves_flags)
+    );
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// Write the given value to the given MSR.
+///
+/// # Safety
+/// This function is unsafe because writing a MSR that does not exist or a reserved bit raises a
+/// general protection fault, and because most MSRs change the behaviour of the CPU.
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe fn write(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") low,
+        in("edx") high,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Read the given MSR, or return `None` if the CPU does not have it.
+///
+/// # Safety
+/// This function is unsafe because reading some MSRs has side effects.
+#[must_use]
+pub unsafe fn try_read(msr: u32) -> Option<u64> {
+    let mut value = 0;
+    (__rdmsr_safe(msr, core::ptr::addr_of_mut!(value)) == 0).then_some(value)
+}
+
+/// Write the given value to the given MSR. Returns false if the CPU does not have it, or if the
+/// value sets a reserved bit.
+///
+/// # Safety
+/// This function is unsafe because most MSRs change the behaviour of the CPU.
+#[must_use]
+pub unsafe fn try_write(msr: u32, value: u64) -> bool {
+    __wrmsr_safe(msr, value) == 0
+}
+
+/// A memory type, used by the PAT and by the MTRRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+
+    /// Uncacheable, unless an MTRR says otherwise. Only valid in the PAT.
+    UncachedMinus = 7,
+}
+
+impl MemoryType {
+    /// Returns the memory type with the given encoding, or `None` if it is reserved.
+    #[must_use]
+    pub const fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Uncacheable),
+            1 => Some(Self::WriteCombining),
+            4 => Some(Self::WriteThrough),
+            5 => Some(Self::WriteProtected),
+            6 => Some(Self::WriteBack),
+            7 => Some(Self::UncachedMinus),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the accesses with this memory type may be cached.
+    #[must_use]
+    pub const fn cacheable(self) -> bool {
+        matches!(
+            self,
+            Self::WriteThrough | Self::WriteProtected | Self::WriteBack
+        )
+    }
+}
+
+/// The `IA32_APIC_BASE` register, which locates and enables the LAPIC of the current CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ApicBase {
+    /// The physical address of the LAPIC registers.
+    pub address: u64,
+
+    /// Set on the bootstrap processor.
+    pub bsp: bool,
+
+    /// Set when the LAPIC is in x2APIC mode.
+    pub x2apic: bool,
+    pub enabled: bool,
+}
+
+impl ApicBase {
+    const BSP: u64 = 1 << 8;
+    const X2APIC: u64 = 1 << 10;
+    const ENABLED: u64 = 1 << 11;
+    const ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
+    #[must_use]
+    pub fn read() -> Self {
+        let value = unsafe { read(IA32_APIC_BASE) };
+        Self {
+            address: value & Self::ADDRESS,
+            bsp: value & Self::BSP != 0,
+            x2apic: value & Self::X2APIC != 0,
+            enabled: value & Self::ENABLED != 0,
+        }
+    }
+
+    /// # Safety
+    /// This function is unsafe because moving or disabling the LAPIC breaks the interrupts.
+    pub unsafe fn write(self) {
+        let mut value = read(IA32_APIC_BASE) & !(Self::ADDRESS | Self::X2APIC | Self::ENABLED);
+        value |= self.address & Self::ADDRESS;
+        if self.x2apic {
+            value |= Self::X2APIC;
+        }
+        if self.enabled {
+            value |= Self::ENABLED;
+        }
+        write(IA32_APIC_BASE, value);
+    }
+}
+
+bitflags! {
+    /// The `IA32_SPEC_CTRL` register, controlling the mitigations of the speculative execution
+    /// attacks.
+    pub struct SpecCtrl : u64 {
+        /// Indirect branch restricted speculation.
+        const IBRS = 1 << 0;
+        /// Single thread indirect branch predictors.
+        const STIBP = 1 << 1;
+        /// Speculative store bypass disable.
+        const SSBD = 1 << 2;
+    }
+}
+
+impl SpecCtrl {
+    /// Returns the register, or `None` if the CPU does not have it.
+    #[must_use]
+    pub fn try_read() -> Option<Self> {
+        unsafe { try_read(IA32_SPEC_CTRL) }.map(Self::from_bits_truncate)
+    }
+
+    /// Write the register. Returns false if the CPU does not have it or does not support one of
+    /// the given mitigations.
+    #[must_use]
+    pub fn try_write(self) -> bool {
+        unsafe { try_write(IA32_SPEC_CTRL, self.bits) }
+    }
+}
+
+/// The `MSR_PLATFORM_INFO` register of the Intel processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlatformInfo {
+    /// The ratio between the maximum non-turbo frequency and the bus frequency (usually 100 MHz):
+    /// this is the frequency of the TSC.
+    pub max_non_turbo_ratio: u8,
+}
+
+impl PlatformInfo {
+    /// Returns the register, or `None` if the CPU does not have it.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn try_read() -> Option<Self> {
+        unsafe { try_read(MSR_PLATFORM_INFO) }.map(|value| Self {
+            max_non_turbo_ratio: (value >> 8) as u8,
+        })
+    }
+}
+
+/// The `IA32_PAT` register, giving the memory type of each of the 8 entries selected by the PAT,
+/// PCD and PWT bits of the page table entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pat(pub [MemoryType; 8]);
+
+impl Pat {
+    /// Returns the register. The entries with a reserved encoding are reported as uncacheable.
+    #[must_use]
+    pub fn read() -> Self {
+        let value = unsafe { read(IA32_PAT) };
+        Self(core::array::from_fn(|entry| {
+            let bits = value.to_le_bytes()[entry] & 0x7;
+            MemoryType::from_bits(bits).unwrap_or(MemoryType::Uncacheable)
+        }))
+    }
+
+    /// # Safety
+    /// This function is unsafe because changing the memory type of the pages already mapped can
+    /// break the devices and the memory coherency. The caches and the TLB must be flushed after
+    /// the write.
+    pub unsafe fn write(self) {
+        let bytes = self.0.map(|kind| kind as u8);
+        write(IA32_PAT, u64::from_le_bytes(bytes));
+    }
+}
+
+/// The `IA32_MTRRCAP` register, describing the MTRRs of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MtrrCap {
+    /// The number of variable range MTRRs.
+    pub variable_count: u8,
+    pub fixed: bool,
+    pub write_combining: bool,
+}
+
+impl MtrrCap {
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read() -> Self {
+        let value = unsafe { read(IA32_MTRRCAP) };
+        Self {
+            variable_count: value as u8,
+            fixed: value & (1 << 8) != 0,
+            write_combining: value & (1 << 10) != 0,
+        }
+    }
+}
+
+/// The `IA32_MTRR_DEF_TYPE` register, giving the memory type of the memory not covered by an MTRR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MtrrDefType {
+    pub default: MemoryType,
+    pub fixed_enabled: bool,
+    pub enabled: bool,
+}
+
+impl MtrrDefType {
+    const FIXED_ENABLED: u64 = 1 << 10;
+    const ENABLED: u64 = 1 << 11;
+
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read() -> Self {
+        let value = unsafe { read(IA32_MTRR_DEF_TYPE) };
+        Self {
+            default: MemoryType::from_bits(value as u8).unwrap_or(MemoryType::Uncacheable),
+            fixed_enabled: value & Self::FIXED_ENABLED != 0,
+            enabled: value & Self::ENABLED != 0,
+        }
+    }
+
+    /// # Safety
+    /// This function is unsafe because changing the memory types can break the devices and the
+    /// memory coherency: the MTRRs must only be changed with the caches disabled and flushed.
+    pub unsafe fn write(self) {
+        let mut value = self.default as u64;
+        if self.fixed_enabled {
+            value |= Self::FIXED_ENABLED;
+        }
+        if self.enabled {
+            value |= Self::ENABLED;
+        }
+        write(IA32_MTRR_DEF_TYPE, value);
+    }
+}
+
+/// A variable range MTRR, giving the memory type of the physical addresses `a` such that
+/// `a & mask == base & mask`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MtrrVariable {
+    pub base: u64,
+    pub mask: u64,
+    pub kind: MemoryType,
+    pub valid: bool,
+}
+
+impl MtrrVariable {
+    const VALID: u64 = 1 << 11;
+    const ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
+    /// Returns the variable range MTRR with the given index, which must be lower than
+    /// [`MtrrCap::variable_count`].
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read(index: u8) -> Self {
+        let msr = IA32_MTRR_PHYSBASE0 + 2 * u32::from(index);
+        let (base, mask) = unsafe { (read(msr), read(msr + 1)) };
+        Self {
+            base: base & Self::ADDRESS,
+            mask: mask & Self::ADDRESS,
+            kind: MemoryType::from_bits(base as u8).unwrap_or(MemoryType::Uncacheable),
+            valid: mask & Self::VALID != 0,
+        }
+    }
+
+    /// # Safety
+    /// See [`MtrrDefType::write`].
+    pub unsafe fn write(self, index: u8) {
+        let msr = IA32_MTRR_PHYSBASE0 + 2 * u32::from(index);
+        let valid = if self.valid { Self::VALID } else { 0 };
+        write(msr, (self.base & Self::ADDRESS) | self.kind as u64);
+        write(msr + 1, (self.mask & Self::ADDRESS) | valid);
+    }
+}
+
+/// The `IA32_STAR` register, giving the code segment selectors loaded by `syscall` and `sysret`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Star {
+    /// The kernel code selector loaded by `syscall`, the kernel stack selector being the next one.
+    pub syscall_base: u16,
+
+    /// The selector preceding the user stack selector loaded by `sysret`, the user code selector
+    /// being 8 bytes after the user stack selector.
+    pub sysret_base: u16,
+}
+
+impl Star {
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read() -> Self {
+        let value = unsafe { read(IA32_STAR) };
+        Self {
+            syscall_base: (value >> 32) as u16,
+            sysret_base: (value >> 48) as u16,
+        }
+    }
+
+    /// # Safety
+    /// This function is unsafe because the next system calls will load the given selectors.
+    pub unsafe fn write(self) {
+        let value = u64::from(self.sysret_base) << 48 | u64::from(self.syscall_base) << 32;
+        write(IA32_STAR, value);
+    }
+}
+
+/// The `IA32_LSTAR` register, giving the entry point of the `syscall` instruction in long mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Lstar(pub u64);
+
+impl Lstar {
+    #[must_use]
+    pub fn read() -> Self {
+        Self(unsafe { read(IA32_LSTAR) })
+    }
+
+    /// # Safety
+    /// This function is unsafe because the next system calls will jump to the given address.
+    pub unsafe fn write(self) {
+        write(IA32_LSTAR, self.0);
+    }
+}
+
+/// The `IA32_FMASK` register, giving the RFLAGS bits cleared by the `syscall` instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fmask(pub u64);
+
+impl Fmask {
+    #[must_use]
+    pub fn read() -> Self {
+        Self(unsafe { read(IA32_FMASK) })
+    }
+
+    /// # Safety
+    /// This function is unsafe because the system call entry relies on some flags being cleared
+    /// (e.g. the interrupt flag).
+    pub unsafe fn write(self) {
+        write(IA32_FMASK, self.0);
+    }
+}
+
+/// The `IA32_TSC_DEADLINE` register, holding the TSC value at which the LAPIC timer fires in
+/// TSC-deadline mode. Writing 0 disarms the timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TscDeadline(pub u64);
+
+impl TscDeadline {
+    #[must_use]
+    pub fn read() -> Self {
+        Self(unsafe { read(IA32_TSC_DEADLINE) })
+    }
+
+    /// Arm or disarm the LAPIC timer. The timer must be in TSC-deadline mode.
+    pub fn write(self) {
+        unsafe { write(IA32_TSC_DEADLINE, self.0) };
+    }
+}
@@ -0,0 +1,178 @@
+//! The memory type range registers, which give a memory type to each range of the physical
+//! memory. The type used by the CPU for an access is the combination of the type given by the
+//! MTRRs and of the type selected in the PAT by the page table entry: [`effective_type`] resolves
+//! it, so that the mappings of the device registers can be checked to be uncached.
+use super::{
+    cpuid,
+    msr::{self, MemoryType, MtrrCap, MtrrDefType, MtrrVariable},
+};
+
+/// Set in the EDX register of the CPUID leaf 1 if the CPU has the MTRRs.
+const CPUID_MTRR: u32 = 1 << 12;
+
+/// The fixed range MTRRs, covering the first megabyte of the physical memory: the first MSR, the
+/// number of MSRs, the start of the memory covered and the size of each of the 8 ranges of a MSR.
+const FIXED_RANGES: [(u32, u32, u64, u64); 3] = [
+    (msr::IA32_MTRR_FIX64K_00000, 1, 0x0_0000, 0x1_0000),
+    (msr::IA32_MTRR_FIX16K_80000, 2, 0x8_0000, 0x4000),
+    (msr::IA32_MTRR_FIX4K_C0000, 8, 0xC_0000, 0x1000),
+];
+
+/// The number of fixed range MSRs, and the end of the memory they cover.
+const FIXED_MSRS: usize = 11;
+const FIXED_END: u64 = 0x10_0000;
+
+/// The maximum number of variable range MTRRs read. The CPUs usually have 8 or 10 of them.
+const MAX_VARIABLE: usize = 32;
+
+/// The granularity of the MTRRs.
+const PAGE_SIZE: usize = 4096;
+
+/// The MTRRs of the current CPU. The firmware programs the same MTRRs on all the CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Mtrrs {
+    pub default: MtrrDefType,
+
+    /// The fixed range MSRs, or `None` if they are not supported or not enabled.
+    fixed: Option<[u64; FIXED_MSRS]>,
+    variable: [Option<MtrrVariable>; MAX_VARIABLE],
+}
+
+impl Mtrrs {
+    /// Read the MTRRs of the current CPU. A CPU without MTRRs is reported as if all the memory
+    /// was write-back, so that only the PAT selects the memory type.
+    #[must_use]
+    pub fn read() -> Self {
+        if cpuid::features().1 & CPUID_MTRR == 0 {
+            return Self {
+                default: MtrrDefType {
+                    default: MemoryType::WriteBack,
+                    fixed_enabled: false,
+                    enabled: true,
+                },
+                fixed: None,
+                variable: [None; MAX_VARIABLE],
+            };
+        }
+
+        let capabilities = MtrrCap::read();
+        let default = MtrrDefType::read();
+        let fixed = (capabilities.fixed && default.fixed_enabled).then(|| {
+            let mut msrs = FIXED_RANGES
+                .iter()
+                .flat_map(|&(first, count, _, _)| first..first + count);
+            core::array::from_fn(|_| unsafe { msr::read(msrs.next().unwrap_or_default()) })
+        });
+
+        let mut variable = [None; MAX_VARIABLE];
+        let count = usize::from(capabilities.variable_count).min(MAX_VARIABLE);
+        for (index, range) in (0..).zip(variable.iter_mut().take(count)) {
+            *range = Some(MtrrVariable::read(index)).filter(|range| range.valid);
+        }
+
+        Self {
+            default,
+            fixed,
+            variable,
+        }
+    }
+
+    /// Returns the valid variable range MTRRs.
+    pub fn variable_ranges(&self) -> impl Iterator<Item = &MtrrVariable> {
+        self.variable.iter().flatten()
+    }
+
+    /// Returns the memory type given by the MTRRs to the given physical address.
+    #[must_use]
+    pub fn memory_type(&self, address: u64) -> MemoryType {
+        if !self.default.enabled {
+            return MemoryType::Uncacheable;
+        }
+        if let Some(fixed) = self.fixed.filter(|_| address < FIXED_END) {
+            return fixed_type(&fixed, address);
+        }
+
+        // When several ranges overlap, uncacheable wins, and write-through wins over write-back.
+        // The other overlaps are undefined, and the first range is used
+        let mut matching = self
+            .variable_ranges()
+            .filter(|range| address & range.mask == range.base & range.mask)
+            .map(|range| range.kind);
+        let Some(first) = matching.next() else {
+            return self.default.default;
+        };
+        matching.fold(first, |kind, other| match (kind, other) {
+            (MemoryType::Uncacheable, _) | (_, MemoryType::Uncacheable) => MemoryType::Uncacheable,
+            (MemoryType::WriteThrough, MemoryType::WriteBack)
+            | (MemoryType::WriteBack, MemoryType::WriteThrough) => MemoryType::WriteThrough,
+            _ => kind,
+        })
+    }
+}
+
+/// Returns the memory type used by the CPU to access the given physical range when it is mapped
+/// with the given PAT memory type, or `None` if the pages of the range have different types.
+#[must_use]
+pub fn effective_type(mtrrs: &Mtrrs, start: u64, len: u64, pat: MemoryType) -> Option<MemoryType> {
+    let first = start & !(PAGE_SIZE as u64 - 1);
+    let mut types = (first..start + len.max(1))
+        .step_by(PAGE_SIZE)
+        .map(|page| combine(mtrrs.memory_type(page), pat));
+    let kind = types.next()?;
+    types.all(|other| other == kind).then_some(kind)
+}
+
+/// Log the MTRRs of the current CPU.
+pub fn report() {
+    let mtrrs = Mtrrs::read();
+    log::debug!(
+        "MTRR: default type {:?}, fixed ranges {}",
+        mtrrs.default.default,
+        if mtrrs.fixed.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    for range in mtrrs.variable_ranges() {
+        log::debug!(
+            "MTRR: {:#014x} (mask {:#014x}): {:?}",
+            range.base,
+            range.mask,
+            range.kind
+        );
+    }
+}
+
+/// Returns the memory type given by the fixed range MTRRs to an address of the first megabyte.
+#[allow(clippy::cast_possible_truncation)]
+fn fixed_type(fixed: &[u64; FIXED_MSRS], address: u64) -> MemoryType {
+    let mut msr = 0;
+    for &(_, count, start, size) in &FIXED_RANGES {
+        let end = start + u64::from(count) * 8 * size;
+        if address < end {
+            let range = ((address - start) / size) as usize;
+            let byte = fixed[msr + range / 8].to_le_bytes()[range % 8];
+            return MemoryType::from_bits(byte).unwrap_or(MemoryType::Uncacheable);
+        }
+        msr += count as usize;
+    }
+    MemoryType::Uncacheable
+}
+
+/// Combine the memory type given by the MTRRs with the one selected in the PAT, as described in
+/// the Intel manual (volume 3, table 11-7).
+const fn combine(mtrr: MemoryType, pat: MemoryType) -> MemoryType {
+    use MemoryType::{
+        Uncacheable, UncachedMinus, WriteBack, WriteCombining, WriteProtected, WriteThrough,
+    };
+    match (mtrr, pat) {
+        (_, WriteCombining)
+        | (WriteCombining | WriteProtected, UncachedMinus)
+        | (WriteCombining, WriteBack) => WriteCombining,
+        (WriteBack | UncachedMinus, pat @ (WriteThrough | WriteProtected | WriteBack)) => pat,
+        (WriteThrough, WriteThrough | WriteBack) | (WriteProtected, WriteThrough) => WriteThrough,
+        (WriteProtected, WriteProtected | WriteBack) => WriteProtected,
+        _ => Uncacheable,
+    }
+}
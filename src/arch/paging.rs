@@ -5,7 +5,7 @@ use core::sync::atomic::Ordering;
 use alloc::sync::Arc;
 use bitflags::bitflags;
 use log::trace;
-use spin::Lazy;
+use sync::Lazy;
 
 use crate::mm::frame::{AllocationFlags, Allocator, Frame};
 use crate::mm::{frame, FRAME_ALLOCATOR, KERNEL_BASE};
@@ -186,12 +186,15 @@ static INIT_TABLE: Lazy<TableRoot> = Lazy::new(|| unsafe {
     TableRoot::from(boot_pml4)
 });
 
-#[thread_local]
-static ACTIVE_TABLE: Lazy<Spinlock<Arc<Spinlock<TableRoot>>>> = Lazy::new(|| unsafe {
-    let pml4 = INIT_TABLE.clone();
-    change_table(&pml4);
-    Spinlock::new(Arc::new(Spinlock::new(pml4)))
-});
+crate::per_cpu! {
+    /// The page table loaded on each CPU, initialized with the kernel page table the first time
+    /// the CPU uses it.
+    static ACTIVE_TABLE: Lazy<Spinlock<Arc<Spinlock<TableRoot>>>> = Lazy::new(|| unsafe {
+        let pml4 = INIT_TABLE.clone();
+        change_table(&pml4);
+        Spinlock::new(Arc::new(Spinlock::new(pml4)))
+    });
+}
 
 /// Sets up the pagination system. This function does not many things, as the as most of the work
 /// has been done by Limine. It only preallocate all the kernel pml4 entries and enable the NXE bit
@@ -208,7 +211,7 @@ static ACTIVE_TABLE: Lazy<Spinlock<Arc<Spinlock<TableRoot>>>> = Lazy::new(|| uns
 /// kernel pml4 entries and voilà, we have a new empty user address space.
 pub fn setup() {
     // Preallocate all the kernel pml4 entries
-    let binding = ACTIVE_TABLE.lock();
+    let binding = ACTIVE_TABLE.local().lock();
     let mut table = binding.lock();
     let start = Virtual::new(KERNEL_BASE).pml4_offset();
     let end = PageTable::COUNT as u64;
@@ -235,7 +238,7 @@ pub fn setup() {
 /// Sets up the pagination system for the current CPU. This function is called by the APs when
 /// they are started. It just forces the `ACTIVE_TABLE` lazy static to be initialized.
 pub fn ap_setup() {
-    Lazy::force(&ACTIVE_TABLE);
+    Lazy::force(ACTIVE_TABLE.local());
 }
 
 /// Maps the given physical address to the given virtual address. If the given physical address is
@@ -284,6 +287,8 @@ pub unsafe fn map(
         // TLB invalidation requests.
         pte.set_address(frame.start());
         pte.set_flags(flags);
+        #[cfg(feature = "tlb-debug")]
+        tlb::debug::record(table, at);
         return Ok(());
     }
     Err(MapError::OutOfMemory)
@@ -293,7 +298,7 @@ pub unsafe fn map(
 /// `map`, but it uses the active page table instead of the given one.
 #[allow(clippy::missing_errors_doc)]
 pub unsafe fn map_current(at: Virtual, frame: Frame, flags: MapFlags) -> Result<(), MapError> {
-    x86_64::irq::without(|| map(&mut ACTIVE_TABLE.lock().lock(), at, frame, flags))
+    x86_64::irq::without(|| map(&mut ACTIVE_TABLE.local().lock().lock(), at, frame, flags))
 }
 
 /// Unmaps the given virtual address and returns the physical address of the unmapped page. If the
@@ -322,6 +327,8 @@ pub unsafe fn unmap(table: &mut PageTable, at: Virtual) -> Option<Physical> {
                 pte.clear();
                 tlb::shootdown();
             });
+            #[cfg(feature = "tlb-debug")]
+            tlb::debug::forget(table, at);
             return Some(Physical::new(addr.as_u64() + offset));
         }
     }
@@ -332,7 +339,7 @@ pub unsafe fn unmap(table: &mut PageTable, at: Virtual) -> Option<Physical> {
 /// function.
 #[must_use]
 pub unsafe fn unmap_current(at: Virtual) -> Option<Physical> {
-    x86_64::irq::without(|| unmap(&mut ACTIVE_TABLE.lock().lock(), at))
+    x86_64::irq::without(|| unmap(&mut ACTIVE_TABLE.local().lock().lock(), at))
 }
 
 /// Returns the protection of the given virtual address. If the given virtual address is not mapped,
@@ -373,6 +380,8 @@ pub fn change_protection(
                 pte.set_flags(flags);
                 tlb::shootdown();
             });
+            #[cfg(feature = "tlb-debug")]
+            tlb::debug::record(table, at);
             return Some(old);
         }
     }
@@ -397,11 +406,45 @@ pub fn translate(table: &PageTable, at: Virtual) -> Option<Physical> {
     }
 }
 
+/// Free the page tables mapping the user space of the given root page table, and clear its user
+/// entries. The pages themselves are not freed: they must have been unmapped and released before.
+///
+/// # Safety
+/// The page table must not be used to access the user space anymore, by any CPU.
+pub unsafe fn free_user_tables(table: &mut PageTable) {
+    for index in 0..256 {
+        free_table(&mut table[index], paging::Level::PageMapLevel4);
+    }
+}
+
+/// Free the page table pointed to by the given entry of a table of the given level, and all the
+/// page tables below it, and clear the entry. Entries of the last level map pages, which are not
+/// freed.
+///
+/// # Safety
+/// The page table must not be used anymore, by any CPU.
+unsafe fn free_table(entry: &mut PageEntry, level: paging::Level) {
+    let Some(next) = level.next() else {
+        return;
+    };
+    if !entry.is_present() {
+        return;
+    }
+
+    let addr = entry.address().unwrap();
+    let table = &mut *(phys_to_virt(addr).as_u64() as *mut PageTable);
+    for index in 0..512 {
+        free_table(&mut table[index], next);
+    }
+    entry.clear();
+    x86_64::irq::without(|| FRAME_ALLOCATOR.lock().deallocate(Frame::new(addr)));
+}
+
 /// Set the current page table to the given one.
 pub fn set_current_table(table: Arc<Spinlock<TableRoot>>) {
-    *ACTIVE_TABLE.lock() = table;
+    *ACTIVE_TABLE.local().lock() = table;
     unsafe {
-        change_table(&ACTIVE_TABLE.lock().lock());
+        change_table(&ACTIVE_TABLE.local().lock().lock());
     }
 }
 
@@ -455,12 +498,16 @@ unsafe fn creat_and_fetch_pte(
         })?;
 
         // Here, we use `PageEntryFlags::WRITABLE` even if the future mapping is not writable.
-        // This is because if the `PageEntryFlags::WRITABLE` (and maybe the `PageEntryFlags::USER`)
-        // are not set in intermediate page tables, the complete range of the virtual address space
-        // are read-only and will cause a page fault if a write is attempted, even if the page entry
-        // in the last level is marked as writable.
-        // TODO: Check if this is correct for `PageEntryFlags::USER` too
+        // This is because if the `PageEntryFlags::WRITABLE` and the `PageEntryFlags::USER` are not
+        // set in intermediate page tables, the complete range of the virtual address space are
+        // read-only (or inaccessible from user mode) and will cause a page fault if a write is
+        // attempted, even if the page entry in the last level is marked as writable.
+        // The `PageEntryFlags::USER` flag is only set for the user space, to avoid giving the user
+        // access to kernel pages mapped with the wrong flags.
         entry.add_flags(PageEntryFlags::PRESENT | PageEntryFlags::WRITABLE);
+        if at.as_u64() < mm::USER_END {
+            entry.add_flags(PageEntryFlags::USER);
+        }
         entry.set_address(frame.start());
     }
 
@@ -521,6 +568,7 @@ unsafe fn fetch_pte_mut(
 /// Called when a page fault occurs. This function does almost nothing (the core function for
 /// handling page faults is [`handle_page_fault`]), it simply detect if we are in the early stage
 /// of the kernel initialization and call the right function to fetch the active page table.
+/// Page faults in the user space are handled by the address space of the current thread.
 ///
 /// # Errors
 /// See [`handle_page_fault`] for more information.
@@ -531,8 +579,14 @@ pub fn page_fault(
     if EARLY.load(Ordering::Relaxed) {
         let table = unsafe { &mut *active_table() };
         handle_page_fault(table, code, addr)
+    } else if addr.as_u64() < mm::USER_END {
+        crate::sched::current_space()
+            .ok_or(PageFaultError::MISSING_PAGE)?
+            .page_fault(code, addr)
     } else {
-        x86_64::irq::without(|| handle_page_fault(&mut ACTIVE_TABLE.lock().lock(), code, addr))
+        x86_64::irq::without(|| {
+            handle_page_fault(&mut ACTIVE_TABLE.local().lock().lock(), code, addr)
+        })
     }
 }
 
@@ -557,6 +611,8 @@ fn handle_page_fault(
         // flush the TLB and return.
         if present && !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
             trace!("Lazy TLB invalidation at {:016x}", addr.as_u64());
+            #[cfg(feature = "tlb-debug")]
+            tlb::debug::verify(table, addr);
             tlb::shootdown();
             return Ok(PageFaultType::LazyTlbInvalidation);
         }
@@ -610,24 +666,30 @@ fn handle_demand_paging(table: &mut PageTable, addr: Virtual) -> Result<(), Page
 }
 
 pub mod tlb {
-    use crate::arch::acpi::TLB_SHOOTDOWN_VECTOR;
-    use x86_64::{
-        cpu,
-        lapic::{self, IpiDestination},
-    };
-
-    /// Flushes the TLB on all cores.
+    use crate::arch::smp::{self, CallTarget};
+    use x86_64::cpu;
+
+    /// Flushes the TLB on all cores. The other online cores flush their TLB asynchronously, with a
+    /// cross-CPU call (see [`smp::call_function_async`]): the cores taken offline are skipped, and
+    /// flush their whole TLB when they are brought back online (see [`smp::park`]). This function
+    /// flushes the entire TLB by simplicity, but it should be improved in the future to avoid
+    /// unnecessary invalidations (and performance penalties).
     pub fn shootdown() {
         flush_all();
-        if lapic::initialized() {
-            unsafe {
-                lapic::send_ipi(
-                    IpiDestination::OtherCores,
-                    lapic::IpiPriority::Normal,
-                    TLB_SHOOTDOWN_VECTOR,
-                );
-            }
-        }
+        smp::call_function_async(CallTarget::Others, flush_all);
+    }
+
+    /// Flushes the TLB on all cores like [`shootdown`], but waits until the other online cores
+    /// have flushed their TLB (see [`smp::call_function`]). Until then, another core can still
+    /// access a page through a stale TLB entry: this must be used before freeing a frame that was
+    /// unmapped, or before relying on a page that was write-protected not being modified anymore.
+    ///
+    /// The caller must not hold a lock that another core may wait for with interrupts disabled,
+    /// such as the lock of a page table: this core could not flush its TLB, and both cores would
+    /// deadlock.
+    pub fn shootdown_sync() {
+        flush_all();
+        smp::call_function(CallTarget::Others, &flush_all);
     }
 
     /// Flushes the entire TLB. This is done by writing the current value of the CR3 register to it.
@@ -637,6 +699,8 @@ pub mod tlb {
         unsafe {
             cpu::cr3::reload();
         }
+        #[cfg(feature = "tlb-debug")]
+        debug::flushed();
     }
 
     /// Flushes the TLB entry for the page containing the given virtual address.
@@ -645,4 +709,180 @@ pub mod tlb {
             cpu::invlpg(addr);
         }
     }
+
+    /// A heavyweight verification of the lazy TLB invalidation protocol, enabled with the
+    /// `tlb-debug` feature.
+    ///
+    /// Every page table modification that is not immediately flushed from the TLB of all CPUs
+    /// (see [`super::map`] and [`super::change_protection`]) is recorded with the set of CPUs
+    /// that may still use a stale entry for it. When a page fault is resolved as a lazy TLB
+    /// invalidation, the log must contain a modification of this page that the faulting CPU has
+    /// not flushed yet: otherwise, the TLB protocol is broken and the kernel panics immediately
+    /// instead of silently using stale translations.
+    ///
+    /// The log is only maintained once the kernel has left the early stage, because the
+    /// identifier of the current CPU is not available before.
+    #[cfg(feature = "tlb-debug")]
+    pub mod debug {
+        use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+        use x86_64::{address::Virtual, paging::PageTable};
+
+        use crate::{arch::smp, config::MAX_CPU, mm, Spinlock, EARLY};
+
+        /// The maximum number of modifications in the log. When the log is full, the
+        /// modifications already flushed by all CPUs are removed, and if there is none, the
+        /// oldest one is evicted.
+        const CAPACITY: usize = 4096;
+
+        #[derive(Debug, Clone, Copy)]
+        struct Record {
+            /// The address of the root table for user pages, or 0 for kernel pages, which are
+            /// shared by all page tables.
+            space: u64,
+            page: u64,
+
+            /// The CPUs that were online when the page was modified.
+            cpus: u64,
+
+            /// A sequence number, increased for each modification.
+            stamp: u64,
+        }
+
+        impl Record {
+            /// Returns true if the given CPU may still have a stale TLB entry for this page.
+            fn pending(&self, cpu: usize) -> bool {
+                self.cpus & (1 << cpu) != 0 && LAST_FLUSH[cpu].load(Ordering::Relaxed) <= self.stamp
+            }
+
+            /// Returns true if all CPUs have flushed their TLB since the modification.
+            fn flushed(&self) -> bool {
+                (0..MAX_CPU).all(|cpu| !self.pending(cpu))
+            }
+        }
+
+        static RECORDS: Spinlock<[Option<Record>; CAPACITY]> = Spinlock::new([None; CAPACITY]);
+
+        /// The sequence number of the next modification.
+        static STAMP: AtomicU64 = AtomicU64::new(1);
+
+        /// For each CPU, the sequence number of the next modification at the time of its last
+        /// TLB flush: all modifications with a lower sequence number are flushed from its TLB.
+        static LAST_FLUSH: [AtomicU64; MAX_CPU] = [const { AtomicU64::new(0) }; MAX_CPU];
+
+        /// Set when a modification not yet flushed has been evicted from the log. Verification
+        /// failures are then only reported, because they may be caused by the eviction.
+        static EVICTED: AtomicBool = AtomicBool::new(false);
+
+        fn enabled() -> bool {
+            !EARLY.load(Ordering::Relaxed)
+        }
+
+        fn key(table: &PageTable, addr: Virtual) -> (u64, u64) {
+            let page = addr.page_align_down().as_u64();
+            if page >= mm::KERNEL_BASE {
+                (0, page)
+            } else {
+                (core::ptr::from_ref(table) as u64, page)
+            }
+        }
+
+        /// Record a modification of the entry mapping the given address.
+        pub fn record(table: &PageTable, addr: Virtual) {
+            if !enabled() {
+                return;
+            }
+
+            let (space, page) = key(table, addr);
+            let cpus = smp::online_mask().bits();
+            let record = Record {
+                space,
+                page,
+                cpus,
+                stamp: STAMP.fetch_add(1, Ordering::Relaxed),
+            };
+
+            x86_64::irq::without(|| {
+                let mut records = RECORDS.lock();
+                let slot = records
+                    .iter()
+                    .position(|r| r.is_some_and(|r| r.space == space && r.page == page))
+                    .or_else(|| records.iter().position(Option::is_none))
+                    .or_else(|| records.iter().position(|r| r.is_some_and(|r| r.flushed())))
+                    .unwrap_or_else(|| {
+                        EVICTED.store(true, Ordering::Relaxed);
+                        records
+                            .iter()
+                            .enumerate()
+                            .min_by_key(|(_, r)| r.map_or(0, |r| r.stamp))
+                            .map(|(slot, _)| slot)
+                            .unwrap()
+                    });
+                records[slot] = Some(record);
+            });
+        }
+
+        /// Remove the modifications of the entry mapping the given address from the log,
+        /// because it has been unmapped: a lazy TLB invalidation fault is no longer possible on
+        /// this page until it is mapped again.
+        pub fn forget(table: &PageTable, addr: Virtual) {
+            if !enabled() {
+                return;
+            }
+
+            let (space, page) = key(table, addr);
+            x86_64::irq::without(|| {
+                for record in RECORDS.lock().iter_mut() {
+                    if record.is_some_and(|r| r.space == space && r.page == page) {
+                        *record = None;
+                    }
+                }
+            });
+        }
+
+        /// Called after each TLB flush on the current CPU.
+        pub fn flushed() {
+            if enabled() {
+                let cpu = smp::current_id() as usize;
+                LAST_FLUSH[cpu].store(STAMP.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+        }
+
+        /// Verify that a lazy TLB invalidation fault at the given address on the current CPU is
+        /// explained by a modification in the log.
+        ///
+        /// # Panics
+        /// Panics if no modification of this page is pending on the current CPU, unless a
+        /// pending modification has been evicted from the log.
+        pub fn verify(table: &PageTable, addr: Virtual) {
+            if !enabled() {
+                return;
+            }
+
+            let cpu = smp::current_id() as usize;
+            let (space, page) = key(table, addr);
+            let expected = x86_64::irq::without(|| {
+                RECORDS
+                    .lock()
+                    .iter()
+                    .flatten()
+                    .any(|r| r.space == space && r.page == page && r.pending(cpu))
+            });
+
+            if !expected {
+                if EVICTED.load(Ordering::Relaxed) {
+                    log::warn!(
+                        "Unexpected lazy TLB invalidation at {:016x} on CPU {} (log overflowed)",
+                        addr.as_u64(),
+                        cpu
+                    );
+                } else {
+                    panic!(
+                        "Unexpected lazy TLB invalidation at {:016x} on CPU {}",
+                        addr.as_u64(),
+                        cpu
+                    );
+                }
+            }
+        }
+    }
 }
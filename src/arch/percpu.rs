@@ -0,0 +1,198 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::config::MAX_CPU;
+
+use super::smp;
+
+/// Declare a per-CPU variable (see [`PerCpu`]). The initializer must be a constant expression: it
+/// is evaluated once for each CPU.
+///
+/// ```ignore
+/// per_cpu! {
+///     /// The number of interrupts handled by each CPU.
+///     static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::arch::percpu::PerCpu<$ty> =
+            $crate::arch::percpu::PerCpu::new([const { $init }; $crate::config::MAX_CPU]);
+    };
+}
+
+/// A variable with one instance per CPU, indexed by CPU id. Unlike a `#[thread_local]` static, the
+/// instances of the other CPUs can be accessed, and the variable can be used before the thread
+/// local storage of the current CPU is allocated.
+///
+/// # Early boot
+/// Until its thread local storage is allocated (see [`smp::bsp_setup`] and [`smp::ap_start`]), the
+/// current CPU has no id. [`PerCpu::local`] then falls back to the instance of the BSP, which is
+/// the only CPU running at that time: APs must not access per-CPU variables before their thread
+/// local storage is allocated. [`PerCpu::try_local`] can be used instead when the caller may run
+/// on such an AP, for example in an exception handler.
+pub struct PerCpu<T> {
+    values: [T; MAX_CPU],
+}
+
+impl<T> PerCpu<T> {
+    /// Create a per-CPU variable from the instances of each CPU. The [`per_cpu!`] macro should be
+    /// preferred.
+    #[must_use]
+    pub const fn new(values: [T; MAX_CPU]) -> Self {
+        Self { values }
+    }
+
+    /// Returns the instance of the current CPU, or the instance of the BSP during the early boot.
+    #[must_use]
+    pub fn local(&self) -> &T {
+        &self.values[smp::try_current_id().unwrap_or(0) as usize]
+    }
+
+    /// Returns the instance of the current CPU, or `None` if the thread local storage of the
+    /// current CPU is not allocated yet.
+    #[must_use]
+    pub fn try_local(&self) -> Option<&T> {
+        smp::try_current_id().map(|cpu| &self.values[cpu as usize])
+    }
+
+    /// Returns the instance of the CPU with the given id.
+    ///
+    /// # Panics
+    /// Panics if the id is not lower than [`MAX_CPU`].
+    #[must_use]
+    pub fn cpu(&self, cpu: u32) -> &T {
+        &self.values[cpu as usize]
+    }
+
+    /// Returns the instances of all the CPUs with their id, including the CPUs that are not
+    /// online.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &T)> {
+        (0..).zip(self.values.iter())
+    }
+
+    /// Call the given function with the instance of each online CPU.
+    pub fn for_each_cpu(&self, mut f: impl FnMut(u32, &T)) {
+        self.iter()
+            .filter(|&(cpu, _)| smp::is_online(cpu))
+            .for_each(|(cpu, value)| f(cpu, value));
+    }
+}
+
+impl PerCpu<AtomicBool> {
+    /// Returns the value of the current CPU.
+    #[must_use]
+    pub fn load(&self) -> bool {
+        self.local().load(Ordering::Relaxed)
+    }
+
+    /// Set the value of the current CPU.
+    pub fn store(&self, value: bool) {
+        self.local().store(value, Ordering::Relaxed);
+    }
+
+    /// Set the value of the current CPU and returns the previous one.
+    pub fn swap(&self, value: bool) -> bool {
+        self.local().swap(value, Ordering::Relaxed)
+    }
+}
+
+/// A value aligned on a cache line. A per-CPU variable written by other CPUs, or monitored by the
+/// idle loop (see [`super::idle::wait`]), should be aligned so that the writes to the instances of
+/// the other CPUs do not invalidate the cache line of its instance.
+#[repr(align(64))]
+pub struct CacheAligned<T>(pub T);
+
+impl<T> core::ops::Deref for CacheAligned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// A counter with one cell per CPU, for the statistics updated often by all the CPUs. Each CPU only
+/// adds to its own cell, which lies on its own cache line, so the updates never contend. Reading
+/// the counter folds the cells of all the CPUs: it is slower, and only approximate while the
+/// counter is being updated.
+pub struct PerCpuCounter {
+    cells: PerCpu<CacheAligned<AtomicU64>>,
+}
+
+impl PerCpuCounter {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            cells: PerCpu::new([const { CacheAligned(AtomicU64::new(0)) }; MAX_CPU]),
+        }
+    }
+
+    /// Add the given value to the cell of the current CPU.
+    pub fn add(&self, value: u64) {
+        self.cells.local().fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Returns the sum of the cells of all the CPUs, wrapping on overflow.
+    #[must_use]
+    pub fn sum(&self) -> u64 {
+        self.cells.iter().fold(0, |sum, (_, cell)| {
+            sum.wrapping_add(cell.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Returns the value of the cell of the given CPU.
+    #[must_use]
+    pub fn cpu(&self, cpu: u32) -> u64 {
+        self.cells.cpu(cpu).load(Ordering::Relaxed)
+    }
+
+    /// Reset the cells of all the CPUs. The additions made concurrently may be lost.
+    pub fn reset(&self) {
+        self.cells
+            .iter()
+            .for_each(|(_, cell)| cell.store(0, Ordering::Relaxed));
+    }
+}
+
+impl Default for PerCpuCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Implement the access helpers of the per-CPU atomic integers. Each CPU only modifies its own
+/// instance, so relaxed accesses are enough.
+macro_rules! impl_atomic_integer {
+    ($atomic:ty, $int:ty) => {
+        impl PerCpu<$atomic> {
+            /// Returns the value of the current CPU.
+            #[must_use]
+            pub fn load(&self) -> $int {
+                self.local().load(Ordering::Relaxed)
+            }
+
+            /// Set the value of the current CPU.
+            pub fn store(&self, value: $int) {
+                self.local().store(value, Ordering::Relaxed);
+            }
+
+            /// Add the given value to the value of the current CPU, and returns the previous value.
+            pub fn add(&self, value: $int) -> $int {
+                self.local().fetch_add(value, Ordering::Relaxed)
+            }
+
+            /// Returns the sum of the values of all the CPUs, wrapping on overflow.
+            #[must_use]
+            pub fn sum(&self) -> $int {
+                self.values.iter().fold(0, |sum, value| {
+                    sum.wrapping_add(value.load(Ordering::Relaxed))
+                })
+            }
+        }
+    };
+}
+
+impl_atomic_integer!(AtomicU32, u32);
+impl_atomic_integer!(AtomicU64, u64);
+impl_atomic_integer!(AtomicUsize, usize);
@@ -0,0 +1,165 @@
+//! The two cascaded 8259 PICs, which deliver the legacy IRQs when there is no IOAPIC. The PICs are
+//! remapped at boot so that their vectors do not overlap the CPU exceptions, and their lines are
+//! masked until a handler is requested (see [`super::irq::request_irq`]). When the IOAPICs take
+//! over, the PICs are fully masked (see [`disable_all`]), but stay remapped so that a spurious
+//! interrupt they may still raise cannot be taken for an exception.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{config, Spinlock};
+
+use super::port::{self, Port};
+
+/// The number of lines of the two PICs.
+pub const LINES: u8 = 16;
+
+/// The line of the master PIC the slave PIC is connected to.
+pub const CASCADE_LINE: u8 = 2;
+
+const MASTER_COMMAND: Port<u8> = Port::new(0x20);
+const MASTER_DATA: Port<u8> = Port::new(0x21);
+const SLAVE_COMMAND: Port<u8> = Port::new(0xA0);
+const SLAVE_DATA: Port<u8> = Port::new(0xA1);
+
+/// The ICW1 command starting the initialization of a PIC, with an ICW4 to come.
+const ICW1_INIT: u8 = 0x11;
+
+/// The ICW4 selecting the 8086 mode.
+const ICW4_8086: u8 = 0x01;
+
+/// The OCW3 command making the next read of the command register return the In-Service Register.
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// The non-specific end of interrupt command.
+const EOI: u8 = 0x20;
+
+/// The mask of all the lines but the cascade line, set when the PICs are remapped.
+const ALL_BUT_CASCADE: u16 = !(1 << CASCADE_LINE);
+
+/// The first vector of the master PIC, or 0 if the PICs have not been remapped.
+static BASE: AtomicU8 = AtomicU8::new(0);
+
+/// The masks of the two PICs, the slave in the high byte. They are cached so that a line can be
+/// masked without reading the data ports.
+static MASKS: Spinlock<u16> = Spinlock::new(u16::MAX);
+
+/// Initialize the PICs so that the lines of the master raise the 8 vectors from `base`, and those
+/// of the slave the 8 next ones. All the lines are masked, except the cascade line.
+///
+/// # Panics
+/// Panics if `base` is not a multiple of 8, or overlaps the CPU exceptions.
+///
+/// # Safety
+/// This function is unsafe because the 16 vectors from `base` must have an interrupt handler.
+pub unsafe fn remap(base: u8) {
+    assert!(base.is_multiple_of(8), "PIC vectors must be aligned on 8");
+    assert!(base >= 32, "PIC vectors overlap the CPU exceptions");
+
+    x86_64::irq::without(|| {
+        let mut masks = MASKS.lock();
+        MASTER_COMMAND.write(ICW1_INIT);
+        port::delay();
+        SLAVE_COMMAND.write(ICW1_INIT);
+        port::delay();
+        MASTER_DATA.write(base);
+        port::delay();
+        SLAVE_DATA.write(base + 8);
+        port::delay();
+        MASTER_DATA.write(1 << CASCADE_LINE);
+        port::delay();
+        SLAVE_DATA.write(CASCADE_LINE);
+        port::delay();
+        MASTER_DATA.write(ICW4_8086);
+        port::delay();
+        SLAVE_DATA.write(ICW4_8086);
+        port::delay();
+
+        *masks = ALL_BUT_CASCADE;
+        write_masks(*masks);
+        BASE.store(base, Ordering::Relaxed);
+    });
+}
+
+/// Mask all the lines of both PICs, including the cascade line, when the legacy IRQs are delivered
+/// by the IOAPICs. The PICs are remapped to [`config::IRQ_BASE`] first if they were not, because a
+/// masked PIC can still raise a spurious interrupt.
+pub fn disable_all() {
+    if !remapped() {
+        unsafe { remap(config::IRQ_BASE) };
+    }
+    x86_64::irq::without(|| {
+        let mut masks = MASKS.lock();
+        *masks = u16::MAX;
+        write_masks(*masks);
+    });
+}
+
+/// Returns true if the PICs have been remapped out of the exception range.
+#[must_use]
+pub fn remapped() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Mask the given line. Masking the cascade line masks all the lines of the slave.
+///
+/// # Panics
+/// Panics if the line is not a valid PIC line.
+pub fn mask(line: u8) {
+    assert!(line < LINES, "Invalid PIC line {line}");
+    x86_64::irq::without(|| {
+        let mut masks = MASKS.lock();
+        *masks |= 1 << line;
+        write_masks(*masks);
+    });
+}
+
+/// Unmask the given line. The cascade line is unmasked as well for a line of the slave.
+///
+/// # Panics
+/// Panics if the line is not a valid PIC line.
+pub fn unmask(line: u8) {
+    assert!(line < LINES, "Invalid PIC line {line}");
+    x86_64::irq::without(|| {
+        let mut masks = MASKS.lock();
+        *masks &= !(1 << line);
+        if line >= 8 {
+            *masks &= !(1 << CASCADE_LINE);
+        }
+        write_masks(*masks);
+    });
+}
+
+/// Returns the masks of the two PICs, the slave in the high byte. A set bit masks its line.
+#[must_use]
+pub fn masks() -> u16 {
+    *MASKS.lock()
+}
+
+/// Returns the In-Service Registers of the two PICs, the slave in the high byte. A set bit means
+/// that the line is being handled and has not received its EOI yet.
+#[must_use]
+pub fn in_service() -> u16 {
+    unsafe {
+        MASTER_COMMAND.write(OCW3_READ_ISR);
+        SLAVE_COMMAND.write(OCW3_READ_ISR);
+        u16::from_le_bytes([MASTER_COMMAND.read(), SLAVE_COMMAND.read()])
+    }
+}
+
+/// Send an end of interrupt for the given line: to the slave and the master for a line of the
+/// slave, to the master only otherwise.
+pub fn send_eoi(line: u8) {
+    unsafe {
+        if line >= 8 {
+            SLAVE_COMMAND.write(EOI);
+        }
+        MASTER_COMMAND.write(EOI);
+    }
+}
+
+fn write_masks(masks: u16) {
+    let [master, slave] = masks.to_le_bytes();
+    unsafe {
+        MASTER_DATA.write(master);
+        SLAVE_DATA.write(slave);
+    }
+}
@@ -0,0 +1,168 @@
+use sync::SpinlockGuard;
+
+use crate::{config::KERNEL_HZ, Spinlock};
+
+use super::{
+    irq::{IrqFlags, IrqReturn},
+    port::{Port, WriteOnlyPort},
+};
+
+/// The frequency of the PIT oscillator, in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0_DATA: Port<u8> = Port::new(0x40);
+const CHANNEL2_DATA: Port<u8> = Port::new(0x42);
+const COMMAND: WriteOnlyPort<u8> = WriteOnlyPort::new(0x43);
+
+/// The port controlling the gate of the channel 2 and the PC speaker, and reporting the output of
+/// the channel 2.
+const CHANNEL2_GATE: Port<u8> = Port::new(0x61);
+const GATE_ENABLE: u8 = 1 << 0;
+const GATE_SPEAKER: u8 = 1 << 1;
+const GATE_OUTPUT: u8 = 1 << 5;
+
+/// The command bytes used by the kernel: channel 0 or 2, low and high bytes of the reload value,
+/// and mode 0 (interrupt on terminal count). The latch command of the channel 0 is a command with
+/// no access mode.
+const CHANNEL0_ONESHOT: u8 = 0b0011_0000;
+const CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+const CHANNEL0_LATCH: u8 = 0b0000_0000;
+
+/// The command byte programming the channel 0 in mode 2 (rate generator), which raises the IRQ 0
+/// periodically.
+const CHANNEL0_PERIODIC: u8 = 0b0011_0100;
+
+/// Serialize the accesses to each channel of the PIT, because its counters are programmed and read
+/// one byte at a time, and because the APs calibrate their timers concurrently. The channels have
+/// their own access state, so they can be used at the same time.
+static CHANNEL0_LOCK: Spinlock<()> = Spinlock::new(());
+static CHANNEL2_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// A one-shot countdown of the channel 2 of the PIT, used as a reference clock to measure other
+/// timers. The channel 2 is not connected to any IRQ: its output is polled with
+/// [`Countdown::expired`]. The channel 2 is locked until the countdown is dropped.
+pub struct Countdown {
+    gate: u8,
+    _guard: SpinlockGuard<'static, ()>,
+}
+
+impl Countdown {
+    /// Start a countdown of the given number of microseconds.
+    ///
+    /// # Panics
+    /// Panics if the duration is longer than the channel 2 can count (about 54 ms).
+    #[must_use]
+    pub fn start(microseconds: u64) -> Self {
+        let reload =
+            u16::try_from(FREQUENCY * microseconds / 1_000_000).expect("PIT countdown too long");
+        let guard = CHANNEL2_LOCK.lock();
+        unsafe {
+            // Disable the gate of the channel 2 and disconnect it from the speaker
+            let gate = CHANNEL2_GATE.read() & !GATE_SPEAKER;
+            CHANNEL2_GATE.write(gate & !GATE_ENABLE);
+
+            COMMAND.write(CHANNEL2_ONESHOT);
+            CHANNEL2_DATA.write(reload.to_le_bytes()[0]);
+            CHANNEL2_DATA.write(reload.to_le_bytes()[1]);
+
+            // The countdown starts when the gate is enabled
+            CHANNEL2_GATE.write(gate | GATE_ENABLE);
+            Self {
+                gate: gate & !GATE_ENABLE,
+                _guard: guard,
+            }
+        }
+    }
+
+    /// Returns true if the countdown has reached zero.
+    #[must_use]
+    pub fn expired(&self) -> bool {
+        unsafe { CHANNEL2_GATE.read() & GATE_OUTPUT != 0 }
+    }
+
+    /// Wait until the countdown reaches zero.
+    pub fn wait(&self) {
+        while !self.expired() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for Countdown {
+    fn drop(&mut self) {
+        unsafe {
+            CHANNEL2_GATE.write(self.gate);
+        }
+    }
+}
+
+/// Program the channel 0 to raise the IRQ 0 once, after the given number of PIT ticks (see
+/// [`FREQUENCY`]). A count of 0 is interpreted as 65536 ticks. The counter continues to count down
+/// after the IRQ, wrapping around without raising another one.
+pub fn oneshot(ticks: u16) {
+    x86_64::irq::without(|| {
+        let _guard = CHANNEL0_LOCK.lock();
+        unsafe {
+            COMMAND.write(CHANNEL0_ONESHOT);
+            CHANNEL0_DATA.write(ticks.to_le_bytes()[0]);
+            CHANNEL0_DATA.write(ticks.to_le_bytes()[1]);
+        }
+    });
+}
+
+/// Returns the current count of the channel 0. The channel counts down at [`FREQUENCY`] Hz and
+/// wraps around every 65536 ticks.
+#[must_use]
+pub fn counter() -> u16 {
+    x86_64::irq::without(|| {
+        let _guard = CHANNEL0_LOCK.lock();
+        unsafe {
+            // Latch the counter of the channel 0, then read it, low byte first
+            COMMAND.write(CHANNEL0_LATCH);
+            u16::from_le_bytes([CHANNEL0_DATA.read(), CHANNEL0_DATA.read()])
+        }
+    })
+}
+
+/// Produce the clock tick with the IRQ 0 of the PIT at [`KERNEL_HZ`] Hz, when the LAPIC timer
+/// cannot be used because ACPI is unusable. The high-resolution timers then only expire on the
+/// clock ticks.
+///
+/// # Panics
+/// Panics if the IRQ 0 is already used.
+pub fn start_clock_tick() {
+    let reload = u16::try_from(FREQUENCY / KERNEL_HZ).expect("KERNEL_HZ too low for the PIT");
+    super::irq::request_irq(0, clock_tick, IrqFlags::NONE, "pit")
+        .expect("Failed to request the IRQ of the PIT");
+    x86_64::irq::without(|| {
+        let _guard = CHANNEL0_LOCK.lock();
+        unsafe {
+            COMMAND.write(CHANNEL0_PERIODIC);
+            CHANNEL0_DATA.write(reload.to_le_bytes()[0]);
+            CHANNEL0_DATA.write(reload.to_le_bytes()[1]);
+        }
+    });
+    log::info!("PIT started, it produces the clock tick");
+}
+
+/// The handler of the IRQ 0 when the PIT produces the clock tick (see [`start_clock_tick`]).
+fn clock_tick(_: u8) -> IrqReturn {
+    crate::sys::time::hrtimer::expire();
+    let timekeeper = crate::sys::time::tick();
+    if crate::sched::tick(timekeeper) {
+        IrqReturn::Reschedule
+    } else {
+        IrqReturn::Handled
+    }
+}
+
+/// Stop the periodic interrupts of the PIT once the LAPIC timer produces the clock tick. The
+/// firmware usually leaves the channel 0 in periodic mode: it is switched to the one-shot mode
+/// with the longest count, and the IRQ 0 is masked in the PIC and in the IOAPIC. The counter of
+/// the channel 0 keeps running, so it can still be read with [`counter`].
+pub fn retire() {
+    oneshot(0);
+    super::pic::mask(0);
+    super::ioapic::mask(0);
+    log::debug!("PIT retired, the LAPIC timer produces the clock tick");
+}
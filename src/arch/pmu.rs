@@ -0,0 +1,445 @@
+//! The architectural performance monitoring unit. Each CPU has a few general counters, which can
+//! count any event selected in their `IA32_PERFEVTSELx` register, and a few fixed counters, which
+//! always count the same event (the retired instructions, the core cycles and the reference
+//! cycles). A [`Counter`] reserves one of them on the current CPU and reads it with `rdpmc`. It can
+//! also call a function each time a given number of events occurred, with the overflow interrupt
+//! delivered by the LAPIC, to sample what the CPU was doing (e.g. where the cache misses happen).
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use bitflags::bitflags;
+use x86_64::cpu::State;
+
+use super::{
+    acpi::PMU_VECTOR,
+    cpuid::{self, Register},
+    lapic::{self, Lvt, LvtEntry},
+    msr,
+};
+use crate::{interrupt_handler, Spinlock};
+
+/// The CPUID leaf describing the architectural performance monitoring.
+const CPUID_PERFORMANCE_MONITORING: u32 = 0x0A;
+
+/// The bits of the `IA32_PERFEVTSELx` registers, besides the event and the unit mask.
+const EVTSEL_USER: u64 = 1 << 16;
+const EVTSEL_KERNEL: u64 = 1 << 17;
+const EVTSEL_INTERRUPT: u64 = 1 << 20;
+const EVTSEL_ENABLE: u64 = 1 << 22;
+
+/// The bits of each fixed counter in the `IA32_FIXED_CTR_CTRL` register.
+const FIXED_KERNEL: u64 = 1 << 0;
+const FIXED_USER: u64 = 1 << 1;
+const FIXED_INTERRUPT: u64 = 1 << 3;
+const FIXED_BITS: u32 = 4;
+
+/// The fixed counters have the bits from 32 in the global control and status registers, the
+/// general counters the bits from 0. A counter is identified by its bit in these registers.
+const FIXED_FIRST: u8 = 32;
+
+/// Set in the index given to `rdpmc` to read a fixed counter.
+const RDPMC_FIXED: u32 = 1 << 30;
+
+/// Only the low 32 bits of the general counters can be written, and they are sign extended: the
+/// period of a counter must fit in 31 bits so that the preset value is the same on all the CPUs.
+const MAX_PERIOD: u64 = (1 << 31) - 1;
+
+/// The counters of the CPUs, read from CPUID by the BSP: they are the same on all the CPUs.
+static VERSION: AtomicU8 = AtomicU8::new(0);
+static GENERAL: AtomicU8 = AtomicU8::new(0);
+static GENERAL_WIDTH: AtomicU8 = AtomicU8::new(0);
+static FIXED: AtomicU8 = AtomicU8::new(0);
+static FIXED_WIDTH: AtomicU8 = AtomicU8::new(0);
+
+/// The events not supported by the CPU, with the bits of the EBX register of the CPUID leaf.
+static UNAVAILABLE: AtomicU8 = AtomicU8::new(0);
+
+crate::per_cpu! {
+    /// The counters reserved on each CPU.
+    static COUNTERS: Spinlock<Counters> = Spinlock::new(Counters::new());
+}
+
+/// The architectural events, counted the same way by all the processors with a PMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    /// The core cycles while the CPU is not halted, whose frequency changes with the frequency of
+    /// the CPU.
+    Cycles,
+    Instructions,
+
+    /// The reference cycles while the CPU is not halted, at a constant frequency.
+    ReferenceCycles,
+    LastLevelCacheReferences,
+    LastLevelCacheMisses,
+    Branches,
+    BranchMisses,
+
+    /// A model specific event, with its event number and unit mask.
+    Raw {
+        event: u8,
+        umask: u8,
+    },
+}
+
+bitflags! {
+    /// The privilege levels at which the events are counted.
+    pub struct CountFlags : u8 {
+        const KERNEL = 1 << 0;
+        const USER = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PmuError {
+    /// The CPU does not have an architectural PMU, or a too old version of it.
+    Unsupported,
+
+    /// The CPU does not support this architectural event.
+    UnsupportedEvent,
+
+    /// All the counters able to count this event are used.
+    NoFreeCounter,
+
+    /// The overflow period is zero or does not fit in 31 bits.
+    InvalidPeriod,
+}
+
+/// A function called on each overflow of a counter, with the state of the interrupted code.
+pub type OverflowHandler = fn(&State);
+
+/// The overflow handler of a counter, and the number of events between two calls.
+#[derive(Clone, Copy)]
+struct Overflow {
+    handler: OverflowHandler,
+    period: u64,
+}
+
+/// The counters reserved on a CPU, with the same bits as the global control register.
+struct Counters {
+    used: u64,
+    overflow: [Option<Overflow>; 64],
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            used: 0,
+            overflow: [None; 64],
+        }
+    }
+}
+
+impl Event {
+    /// Returns the event number and the unit mask of the event, and its bit in the EBX register
+    /// of the CPUID leaf if it is an architectural event.
+    const fn encoding(self) -> (u8, u8, Option<u8>) {
+        match self {
+            Self::Cycles => (0x3C, 0x00, Some(0)),
+            Self::Instructions => (0xC0, 0x00, Some(1)),
+            Self::ReferenceCycles => (0x3C, 0x01, Some(2)),
+            Self::LastLevelCacheReferences => (0x2E, 0x4F, Some(3)),
+            Self::LastLevelCacheMisses => (0x2E, 0x41, Some(4)),
+            Self::Branches => (0xC4, 0x00, Some(5)),
+            Self::BranchMisses => (0xC5, 0x00, Some(6)),
+            Self::Raw { event, umask } => (event, umask, None),
+        }
+    }
+
+    /// Returns the index of the fixed counter counting this event, if any.
+    const fn fixed(self) -> Option<u8> {
+        match self {
+            Self::Instructions => Some(0),
+            Self::Cycles => Some(1),
+            Self::ReferenceCycles => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// A performance counter reserved on the current CPU, released when dropped. The counter only
+/// counts the events of the CPU where it was created, so it cannot be sent to another thread, and
+/// it must be created by a thread that does not migrate while it is used.
+pub struct Counter {
+    /// The bit of the counter in the global control register.
+    bit: u8,
+    event: Event,
+    flags: CountFlags,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Counter {
+    /// Reserve a counter for the given event on the current CPU. A fixed counter is used if the
+    /// event has one and it is free, a general counter otherwise. The counter is stopped and
+    /// zeroed: it starts counting when [`Counter::start`] is called.
+    ///
+    /// # Errors
+    /// - [`PmuError::Unsupported`]: The CPU does not have an architectural PMU.
+    /// - [`PmuError::UnsupportedEvent`]: The CPU does not support this architectural event.
+    /// - [`PmuError::NoFreeCounter`]: All the counters able to count this event are used.
+    pub fn new(event: Event, flags: CountFlags) -> Result<Self, PmuError> {
+        Self::reserve(event, flags, None)
+    }
+
+    /// Reserve a counter as [`Counter::new`] does, which calls the given handler each time the
+    /// given number of events occurred once started. The handler runs in the overflow interrupt.
+    ///
+    /// # Errors
+    /// The errors of [`Counter::new`], or [`PmuError::InvalidPeriod`] if the period is zero or
+    /// does not fit in 31 bits.
+    pub fn with_overflow(
+        event: Event,
+        flags: CountFlags,
+        period: u64,
+        handler: OverflowHandler,
+    ) -> Result<Self, PmuError> {
+        if period == 0 || period > MAX_PERIOD {
+            return Err(PmuError::InvalidPeriod);
+        }
+        Self::reserve(event, flags, Some(Overflow { handler, period }))
+    }
+
+    #[must_use]
+    pub const fn event(&self) -> Event {
+        self.event
+    }
+
+    /// Start counting the events.
+    pub fn start(&self) {
+        x86_64::irq::without(|| {
+            let counters = COUNTERS.local().lock();
+            self.program(counters.overflow[usize::from(self.bit)].is_some());
+            unsafe {
+                let global = msr::read(msr::IA32_PERF_GLOBAL_CTRL);
+                msr::write(msr::IA32_PERF_GLOBAL_CTRL, global | 1 << self.bit);
+            }
+        });
+    }
+
+    /// Stop counting the events. The value of the counter is kept.
+    pub fn stop(&self) {
+        x86_64::irq::without(|| {
+            let _counters = COUNTERS.local().lock();
+            unsafe {
+                let global = msr::read(msr::IA32_PERF_GLOBAL_CTRL);
+                msr::write(msr::IA32_PERF_GLOBAL_CTRL, global & !(1 << self.bit));
+            }
+        });
+    }
+
+    /// Returns the number of events counted since the counter was created or reset. For a counter
+    /// with an overflow handler, this is the number of events since the last overflow, offset by
+    /// the preset value of the counter.
+    #[must_use]
+    pub fn read(&self) -> u64 {
+        let (index, width) = if self.bit >= FIXED_FIRST {
+            let index = RDPMC_FIXED | u32::from(self.bit - FIXED_FIRST);
+            (index, FIXED_WIDTH.load(Ordering::Relaxed))
+        } else {
+            (u32::from(self.bit), GENERAL_WIDTH.load(Ordering::Relaxed))
+        };
+
+        let (low, high): (u32, u32);
+        unsafe {
+            core::arch::asm!(
+                "rdpmc",
+                in("ecx") index,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        (u64::from(high) << 32 | u64::from(low)) & width_mask(width)
+    }
+
+    /// Set the counter back to zero, or to its preset value if it has an overflow handler.
+    pub fn reset(&self) {
+        x86_64::irq::without(|| {
+            let counters = COUNTERS.local().lock();
+            let period =
+                counters.overflow[usize::from(self.bit)].map_or(0, |overflow| overflow.period);
+            unsafe { preset(self.bit, period) };
+        });
+    }
+
+    fn reserve(
+        event: Event,
+        flags: CountFlags,
+        overflow: Option<Overflow>,
+    ) -> Result<Self, PmuError> {
+        if VERSION.load(Ordering::Relaxed) < 2 {
+            return Err(PmuError::Unsupported);
+        }
+        let (_, _, architectural) = event.encoding();
+        if architectural.is_some_and(|bit| UNAVAILABLE.load(Ordering::Relaxed) & (1 << bit) != 0) {
+            return Err(PmuError::UnsupportedEvent);
+        }
+
+        let bit = x86_64::irq::without(|| {
+            let mut counters = COUNTERS.local().lock();
+            let fixed = event
+                .fixed()
+                .filter(|&index| index < FIXED.load(Ordering::Relaxed))
+                .map(|index| FIXED_FIRST + index)
+                .filter(|&bit| counters.used & (1 << bit) == 0);
+            let bit = fixed.or_else(|| {
+                (0..GENERAL.load(Ordering::Relaxed)).find(|&bit| counters.used & (1 << bit) == 0)
+            })?;
+
+            counters.used |= 1 << bit;
+            counters.overflow[usize::from(bit)] = overflow;
+            Some(bit)
+        })
+        .ok_or(PmuError::NoFreeCounter)?;
+
+        let counter = Self {
+            bit,
+            event,
+            flags,
+            _not_send: PhantomData,
+        };
+        counter.program(false);
+        unsafe { preset(bit, overflow.map_or(0, |overflow| overflow.period)) };
+        Ok(counter)
+    }
+
+    /// Write the configuration of the counter, with the overflow interrupt if requested. The
+    /// counter itself is started by the global control register.
+    fn program(&self, interrupt: bool) {
+        if self.bit >= FIXED_FIRST {
+            let shift = u32::from(self.bit - FIXED_FIRST) * FIXED_BITS;
+            let mut bits = 0;
+            if self.flags.contains(CountFlags::KERNEL) {
+                bits |= FIXED_KERNEL;
+            }
+            if self.flags.contains(CountFlags::USER) {
+                bits |= FIXED_USER;
+            }
+            if interrupt {
+                bits |= FIXED_INTERRUPT;
+            }
+            unsafe {
+                let control = msr::read(msr::IA32_FIXED_CTR_CTRL) & !(0b1111 << shift);
+                msr::write(msr::IA32_FIXED_CTR_CTRL, control | bits << shift);
+            }
+        } else {
+            let (event, umask, _) = self.event.encoding();
+            let mut select = u64::from(event) | u64::from(umask) << 8 | EVTSEL_ENABLE;
+            if self.flags.contains(CountFlags::KERNEL) {
+                select |= EVTSEL_KERNEL;
+            }
+            if self.flags.contains(CountFlags::USER) {
+                select |= EVTSEL_USER;
+            }
+            if interrupt {
+                select |= EVTSEL_INTERRUPT;
+            }
+            unsafe { msr::write(msr::IA32_PERFEVTSEL0 + u32::from(self.bit), select) };
+        }
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.stop();
+        x86_64::irq::without(|| {
+            let mut counters = COUNTERS.local().lock();
+            unsafe {
+                if self.bit >= FIXED_FIRST {
+                    let shift = u32::from(self.bit - FIXED_FIRST) * FIXED_BITS;
+                    let control = msr::read(msr::IA32_FIXED_CTR_CTRL) & !(0b1111 << shift);
+                    msr::write(msr::IA32_FIXED_CTR_CTRL, control);
+                } else {
+                    msr::write(msr::IA32_PERFEVTSEL0 + u32::from(self.bit), 0);
+                }
+            }
+            counters.used &= !(1 << self.bit);
+            counters.overflow[usize::from(self.bit)] = None;
+        });
+    }
+}
+
+/// Read the performance counters of the CPU, which must be the BSP, and make its LAPIC deliver the
+/// overflow interrupts.
+pub fn setup() {
+    let Some(leaf) = cpuid::cpuid(CPUID_PERFORMANCE_MONITORING, 0) else {
+        log::info!("PMU: not supported");
+        return;
+    };
+
+    let [version, general, general_width, vector_length] = leaf.eax.to_le_bytes();
+    if version < 2 {
+        log::info!("PMU: version {version} not supported");
+        return;
+    }
+
+    // The events beyond the length of the bit vector are not supported either
+    let unavailable = cpuid::register(CPUID_PERFORMANCE_MONITORING, 0, Register::Ebx);
+    let unavailable = unavailable | u32::MAX.checked_shl(u32::from(vector_length)).unwrap_or(0);
+    let fixed = (leaf.edx & 0x1F) as u8;
+    let fixed_width = ((leaf.edx >> 5) & 0xFF) as u8;
+
+    VERSION.store(version, Ordering::Relaxed);
+    GENERAL.store(general.min(FIXED_FIRST), Ordering::Relaxed);
+    GENERAL_WIDTH.store(general_width, Ordering::Relaxed);
+    FIXED.store(fixed, Ordering::Relaxed);
+    FIXED_WIDTH.store(fixed_width, Ordering::Relaxed);
+    UNAVAILABLE.store(unavailable.to_le_bytes()[0], Ordering::Relaxed);
+    enable();
+
+    log::info!(
+        "PMU: version {version}, {general} counters of {general_width} bits, {fixed} fixed \
+         counters of {fixed_width} bits"
+    );
+}
+
+/// Make the LAPIC of the current CPU deliver the overflow interrupts of its counters to
+/// [`PMU_VECTOR`]. This must be done by each AP when it starts.
+pub fn enable() {
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        lapic::set_lvt(Lvt::PerformanceCounter, LvtEntry::fixed(PMU_VECTOR));
+    }
+}
+
+/// Handler for the overflow interrupt of the performance counters. The handlers of the counters
+/// that overflowed are called, and the counters are preset again for their next period. The LAPIC
+/// masks the interrupt when it delivers it, so it is unmasked before returning.
+pub extern "C" fn overflow_handler(state: &State) {
+    let counters = COUNTERS.local().lock();
+    let status = unsafe { msr::read(msr::IA32_PERF_GLOBAL_STATUS) } & counters.used;
+    for bit in (0..64).filter(|bit| status & (1 << bit) != 0) {
+        if let Some(overflow) = counters.overflow[usize::from(bit)] {
+            unsafe { preset(bit, overflow.period) };
+            (overflow.handler)(state);
+        }
+    }
+    drop(counters);
+
+    unsafe { msr::write(msr::IA32_PERF_GLOBAL_OVF_CTRL, status) };
+    lapic::set_lvt(Lvt::PerformanceCounter, LvtEntry::fixed(PMU_VECTOR));
+    lapic::send_eoi();
+}
+
+/// Write the value of the given counter so that it overflows after the given number of events, or
+/// zero it if the period is zero.
+unsafe fn preset(bit: u8, period: u64) {
+    let value = period.wrapping_neg();
+    if bit >= FIXED_FIRST {
+        let width = FIXED_WIDTH.load(Ordering::Relaxed);
+        msr::write(
+            msr::IA32_FIXED_CTR0 + u32::from(bit - FIXED_FIRST),
+            value & width_mask(width),
+        );
+    } else {
+        let width = GENERAL_WIDTH.load(Ordering::Relaxed);
+        msr::write(msr::IA32_PMC0 + u32::from(bit), value & width_mask(width));
+    }
+}
+
+/// Returns the mask of the bits of a counter with the given width.
+fn width_mask(width: u8) -> u64 {
+    u64::MAX.checked_shr(64 - u32::from(width)).unwrap_or(0)
+}
+
+interrupt_handler!(PMU_VECTOR, overflow, overflow_handler);
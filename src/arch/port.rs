@@ -0,0 +1,167 @@
+//! Typed access to the I/O ports. A port carries the width of its accesses in its type, so that a
+//! 16-bit register cannot be read with an 8-bit access by mistake, and the read-only and
+//! write-only registers can only be accessed in their direction.
+use core::marker::PhantomData;
+
+/// The port written by [`delay`]. It receives the POST codes of the firmware, and no device
+/// answers to it once the system is booted.
+const DELAY_PORT: u16 = 0x80;
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+/// A value that can be read from or written to an I/O port: `u8`, `u16` or `u32`.
+pub trait PortValue: Copy + private::Sealed {
+    /// Read a value from the given port.
+    ///
+    /// # Safety
+    /// This function is unsafe because reading a port can have side effects on the device.
+    unsafe fn read(port: u16) -> Self;
+
+    /// Write a value to the given port.
+    ///
+    /// # Safety
+    /// This function is unsafe because writing a port can break the device or the memory safety
+    /// (e.g. by starting a DMA transfer).
+    unsafe fn write(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read(port: u16) -> Self {
+        let value: u8;
+        core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack));
+        value
+    }
+
+    unsafe fn write(port: u16, value: Self) {
+        core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read(port: u16) -> Self {
+        let value: u16;
+        core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack));
+        value
+    }
+
+    unsafe fn write(port: u16, value: Self) {
+        core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack));
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read(port: u16) -> Self {
+        let value: u32;
+        core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack));
+        value
+    }
+
+    unsafe fn write(port: u16, value: Self) {
+        core::arch::asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack));
+    }
+}
+
+/// An I/O port that can be read and written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Port<T: PortValue> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+/// An I/O port that can only be read, such as a status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReadOnlyPort<T: PortValue> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+/// An I/O port that can only be written, such as a command register whose reads return another
+/// register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WriteOnlyPort<T: PortValue> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    #[must_use]
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub const fn number(self) -> u16 {
+        self.port
+    }
+
+    /// # Safety
+    /// See [`PortValue::read`].
+    #[must_use]
+    pub unsafe fn read(self) -> T {
+        T::read(self.port)
+    }
+
+    /// # Safety
+    /// See [`PortValue::write`].
+    pub unsafe fn write(self, value: T) {
+        T::write(self.port, value);
+    }
+}
+
+impl<T: PortValue> ReadOnlyPort<T> {
+    #[must_use]
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub const fn number(self) -> u16 {
+        self.port
+    }
+
+    /// # Safety
+    /// See [`PortValue::read`].
+    #[must_use]
+    pub unsafe fn read(self) -> T {
+        T::read(self.port)
+    }
+}
+
+impl<T: PortValue> WriteOnlyPort<T> {
+    #[must_use]
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub const fn number(self) -> u16 {
+        self.port
+    }
+
+    /// # Safety
+    /// See [`PortValue::write`].
+    pub unsafe fn write(self, value: T) {
+        T::write(self.port, value);
+    }
+}
+
+/// Wait for about a microsecond, the time taken by an I/O port write on the ISA bus. Some legacy
+/// devices, like the 8259 PICs, need this delay between two commands.
+pub fn delay() {
+    unsafe { u8::write(DELAY_PORT, 0) };
+}
@@ -0,0 +1,133 @@
+use core::time::Duration;
+
+use crate::sys::time::Instant;
+
+use super::{
+    acpi::FixedHardware,
+    irq::{self, IrqFlags, IrqReturn},
+    port::{Port, WriteOnlyPort},
+};
+
+/// The time given to the firmware to switch the system to ACPI mode.
+const ACPI_ENABLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Set in the PM1 control register once the system is in ACPI mode, where the fixed events raise
+/// the SCI instead of a SMI.
+const PM1_CONTROL_SCI_ENABLE: u16 = 1 << 0;
+
+/// The power button bit, in the PM1 status and enable registers. A status bit is cleared by
+/// writing one to it.
+const PM1_POWER_BUTTON: u16 = 1 << 8;
+
+/// Switch the system to ACPI mode and handle the System Control Interrupt. The power button is
+/// the only fixed event enabled: pressing it shuts the system down (see
+/// [`crate::sys::power::shutdown`]). Does nothing if there is no FADT or if the SCI is not
+/// connected to an ISA IRQ line.
+pub fn setup() {
+    let Some(hardware) = super::acpi::fadt() else {
+        log::info!("No FADT found, the ACPI fixed events are ignored");
+        return;
+    };
+    let (Some(sci), Some(_)) = (hardware.sci, hardware.pm1a_event) else {
+        log::info!("No SCI or PM1 event block, the ACPI fixed events are ignored");
+        return;
+    };
+
+    if !enable_acpi(hardware) {
+        log::warn!("Failed to switch the system to ACPI mode");
+        return;
+    }
+
+    // Clear the power button event that may have been latched before, then enable it
+    for block in [hardware.pm1a_event, hardware.pm1b_event]
+        .into_iter()
+        .flatten()
+    {
+        unsafe {
+            status_port(block).write(PM1_POWER_BUTTON);
+            let enable = enable_port(hardware, block);
+            enable.write(enable.read() | PM1_POWER_BUTTON);
+        }
+    }
+
+    // The SCI is level-triggered and can be shared with the PCI devices
+    if let Err(error) =
+        irq::request_threaded_irq(sci, interrupt, power_button, IrqFlags::SHARED, "acpi")
+    {
+        log::warn!("Failed to request the SCI (IRQ {sci}): {error:?}");
+        return;
+    }
+    log::info!("ACPI mode enabled, SCI on IRQ {sci}");
+}
+
+/// Switch the system to ACPI mode by sending the ACPI enable command to the firmware, if it is not
+/// already in this mode, and wait until the switch is done. Returns true if the system is in ACPI
+/// mode.
+fn enable_acpi(hardware: &FixedHardware) -> bool {
+    let Some(control) = hardware.pm1a_control else {
+        return false;
+    };
+    let control = Port::<u16>::new(control);
+    let enabled = || unsafe { control.read() & PM1_CONTROL_SCI_ENABLE != 0 };
+    if enabled() {
+        return true;
+    }
+    let Some(command) = hardware.smi_command else {
+        return false;
+    };
+
+    unsafe {
+        WriteOnlyPort::new(command).write(hardware.acpi_enable);
+    }
+    let deadline = Instant::now() + ACPI_ENABLE_TIMEOUT;
+    while Instant::now() < deadline {
+        if enabled() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// The hard handler of the SCI: the power button event is acknowledged, and the shutdown is done
+/// by the threaded handler. The other events are not enabled, so they never raise the SCI.
+fn interrupt(_: u8) -> IrqReturn {
+    let Some(hardware) = super::acpi::fadt() else {
+        return IrqReturn::None;
+    };
+
+    let mut pressed = false;
+    for block in [hardware.pm1a_event, hardware.pm1b_event]
+        .into_iter()
+        .flatten()
+    {
+        let status = status_port(block);
+        unsafe {
+            if status.read() & PM1_POWER_BUTTON != 0 {
+                status.write(PM1_POWER_BUTTON);
+                pressed = true;
+            }
+        }
+    }
+    if pressed {
+        IrqReturn::WakeThread
+    } else {
+        IrqReturn::None
+    }
+}
+
+/// The threaded handler of the SCI, run when the power button was pressed.
+fn power_button(_: u8) {
+    log::info!("Power button pressed");
+    crate::sys::power::shutdown();
+}
+
+/// Returns the port of the status register of a PM1 event block.
+const fn status_port(block: u16) -> Port<u16> {
+    Port::new(block)
+}
+
+/// Returns the port of the enable register of a PM1 event block, in the second half of the block.
+fn enable_port(hardware: &FixedHardware, block: u16) -> Port<u16> {
+    Port::new(block + u16::from(hardware.pm1_event_length / 2))
+}
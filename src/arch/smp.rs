@@ -1,14 +1,23 @@
 use core::{
     mem::size_of,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
 };
 
 use limine::LimineSmpInfo;
+use sync::Once;
 use x86_64::{address::Virtual, cpu::msr};
 
 use crate::{
     config::MAX_CPU,
     mm::vmm::{self, AllocationFlags},
+    Spinlock,
+};
+
+use super::{
+    acpi::{CALL_FUNCTION_VECTOR, RESCHEDULE_VECTOR},
+    cpumask::CpuMask,
+    lapic::{self, DeliveryMode, IpiDestination},
+    topology::CpuTopology,
 };
 
 /// Represent the thread local information for a CPU. This structure is used by the compiler to
@@ -21,14 +30,202 @@ pub struct ThreadLocalInfo {
     pub tls_base: Virtual,
     pub lapic_id: u32,
     pub cpu_id: u32,
+    /// Top of the kernel stack of the thread running on this CPU, loaded by the system call entry
+    /// because the `syscall` instruction does not switch the stack.
+    pub kernel_stack: AtomicU64,
+    /// Scratch slot used by the system call entry to save the user stack pointer.
+    pub user_stack: u64,
+    /// The number of interrupt handlers executing on this CPU (see [`super::interrupt`]).
+    pub interrupt_depth: AtomicU32,
+}
+
+/// The maximum number of calls that can be pending in the call queue of a CPU. When the queue of a
+/// target is full, the caller waits until the target has executed some of its pending calls.
+const CALL_QUEUE_SIZE: usize = 16;
+
+/// How long the BSP waits for the APs to start, in milliseconds. The APs that are not ready by then
+/// are abandoned, and the kernel continues with the CPUs that did start.
+const AP_START_TIMEOUT_MS: u64 = 1000;
+
+/// The CPUs that have completed their initialization, one bit per CPU id. A CPU stays present when
+/// it is taken offline (see [`offline`]).
+static PRESENT: AtomicU64 = AtomicU64::new(0);
+
+/// The CPUs that can receive cross-CPU calls, one bit per CPU id. A CPU is added to this set once
+/// its thread local storage is allocated and its LAPIC is enabled.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// The CPUs on which the scheduler runs threads, one bit per CPU id. A CPU is added to this set
+/// once it has registered with the scheduler, and removed from it as soon as it is asked to be
+/// parked, so that no thread is handed to it anymore.
+static ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+crate::per_cpu! {
+    /// The LAPIC id of each CPU, needed to send an IPI to another CPU.
+    static LAPIC_IDS: AtomicU32 = AtomicU32::new(0);
+}
+
+crate::per_cpu! {
+    /// The position of each CPU in the processor topology, detected when the CPU goes online.
+    static TOPOLOGIES: Once<CpuTopology> = Once::new();
+}
+
+crate::per_cpu! {
+    /// Set while a CPU is parked by [`offline`], until [`online`] wakes it up.
+    static PARKED: AtomicBool = AtomicBool::new(false);
+}
+
+crate::per_cpu! {
+    /// The last stage of [`ap_start`] reached by each AP (see [`ApStage`]). It is indexed by the
+    /// processor id given by Limine, because it is written before the thread local storage of the
+    /// AP is allocated.
+    static AP_STAGES: AtomicU8 = AtomicU8::new(ApStage::NotStarted as u8);
+}
+
+crate::per_cpu! {
+    /// The calls waiting to be executed by each CPU.
+    static CALL_QUEUES: Spinlock<CallQueue> = Spinlock::new(CallQueue::new());
+}
+
+/// The CPUs on which a function is executed by [`call_function`] or [`call_function_async`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallTarget {
+    /// A single CPU, designated by its id.
+    Cpu(u32),
+
+    /// A set of CPUs.
+    Mask(CpuMask),
+
+    /// All the online CPUs except the current one.
+    Others,
+}
+
+/// The errors returned by [`offline`] and [`online`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HotplugError {
+    /// The CPU does not exist or was never started.
+    InvalidCpu,
+
+    /// The BSP cannot be taken offline: it is the timekeeper, and the only CPU receiving the IRQs
+    /// of the 8259 PIC.
+    Bsp,
+
+    /// The CPU is already offline.
+    AlreadyOffline,
+
+    /// The CPU is already online.
+    AlreadyOnline,
+
+    /// The IRQs delivered to the CPU could not be moved to the BSP.
+    IrqMigration,
+}
+
+/// The stages of the initialization of an AP in [`ap_start`], recorded so that the BSP can tell
+/// where an AP stalled if it does not start in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+enum ApStage {
+    /// The AP has not entered the kernel: it was not started by Limine, or it crashed before.
+    NotStarted,
+
+    /// The AP has entered [`ap_start`] and loads the GDT and the IDT of the BSP.
+    Entered,
+
+    /// The AP allocates its thread local storage and enables its LAPIC.
+    LocalStorage,
+
+    /// The AP checks its features against the features of the other CPUs.
+    Features,
+
+    /// The AP loads its own GDT and goes online.
+    Gdt,
+
+    /// The AP calibrates its timers and starts its clock tick.
+    Timer,
+
+    /// The AP loads its TSS and the kernel page tables.
+    Paging,
+
+    /// The AP enables SMAP and the system calls.
+    User,
+
+    /// The AP is started and waits for the scheduler.
+    Ready,
+
+    /// The AP did not start in time and was abandoned by the BSP: it stops as soon as it reaches
+    /// its next stage.
+    Abandoned,
+}
+
+impl ApStage {
+    /// The stages, indexed by their value in [`AP_STAGES`].
+    const ALL: [Self; 10] = [
+        Self::NotStarted,
+        Self::Entered,
+        Self::LocalStorage,
+        Self::Features,
+        Self::Gdt,
+        Self::Timer,
+        Self::Paging,
+        Self::User,
+        Self::Ready,
+        Self::Abandoned,
+    ];
+}
+
+/// A function queued for execution on another CPU.
+#[derive(Clone, Copy)]
+struct Call {
+    func: unsafe fn(*const ()),
+    data: *const (),
+
+    /// The number of CPUs that have not executed the call yet, owned by the caller, or a null
+    /// pointer if the caller does not wait for the completion of the call.
+    pending: *const AtomicU32,
+}
+
+// SAFETY: The data of a call is either a function pointer or a closure that is `Sync` and outlives
+// the call, because the caller waits for all the CPUs to execute it before returning.
+unsafe impl Send for Call {}
+
+/// A fixed size FIFO of calls. It does not allocate memory, because calls are sent while the
+/// page tables or the heap are locked (for example, to shoot down the TLB of other CPUs).
+struct CallQueue {
+    calls: [Option<Call>; CALL_QUEUE_SIZE],
+    head: usize,
+    len: usize,
 }
 
-/// Represent the number of CPUs that have started. After the initialization of the kernel, this
-/// variable could be used to determine the number of CPUs in the system.
-pub static CPU_COUNT: AtomicU64 = AtomicU64::new(1);
+impl CallQueue {
+    const fn new() -> Self {
+        Self {
+            calls: [None; CALL_QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
 
-/// Allocate the thread local storage for the current CPU. The caller CPU must be the BSP, otherwise
-/// the behavior is undefined.
+    /// Push a call at the end of the queue. Returns false if the queue is full.
+    fn push(&mut self, call: Call) -> bool {
+        if self.len == CALL_QUEUE_SIZE {
+            return false;
+        }
+        self.calls[(self.head + self.len) % CALL_QUEUE_SIZE] = Some(call);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Call> {
+        let call = self.calls[self.head].take()?;
+        self.head = (self.head + 1) % CALL_QUEUE_SIZE;
+        self.len -= 1;
+        Some(call)
+    }
+}
+
+/// Allocate the thread local storage for the current CPU, and register the id of the current CPU
+/// with the synchronization primitives. The caller CPU must be the BSP, otherwise the behavior is
+/// undefined.
 pub fn bsp_setup() {
     let reponse = crate::LIMINE_SMP.get_response().get_mut().unwrap();
     let smp_info = reponse
@@ -39,6 +236,13 @@ pub fn bsp_setup() {
     unsafe {
         allocate_thread_local_storage(smp_info);
     }
+    sync::cpu::register(try_current_id);
+    #[cfg(feature = "lockdep")]
+    sync::lockdep::register(
+        super::interrupt::in_interrupt,
+        super::irq::enabled,
+        crate::sched::lockdep_thread,
+    );
 }
 
 /// This function is called by the APs when they start. It will initialize the current core and
@@ -46,53 +250,170 @@ pub fn bsp_setup() {
 ///
 /// This function should not be called directly, but only by the `_ap_start` function.
 pub fn ap_start(smp_info: &LimineSmpInfo) -> ! {
+    let boot_stack = super::backtrace::boot_stack_top();
+    let cpu = smp_info.processor_id;
+    set_stage(cpu, ApStage::Entered);
     super::gdt::reload();
     super::idt::reload();
+    super::exception::enable_alignment_check();
+
+    set_stage(cpu, ApStage::LocalStorage);
     unsafe {
         allocate_thread_local_storage(smp_info);
-        x86_64::lapic::enable();
     }
-    super::tss::install(smp_info.processor_id as usize);
+    super::backtrace::set_boot_stack(boot_stack);
+    lapic::enable();
+
+    set_stage(cpu, ApStage::Features);
+    super::features::ap_check(cpu);
+    super::fpu::setup();
+    super::debugreg::load();
+
+    set_stage(cpu, ApStage::Gdt);
+    super::gdt::setup();
+    super::spurious::enable();
+    super::pmu::enable();
+    super::kvm::enable();
+    set_online();
+
+    set_stage(cpu, ApStage::Timer);
+    super::timer::enable();
+
+    set_stage(cpu, ApStage::Paging);
+    super::tss::install();
     super::paging::ap_setup();
 
-    // Signal to the BSP that the AP is ready, enable interrupts and loop forever
-    CPU_COUNT.fetch_add(1, Ordering::Relaxed);
-    loop {
-        unsafe {
-            x86_64::irq::enable();
-            x86_64::cpu::hlt();
-        }
-    }
+    set_stage(cpu, ApStage::User);
+    crate::mm::user::setup();
+    super::syscall::setup();
+
+    // Signal to the BSP that the AP is ready, then become the idle thread of the AP once the
+    // scheduler is initialized
+    set_stage(cpu, ApStage::Ready);
+    PRESENT.fetch_or(1 << cpu, Ordering::Release);
+    crate::sched::ap_setup();
+    crate::sched::idle();
 }
 
-/// Start all the APs and wait for them before returning. If an AP fails to start, this function
-/// will be stuck forever in an infinite loop. I think this is the best behavior, because if an AP
-/// fails to start, it means that something is wrong with the system and the kernel should not
-/// continue to run.
+/// Start all the APs and wait for them before returning. The APs that are not ready after
+/// [`AP_START_TIMEOUT_MS`] milliseconds are reported with the stage at which they stalled and are
+/// abandoned: the kernel continues with the CPUs that did start. Without a MADT (see
+/// [`super::acpi::setup`]), only the BSP is used.
 pub fn start_cpus() {
     let reponse = crate::LIMINE_SMP.get_response().get_mut().unwrap();
     assert!(!reponse.cpus().is_empty(), "No core found");
     assert!(reponse.cpus().len() <= MAX_CPU, "Too many core found");
+    set_online();
+    PRESENT.fetch_or(1 << current_id(), Ordering::Release);
+
+    // The CPUs are described by Limine, but the MADT also describes the processors that are
+    // disabled or that can be hot-plugged later
+    let Some(topology) = super::acpi::topology() else {
+        log::warn!("No MADT, the APs are not started");
+        return;
+    };
+    for cpu in reponse.cpus().iter() {
+        if !topology
+            .enabled_processors()
+            .any(|processor| processor.apic_id == cpu.lapic_id)
+        {
+            log::warn!("CPU with LAPIC {} is not enabled in the MADT", cpu.lapic_id);
+        }
+    }
+    let hotpluggable = topology
+        .processors
+        .iter()
+        .filter(|processor| !processor.enabled && processor.online_capable)
+        .count();
+    if hotpluggable > 0 {
+        log::info!("{hotpluggable} processors can be hot-plugged");
+    }
+
     for cpu in reponse.cpus().iter_mut().filter(|cpu| cpu.lapic_id != 0) {
         log::debug!("Starting AP {}", cpu.lapic_id);
         cpu.goto_address = crate::arch::_ap_start;
     }
 
-    // Wait for all APs to start
-    while CPU_COUNT.load(Ordering::Relaxed) != reponse.cpus().len() as u64 {
+    // Wait for all APs to start. The timeout is measured with the TSC, because the clock tick does
+    // not run yet. The timers of the BSP are always calibrated when there is a MADT
+    let timeout = super::timer::calibration()
+        .tsc
+        .ticks(AP_START_TIMEOUT_MS * 1_000_000);
+    let start = super::tsc::rdtsc();
+    while present_mask().count() as usize != reponse.cpus().len() {
+        if super::tsc::rdtsc() - start > timeout {
+            reponse
+                .cpus()
+                .iter()
+                .filter(|cpu| cpu.lapic_id != 0 && !present_mask().contains(cpu.processor_id))
+                .for_each(|cpu| abandon(cpu));
+            break;
+        }
         core::hint::spin_loop();
     }
-    log::info!("All APs started");
+    if present_mask().count() as usize == reponse.cpus().len() {
+        log::info!("All APs started");
+    }
+
+    let topologies = online_mask().iter().filter_map(cpu_topology);
+    let cores = topologies
+        .clone()
+        .filter(|topology| topology.smt_id == 0)
+        .count();
+    let packages = topologies
+        .filter(|topology| topology.smt_id == 0 && topology.core_id == 0)
+        .count();
+    log::info!(
+        "{} CPUs: {packages} packages, {cores} physical cores",
+        present_mask().count()
+    );
+}
+
+/// Abandon an AP that did not start in time, and log the stage at which it stalled. It is removed
+/// from the online CPUs, so that the cross-CPU calls do not wait for it. An AP that becomes ready
+/// in the meantime is kept.
+fn abandon(cpu: &LimineSmpInfo) {
+    let stage = AP_STAGES.cpu(cpu.processor_id).fetch_update(
+        Ordering::AcqRel,
+        Ordering::Acquire,
+        |stage| (stage != ApStage::Ready as u8).then_some(ApStage::Abandoned as u8),
+    );
+    if let Ok(stage) = stage {
+        ONLINE.fetch_and(!(1 << cpu.processor_id), Ordering::Release);
+        log::error!(
+            "AP {} (LAPIC {}) did not start in {AP_START_TIMEOUT_MS} ms, stalled at {:?}",
+            cpu.processor_id,
+            cpu.lapic_id,
+            ApStage::ALL[usize::from(stage)]
+        );
+    }
 }
 
 /// Get the thread local structure for the current CPU. See `ThreadLocalInfo` for more information
 /// about this structure.
+///
+/// # Panics
+/// Panics if the thread local storage of the current CPU is not allocated yet, which can only
+/// happen during the early boot (see [`try_cpu_info`]).
 #[must_use]
 pub fn get_cpu_info() -> &'static ThreadLocalInfo {
-    // SAFETY: This is safe because the pointer is valid (never freed during the lifetime of the
-    // kernel) and the structure is properly initialized. We also only deliver a reference to it,
-    // so the caller can't modify it.
-    unsafe { &*(msr::read(msr::Register::FsBase) as *const ThreadLocalInfo) }
+    try_cpu_info().expect("Thread local storage used before being allocated")
+}
+
+/// Get the thread local structure for the current CPU, or `None` if it is not allocated yet. The
+/// FS base is zero until then, on the BSP before [`bsp_setup`] and on an AP before the allocation
+/// in [`ap_start`].
+#[must_use]
+pub fn try_cpu_info() -> Option<&'static ThreadLocalInfo> {
+    // SAFETY: This is safe because the pointer is either null or valid (never freed during the
+    // lifetime of the kernel) and the structure is properly initialized. We also only deliver a
+    // reference to it, so the caller can't modify it.
+    unsafe { (msr::read(msr::Register::FsBase) as *const ThreadLocalInfo).as_ref() }
+}
+
+/// Set the kernel stack loaded by the system call entry on the current CPU.
+pub fn set_kernel_stack(top: u64) {
+    get_cpu_info().kernel_stack.store(top, Ordering::Relaxed);
 }
 
 /// Return the CPU id of the current CPU
@@ -101,6 +422,329 @@ pub fn current_id() -> u32 {
     get_cpu_info().cpu_id
 }
 
+/// Return the CPU id of the current CPU, or `None` if its thread local storage is not allocated
+/// yet (see [`try_cpu_info`]).
+#[must_use]
+pub fn try_current_id() -> Option<u32> {
+    try_cpu_info().map(|info| info.cpu_id)
+}
+
+/// Run the given closure on the target CPUs and wait until all of them have executed it. The
+/// closure runs with interrupts disabled on the other CPUs, from the handler of the
+/// [`CALL_FUNCTION_VECTOR`] IPI, and directly on the current CPU if it is part of the targets.
+/// CPUs that are not online yet are silently ignored.
+///
+/// While waiting, the current CPU executes the calls sent to it by other CPUs, so this function
+/// can be used with interrupts disabled without deadlocking when two CPUs call each other.
+pub fn call_function<F: Fn() + Sync>(target: CallTarget, func: &F) {
+    unsafe fn call_closure<F: Fn()>(data: *const ()) {
+        (*data.cast::<F>())();
+    }
+
+    let pending = AtomicU32::new(0);
+    let current = sender();
+    let call = Call {
+        func: call_closure::<F>,
+        data: core::ptr::addr_of!(*func).cast(),
+        pending: core::ptr::addr_of!(pending),
+    };
+
+    if send(current, target, call) {
+        x86_64::irq::without(func);
+    }
+    while pending.load(Ordering::Acquire) != 0 {
+        if let Some(cpu) = current {
+            x86_64::irq::without(|| run_queue(cpu));
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Run the given function on the target CPUs without waiting for them to execute it. Unlike
+/// [`call_function`], only a function pointer can be given, because a closure could be dropped
+/// before the other CPUs have executed it. See [`call_function`] for more details.
+pub fn call_function_async(target: CallTarget, func: fn()) {
+    unsafe fn call_pointer(data: *const ()) {
+        let func: fn() = core::mem::transmute(data);
+        func();
+    }
+
+    let call = Call {
+        func: call_pointer,
+        data: func as *const (),
+        pending: core::ptr::null(),
+    };
+    if send(sender(), target, call) {
+        x86_64::irq::without(func);
+    }
+}
+
+/// Execute all the calls queued for the current CPU. This function is called by the handler of
+/// the [`CALL_FUNCTION_VECTOR`] IPI, with interrupts disabled.
+pub fn handle_calls() {
+    run_queue(current_id());
+}
+
+/// Queue the call on each online target CPU except the current one and send them an IPI. Returns
+/// true if the current CPU is part of the targets, in which case the caller must execute the call
+/// itself.
+fn send(current: Option<u32>, target: CallTarget, call: Call) -> bool {
+    let mut mask = online_mask()
+        & match target {
+            CallTarget::Cpu(cpu) => CpuMask::single(cpu),
+            CallTarget::Mask(mask) => mask,
+            CallTarget::Others => CpuMask::all(),
+        };
+
+    let mut local = false;
+    if let Some(cpu) = current {
+        local = mask.contains(cpu) && target != CallTarget::Others;
+        mask.remove(cpu);
+    }
+
+    for cpu in mask.iter() {
+        if let Some(pending) = unsafe { call.pending.as_ref() } {
+            pending.fetch_add(1, Ordering::AcqRel);
+        }
+        while !x86_64::irq::without(|| CALL_QUEUES.cpu(cpu).lock().push(call)) {
+            // The queue of the target is full: execute our own calls while waiting, because the
+            // target may itself be waiting for us with interrupts disabled.
+            if let Some(current) = current {
+                x86_64::irq::without(|| run_queue(current));
+            }
+            core::hint::spin_loop();
+        }
+
+        send_ipi(cpu, CALL_FUNCTION_VECTOR);
+    }
+    local
+}
+
+/// Send the [`RESCHEDULE_VECTOR`] IPI to the given CPU, to wake it up when it is idle and a thread
+/// has been made ready. Does nothing if the CPU is not online.
+pub fn send_reschedule(cpu: u32) {
+    if is_online(cpu) {
+        send_ipi(cpu, RESCHEDULE_VECTOR);
+    }
+}
+
+/// Send an IPI with the given vector to the given CPU.
+fn send_ipi(cpu: u32, vector: u8) {
+    let lapic_id = LAPIC_IDS.cpu(cpu).load(Ordering::Relaxed);
+    unsafe {
+        lapic::send_ipi(IpiDestination::Core(lapic_id), DeliveryMode::Fixed, vector);
+    }
+}
+
+/// Execute the calls queued for the given CPU until its queue is empty.
+fn run_queue(cpu: u32) {
+    while let Some(call) = x86_64::irq::without(|| CALL_QUEUES.cpu(cpu).lock().pop()) {
+        unsafe {
+            (call.func)(call.data);
+            if let Some(pending) = call.pending.as_ref() {
+                pending.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Return the id of the current CPU if it is online, or `None` otherwise. During the early stage,
+/// an AP may send calls before its thread local storage is allocated, and thus before it is online.
+fn sender() -> Option<u32> {
+    try_current_id().filter(|&cpu| is_online(cpu))
+}
+
+/// Returns true if the CPU with the given id can receive cross-CPU calls.
+#[must_use]
+pub fn is_online(cpu: u32) -> bool {
+    online_mask().contains(cpu)
+}
+
+/// Returns the number of CPUs that can receive cross-CPU calls.
+#[must_use]
+pub fn online_count() -> u32 {
+    online_mask().count()
+}
+
+/// Returns the CPUs that have completed their initialization, including the CPUs taken offline.
+#[must_use]
+pub fn present_mask() -> CpuMask {
+    CpuMask::from_bits(PRESENT.load(Ordering::Acquire))
+}
+
+/// Returns the CPUs that can receive cross-CPU calls and TLB shootdowns.
+#[must_use]
+pub fn online_mask() -> CpuMask {
+    CpuMask::from_bits(ONLINE.load(Ordering::Acquire))
+}
+
+/// Returns the CPUs on which the scheduler can run threads.
+#[must_use]
+pub fn active_mask() -> CpuMask {
+    CpuMask::from_bits(ACTIVE.load(Ordering::Acquire))
+}
+
+/// Add the current CPU to the set of active CPUs, or remove the given CPU from it. This is called
+/// by the scheduler when the current CPU registers with it, and when a CPU is taken offline.
+pub fn set_active(cpu: u32, active: bool) {
+    let bit = CpuMask::single(cpu).bits();
+    if active {
+        ACTIVE.fetch_or(bit, Ordering::Release);
+    } else {
+        ACTIVE.fetch_and(!bit, Ordering::Release);
+    }
+}
+
+/// Call the given function with the id of each online CPU, in ascending order.
+pub fn for_each_online_cpu(f: impl FnMut(u32)) {
+    online_mask().iter().for_each(f);
+}
+
+/// Returns the LAPIC id of the CPU with the given id, or `None` if this CPU is not online.
+#[must_use]
+pub fn lapic_id(cpu: u32) -> Option<u32> {
+    is_online(cpu).then(|| LAPIC_IDS.cpu(cpu).load(Ordering::Relaxed))
+}
+
+/// Returns the position of the CPU with the given id in the processor topology, or `None` if this
+/// CPU is not online.
+#[must_use]
+pub fn cpu_topology(cpu: u32) -> Option<CpuTopology> {
+    is_online(cpu)
+        .then(|| TOPOLOGIES.cpu(cpu).get().copied())
+        .flatten()
+}
+
+/// Returns the online CPUs sharing the physical core of the CPU with the given id, including this
+/// CPU. The scheduler prefers an idle CPU whose siblings are also idle, so that a thread does not
+/// share the execution units of a core while another core is idle.
+#[must_use]
+pub fn smt_siblings(cpu: u32) -> CpuMask {
+    let Some(topology) = cpu_topology(cpu) else {
+        return CpuMask::EMPTY;
+    };
+    online_mask()
+        .iter()
+        .filter(|&other| cpu_topology(other).is_some_and(|other| other.same_core(&topology)))
+        .collect()
+}
+
+/// Take the given CPU offline, and wait until it is parked. The legacy IRQs delivered to this CPU
+/// are moved to the BSP, and the scheduler of the CPU gives up its current thread, which migrates
+/// to another CPU. The CPU then leaves the set of online CPUs, so that it no longer receives
+/// cross-CPU calls nor TLB shootdowns, and halts until [`online`] is called. The high-resolution
+/// timers pending on the CPU only fire once it is back online.
+///
+/// This function must be called with interrupts enabled, because the CPU may only be parked once
+/// the current thread has given it up, when the CPU taken offline is the current one.
+///
+/// # Errors
+/// - [`HotplugError::Bsp`]: The BSP cannot be taken offline.
+/// - [`HotplugError::AlreadyOffline`]: The CPU is already parked.
+/// - [`HotplugError::InvalidCpu`]: The CPU does not exist or was never started.
+/// - [`HotplugError::IrqMigration`]: An IRQ delivered to the CPU could not be moved.
+pub fn offline(cpu: u32) -> Result<(), HotplugError> {
+    if cpu == 0 {
+        return Err(HotplugError::Bsp);
+    }
+    if !is_online(cpu) {
+        return Err(if parked(cpu) {
+            HotplugError::AlreadyOffline
+        } else {
+            HotplugError::InvalidCpu
+        });
+    }
+
+    super::irq::evacuate(cpu, 0).map_err(|_| HotplugError::IrqMigration)?;
+    set_active(cpu, false);
+    crate::sched::request_park(cpu);
+    while is_online(cpu) {
+        crate::preempt_point!();
+        core::hint::spin_loop();
+    }
+    log::info!("CPU {cpu} is offline");
+    Ok(())
+}
+
+/// Bring back online a CPU taken offline by [`offline`], and wait until it can receive cross-CPU
+/// calls again. Its scheduler then executes the ready threads as before.
+///
+/// # Errors
+/// - [`HotplugError::AlreadyOnline`]: The CPU is already online.
+/// - [`HotplugError::InvalidCpu`]: The CPU does not exist or was never started.
+pub fn online(cpu: u32) -> Result<(), HotplugError> {
+    if is_online(cpu) {
+        return Err(HotplugError::AlreadyOnline);
+    }
+    if !parked(cpu) {
+        return Err(HotplugError::InvalidCpu);
+    }
+
+    PARKED.cpu(cpu).store(false, Ordering::Release);
+    send_ipi(cpu, CALL_FUNCTION_VECTOR);
+    while !is_online(cpu) {
+        core::hint::spin_loop();
+    }
+    set_active(cpu, true);
+    log::info!("CPU {cpu} is online");
+    Ok(())
+}
+
+/// Park the current CPU until it is brought back online. This is called by the idle thread of the
+/// CPU once its scheduler has given up its other threads (see [`offline`]). The calls queued
+/// before the CPU left the set of online CPUs are executed before halting, and the whole TLB is
+/// flushed when the CPU leaves the parking, because the shootdowns were not sent to it.
+pub fn park() {
+    let cpu = current_id();
+    x86_64::irq::without(|| {
+        super::timer::disable();
+        PARKED.local().store(true, Ordering::Release);
+        ONLINE.fetch_and(!CpuMask::single(cpu).bits(), Ordering::AcqRel);
+        run_queue(cpu);
+
+        // The interrupts are enabled and the CPU halted atomically, so that the IPI sent by
+        // `online` cannot be received between them and lost
+        while PARKED.local().load(Ordering::Acquire) {
+            unsafe {
+                core::arch::asm!("sti", "hlt", "cli", options(nomem, nostack));
+            }
+        }
+
+        super::paging::tlb::flush_all();
+        set_online();
+        super::timer::enable();
+    });
+}
+
+/// Returns true if the CPU with the given id is parked by [`offline`].
+fn parked(cpu: u32) -> bool {
+    present_mask().contains(cpu) && PARKED.cpu(cpu).load(Ordering::Acquire)
+}
+
+/// Record that the given AP reached a stage of its initialization. If the AP was abandoned by the
+/// BSP because it did not start in time, it is stopped instead: the kernel already continued
+/// without it.
+fn set_stage(cpu: u32, stage: ApStage) {
+    let abandoned = AP_STAGES
+        .cpu(cpu)
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            (current != ApStage::Abandoned as u8).then_some(stage as u8)
+        })
+        .is_err();
+    if abandoned {
+        ONLINE.fetch_and(!(1 << cpu), Ordering::Release);
+        log::error!("AP {cpu} started too late, it is not used");
+        x86_64::cpu::freeze();
+    }
+}
+
+/// Add the current CPU to the set of CPUs that can receive cross-CPU calls, and record its position
+/// in the processor topology. Its thread local storage must be allocated and its LAPIC enabled.
+fn set_online() {
+    TOPOLOGIES.local().call_once(super::topology::detect);
+    ONLINE.fetch_or(1 << current_id(), Ordering::Release);
+}
+
 /// Allocate the thread local storage for the current CPU
 ///
 /// # Safety
@@ -136,6 +780,9 @@ unsafe fn allocate_thread_local_storage(smp_info: &LimineSmpInfo) {
     (*tls_info).lapic_id = smp_info.lapic_id;
     (*tls_info).tls_base = data.start();
     (*tls_info).self_ptr = tls_info;
+    LAPIC_IDS
+        .cpu(smp_info.processor_id)
+        .store(smp_info.lapic_id, Ordering::Relaxed);
 
     // Copy the per-cpu data from the kernel to the allocated memory
     core::ptr::copy_nonoverlapping(
@@ -144,7 +791,9 @@ unsafe fn allocate_thread_local_storage(smp_info: &LimineSmpInfo) {
         per_cpu_size,
     );
 
-    // Set the GS Kernel Base MSR to the address of the TLS info
+    // Set the GS Base MSR to the address of the TLS info, and the GS Kernel Base MSR to the GS
+    // base of user code: they are exchanged by `swapgs` each time the kernel is entered from user
+    // mode or returns to it (see [`super::interrupt::enter`]).
     //
     // Unfortunately, we must also set the FS Base MSR to the same address, because the Rust
     // compiler uses the FS register to access thread-local variables (as user applications do), but
@@ -153,4 +802,5 @@ unsafe fn allocate_thread_local_storage(smp_info: &LimineSmpInfo) {
     // when switching between kernel and user mode.
     msr::write(msr::Register::KernelGsBase, tls_info as u64);
     msr::write(msr::Register::FsBase, tls_info as u64);
+    core::arch::asm!("swapgs", options(nomem, nostack, preserves_flags));
 }
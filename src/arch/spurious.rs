@@ -0,0 +1,73 @@
+use x86_64::cpu::State;
+
+use crate::interrupt_handler;
+
+use super::{
+    acpi::SPURIOUS_VECTOR,
+    lapic,
+    percpu::PerCpuCounter,
+    pic::{self, CASCADE_LINE},
+};
+
+/// The lowest priority IRQ of each PIC, raised by the PIC when an IRQ disappears before being
+/// acknowledged by the CPU.
+const PIC_MASTER_SPURIOUS_LINE: u8 = 7;
+const PIC_SLAVE_SPURIOUS_LINE: u8 = 15;
+
+static LAPIC_SPURIOUS: PerCpuCounter = PerCpuCounter::new();
+static PIC_SPURIOUS: PerCpuCounter = PerCpuCounter::new();
+
+/// Make the LAPIC of the current CPU deliver its spurious interrupts to [`SPURIOUS_VECTOR`]. This
+/// must be done on each CPU after its LAPIC is enabled (see [`lapic::enable`]).
+pub fn enable() {
+    lapic::set_spurious_vector(SPURIOUS_VECTOR);
+}
+
+/// Returns the number of spurious interrupts raised by the LAPICs since the boot.
+#[must_use]
+pub fn lapic_count() -> u64 {
+    LAPIC_SPURIOUS.sum()
+}
+
+/// Returns the number of spurious IRQ 7 and IRQ 15 raised by the 8259 PICs since the boot.
+#[must_use]
+pub fn pic_count() -> u64 {
+    PIC_SPURIOUS.sum()
+}
+
+/// Check if the given legacy IRQ, raised by the 8259 PICs, is spurious. A PIC raises its lowest
+/// priority line when an IRQ disappears before being acknowledged, but the line is then not set
+/// in its In-Service Register. No EOI must be sent to the PIC for a spurious IRQ, except to the
+/// master PIC for a spurious IRQ of the slave, because the master does not know that the IRQ of
+/// the cascade line was spurious. This function sends that EOI itself.
+///
+/// This must only be used when the legacy IRQs are delivered by the PICs: with the IOAPIC, the
+/// IRQ 7 and IRQ 15 are genuine device interrupts.
+#[must_use]
+pub fn pic_spurious(line: u8) -> bool {
+    if line != PIC_MASTER_SPURIOUS_LINE && line != PIC_SLAVE_SPURIOUS_LINE {
+        return false;
+    }
+    if pic::in_service() & (1 << line) != 0 {
+        return false;
+    }
+
+    if line == PIC_SLAVE_SPURIOUS_LINE {
+        pic::send_eoi(CASCADE_LINE);
+    }
+    PIC_SPURIOUS.add(1);
+    log::trace!("Spurious IRQ {line}");
+    true
+}
+
+/// Handler for the spurious interrupts of the LAPIC. The LAPIC does not set its In-Service Register
+/// for a spurious interrupt, so no EOI must be sent.
+pub extern "C" fn spurious_interrupt_handler(_state: &State) {
+    LAPIC_SPURIOUS.add(1);
+}
+
+interrupt_handler!(
+    SPURIOUS_VECTOR,
+    spurious_interrupt,
+    spurious_interrupt_handler
+);
@@ -0,0 +1,142 @@
+use core::mem::offset_of;
+
+use x86_64::segment::Selector;
+
+use super::{
+    control::Efer,
+    msr::{Fmask, Lstar, Star},
+    smp::ThreadLocalInfo,
+};
+
+/// The RFLAGS bits cleared when entering the kernel with a system call: the trap flag, the
+/// interrupt flag, the direction flag and the alignment check flag.
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/// The registers saved by the system call entry on the kernel stack. The system call number is in
+/// `rax` and the arguments are in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9` (the Linux system call
+/// convention). The return value must be written in `rax`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+extern "C" {
+    fn __syscall_entry();
+}
+
+// The `syscall` instruction does not switch the stack, so we use the `swapgs` instruction to get
+// the thread local information of the current CPU and load the kernel stack of the current thread.
+// The kernel always runs with the GS base of the thread local information, so `swapgs` is executed
+// again only just before returning to user mode (see [`super::interrupt::enter`]). Interrupts are
+// disabled by the FMASK register until we are on the kernel stack, and while leaving it.
+//
+// `sysretq` raises a general protection fault in kernel mode, with the user stack and the user GS
+// base already loaded, if the return address in RCX is not canonical. A `syscall` instruction at
+// the very end of the user space returns to such an address, so the return address is checked
+// before leaving the kernel stack: the thread is killed instead (see [`non_canonical_return`]),
+// as it would have been by the fault raised when fetching its next instruction.
+core::arch::global_asm!(
+    r#"
+.global __syscall_entry
+__syscall_entry:
+    swapgs
+    mov gs:[{user_stack}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    push qword ptr gs:[{user_stack}]
+
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    mov rdi, rsp
+    sti
+    call {handler}
+    cli
+
+    mov rcx, [rsp + {rip}]
+    shl rcx, 16
+    sar rcx, 16
+    cmp rcx, [rsp + {rip}]
+    jne 1f
+
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+
+1:
+    mov rdi, rsp
+    sti
+    call {non_canonical_return}
+    ud2
+"#,
+    user_stack = const offset_of!(ThreadLocalInfo, user_stack),
+    kernel_stack = const offset_of!(ThreadLocalInfo, kernel_stack),
+    rip = const offset_of!(SyscallFrame, rip),
+    handler = sym syscall_handler,
+    non_canonical_return = sym non_canonical_return,
+);
+
+/// Enable the `syscall` instruction on the current CPU. This function must be called on each CPU,
+/// because the MSRs are not shared between cores.
+///
+/// The `sysret` instruction loads the user code segment with the selector written in the STAR MSR
+/// plus 16, and the user stack segment with the same selector plus 8: this is why the user code
+/// segment is duplicated after the user data segment in the GDT (see [`super::gdt`]).
+pub fn setup() {
+    unsafe {
+        Star {
+            syscall_base: Selector::KERNEL_CODE64.value(),
+            sysret_base: super::gdt::USER_DATA_SELECTOR - 8,
+        }
+        .write();
+        Lstar(__syscall_entry as *const () as u64).write();
+        Fmask(FMASK).write();
+        Efer::update(|efer| efer | Efer::SCE);
+    }
+}
+
+/// Called by the system call entry with interrupts enabled. The return value of the system call
+/// is written in the `rax` register of the frame and will be restored when returning to user mode.
+#[allow(clippy::cast_sign_loss)]
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    frame.rax = crate::syscall::dispatch(frame.rax, &args) as u64;
+}
+
+/// Called instead of returning to user mode when the return address of a system call is not
+/// canonical, which `sysretq` cannot return to. The thread is killed with interrupts enabled.
+extern "C" fn non_canonical_return(frame: &SyscallFrame) -> ! {
+    log::warn!(
+        "Thread {} killed: system call returning to the non-canonical address {:#018x}",
+        crate::sched::current_tid().as_u64(),
+        frame.rip
+    );
+    crate::sched::exit();
+}
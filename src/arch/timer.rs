@@ -0,0 +1,355 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use sync::Once;
+
+use crate::{config::KERNEL_HZ, sys::time::Instant};
+
+use super::{
+    acpi::CLOCK_TICK_VECTOR,
+    features::CpuFeatures,
+    lapic::{self, Lvt, LvtEntry, TimerDivide, TimerMode},
+};
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// The duration of the calibration, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// The divider of the LAPIC timer, matching [`TimerDivide::By16`].
+const LAPIC_DIVIDER: u64 = 16;
+
+crate::per_cpu! {
+    /// The calibration of the timers of each CPU, measured by the CPU itself when it starts its
+    /// clock tick.
+    static CALIBRATION: Once<Calibration> = Once::new();
+}
+
+/// Set if the timer events are programmed with the TSC-deadline MSR rather than with the initial
+/// count register of the LAPIC timer.
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+crate::per_cpu! {
+    /// The TSC value of the next clock tick of each CPU. The next deadline is computed from the
+    /// previous one rather than from the current time, so that the clock does not drift because of
+    /// the interrupt latency. The TSC is used as the time base in both timer modes, because the
+    /// timer events are shared between the clock tick and the high-resolution timers (see
+    /// [`crate::sys::time::hrtimer`]).
+    static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+}
+
+/// A conversion factor between nanoseconds and the ticks of a clock, in 32.32 fixed point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TicksPerNs(u64);
+
+impl TicksPerNs {
+    /// Create the conversion factor of a clock with the given frequency.
+    ///
+    /// # Panics
+    /// Panics if the frequency is 0.
+    #[must_use]
+    pub fn from_hz(hz: u64) -> Self {
+        assert!(hz > 0, "Clock frequency cannot be null");
+        let factor = (u128::from(hz) << 32) / u128::from(NANOSECONDS_PER_SECOND);
+        Self(u64::try_from(factor).unwrap().max(1))
+    }
+
+    /// Convert a duration in nanoseconds to a number of ticks, rounded down.
+    #[must_use]
+    pub fn ticks(self, nanoseconds: u64) -> u64 {
+        let ticks = (u128::from(nanoseconds) * u128::from(self.0)) >> 32;
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    }
+
+    /// Convert a number of ticks to a duration in nanoseconds, rounded down.
+    #[must_use]
+    pub fn nanoseconds(self, ticks: u64) -> u64 {
+        let nanoseconds = (u128::from(ticks) << 32) / u128::from(self.0);
+        u64::try_from(nanoseconds).unwrap_or(u64::MAX)
+    }
+
+    /// Returns the multiplier and the shift converting a number of ticks to nanoseconds with
+    /// `(ticks * mult) >> shift`, as the user space does with the time page (see
+    /// [`crate::sys::time::vdso`]). The largest shift for which the multiplier fits in 32 bits is
+    /// chosen, for the best precision.
+    #[must_use]
+    pub fn mult_shift(self) -> (u32, u32) {
+        (0..=32)
+            .rev()
+            .find_map(|shift| {
+                let mult = (1u128 << (32 + shift)) / u128::from(self.0);
+                u32::try_from(mult).ok().map(|mult| (mult, shift))
+            })
+            .unwrap_or((u32::MAX, 0))
+    }
+}
+
+/// The frequencies of the timers of a CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Calibration {
+    /// The frequency of the LAPIC timer, after the divider, in Hz.
+    pub lapic_hz: u64,
+
+    /// The frequency of the TSC, in Hz.
+    pub tsc_hz: u64,
+
+    /// The conversion factor between nanoseconds and LAPIC timer counts.
+    pub lapic: TicksPerNs,
+
+    /// The conversion factor between nanoseconds and TSC cycles.
+    pub tsc: TicksPerNs,
+}
+
+impl Calibration {
+    fn new(lapic_hz: u64, tsc_hz: u64) -> Self {
+        Self {
+            lapic_hz,
+            tsc_hz,
+            lapic: TicksPerNs::from_hz(lapic_hz),
+            tsc: TicksPerNs::from_hz(tsc_hz),
+        }
+    }
+}
+
+/// Select the timer mode and start the clock tick on the BSP. This must be called by the BSP once
+/// its LAPIC is enabled, and after the HPET has been set up if there is one.
+///
+/// The timer events are programmed with the TSC-deadline mode of the LAPIC timer when the CPU
+/// supports it, because the TSC has a far better resolution than the LAPIC timer. Otherwise, the
+/// LAPIC timer is used in one-shot mode. In both cases, the next clock tick is programmed by the
+/// clock tick itself. The TSC-deadline mode is then required on all the CPUs (see
+/// [`super::features::require`]).
+pub fn setup() {
+    let deadline = super::features::require(CpuFeatures::TSC_DEADLINE);
+    TSC_DEADLINE.store(deadline, Ordering::Relaxed);
+    log::debug!(
+        "Timer mode: {}",
+        if deadline { "TSC-deadline" } else { "one-shot" }
+    );
+
+    enable();
+}
+
+/// Calibrate the timers of the current CPU and start its clock tick: the LAPIC timer raises the
+/// [`CLOCK_TICK_VECTOR`] at [`KERNEL_HZ`] Hz.
+///
+/// # Panics
+/// Panics if the LAPIC timer is too slow or too fast to produce the clock tick.
+pub fn enable() {
+    let calibration = *CALIBRATION.local().call_once(calibrate);
+    assert!(
+        calibration.lapic_hz >= KERNEL_HZ,
+        "LAPIC timer too slow for the clock tick"
+    );
+    assert!(
+        u32::try_from(calibration.lapic_hz / KERNEL_HZ).is_ok(),
+        "LAPIC timer too fast for the clock tick"
+    );
+
+    let entry = LvtEntry::fixed(CLOCK_TICK_VECTOR);
+    if tsc_deadline() {
+        // The write to the LVT must be serialized before the first write to the deadline MSR,
+        // otherwise the deadline could be interpreted in the previous timer mode
+        lapic::set_lvt(Lvt::Timer, entry.with_timer_mode(TimerMode::TscDeadline));
+        super::barrier::mfence();
+    } else {
+        lapic::set_timer_divide(TimerDivide::By16);
+        lapic::set_lvt(Lvt::Timer, entry.with_timer_mode(TimerMode::OneShot));
+    }
+    NEXT_DEADLINE.store(timestamp() + calibration.tsc_hz / KERNEL_HZ);
+    program_next_event();
+}
+
+/// Stop the clock tick and the timer events of the current CPU, before it is parked (see
+/// [`super::smp::offline`]). They are restarted by [`enable`].
+pub fn disable() {
+    if tsc_deadline() {
+        write_deadline(0);
+    } else {
+        lapic::set_initial_count(0);
+    }
+    lapic::set_lvt(Lvt::Timer, LvtEntry::MASKED);
+}
+
+/// Returns the calibration of the timers of the current CPU.
+///
+/// # Panics
+/// Panics if the timers of the current CPU were not calibrated yet (see [`enable`]).
+#[must_use]
+pub fn calibration() -> Calibration {
+    *CALIBRATION
+        .local()
+        .get()
+        .expect("Timers not calibrated on this CPU")
+}
+
+/// Returns true if the timers of the current CPU are calibrated. They are never calibrated when ACPI
+/// is unusable, because the LAPIC is not set up (see [`super::pit::start_clock_tick`]).
+#[must_use]
+pub fn calibrated() -> bool {
+    CALIBRATION.local().get().is_some()
+}
+
+/// Returns true if the timer events are programmed with the TSC-deadline mode.
+#[must_use]
+pub fn tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Program a timer event on the current CPU in the given number of nanoseconds, replacing the
+/// event already programmed if any. The event raises the [`CLOCK_TICK_VECTOR`]. This is a low
+/// level function: the clock tick reprograms the next event when it fires, so the high-resolution
+/// timers (see [`crate::sys::time::hrtimer`]) should be used instead.
+pub fn program(nanoseconds: u64) {
+    let calibration = calibration();
+    if tsc_deadline() {
+        let delay = calibration.tsc.ticks(nanoseconds);
+        write_deadline(timestamp().saturating_add(delay.max(1)));
+    } else {
+        let counts = calibration.lapic.ticks(nanoseconds);
+        lapic::set_initial_count(u32::try_from(counts).unwrap_or(u32::MAX).max(1));
+    }
+}
+
+/// Called on each CPU when its LAPIC timer fires, either for the clock tick or for a
+/// high-resolution timer. The expired high-resolution timers are run, and the next event is
+/// programmed. Only the timekeeper advances the jiffies (see [`crate::sys::time::tick`]) and lets
+/// the scheduler wake up sleeping threads, but each CPU runs the scheduler tick for its own current
+/// thread. Returns true if the current thread should be preempted.
+#[must_use]
+pub fn tick() -> bool {
+    let next = NEXT_DEADLINE.local();
+    let now = timestamp();
+    let due = now >= next.load(Ordering::Relaxed);
+    if due {
+        // Skip the ticks that were missed instead of firing them all at once
+        let per_tick = calibration().tsc_hz / KERNEL_HZ;
+        let mut deadline = next.load(Ordering::Relaxed) + per_tick;
+        if deadline <= now {
+            deadline = now + per_tick;
+        }
+        next.store(deadline, Ordering::Relaxed);
+    }
+
+    crate::sys::time::hrtimer::expire();
+    program_next_event();
+    if !due {
+        return false;
+    }
+    let timekeeper = crate::sys::time::tick();
+    crate::sched::tick(timekeeper)
+}
+
+/// Program the next timer event of the current CPU: the next clock tick, or the first
+/// high-resolution timer of the CPU if it expires before.
+pub fn program_next_event() {
+    // Without the LAPIC timer, the high-resolution timers expire on the clock ticks of the PIT
+    if !calibrated() {
+        return;
+    }
+    let calibration = calibration();
+    let mut deadline = NEXT_DEADLINE.load();
+    if let Some(expires) = crate::sys::time::hrtimer::next_deadline() {
+        let delay = expires.saturating_duration_since(Instant::now());
+        let delay = u64::try_from(delay.as_nanos()).unwrap_or(u64::MAX);
+        deadline = deadline.min(timestamp().saturating_add(calibration.tsc.ticks(delay)));
+    }
+
+    if tsc_deadline() {
+        write_deadline(deadline.max(1));
+    } else {
+        let delay = deadline.saturating_sub(timestamp());
+        program(calibration.tsc.nanoseconds(delay));
+    }
+}
+
+/// Find the frequencies of the LAPIC timer and of the TSC of the current CPU. They are taken from
+/// the hypervisor if it reports them (see [`super::hypervisor::timing`]). Otherwise, they are
+/// measured against the kvmclock under KVM, against the HPET if there is one, or against the PIT.
+/// The LAPIC timer is left masked.
+fn calibrate() -> Calibration {
+    lapic::set_timer_divide(TimerDivide::By16);
+    lapic::set_lvt(Lvt::Timer, LvtEntry::MASKED);
+    let calibration = if let Some(timing) = super::hypervisor::timing() {
+        Calibration::new(timing.lapic_hz / LAPIC_DIVIDER, timing.tsc_hz)
+    } else {
+        let (counts, cycles) = if super::kvm::clock_available() {
+            measure_with_kvmclock()
+        } else if super::hpet::available() {
+            measure_with_hpet()
+        } else {
+            measure_with_pit()
+        };
+        lapic::set_initial_count(0);
+        Calibration::new(
+            counts * 1000 / CALIBRATION_MS,
+            cycles * 1000 / CALIBRATION_MS,
+        )
+    };
+    log::debug!(
+        "CPU {}: LAPIC timer at {} Hz, TSC at {} Hz",
+        super::smp::current_id(),
+        calibration.lapic_hz,
+        calibration.tsc_hz
+    );
+    calibration
+}
+
+/// Measure the number of LAPIC timer counts and TSC cycles elapsed during [`CALIBRATION_MS`]
+/// milliseconds of the kvmclock, which is far cheaper to read than the emulated HPET and PIT.
+fn measure_with_kvmclock() -> (u64, u64) {
+    let duration = CALIBRATION_MS * (NANOSECONDS_PER_SECOND / 1000);
+
+    let start = super::kvm::nanoseconds();
+    lapic::set_initial_count(u32::MAX);
+    let tsc = timestamp();
+    while super::kvm::nanoseconds().wrapping_sub(start) < duration {
+        core::hint::spin_loop();
+    }
+
+    let cycles = timestamp() - tsc;
+    let counts = u32::MAX - lapic::current_count();
+    (u64::from(counts), cycles)
+}
+
+/// Measure the number of LAPIC timer counts and TSC cycles elapsed during [`CALIBRATION_MS`]
+/// milliseconds of the HPET main counter. The main counter may be only 32 bits wide, so the elapsed
+/// ticks are masked to its width in case it wraps around during the measurement.
+fn measure_with_hpet() -> (u64, u64) {
+    let duration = CALIBRATION_MS * (super::hpet::FEMTOSECONDS_PER_SECOND / 1000);
+    let target = duration / super::hpet::period();
+    let mask = super::hpet::counter_mask();
+
+    let start = super::hpet::counter();
+    lapic::set_initial_count(u32::MAX);
+    let tsc = timestamp();
+    while super::hpet::counter().wrapping_sub(start) & mask < target {
+        core::hint::spin_loop();
+    }
+
+    let cycles = timestamp() - tsc;
+    let counts = u32::MAX - lapic::current_count();
+    (u64::from(counts), cycles)
+}
+
+/// Measure the number of LAPIC timer counts and TSC cycles elapsed during [`CALIBRATION_MS`]
+/// milliseconds, using the PIT channel 2 in one-shot mode as a reference.
+fn measure_with_pit() -> (u64, u64) {
+    // Start the PIT countdown, the LAPIC timer and the TSC measurement at the same time
+    let countdown = super::pit::Countdown::start(CALIBRATION_MS * 1000);
+    lapic::set_initial_count(u32::MAX);
+    let start = timestamp();
+    countdown.wait();
+
+    let cycles = timestamp() - start;
+    let counts = u32::MAX - lapic::current_count();
+    (u64::from(counts), cycles)
+}
+
+fn timestamp() -> u64 {
+    super::tsc::rdtsc()
+}
+
+/// Arm the timer in TSC-deadline mode to fire when the TSC reaches the given value.
+fn write_deadline(deadline: u64) {
+    super::msr::TscDeadline(deadline).write();
+}
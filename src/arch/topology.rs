@@ -0,0 +1,117 @@
+use super::cpuid::{self, Register};
+
+/// The CPUID leaves describing the processor topology: the V2 extended topology leaf, the extended
+/// topology leaf, and the legacy leaves giving the number of logical processors and of cores per
+/// package.
+const CPUID_FEATURES: u32 = 0x01;
+const CPUID_CACHE_PARAMETERS: u32 = 0x04;
+const CPUID_EXTENDED_TOPOLOGY: u32 = 0x0B;
+const CPUID_EXTENDED_TOPOLOGY_V2: u32 = 0x1F;
+
+/// Set in the EDX register of the leaf 1 if the logical processor count of the EBX register is
+/// valid.
+const CPUID_HTT: u32 = 1 << 28;
+
+/// The level types of the extended topology leaves. The levels between the core and the package
+/// (module, tile, die) are only reported by the leaf 0x1F, and are merged into the core id.
+const LEVEL_INVALID: u32 = 0;
+const LEVEL_SMT: u32 = 1;
+
+/// The position of a logical processor in the topology of the system, derived from its APIC id.
+/// Two logical processors with the same package and core ids are SMT siblings: they share the
+/// execution units of a physical core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuTopology {
+    pub apic_id: u32,
+
+    /// The id of the logical processor in its core, of the core in its package, and of the
+    /// package.
+    pub smt_id: u32,
+    pub core_id: u32,
+    pub package_id: u32,
+
+    /// The number of bits of the APIC id used by the SMT id, and by the SMT and core ids together.
+    pub smt_bits: u32,
+    pub core_bits: u32,
+}
+
+impl CpuTopology {
+    /// Returns true if the given logical processor is in the same physical core.
+    #[must_use]
+    pub fn same_core(&self, other: &Self) -> bool {
+        self.package_id == other.package_id && self.core_id == other.core_id
+    }
+}
+
+/// Detect the topology of the current logical processor with the extended topology leaves of
+/// CPUID (0x1F, then 0xB), or with the legacy leaves 1 and 4 if the processor does not have them.
+#[must_use]
+pub fn detect() -> CpuTopology {
+    let extended = [CPUID_EXTENDED_TOPOLOGY_V2, CPUID_EXTENDED_TOPOLOGY]
+        .into_iter()
+        .find_map(extended_topology);
+    let (apic_id, smt_bits, core_bits) = extended.unwrap_or_else(legacy_topology);
+
+    CpuTopology {
+        apic_id,
+        smt_id: apic_id & mask(smt_bits),
+        core_id: (apic_id & mask(core_bits)) >> smt_bits,
+        package_id: apic_id.checked_shr(core_bits).unwrap_or(0),
+        smt_bits,
+        core_bits,
+    }
+}
+
+/// Returns the x2APIC id, and the widths of the SMT and SMT-and-core fields of the id, from the
+/// given extended topology leaf. Returns `None` if the leaf is not supported: either the CPU does
+/// not have it, or its first sub-leaf reports no logical processor.
+fn extended_topology(leaf: u32) -> Option<(u32, u32, u32)> {
+    let first = cpuid::cpuid(leaf, 0)?;
+    if first.ebx.trailing_zeros() >= 16 {
+        return None;
+    }
+
+    // Each level gives the shift of the APIC id to get the id of the next level: the last one is
+    // the shift giving the package id
+    let mut smt_bits = 0;
+    let mut core_bits = 0;
+    for level in 0.. {
+        let kind = (cpuid::register(leaf, level, Register::Ecx) >> 8) & 0xFF;
+        if kind == LEVEL_INVALID {
+            break;
+        }
+        let shift = cpuid::register(leaf, level, Register::Eax) & 0x1F;
+        if kind == LEVEL_SMT {
+            smt_bits = shift;
+        }
+        core_bits = shift;
+    }
+    Some((first.edx, smt_bits, core_bits))
+}
+
+/// Returns the 8 bits APIC id, and the widths of the SMT and SMT-and-core fields of the id, from
+/// the leaf 1 and, on Intel processors, the leaf 4. Those leaves give the maximum number of ids
+/// reserved per package and per core, not the number of enabled processors.
+fn legacy_topology() -> (u32, u32, u32) {
+    let ebx = cpuid::register(CPUID_FEATURES, 0, Register::Ebx);
+    let apic_id = ebx >> 24;
+    let logical = if cpuid::features().1 & CPUID_HTT != 0 {
+        (ebx >> 16) & 0xFF
+    } else {
+        1
+    };
+    let cores = cpuid::cpuid(CPUID_CACHE_PARAMETERS, 0).map_or(1, |result| (result.eax >> 26) + 1);
+
+    let core_bits = bits(logical.max(1));
+    let smt_bits = bits((logical / cores).max(1));
+    (apic_id, smt_bits.min(core_bits), core_bits)
+}
+
+/// Returns the number of bits needed to number the given count of ids.
+fn bits(count: u32) -> u32 {
+    count.next_power_of_two().trailing_zeros()
+}
+
+fn mask(bits: u32) -> u32 {
+    1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1)
+}
@@ -0,0 +1,52 @@
+//! Reading the time stamp counter. `rdtsc` is not a serializing instruction: the CPU may execute it
+//! before the previous instructions complete, or after the next ones start, so the plain read is
+//! only suitable when a few cycles of error do not matter. The ordered and fenced variants bound
+//! the read with `lfence`, which does not let the later instructions start before the previous ones
+//! complete, to measure exactly the cycles spent by a piece of code.
+
+/// Read the TSC. The read may be reordered with the surrounding instructions.
+#[must_use]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Read the TSC once all the previous instructions have completed. The next instructions may
+/// still start before the read: this is the read to use at the start of a measure.
+#[must_use]
+pub fn rdtsc_ordered() -> u64 {
+    super::barrier::lfence();
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Read the TSC once all the previous instructions have completed, and before the next ones start.
+/// This is the read to use at the end of a measure, so that the measured code does not overlap
+/// with the code after it.
+#[must_use]
+pub fn rdtsc_fenced() -> u64 {
+    super::barrier::lfence();
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    super::barrier::lfence();
+    tsc
+}
+
+/// Read the TSC with `rdtscp`, once all the previous instructions have completed, with the value
+/// of the `IA32_TSC_AUX` register, set to the id of the CPU by the operating systems that use it.
+/// The next instructions may start before the read, as with [`rdtsc_ordered`]. The CPU must
+/// support the instruction (see [`super::features::CpuFeatures::RDTSCP`]).
+#[must_use]
+pub fn rdtscp() -> (u64, u32) {
+    let mut aux = 0;
+    let tsc = unsafe { core::arch::x86_64::__rdtscp(core::ptr::addr_of_mut!(aux)) };
+    (tsc, aux)
+}
+
+/// Convert a number of TSC cycles to nanoseconds, with the frequency of the TSC measured when the
+/// timers of the current CPU were calibrated.
+///
+/// # Panics
+/// Panics if the timers of the current CPU were not calibrated yet (see
+/// [`super::timer::calibrated`]).
+#[must_use]
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    super::timer::calibration().tsc.nanoseconds(cycles)
+}
@@ -1,20 +1,147 @@
-use crate::Spinlock;
-use x86_64::{cpu::Privilege, segment::Selector, tss::TaskStateSegment};
+use crate::{
+    mm::vmm::{self, AllocationFlags},
+    Spinlock,
+};
+use x86_64::{
+    cpu::Privilege, gdt::Descriptor, paging::PAGE_SIZE, segment::Selector, tss::TaskStateSegment,
+};
 
-const SELECTOR_BASE: usize = 6;
+/// The indexes in the Interrupt Stack Table of the stacks used by the critical exceptions. These
+/// exceptions always switch to their own stack, so that they can be reported even if the kernel
+/// stack is overflowed or corrupted.
+pub const DOUBLE_FAULT_IST: IstIndex = IstIndex(1);
+pub const NMI_IST: IstIndex = IstIndex(2);
+pub const MACHINE_CHECK_IST: IstIndex = IstIndex(3);
 
-#[thread_local]
-static TSS: Spinlock<TaskStateSegment> = Spinlock::new(TaskStateSegment::new());
+/// The size of each IST stack.
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+/// The offset of the RSP0 field in the TSS, as defined by the Intel manual.
+const RSP0_OFFSET: usize = 4;
+
+/// The offset of the IST1 field in the TSS, as defined by the Intel manual. The other IST fields
+/// follow it.
+const IST_OFFSET: usize = 0x24;
+
+/// An index in the Interrupt Stack Table, between 1 and 7. The index 0 is not a stack, but means
+/// that an interrupt does not switch to an IST stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IstIndex(u8);
+
+crate::per_cpu! {
+    /// The TSS of each CPU, which holds the stack used when an interrupt is raised in user mode and
+    /// the IST stacks.
+    static TSS: Spinlock<TaskStateSegment> = Spinlock::new(TaskStateSegment::new());
+}
+
+impl IstIndex {
+    /// Returns the given IST index, or `None` if it is not between 1 and 7.
+    #[must_use]
+    pub const fn new(index: u8) -> Option<Self> {
+        if index >= 1 && index <= 7 {
+            Some(Self(index))
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// Returns the offset of the field of this stack in the TSS.
+    const fn offset(self) -> usize {
+        IST_OFFSET + (self.0 as usize - 1) * 8
+    }
+}
+
+/// Returns the GDT descriptor of the TSS of the current CPU (see [`super::gdt::setup`]).
+#[must_use]
+pub fn descriptor() -> Descriptor {
+    Descriptor::tss(&TSS.local().lock())
+}
+
+/// Loads the TSS into the current CPU. This function must be called after the GDT of the current
+/// CPU is built with the descriptor of its TSS. The IST stacks of the current CPU are allocated
+/// here.
+pub fn install() {
+    for ist in [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST] {
+        let top = allocate_ist_stack();
+        set_ist(ist, top);
+        super::backtrace::set_ist_stack(ist, top - IST_STACK_SIZE as u64..top);
+    }
 
-/// Loads the TSS into the current CPU. This function must be called after the TSS
-/// is installed in the GDT.
-pub fn install(id: usize) {
     unsafe {
-        let index = SELECTOR_BASE + id * 2;
-        let selector = Selector::new(u16::try_from(index).unwrap(), Privilege::Ring0);
-        super::gdt::GDT
-            .lock()
-            .set_descriptor(index, &x86_64::gdt::Descriptor::tss(&TSS.lock()));
+        let selector = Selector::new(super::gdt::TSS_INDEX, Privilege::Ring0);
         x86_64::cpu::ltr(selector.value());
     }
 }
+
+/// Set the stack pointer loaded by the CPU when an interrupt occurs while the current CPU is
+/// running in user mode (the `RSP0` field of the TSS).
+pub fn set_kernel_stack(top: u64) {
+    write_field(RSP0_OFFSET, top);
+}
+
+/// Returns the stack pointer loaded by the CPU when an interrupt occurs while the current CPU is
+/// running in user mode.
+#[must_use]
+pub fn kernel_stack() -> u64 {
+    read_field(RSP0_OFFSET)
+}
+
+/// Set the stack pointer loaded by the CPU when an interrupt configured to use the given IST index
+/// (see [`super::idt::set_stack_index`]) occurs on the current CPU.
+pub fn set_ist(index: IstIndex, top: u64) {
+    write_field(index.offset(), top);
+}
+
+/// Returns the stack pointer loaded by the CPU when an interrupt configured to use the given IST
+/// index occurs on the current CPU.
+#[must_use]
+pub fn ist(index: IstIndex) -> u64 {
+    read_field(index.offset())
+}
+
+/// Write a stack pointer field of the TSS of the current CPU. The TSS is packed, so the fields are
+/// not aligned.
+#[allow(clippy::cast_ptr_alignment)]
+fn write_field(offset: usize, value: u64) {
+    let mut tss = TSS.local().lock();
+    unsafe {
+        let field = core::ptr::addr_of_mut!(*tss)
+            .cast::<u8>()
+            .add(offset)
+            .cast::<u64>();
+        field.write_unaligned(value);
+    }
+}
+
+#[allow(clippy::cast_ptr_alignment)]
+fn read_field(offset: usize) -> u64 {
+    let tss = TSS.local().lock();
+    unsafe {
+        core::ptr::addr_of!(*tss)
+            .cast::<u8>()
+            .add(offset)
+            .cast::<u64>()
+            .read_unaligned()
+    }
+}
+
+/// Allocate an IST stack and returns its top. The stack is populated immediately because the
+/// kernel memory is mapped on demand, and a page fault while switching to an IST stack would
+/// escalate to a triple fault.
+fn allocate_ist_stack() -> u64 {
+    let stack = vmm::allocate(IST_STACK_SIZE, AllocationFlags::MAP)
+        .expect("Failed to allocate an IST stack");
+    for page in (0..IST_STACK_SIZE).step_by(PAGE_SIZE) {
+        unsafe {
+            (stack.start() + page as u64)
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, PAGE_SIZE);
+        }
+    }
+    stack.end().as_u64()
+}
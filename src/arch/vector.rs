@@ -0,0 +1,317 @@
+//! The metadata of the CPU exception vectors, and the decoding of their error codes, so that an
+//! unhandled exception is reported with its name and what its error code means rather than with
+//! raw numbers (see [`Fault`] and [`Registers`]).
+use core::fmt;
+
+use x86_64::{cpu::State, paging::PageFaultErrorCode};
+
+use super::interrupt;
+
+/// The number of vectors reserved for the CPU exceptions.
+pub const EXCEPTION_COUNT: u8 = 32;
+
+/// The vectors whose error code is a segment selector.
+const INVALID_TSS: u8 = 10;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT_FAULT: u8 = 12;
+const GENERAL_PROTECTION_FAULT: u8 = 13;
+
+const PAGE_FAULT: u8 = 14;
+const CONTROL_PROTECTION: u8 = 21;
+
+/// How the CPU reports an exception, which tells where the saved instruction pointer points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExceptionKind {
+    /// The saved instruction pointer is the faulting instruction, which is restarted on return.
+    Fault,
+    /// The saved instruction pointer is the instruction after the one that trapped.
+    Trap,
+    /// The exception cannot be recovered from: the saved state may be inconsistent.
+    Abort,
+    /// An external interrupt delivered on an exception vector.
+    Interrupt,
+    Reserved,
+}
+
+/// The description of a CPU exception vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Exception {
+    /// The mnemonic of the exception in the Intel manuals (e.g. `#GP`), or an empty string if it
+    /// has none.
+    pub mnemonic: &'static str,
+    pub name: &'static str,
+    pub kind: ExceptionKind,
+}
+
+impl Exception {
+    const fn new(mnemonic: &'static str, name: &'static str, kind: ExceptionKind) -> Self {
+        Self {
+            mnemonic,
+            name,
+            kind,
+        }
+    }
+
+    const fn reserved() -> Self {
+        Self::new("", "Reserved exception", ExceptionKind::Reserved)
+    }
+}
+
+/// The description of each exception vector, indexed by vector number.
+pub const EXCEPTIONS: [Exception; EXCEPTION_COUNT as usize] = [
+    Exception::new("#DE", "Divide error", ExceptionKind::Fault),
+    Exception::new("#DB", "Debug exception", ExceptionKind::Trap),
+    Exception::new("NMI", "Non-maskable interrupt", ExceptionKind::Interrupt),
+    Exception::new("#BP", "Breakpoint", ExceptionKind::Trap),
+    Exception::new("#OF", "Overflow", ExceptionKind::Trap),
+    Exception::new("#BR", "Bound range exceeded", ExceptionKind::Fault),
+    Exception::new("#UD", "Invalid opcode", ExceptionKind::Fault),
+    Exception::new("#NM", "Device not available", ExceptionKind::Fault),
+    Exception::new("#DF", "Double fault", ExceptionKind::Abort),
+    Exception::new("", "Coprocessor segment overrun", ExceptionKind::Abort),
+    Exception::new("#TS", "Invalid TSS", ExceptionKind::Fault),
+    Exception::new("#NP", "Segment not present", ExceptionKind::Fault),
+    Exception::new("#SS", "Stack segment fault", ExceptionKind::Fault),
+    Exception::new("#GP", "General protection fault", ExceptionKind::Fault),
+    Exception::new("#PF", "Page fault", ExceptionKind::Fault),
+    Exception::reserved(),
+    Exception::new("#MF", "x87 floating point exception", ExceptionKind::Fault),
+    Exception::new("#AC", "Alignment check", ExceptionKind::Fault),
+    Exception::new("#MC", "Machine check", ExceptionKind::Abort),
+    Exception::new("#XM", "SIMD floating point exception", ExceptionKind::Fault),
+    Exception::new("#VE", "Virtualization exception", ExceptionKind::Fault),
+    Exception::new("#CP", "Control protection exception", ExceptionKind::Fault),
+    Exception::reserved(),
+    Exception::reserved(),
+    Exception::reserved(),
+    Exception::reserved(),
+    Exception::reserved(),
+    Exception::reserved(),
+    Exception::new(
+        "#HV",
+        "Hypervisor injection exception",
+        ExceptionKind::Fault,
+    ),
+    Exception::new("#VC", "VMM communication exception", ExceptionKind::Fault),
+    Exception::new("#SX", "Security exception", ExceptionKind::Fault),
+    Exception::reserved(),
+];
+
+/// The descriptor table referenced by a selector error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code of the exceptions caused by a segment selector or an IDT entry: the invalid
+/// TSS, segment not present, stack segment and general protection faults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SelectorError(u64);
+
+impl SelectorError {
+    #[must_use]
+    pub const fn new(code: u64) -> Self {
+        Self(code)
+    }
+
+    /// Returns true if the exception was caused by an event external to the program, such as an
+    /// interrupt delivered through a faulty IDT entry.
+    #[must_use]
+    pub const fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    #[must_use]
+    pub const fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0 => DescriptorTable::Gdt,
+            2 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Returns the index of the faulty entry in its table.
+    #[must_use]
+    pub const fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1FFF
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} entry {}", self.table(), self.index())?;
+        if self.external() {
+            write!(f, " (external event)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The error code of a page fault, which describes the access that faulted.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultError(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::WRITE_ACCESS) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a present page (protection violation)"
+        } else {
+            "a non-present page"
+        };
+        write!(f, "{mode} {access} {page}")?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table")?;
+        }
+        Ok(())
+    }
+}
+
+/// The decoded error code of an exception.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// The exception has no error code, or its error code is always zero.
+    None,
+    Selector(SelectorError),
+    PageFault(PageFaultError),
+    /// The cause of a control protection exception.
+    ControlProtection(&'static str),
+    /// An error code this module does not decode.
+    Raw(u64),
+}
+
+impl ErrorCode {
+    /// Decode the error code pushed by the CPU for the given vector.
+    #[must_use]
+    pub fn decode(vector: u8, code: u64) -> Self {
+        if !interrupt::has_error_code(i64::from(vector)) {
+            return Self::None;
+        }
+        match vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT
+                if code != 0 =>
+            {
+                Self::Selector(SelectorError::new(code))
+            }
+            PAGE_FAULT => {
+                Self::PageFault(PageFaultError(PageFaultErrorCode::from_bits_truncate(code)))
+            }
+            CONTROL_PROTECTION => Self::ControlProtection(match code & 0x7FFF {
+                1 => "near return to a different address than the shadow stack",
+                2 => "far return or iret to a different address than the shadow stack",
+                3 => "indirect branch to an instruction other than endbr",
+                4 => "rstorssp with an invalid shadow stack token",
+                5 => "setssbsy with an invalid shadow stack token",
+                _ => "unknown cause",
+            }),
+            _ if code == 0 => Self::None,
+            _ => Self::Raw(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => Ok(()),
+            Self::Selector(error) => write!(f, "{error}"),
+            Self::PageFault(error) => write!(f, "{error}"),
+            Self::ControlProtection(cause) => write!(f, "{cause}"),
+            Self::Raw(code) => write!(f, "error code {code:#x}"),
+        }
+    }
+}
+
+/// Returns the description of the given exception vector, or `None` if the vector is not an
+/// exception.
+#[must_use]
+pub fn exception(vector: u8) -> Option<&'static Exception> {
+    EXCEPTIONS.get(usize::from(vector))
+}
+
+/// Returns the name of the given vector: the name of the exception, or "Interrupt" for the other
+/// vectors.
+#[must_use]
+pub fn name(vector: u8) -> &'static str {
+    exception(vector).map_or("Interrupt", |exception| exception.name)
+}
+
+/// Formats the exception that interrupted the given state on a single line: its name, where it
+/// happened, and its decoded error code. For example: `General protection fault (#GP, vector 13)
+/// at 0xffffffff80012345 in kernel mode: Idt entry 13 (external event)`.
+pub struct Fault<'a>(pub &'a State);
+
+impl fmt::Display for Fault<'_> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.0;
+        let vector = state.number as u8;
+        write!(f, "{}", name(vector))?;
+        match exception(vector) {
+            Some(exception) if !exception.mnemonic.is_empty() => {
+                write!(f, " ({}, vector {vector})", exception.mnemonic)?;
+            }
+            _ => write!(f, " (vector {vector})")?,
+        }
+        let mode = if state.cs & 3 == 3 { "user" } else { "kernel" };
+        write!(f, " at {:#018x} in {mode} mode", state.rip)?;
+        match ErrorCode::decode(vector, state.code) {
+            ErrorCode::None => Ok(()),
+            error => write!(f, ": {error}"),
+        }
+    }
+}
+
+/// Formats the registers of the given state on several lines, four registers per line.
+pub struct Registers<'a>(pub &'a State);
+
+impl fmt::Display for Registers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.0;
+        let registers = [
+            ("rip", state.rip),
+            ("rsp", state.rsp),
+            ("rflags", state.rflags),
+            ("cs", state.cs),
+            ("rax", state.rax),
+            ("rbx", state.rbx),
+            ("rcx", state.rcx),
+            ("rdx", state.rdx),
+            ("rsi", state.rsi),
+            ("rdi", state.rdi),
+            ("rbp", state.rbp),
+            ("ss", state.ss),
+            ("r8", state.r8),
+            ("r9", state.r9),
+            ("r10", state.r10),
+            ("r11", state.r11),
+            ("r12", state.r12),
+            ("r13", state.r13),
+            ("r14", state.r14),
+            ("r15", state.r15),
+        ];
+        for (index, line) in registers.chunks(4).enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            for (name, value) in line {
+                write!(f, " {name:>6}={value:#018x}")?;
+            }
+        }
+        Ok(())
+    }
+}
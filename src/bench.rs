@@ -0,0 +1,218 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use alloc::sync::Arc;
+use log::{error, info};
+
+use crate::{
+    arch::smp,
+    config::MAX_CPU,
+    mm::space::{AddressSpace, Placement, Protection, Sharing},
+    sched::{self, thread::Thread, Switch},
+    sys::qemu::{self, ExitCode},
+};
+
+/// The number of round trips between the two threads of the voluntary context switch benchmark.
+const VOLUNTARY_ITERATIONS: u64 = 10_000;
+
+/// The number of clock ticks during which the preemption benchmark runs.
+const PREEMPTION_TICKS: u64 = 200;
+
+/// The number of user threads competing for the CPU during the preemption benchmark.
+const PREEMPTION_THREADS: usize = 2;
+
+/// The address where the spinning program of the preemption benchmark is loaded.
+const SPINNER_BASE: u64 = 0x40_0000;
+
+/// The spinning program of the preemption benchmark: `1: jmp 1b`.
+const SPINNER_CODE: [u8; 2] = [0xEB, 0xFE];
+
+/// The reference cost of a voluntary context switch and of a preemption in cycles, and the
+/// tolerated regression in percent. They are configured at build time with the environment
+/// variables `SILICIUM_BENCH_VOLUNTARY_BASELINE`, `SILICIUM_BENCH_PREEMPTION_BASELINE` and
+/// `SILICIUM_BENCH_TOLERANCE`. A missing baseline disables the gating of its benchmark.
+const VOLUNTARY_BASELINE: Option<&str> = option_env!("SILICIUM_BENCH_VOLUNTARY_BASELINE");
+const PREEMPTION_BASELINE: Option<&str> = option_env!("SILICIUM_BENCH_PREEMPTION_BASELINE");
+const TOLERANCE: Option<&str> = option_env!("SILICIUM_BENCH_TOLERANCE");
+
+/// The tolerated regression in percent when `SILICIUM_BENCH_TOLERANCE` is not set.
+const DEFAULT_TOLERANCE: u64 = 20;
+
+/// The cycles spent in the context switches of one kind on one CPU.
+struct Sample {
+    count: AtomicU64,
+    cycles: AtomicU64,
+}
+
+impl Sample {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+static VOLUNTARY: [Sample; MAX_CPU] = [const { Sample::new() }; MAX_CPU];
+static PREEMPTION: [Sample; MAX_CPU] = [const { Sample::new() }; MAX_CPU];
+
+/// The timestamp of the context switch in progress on each CPU, or 0 if there is none.
+static SWITCH_START: [AtomicU64; MAX_CPU] = [const { AtomicU64::new(0) }; MAX_CPU];
+
+/// The reason of the context switch in progress on each CPU.
+static SWITCH_REASON: [AtomicU8; MAX_CPU] = [const { AtomicU8::new(0) }; MAX_CPU];
+
+/// Set to stop the partner thread of the voluntary context switch benchmark.
+static DONE: AtomicBool = AtomicBool::new(false);
+
+/// Start the benchmarks in a kernel thread. They replace the init process when the kernel is built
+/// with the `bench` feature, and QEMU exits with a failure status if a regression is detected.
+pub fn spawn() {
+    sched::spawn(Thread::kernel(runner, 0, 0));
+}
+
+/// Called by the scheduler just before switching to another thread.
+pub fn before_switch(reason: Switch) {
+    let cpu = smp::current_id() as usize;
+    SWITCH_REASON[cpu].store(reason as u8, Ordering::Relaxed);
+    SWITCH_START[cpu].store(timestamp(), Ordering::Relaxed);
+}
+
+/// Called by the scheduler when a thread resumes after a context switch. Threads that run for the
+/// first time do not return from a context switch, so the switches to them are not measured.
+pub fn after_switch() {
+    let cpu = smp::current_id() as usize;
+    let start = SWITCH_START[cpu].swap(0, Ordering::Relaxed);
+    if start == 0 {
+        return;
+    }
+
+    let sample = if SWITCH_REASON[cpu].load(Ordering::Relaxed) == Switch::Preemption as u8 {
+        &PREEMPTION[cpu]
+    } else {
+        &VOLUNTARY[cpu]
+    };
+    sample.count.fetch_add(1, Ordering::Relaxed);
+    sample
+        .cycles
+        .fetch_add(timestamp().saturating_sub(start), Ordering::Relaxed);
+}
+
+/// The entry point of the benchmark thread.
+unsafe extern "C" fn runner(_: u64, _: u64) -> ! {
+    let voluntary = voluntary();
+    let preemption = preemption();
+
+    let tolerance = TOLERANCE
+        .and_then(|tolerance| tolerance.parse().ok())
+        .unwrap_or(DEFAULT_TOLERANCE);
+    let mut success = true;
+    success &= check("voluntary_switch", voluntary, VOLUNTARY_BASELINE, tolerance);
+    success &= check("preemption", preemption, PREEMPTION_BASELINE, tolerance);
+
+    info!("bench: result={}", if success { "pass" } else { "fail" });
+    let code = if success {
+        ExitCode::Success
+    } else {
+        ExitCode::Failure
+    };
+    qemu::exit(code.into());
+}
+
+/// Measure the cost of a voluntary context switch by bouncing between two kernel threads. Returns
+/// the average number of cycles per switch on all CPUs.
+fn voluntary() -> Option<u64> {
+    VOLUNTARY.iter().for_each(Sample::reset);
+    sched::spawn(Thread::kernel(partner, 0, 0));
+    for _ in 0..VOLUNTARY_ITERATIONS {
+        sched::schedule();
+    }
+    DONE.store(true, Ordering::Relaxed);
+    report("voluntary_switch", &VOLUNTARY)
+}
+
+/// The partner of the voluntary context switch benchmark, which gives the CPU back immediately.
+unsafe extern "C" fn partner(_: u64, _: u64) -> ! {
+    while !DONE.load(Ordering::Relaxed) {
+        sched::schedule();
+    }
+    sched::exit();
+}
+
+/// Measure the cost of a preemption by the clock tick between user threads that never give up the
+/// CPU. Returns the average number of cycles per preemption on all CPUs.
+///
+/// The spinning threads cannot be killed yet and keep running after the benchmark: this is fine
+/// because the benchmarks are the last thing the kernel does before QEMU exits.
+fn preemption() -> Option<u64> {
+    for _ in 0..PREEMPTION_THREADS {
+        let space = AddressSpace::new();
+        space
+            .map(
+                Placement::Fixed(SPINNER_BASE),
+                SPINNER_CODE.len() as u64,
+                Protection::READ | Protection::EXEC,
+                Sharing::Private,
+                None,
+            )
+            .expect("Failed to map the spinning program");
+        space
+            .write(SPINNER_BASE, &SPINNER_CODE)
+            .expect("Failed to load the spinning program");
+        sched::spawn(Thread::user(Arc::new(space), SPINNER_BASE, 0));
+    }
+
+    PREEMPTION.iter().for_each(Sample::reset);
+    sched::sleep(PREEMPTION_TICKS);
+    report("preemption", &PREEMPTION)
+}
+
+/// Print the results of a benchmark for each CPU and for the whole system, one result per line,
+/// and returns the average number of cycles per switch on all CPUs.
+fn report(name: &str, samples: &[Sample; MAX_CPU]) -> Option<u64> {
+    let mut total_count = 0;
+    let mut total_cycles = 0;
+    for (cpu, sample) in samples.iter().enumerate() {
+        let count = sample.count.load(Ordering::Relaxed);
+        let cycles = sample.cycles.load(Ordering::Relaxed);
+        if let Some(average) = cycles.checked_div(count) {
+            info!("bench: name={name} cpu={cpu} samples={count} cycles={average}");
+        }
+        total_count += count;
+        total_cycles += cycles;
+    }
+
+    let average = total_cycles.checked_div(total_count);
+    info!(
+        "bench: name={name} cpu=all samples={total_count} cycles={}",
+        average.unwrap_or(0)
+    );
+    average
+}
+
+/// Compare the result of a benchmark with its baseline. Returns false if the benchmark did not
+/// produce any result or if it regressed by more than the given tolerance, in percent.
+fn check(name: &str, average: Option<u64>, baseline: Option<&str>, tolerance: u64) -> bool {
+    let Some(average) = average else {
+        error!("bench: name={name} no sample collected");
+        return false;
+    };
+    let Some(baseline) = baseline.and_then(|baseline| baseline.parse::<u64>().ok()) else {
+        return true;
+    };
+
+    let limit = baseline * (100 + tolerance) / 100;
+    if average > limit {
+        error!("bench: name={name} regression cycles={average} baseline={baseline} limit={limit}");
+        return false;
+    }
+    true
+}
+
+fn timestamp() -> u64 {
+    crate::arch::tsc::rdtsc_ordered()
+}
@@ -1,6 +1,31 @@
+use core::time::Duration;
+
 /// Determines the maximum number of CPUs that can be used by the kernel. If more CPUs are detected,
 /// the kernel will panic. The limit is a little arbitrary, but it is set to 32 to avoid using too
 /// much memory for per-cpu data, and should be enough for most use cases.
 pub const MAX_CPU: usize = 32;
 pub const IRQ_BASE: u8 = 32;
 pub const KERNEL_HZ: u64 = 100;
+
+/// The size of the stack given by the bootloader to each CPU, which is also the stack of its idle
+/// thread. This is absolutely humongous, but it may be required for unoptimized debug builds.
+pub const BOOT_STACK_SIZE: u64 = 128 * 1024;
+
+/// The baud rate of the serial port used by the logs and the console. It must divide 115200.
+pub const SERIAL_BAUD: u32 = 115_200;
+
+/// The size of the ramdisk carved from the physical memory at boot, in bytes. It is rounded up to
+/// a whole number of pages, and no ramdisk is created if it is zero.
+pub const RAMDISK_SIZE: usize = 8 * 1024 * 1024;
+
+/// The timeout of the hardware watchdog, if there is one: the system is reset if the watchdog is
+/// not pinged for this long, which happens when the kernel hangs.
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of records kept by the kernel log ring buffer (see [`crate::log::read_since`]): the
+/// oldest records are overwritten once it is full.
+pub const LOG_RING_RECORDS: usize = 512;
+
+/// The maximum length of the text of a record in the kernel log ring buffer, in bytes. The longer
+/// records are truncated.
+pub const LOG_RECORD_SIZE: usize = 224;
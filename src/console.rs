@@ -0,0 +1,179 @@
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::{
+    sched::{self, thread::Tid},
+    Spinlock,
+};
+
+/// The maximum number of output sinks that can be registered.
+const MAX_SINKS: usize = 4;
+
+/// The maximum length of the line being edited. The bytes typed beyond are ignored.
+const LINE_CAPACITY: usize = 256;
+
+/// The maximum number of bytes of complete lines waiting to be read. A line that does not fit is
+/// dropped.
+const INPUT_CAPACITY: usize = 4096;
+
+/// The registered output sinks.
+static SINKS: Spinlock<[Option<&'static dyn Sink>; MAX_SINKS]> = Spinlock::new([None; MAX_SINKS]);
+
+/// The input of the console, shared by all the input devices.
+static INPUT: Spinlock<Input> = Spinlock::new(Input::new());
+
+/// The sink writing the output of the console to the serial port.
+static SERIAL: SerialSink = SerialSink;
+
+/// An output device of the console.
+pub trait Sink: Sync {
+    /// A short name of the sink, used in the logs.
+    fn name(&self) -> &'static str;
+
+    /// Write the given string to the device. This may be called from an interrupt handler, to
+    /// echo the input, so it must not sleep.
+    fn write(&self, s: &str);
+}
+
+struct SerialSink;
+
+impl Sink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write(&self, s: &str) {
+        crate::log::write(s);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsoleError {
+    /// [`MAX_SINKS`] sinks are already registered.
+    TooManySinks,
+}
+
+/// The line discipline of the console: the input is edited line by line, and a line can only be
+/// read once it is complete.
+struct Input {
+    /// The line being edited.
+    line: Vec<u8>,
+
+    /// The complete lines, each ending with a newline, waiting to be read.
+    ready: VecDeque<u8>,
+
+    /// The threads waiting in [`read`] for a complete line.
+    readers: Vec<Tid>,
+}
+
+impl Input {
+    const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            readers: Vec::new(),
+        }
+    }
+}
+
+/// Initialize the console, with the serial port as its only output sink.
+pub fn setup() {
+    register_sink(&SERIAL).expect("No room for the serial console sink");
+}
+
+/// Register an output sink: all the output of the console, including the echo of the input, is
+/// written to it.
+///
+/// # Errors
+/// - [`ConsoleError::TooManySinks`]: [`MAX_SINKS`] sinks are already registered.
+pub fn register_sink(sink: &'static dyn Sink) -> Result<(), ConsoleError> {
+    x86_64::irq::without(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ConsoleError::TooManySinks)?;
+        *slot = Some(sink);
+        Ok(())
+    })?;
+    log::debug!("Console sink {} registered", sink.name());
+    Ok(())
+}
+
+/// Write the given string to all the output sinks.
+pub fn write(s: &str) {
+    let sinks = x86_64::irq::without(|| *SINKS.lock());
+    for sink in sinks.iter().flatten() {
+        sink.write(s);
+    }
+}
+
+/// Give a byte received by an input device (the serial port, the keyboard...) to the console. The
+/// byte is echoed to the output sinks, and the threads waiting in [`read`] are woken up when a
+/// line is complete. Backspace and delete erase the last byte of the line, and the other control
+/// characters are ignored. This is called from the interrupt handlers of the input devices.
+pub fn input(byte: u8) {
+    let printable = byte.is_ascii_graphic() || byte == b' ';
+    let (echo, readers) = x86_64::irq::without(|| {
+        let mut input = INPUT.lock();
+        match byte {
+            b'\r' | b'\n' => {
+                let mut line = core::mem::take(&mut input.line);
+                line.push(b'\n');
+                if input.ready.len() + line.len() <= INPUT_CAPACITY {
+                    input.ready.extend(line);
+                }
+                (Some(b'\n'), core::mem::take(&mut input.readers))
+            }
+            0x08 | 0x7F if input.line.pop().is_some() => (Some(0x08), Vec::new()),
+            byte if printable && input.line.len() < LINE_CAPACITY => {
+                input.line.push(byte);
+                (Some(byte), Vec::new())
+            }
+            _ => (None, Vec::new()),
+        }
+    });
+
+    match echo {
+        // Move back, erase the last character, and move back again
+        Some(0x08) => write("\x08 \x08"),
+        Some(byte) => write(char::from(byte).encode_utf8(&mut [0; 4])),
+        None => (),
+    }
+    for tid in readers {
+        sched::wake(tid);
+    }
+}
+
+/// Read the input of the console into the given buffer, and returns the number of bytes read.
+/// The current thread is blocked until a complete line is available: at most one line is returned,
+/// including its newline, and the rest of a line longer than the buffer is returned by the next
+/// reads.
+pub fn read(buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+
+    let tid = sched::current_tid();
+    loop {
+        let read = x86_64::irq::without(|| {
+            let mut input = INPUT.lock();
+            let Some(end) = input.ready.iter().position(|&byte| byte == b'\n') else {
+                if !input.readers.contains(&tid) {
+                    input.readers.push(tid);
+                }
+                return None;
+            };
+
+            let len = core::cmp::min(end + 1, buffer.len());
+            for (dst, src) in buffer.iter_mut().zip(input.ready.drain(..len)) {
+                *dst = src;
+            }
+            Some(len)
+        });
+
+        match read {
+            Some(len) => return len,
+            None => sched::block(),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+
+use crate::{
+    sched::{self, thread::Tid},
+    Spinlock,
+};
+
+pub mod ramdisk;
+
+/// The registered block devices. A device is never unregistered, so the disks can be shared as
+/// static references.
+static DISKS: Spinlock<Vec<&'static Disk>> = Spinlock::new(Vec::new());
+
+/// Set by the panic hook of the block layer: no request is started once the queues are frozen, so
+/// that no write is half-applied while the system is halted.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// The function called when a request completes, with the request (and its buffer) and its result.
+/// It may be called from an interrupt handler, so it must not sleep.
+pub type Completion = fn(request: Request, result: Result<(), BlockError>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockError {
+    /// The range of blocks is empty or extends beyond the end of the device.
+    OutOfRange,
+
+    /// The size of the buffer does not match the number of blocks of the request.
+    InvalidBuffer,
+
+    /// The device failed to transfer the data.
+    Io,
+
+    /// The queues are frozen because the kernel panicked.
+    Frozen,
+
+    /// A device with the same name is already registered.
+    AlreadyRegistered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// A transfer of a range of consecutive blocks between a device and a buffer.
+#[derive(Debug)]
+pub struct Request {
+    pub operation: Operation,
+
+    /// The index of the first block of the range.
+    pub block: u64,
+
+    /// The number of blocks of the range.
+    pub count: u64,
+
+    /// The data to write, or the buffer receiving the data read. Its size must be the number of
+    /// blocks times the block size of the device.
+    pub buffer: Vec<u8>,
+
+    /// The function called when the request completes, and an argument given back to it.
+    pub completion: Completion,
+    pub data: u64,
+}
+
+/// A device storing data in fixed-size blocks. The driver only has to execute the requests one by
+/// one: the block layer queues them and checks them before they are started (see [`Disk`]).
+pub trait BlockDevice: Sync {
+    /// The name of the device, which must be unique.
+    fn name(&self) -> &'static str;
+
+    /// The size of a block, in bytes.
+    fn block_size(&self) -> usize;
+
+    /// The number of blocks of the device.
+    fn capacity(&self) -> u64;
+
+    /// Start the given request. The range of the request is within the device and its buffer has
+    /// the right size. The driver must call [`Disk::complete`] on the given disk once the request
+    /// is done, either before returning or later, for example from its interrupt handler. Only
+    /// one request is started at a time.
+    fn start(&self, disk: &'static Disk, request: Request);
+}
+
+/// A registered block device and its request queue.
+pub struct Disk {
+    device: &'static dyn BlockDevice,
+    queue: Spinlock<Queue>,
+}
+
+struct Queue {
+    /// The requests waiting to be started.
+    pending: VecDeque<Request>,
+
+    /// Set while a request is started and not completed yet.
+    busy: bool,
+
+    /// Set while [`Disk::dispatch`] is starting requests. A request completed during its start is
+    /// not followed by a nested dispatch, so that a synchronous driver does not recurse once per
+    /// queued request.
+    dispatching: bool,
+}
+
+impl Disk {
+    /// Returns the name of the device.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.device.name()
+    }
+
+    /// Returns the size of a block of the device, in bytes.
+    #[must_use]
+    pub fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    /// Returns the number of blocks of the device.
+    #[must_use]
+    pub fn capacity(&self) -> u64 {
+        self.device.capacity()
+    }
+
+    /// Queue a request, which will be started once the requests queued before it are completed.
+    /// An invalid request is completed immediately with an error.
+    pub fn submit(&'static self, request: Request) {
+        if let Err(error) = self.check(&request) {
+            (request.completion)(request, Err(error));
+            return;
+        }
+        x86_64::irq::without(|| self.queue.lock().pending.push_back(request));
+        self.dispatch();
+    }
+
+    /// Complete the request started by the driver, and start the next queued request. This must
+    /// be called by the driver for each request given to [`BlockDevice::start`].
+    pub fn complete(&'static self, request: Request, result: Result<(), BlockError>) {
+        let dispatching = x86_64::irq::without(|| {
+            let mut queue = self.queue.lock();
+            queue.busy = false;
+            queue.dispatching
+        });
+        (request.completion)(request, result);
+        if !dispatching {
+            self.dispatch();
+        }
+    }
+
+    /// Read `count` blocks starting at the given block, and wait for the data.
+    ///
+    /// # Errors
+    /// Any error of the request (see [`BlockError`]).
+    pub fn read(&'static self, block: u64, count: u64) -> Result<Vec<u8>, BlockError> {
+        let size = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(self.block_size()))
+            .ok_or(BlockError::OutOfRange)?;
+        let (request, result) = self.wait(Operation::Read, block, count, vec![0; size]);
+        result.map(|()| request.buffer)
+    }
+
+    /// Write the given buffer, whose size must be a multiple of the block size, starting at the
+    /// given block, and wait until it is written.
+    ///
+    /// # Errors
+    /// Any error of the request (see [`BlockError`]).
+    pub fn write(&'static self, block: u64, buffer: Vec<u8>) -> Result<(), BlockError> {
+        let count = buffer.len() / self.block_size();
+        if count * self.block_size() != buffer.len() {
+            return Err(BlockError::InvalidBuffer);
+        }
+        self.wait(Operation::Write, block, count as u64, buffer).1
+    }
+
+    /// Submit a request and block the current thread until it completes.
+    fn wait(
+        &'static self,
+        operation: Operation,
+        block: u64,
+        count: u64,
+        buffer: Vec<u8>,
+    ) -> (Request, Result<(), BlockError>) {
+        let waiter = Waiter {
+            tid: sched::current_tid(),
+            done: Spinlock::new(None),
+        };
+        self.submit(Request {
+            operation,
+            block,
+            count,
+            buffer,
+            completion: Waiter::complete,
+            data: core::ptr::addr_of!(waiter) as u64,
+        });
+
+        loop {
+            if let Some(done) = x86_64::irq::without(|| waiter.done.lock().take()) {
+                return done;
+            }
+            sched::block();
+        }
+    }
+
+    fn check(&self, request: &Request) -> Result<(), BlockError> {
+        if FROZEN.load(Ordering::Relaxed) {
+            return Err(BlockError::Frozen);
+        }
+        let end = request.block.checked_add(request.count);
+        if request.count == 0 || !matches!(end, Some(end) if end <= self.capacity()) {
+            return Err(BlockError::OutOfRange);
+        }
+        let size = usize::try_from(request.count)
+            .ok()
+            .and_then(|count| count.checked_mul(self.block_size()));
+        if size != Some(request.buffer.len()) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        Ok(())
+    }
+
+    /// Start the queued requests one after the other, until the device is busy with a request that
+    /// does not complete immediately or the queue is empty.
+    fn dispatch(&'static self) {
+        loop {
+            let next = x86_64::irq::without(|| {
+                let mut queue = self.queue.lock();
+                let next = if queue.busy || FROZEN.load(Ordering::Relaxed) {
+                    None
+                } else {
+                    queue.pending.pop_front()
+                };
+                // The device may still be busy with a request started by a previous dispatch
+                if next.is_some() {
+                    queue.busy = true;
+                    queue.dispatching = true;
+                } else {
+                    queue.dispatching = false;
+                }
+                next
+            });
+            let Some(request) = next else {
+                return;
+            };
+            self.device.start(self, request);
+        }
+    }
+}
+
+/// A thread waiting for the completion of a request, pointed to by the data of the request.
+struct Waiter {
+    tid: Tid,
+    done: Spinlock<Option<(Request, Result<(), BlockError>)>>,
+}
+
+impl Waiter {
+    fn complete(request: Request, result: Result<(), BlockError>) {
+        // The waiter may be gone as soon as the result is stored
+        let waiter = unsafe { &*(request.data as *const Waiter) };
+        let tid = waiter.tid;
+        x86_64::irq::without(|| *waiter.done.lock() = Some((request, result)));
+        sched::wake(tid);
+    }
+}
+
+/// Initialize the block layer. The request queues are frozen if the kernel panics.
+pub fn setup() {
+    crate::glue::register_panic_hook(freeze);
+}
+
+/// Register a block device, and returns its disk.
+///
+/// # Errors
+/// - [`BlockError::AlreadyRegistered`]: A device with the same name is already registered.
+pub fn register(device: &'static dyn BlockDevice) -> Result<&'static Disk, BlockError> {
+    let disk = x86_64::irq::without(|| {
+        let mut disks = DISKS.lock();
+        if disks.iter().any(|disk| disk.name() == device.name()) {
+            return Err(BlockError::AlreadyRegistered);
+        }
+        let disk: &'static Disk = Box::leak(Box::new(Disk {
+            device,
+            queue: Spinlock::new(Queue {
+                pending: VecDeque::new(),
+                busy: false,
+                dispatching: false,
+            }),
+        }));
+        disks.push(disk);
+        Ok(disk)
+    })?;
+
+    log::info!(
+        "Block device {}: {} blocks of {} bytes",
+        device.name(),
+        device.capacity(),
+        device.block_size()
+    );
+    Ok(disk)
+}
+
+/// Returns the disk of the block device with the given name, if any.
+#[must_use]
+pub fn lookup(name: &str) -> Option<&'static Disk> {
+    x86_64::irq::without(|| {
+        DISKS
+            .lock()
+            .iter()
+            .find(|disk| disk.name() == name)
+            .copied()
+    })
+}
+
+/// Returns the disks of all the registered block devices.
+#[must_use]
+pub fn disks() -> Vec<&'static Disk> {
+    x86_64::irq::without(|| DISKS.lock().clone())
+}
+
+/// Freeze the request queues: the requests already started may complete, but no other request is
+/// started and the new requests fail with [`BlockError::Frozen`]. This is the panic hook of the
+/// block layer, and is also called when the system is shut down.
+pub fn freeze() {
+    FROZEN.store(true, Ordering::Relaxed);
+}
@@ -0,0 +1,118 @@
+use alloc::{boxed::Box, format};
+
+use crate::{
+    arch::address::phys_to_virt,
+    config::RAMDISK_SIZE,
+    mm::{
+        frame::{AllocationFlags, Allocator},
+        FRAME_ALLOCATOR,
+    },
+};
+
+use super::{BlockDevice, Disk, Operation, Request};
+
+/// The size of a block of a ramdisk. It is the usual size of a disk sector, so that the
+/// filesystems see the same geometry as on a real disk.
+const BLOCK_SIZE: usize = 512;
+
+/// A block device whose blocks are stored in memory. Its content is lost when the system is
+/// halted.
+pub struct Ramdisk {
+    name: &'static str,
+    base: *mut u8,
+    blocks: u64,
+}
+
+/// The memory of a ramdisk is only accessed by the request started by the block layer, one at a
+/// time.
+unsafe impl Sync for Ramdisk {}
+unsafe impl Send for Ramdisk {}
+
+impl Ramdisk {
+    /// Create a ramdisk stored in the given memory. The trailing bytes that do not fill a whole
+    /// block are not used.
+    ///
+    /// # Safety
+    /// The memory must be valid for reads and writes, and must not be used by anything else for
+    /// as long as the ramdisk exists.
+    #[must_use]
+    pub unsafe fn new(name: &'static str, base: *mut u8, size: usize) -> Self {
+        Self {
+            name,
+            base,
+            blocks: (size / BLOCK_SIZE) as u64,
+        }
+    }
+}
+
+impl BlockDevice for Ramdisk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        self.blocks
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn start(&self, disk: &'static Disk, mut request: Request) {
+        // The range of the request is within the ramdisk, which fits in memory
+        let offset = request.block as usize * BLOCK_SIZE;
+        let len = request.buffer.len();
+        unsafe {
+            let data = self.base.add(offset);
+            match request.operation {
+                Operation::Read => {
+                    core::ptr::copy_nonoverlapping(data, request.buffer.as_mut_ptr(), len);
+                }
+                Operation::Write => {
+                    core::ptr::copy_nonoverlapping(request.buffer.as_ptr(), data, len);
+                }
+            }
+        }
+        disk.complete(request, Ok(()));
+    }
+}
+
+/// Create the ramdisks: `ram0`, of [`RAMDISK_SIZE`] bytes carved from the physical memory, and
+/// one ramdisk per module loaded by Limine, named `module0`, `module1`..., whose content is the
+/// module. The ramdisks of the modules are writable, but the writes are not saved anywhere.
+pub fn setup() {
+    if RAMDISK_SIZE > 0 {
+        let count = RAMDISK_SIZE.div_ceil(x86_64::paging::PAGE_SIZE);
+        let flags = AllocationFlags::KERNEL | AllocationFlags::ZEROED;
+        let range =
+            x86_64::irq::without(|| unsafe { FRAME_ALLOCATOR.lock().allocate_range(count, flags) });
+        if let Some(range) = range {
+            let base = phys_to_virt(range.start.start()).as_mut_ptr::<u8>();
+            let size = count * x86_64::paging::PAGE_SIZE;
+            register(unsafe { Ramdisk::new("ram0", base, size) });
+        } else {
+            log::warn!("Not enough contiguous memory for a ramdisk of {RAMDISK_SIZE} bytes");
+        }
+    }
+
+    let Some(response) = crate::LIMINE_MODULES.get_response().get() else {
+        return;
+    };
+    for (index, module) in response.modules().iter().enumerate() {
+        let Some(base) = module.base.as_ptr() else {
+            continue;
+        };
+        let name: &'static str = Box::leak(format!("module{index}").into_boxed_str());
+        #[allow(clippy::cast_possible_truncation)]
+        register(unsafe { Ramdisk::new(name, base, module.length as usize) });
+    }
+}
+
+/// Register the given ramdisk in the block layer. The ramdisks are never removed.
+fn register(ramdisk: Ramdisk) {
+    let ramdisk: &'static Ramdisk = Box::leak(Box::new(ramdisk));
+    if let Err(error) = super::register(ramdisk) {
+        log::warn!("Failed to register the ramdisk {}: {error:?}", ramdisk.name);
+    }
+}
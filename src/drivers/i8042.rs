@@ -0,0 +1,331 @@
+use core::time::Duration;
+
+use crate::{
+    arch::{
+        irq::{self, IrqFlags, IrqReturn},
+        port::{self, ReadOnlyPort, WriteOnlyPort},
+    },
+    sys::time::Instant,
+    Spinlock,
+};
+
+/// The data port, and the status (read) and command (write) port of the controller.
+const DATA: port::Port<u8> = port::Port::new(0x60);
+const STATUS: ReadOnlyPort<u8> = ReadOnlyPort::new(0x64);
+const COMMAND: WriteOnlyPort<u8> = WriteOnlyPort::new(0x64);
+
+/// The bits of the status register: a byte can be read from the data port, and the controller has
+/// not consumed the last byte written to it yet.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// The commands of the controller.
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND: u8 = 0xA7;
+const COMMAND_ENABLE_SECOND: u8 = 0xA8;
+const COMMAND_TEST_SECOND: u8 = 0xA9;
+const COMMAND_SELF_TEST: u8 = 0xAA;
+const COMMAND_TEST_FIRST: u8 = 0xAB;
+const COMMAND_DISABLE_FIRST: u8 = 0xAD;
+const COMMAND_ENABLE_FIRST: u8 = 0xAE;
+const COMMAND_WRITE_SECOND: u8 = 0xD4;
+
+/// The answers of the controller to its self test and to the test of a port.
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// The bits of the configuration byte.
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Translate the scan codes of the keyboard to the scan code set 1. The keyboard driver then
+/// only has to decode this set, whatever the set used by the keyboard.
+const TRANSLATION: bool = true;
+
+/// The time given to the controller to accept or produce a byte.
+const TIMEOUT: Duration = Duration::from_millis(10);
+
+/// The maximum number of stale bytes drained from the output buffer.
+const DRAIN_LIMIT: usize = 64;
+
+/// The legacy IRQ lines of the two ports.
+const FIRST_IRQ: u8 = 1;
+const SECOND_IRQ: u8 = 12;
+
+static CONTROLLER: Spinlock<Controller> = Spinlock::new(Controller {
+    present: [false; 2],
+    receivers: [None; 2],
+});
+
+/// The function receiving the bytes sent by the device of a port. It is called from the
+/// interrupt handler of the port.
+pub type Receiver = fn(byte: u8);
+
+/// A port of the controller: the first one is usually connected to the keyboard, and the second
+/// one, which not all controllers have, to the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Port {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum I8042Error {
+    /// There is no controller.
+    NoController,
+
+    /// The controller did not accept or produce a byte in time.
+    Timeout,
+
+    /// The controller failed its self test.
+    SelfTestFailed,
+
+    /// The port does not exist or failed its test.
+    NoSuchPort,
+
+    /// A receiver is already attached to the port.
+    Busy,
+
+    /// The IRQ line of the port could not be requested.
+    Irq(irq::IrqError),
+}
+
+struct Controller {
+    /// The ports that exist and passed their test.
+    present: [bool; 2],
+
+    /// The receivers attached to the ports.
+    receivers: [Option<Receiver>; 2],
+}
+
+impl Port {
+    const fn index(self) -> usize {
+        match self {
+            Self::First => 0,
+            Self::Second => 1,
+        }
+    }
+
+    const fn irq(self) -> u8 {
+        match self {
+            Self::First => FIRST_IRQ,
+            Self::Second => SECOND_IRQ,
+        }
+    }
+
+    const fn config_irq(self) -> u8 {
+        match self {
+            Self::First => CONFIG_FIRST_IRQ,
+            Self::Second => CONFIG_SECOND_IRQ,
+        }
+    }
+}
+
+/// Initialize the controller, without relying on the state the firmware left it in: the ports are
+/// disabled and the stale bytes drained, the controller and its ports are tested, and the ports
+/// that work are enabled again with their interrupts disabled until a driver attaches to them
+/// (see [`attach`]). Does nothing if there is no controller.
+pub fn setup() {
+    match x86_64::irq::without(|| CONTROLLER.lock().init()) {
+        Ok(present) => log::info!(
+            "i8042: first port {}, second port {}",
+            if present[0] { "present" } else { "absent" },
+            if present[1] { "present" } else { "absent" }
+        ),
+        Err(error) => log::info!("No usable i8042 controller: {error:?}"),
+    }
+}
+
+/// Returns true if the given port exists and works.
+#[must_use]
+pub fn present(port: Port) -> bool {
+    x86_64::irq::without(|| CONTROLLER.lock().present[port.index()])
+}
+
+/// Attach a receiver to the given port: the interrupt of the port is enabled, and each byte sent
+/// by its device is given to the receiver.
+///
+/// # Errors
+/// - [`I8042Error::NoSuchPort`]: The port does not exist or does not work.
+/// - [`I8042Error::Busy`]: A receiver is already attached to the port.
+/// - [`I8042Error::Irq`]: The IRQ line of the port could not be requested.
+/// - [`I8042Error::Timeout`]: The controller did not answer.
+pub fn attach(port: Port, receiver: Receiver) -> Result<(), I8042Error> {
+    x86_64::irq::without(|| {
+        let mut controller = CONTROLLER.lock();
+        if !controller.present[port.index()] {
+            return Err(I8042Error::NoSuchPort);
+        }
+        if controller.receivers[port.index()].is_some() {
+            return Err(I8042Error::Busy);
+        }
+        controller.receivers[port.index()] = Some(receiver);
+        Ok(())
+    })?;
+
+    let handler = match port {
+        Port::First => first_interrupt,
+        Port::Second => second_interrupt,
+    };
+    let enabled = irq::request_irq(port.irq(), handler, IrqFlags::NONE, "i8042")
+        .map_err(I8042Error::Irq)
+        .and_then(|()| {
+            x86_64::irq::without(|| {
+                let _controller = CONTROLLER.lock();
+                let config = command_read(COMMAND_READ_CONFIG)?;
+                command_write(COMMAND_WRITE_CONFIG, config | port.config_irq())
+            })
+        });
+    if enabled.is_err() {
+        x86_64::irq::without(|| CONTROLLER.lock().receivers[port.index()] = None);
+    }
+    enabled
+}
+
+/// Send a byte to the device connected to the given port.
+///
+/// # Errors
+/// - [`I8042Error::NoSuchPort`]: The port does not exist or does not work.
+/// - [`I8042Error::Timeout`]: The controller did not accept the byte.
+pub fn write(port: Port, byte: u8) -> Result<(), I8042Error> {
+    x86_64::irq::without(|| {
+        let controller = CONTROLLER.lock();
+        if !controller.present[port.index()] {
+            return Err(I8042Error::NoSuchPort);
+        }
+        if port == Port::Second {
+            command(COMMAND_WRITE_SECOND)?;
+        }
+        write_data(byte)
+    })
+}
+
+impl Controller {
+    /// Initialize the controller, and returns the ports that exist and work.
+    fn init(&mut self) -> Result<[bool; 2], I8042Error> {
+        // The status register of a missing controller reads as all ones
+        if unsafe { STATUS.read() } == 0xFF {
+            return Err(I8042Error::NoController);
+        }
+
+        command(COMMAND_DISABLE_FIRST)?;
+        command(COMMAND_DISABLE_SECOND)?;
+        drain();
+
+        // Disable the interrupts and configure the translation while the controller is tested
+        let mut config = command_read(COMMAND_READ_CONFIG)?;
+        let dual = config & CONFIG_SECOND_CLOCK_DISABLED != 0;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        if TRANSLATION {
+            config |= CONFIG_TRANSLATION;
+        }
+        command_write(COMMAND_WRITE_CONFIG, config)?;
+
+        // The self test may reset the controller, so the configuration is written again
+        if command_read(COMMAND_SELF_TEST)? != SELF_TEST_PASSED {
+            return Err(I8042Error::SelfTestFailed);
+        }
+        command_write(COMMAND_WRITE_CONFIG, config)?;
+
+        // The clock of the second port can only be enabled if the second port exists. It was
+        // disabled by the command above, so it should now be enabled if it exists.
+        let dual = dual && {
+            command(COMMAND_ENABLE_SECOND)?;
+            let enabled = command_read(COMMAND_READ_CONFIG)? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            command(COMMAND_DISABLE_SECOND)?;
+            enabled
+        };
+
+        let first = command_read(COMMAND_TEST_FIRST)? == PORT_TEST_PASSED;
+        let second = dual && command_read(COMMAND_TEST_SECOND)? == PORT_TEST_PASSED;
+        if first {
+            command(COMMAND_ENABLE_FIRST)?;
+        }
+        if second {
+            command(COMMAND_ENABLE_SECOND)?;
+        }
+        drain();
+
+        self.present = [first, second];
+        Ok(self.present)
+    }
+}
+
+fn first_interrupt(_: u8) -> IrqReturn {
+    receive(Port::First)
+}
+
+fn second_interrupt(_: u8) -> IrqReturn {
+    receive(Port::Second)
+}
+
+/// Give the byte waiting in the output buffer to the receiver of the port that raised the
+/// interrupt.
+fn receive(port: Port) -> IrqReturn {
+    if unsafe { STATUS.read() } & STATUS_OUTPUT_FULL == 0 {
+        return IrqReturn::None;
+    }
+    let byte = unsafe { DATA.read() };
+    let receiver = x86_64::irq::without(|| CONTROLLER.lock().receivers[port.index()]);
+    if let Some(receiver) = receiver {
+        receiver(byte);
+    }
+    IrqReturn::Handled
+}
+
+/// Send a command to the controller.
+fn command(command: u8) -> Result<(), I8042Error> {
+    wait(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { COMMAND.write(command) };
+    Ok(())
+}
+
+/// Send a command to the controller, and returns its answer.
+fn command_read(command_byte: u8) -> Result<u8, I8042Error> {
+    command(command_byte)?;
+    read_data()
+}
+
+/// Send a command to the controller, followed by its argument.
+fn command_write(command_byte: u8, argument: u8) -> Result<(), I8042Error> {
+    command(command_byte)?;
+    write_data(argument)
+}
+
+fn write_data(byte: u8) -> Result<(), I8042Error> {
+    wait(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { DATA.write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, I8042Error> {
+    wait(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(unsafe { DATA.read() })
+}
+
+/// Discard the bytes waiting in the output buffer, for example the keys pressed during the boot.
+fn drain() {
+    for _ in 0..DRAIN_LIMIT {
+        if unsafe { STATUS.read() } & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        let _ = unsafe { DATA.read() };
+    }
+}
+
+/// Wait until the status register satisfies the given condition, or until [`TIMEOUT`] elapsed.
+fn wait(ready: impl Fn(u8) -> bool) -> Result<(), I8042Error> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if ready(unsafe { STATUS.read() }) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(I8042Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,8 @@
+pub mod block;
+pub mod i8042;
+pub mod pci;
+pub mod rtc;
+pub mod serial;
+pub mod uart;
+pub mod virtio;
+pub mod watchdog;
@@ -0,0 +1,239 @@
+use alloc::vec::Vec;
+
+use crate::arch::acpi::{self, aml::PciRoute};
+
+use super::{
+    Address, BAR0, COMMAND, COMMAND_IO_SPACE, COMMAND_MEMORY_SPACE, INTERRUPT_LINE, INTERRUPT_PIN,
+};
+
+/// The number of BARs of a general device, and of a PCI-to-PCI bridge.
+const DEVICE_BAR_COUNT: u8 = 6;
+const BRIDGE_BAR_COUNT: u8 = 2;
+
+/// The bits of a BAR describing its kind.
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_64BIT: u32 = 0b10 << 1;
+const BAR_MEMORY_TYPE: u32 = 0b11 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// A base address register of a PCI function, decoded and sized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bar {
+    /// A memory-mapped region. A 64 bits BAR also uses the next BAR for the upper half of its
+    /// address: the next BAR is then absent.
+    Memory {
+        base: u64,
+        size: u64,
+        prefetchable: bool,
+        wide: bool,
+    },
+
+    /// A range of I/O ports.
+    Io { port: u16, size: u16 },
+}
+
+/// The legacy `INTx` pin used by a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InterruptPin {
+    A,
+    B,
+    C,
+    D,
+}
+
+/// A PCI function found during the scan of the buses (see [`super::setup`]).
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+
+    /// The layout of the configuration space, without the multi-function bit: 0 for a general
+    /// device, 1 for a PCI-to-PCI bridge and 2 for a `CardBus` bridge.
+    pub header_type: u8,
+
+    /// The decoded BARs. The unused BARs, and the upper halves of 64 bits BARs, are `None`.
+    pub bars: [Option<Bar>; DEVICE_BAR_COUNT as usize],
+
+    /// The legacy interrupt pin of the function, if it uses one, and the IRQ line the firmware
+    /// connected it to.
+    pub interrupt_pin: Option<InterruptPin>,
+    pub interrupt_line: u8,
+
+    /// The capabilities of the function, as pairs of identifier and offset in the configuration
+    /// space.
+    pub capabilities: Vec<(u8, u8)>,
+}
+
+impl PciDevice {
+    /// Read the configuration space of the function at the given address. The function must
+    /// exist.
+    #[must_use]
+    pub fn probe(address: Address, header_type: u8) -> Self {
+        let [vendor_low, vendor_high, device_low, device_high] = address.read32(0x00).to_le_bytes();
+        let [revision, prog_if, subclass, class] = address.read32(0x08).to_le_bytes();
+        let bar_count = match header_type {
+            0 => DEVICE_BAR_COUNT,
+            1 => BRIDGE_BAR_COUNT,
+            _ => 0,
+        };
+
+        let interrupt_pin = match address.read16(INTERRUPT_PIN).to_le_bytes()[1] {
+            1 => Some(InterruptPin::A),
+            2 => Some(InterruptPin::B),
+            3 => Some(InterruptPin::C),
+            4 => Some(InterruptPin::D),
+            _ => None,
+        };
+
+        Self {
+            address,
+            vendor_id: u16::from_le_bytes([vendor_low, vendor_high]),
+            device_id: u16::from_le_bytes([device_low, device_high]),
+            class,
+            subclass,
+            prog_if,
+            revision,
+            header_type,
+            bars: read_bars(address, bar_count),
+            interrupt_pin,
+            interrupt_line: address.read16(INTERRUPT_LINE).to_le_bytes()[0],
+            capabilities: address.capabilities().collect(),
+        }
+    }
+
+    /// Returns true if the function has the capability with the given identifier.
+    #[must_use]
+    pub fn has_capability(&self, id: u8) -> bool {
+        self.capabilities
+            .iter()
+            .any(|&(capability, _)| capability == id)
+    }
+
+    /// Returns how the legacy interrupt pin of the function is connected to the IOAPICs, according
+    /// to the `_PRT` routing table of the firmware, or `None` if the function has no interrupt pin
+    /// or is not on the root bus. The `interrupt_line` set by the firmware is only meaningful with
+    /// the 8259 PIC.
+    #[must_use]
+    pub fn interrupt_route(&self) -> Option<PciRoute> {
+        let pin = match self.interrupt_pin? {
+            InterruptPin::A => 1,
+            InterruptPin::B => 2,
+            InterruptPin::C => 3,
+            InterruptPin::D => 4,
+        };
+        if self.address.bus != 0 {
+            return None;
+        }
+        acpi::aml::pci_route(self.address.device, self.address.function, pin)
+    }
+
+    /// Returns a short description of the class and subclass of the function.
+    #[must_use]
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x00, _) => "Unclassified device",
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA controller",
+            (0x03, _) => "Display controller",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI-to-PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x09, _) => "Input device controller",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "Serial bus controller",
+            _ => "Unknown device",
+        }
+    }
+}
+
+/// Decode and size the given number of BARs of a function. Each BAR is sized by writing all ones
+/// to it and reading back the bits that stuck, with the decoding of the function disabled so that
+/// the temporary address does not conflict with another device.
+fn read_bars(address: Address, count: u8) -> [Option<Bar>; DEVICE_BAR_COUNT as usize] {
+    let mut bars = [None; DEVICE_BAR_COUNT as usize];
+    let command = address.read16(COMMAND);
+    address.write16(
+        COMMAND,
+        command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index * 4;
+        let value = address.read32(offset);
+        let mask = size_mask(address, offset);
+
+        if value & BAR_IO != 0 {
+            // Only the low 16 bits of an I/O BAR are meaningful
+            let mask = mask & 0xFFFC;
+            if mask != 0 {
+                bars[usize::from(index)] = Some(Bar::Io {
+                    port: u16::try_from(value & 0xFFFC).unwrap(),
+                    size: u16::try_from((!mask & 0xFFFF) + 1).unwrap_or(u16::MAX),
+                });
+            }
+            index += 1;
+            continue;
+        }
+
+        let wide = value & BAR_MEMORY_TYPE == BAR_MEMORY_64BIT && index + 1 < count;
+        let (base, mask) = if wide {
+            let high = address.read32(offset + 4);
+            let high_mask = size_mask(address, offset + 4);
+            (
+                u64::from(value & !0xF) | u64::from(high) << 32,
+                u64::from(mask & !0xF) | u64::from(high_mask) << 32,
+            )
+        } else {
+            (
+                u64::from(value & !0xF),
+                u64::from(mask & !0xF) | 0xFFFF_FFFF_0000_0000,
+            )
+        };
+
+        // The upper half of the mask of a 32 bits BAR is filled with ones
+        let used = if wide {
+            mask != 0
+        } else {
+            mask & 0xFFFF_FFFF != 0
+        };
+        if used {
+            bars[usize::from(index)] = Some(Bar::Memory {
+                base,
+                size: (!mask).wrapping_add(1),
+                prefetchable: value & BAR_PREFETCHABLE != 0,
+                wide,
+            });
+        }
+        index += if wide { 2 } else { 1 };
+    }
+
+    address.write16(COMMAND, command);
+    bars
+}
+
+/// Returns the bits of a BAR that can be written, which give the size of the region it decodes.
+/// The original value of the BAR is restored.
+fn size_mask(address: Address, offset: u8) -> u32 {
+    let value = address.read32(offset);
+    address.write32(offset, u32::MAX);
+    let mask = address.read32(offset);
+    address.write32(offset, value);
+    mask
+}
@@ -0,0 +1,234 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::Spinlock;
+
+use super::{Address, PciDevice};
+
+/// The registered drivers, in the order of their registration.
+static DRIVERS: Spinlock<Vec<&'static dyn PciDriver>> = Spinlock::new(Vec::new());
+
+/// The driver bound to each function. A function is bound to its driver before the driver is
+/// probed, so that two drivers are never probed for the same function at the same time.
+static BINDINGS: Spinlock<BTreeMap<Address, &'static dyn PciDriver>> =
+    Spinlock::new(BTreeMap::new());
+
+/// A pattern matching PCI functions, used in the identifier table of a driver. The fields set to
+/// `None` match any value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl DeviceId {
+    /// Match the functions with the given vendor and device identifiers.
+    #[must_use]
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Match the functions with the given class and subclass, whatever their vendor.
+    #[must_use]
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    /// Match the functions with the given class, subclass and programming interface.
+    #[must_use]
+    pub const fn interface(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self {
+            prog_if: Some(prog_if),
+            ..Self::class(class, subclass)
+        }
+    }
+
+    /// Returns true if the given function matches this pattern.
+    #[must_use]
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn matches<T: PartialEq + Copy>(pattern: Option<T>, value: T) -> bool {
+            pattern.is_none() || pattern == Some(value)
+        }
+
+        matches(self.vendor_id, device.vendor_id)
+            && matches(self.device_id, device.device_id)
+            && matches(self.class, device.class)
+            && matches(self.subclass, device.subclass)
+            && matches(self.prog_if, device.prog_if)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProbeError {
+    /// The driver does not support this function after all, even though it matches its
+    /// identifier table. Another driver may be probed for it.
+    NotSupported,
+
+    /// The function could not be initialized.
+    InitFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DriverError {
+    /// A driver with the same name is already registered.
+    AlreadyRegistered,
+
+    /// No driver with this name is registered.
+    NotFound,
+}
+
+/// A driver of PCI functions. A driver is registered with [`register_driver`], and is then bound
+/// automatically to the functions matching its identifier table that are not bound to another
+/// driver yet, whether they were found before or after its registration.
+pub trait PciDriver: Sync {
+    /// The name of the driver, which must be unique.
+    fn name(&self) -> &'static str;
+
+    /// The patterns of the functions handled by the driver.
+    fn id_table(&self) -> &'static [DeviceId];
+
+    /// Initialize a function matching the identifier table of the driver. The function is bound to
+    /// the driver if this succeeds. This is called in the context of a thread, and may sleep.
+    ///
+    /// # Errors
+    /// - [`ProbeError::NotSupported`]: The driver does not handle this function.
+    /// - [`ProbeError::InitFailed`]: The function could not be initialized.
+    fn probe(&self, device: &PciDevice) -> Result<(), ProbeError>;
+
+    /// Stop using a function bound to the driver, when the driver is unregistered. The function
+    /// must not raise interrupts or access the memory after this returns.
+    fn remove(&self, device: &PciDevice);
+}
+
+/// Register a driver, and probe it for all the functions it matches that are not bound to a
+/// driver yet.
+///
+/// # Errors
+/// - [`DriverError::AlreadyRegistered`]: A driver with the same name is already registered.
+pub fn register_driver(driver: &'static dyn PciDriver) -> Result<(), DriverError> {
+    x86_64::irq::without(|| {
+        let mut drivers = DRIVERS.lock();
+        if drivers.iter().any(|other| other.name() == driver.name()) {
+            return Err(DriverError::AlreadyRegistered);
+        }
+        drivers.push(driver);
+        Ok(())
+    })?;
+
+    log::debug!("PCI driver {} registered", driver.name());
+    for device in super::devices() {
+        try_bind(driver, device);
+    }
+    Ok(())
+}
+
+/// Unregister a driver, after removing it from all the functions it is bound to.
+///
+/// # Errors
+/// - [`DriverError::NotFound`]: No driver with this name is registered.
+pub fn unregister_driver(name: &str) -> Result<(), DriverError> {
+    x86_64::irq::without(|| {
+        let mut drivers = DRIVERS.lock();
+        let index = drivers
+            .iter()
+            .position(|driver| driver.name() == name)
+            .ok_or(DriverError::NotFound)?;
+        drivers.remove(index);
+        Ok(())
+    })?;
+
+    for device in super::devices() {
+        let unbound = x86_64::irq::without(|| {
+            let mut bindings = BINDINGS.lock();
+            match bindings.get(&device.address) {
+                Some(driver) if driver.name() == name => bindings.remove(&device.address),
+                _ => None,
+            }
+        });
+        if let Some(driver) = unbound {
+            driver.remove(device);
+        }
+    }
+    log::debug!("PCI driver {name} unregistered");
+    Ok(())
+}
+
+/// Returns the name of the driver bound to the function at the given address, if any.
+#[must_use]
+pub fn bound_driver(address: Address) -> Option<&'static str> {
+    x86_64::irq::without(|| BINDINGS.lock().get(&address).map(|driver| driver.name()))
+}
+
+/// Probe the registered drivers for each function that is not bound yet. This is called once the
+/// buses have been scanned, for the drivers registered before.
+pub(super) fn bind_all() {
+    let drivers = x86_64::irq::without(|| DRIVERS.lock().clone());
+    for device in super::devices() {
+        for &driver in &drivers {
+            if try_bind(driver, device) {
+                break;
+            }
+        }
+    }
+}
+
+/// Probe the driver for the function if it matches its identifier table and if the function is
+/// not bound yet. Returns true if the function is bound to the driver.
+fn try_bind(driver: &'static dyn PciDriver, device: &PciDevice) -> bool {
+    if !driver.id_table().iter().any(|id| id.matches(device)) {
+        return false;
+    }
+
+    let claimed = x86_64::irq::without(|| {
+        let mut bindings = BINDINGS.lock();
+        if bindings.contains_key(&device.address) {
+            return false;
+        }
+        bindings.insert(device.address, driver);
+        true
+    });
+    if !claimed {
+        return false;
+    }
+
+    let address = device.address;
+    match driver.probe(device) {
+        Ok(()) => {
+            log::info!(
+                "PCI {:02x}:{:02x}.{} bound to {}",
+                address.bus,
+                address.device,
+                address.function,
+                driver.name()
+            );
+            true
+        }
+        Err(error) => {
+            x86_64::irq::without(|| BINDINGS.lock().remove(&address));
+            if error != ProbeError::NotSupported {
+                log::warn!(
+                    "PCI {:02x}:{:02x}.{}: {} failed to probe: {error:?}",
+                    address.bus,
+                    address.device,
+                    address.function,
+                    driver.name()
+                );
+            }
+            false
+        }
+    }
+}
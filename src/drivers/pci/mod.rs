@@ -0,0 +1,282 @@
+use alloc::vec::Vec;
+use sync::Once;
+
+use crate::{arch::port::Port, Spinlock};
+
+pub mod device;
+pub mod driver;
+pub mod msi;
+
+pub use device::{Bar, InterruptPin, PciDevice};
+pub use driver::{register_driver, unregister_driver, DeviceId, PciDriver};
+
+/// The port used to select the configuration register accessed through [`CONFIG_DATA`].
+const CONFIG_ADDRESS: Port<u32> = Port::new(0xCF8);
+
+/// The port used to read or write the configuration register selected with [`CONFIG_ADDRESS`].
+const CONFIG_DATA: Port<u32> = Port::new(0xCFC);
+
+/// The number of buses, devices per bus and functions per device.
+const BUS_COUNT: u16 = 256;
+const DEVICE_COUNT: u8 = 32;
+const FUNCTION_COUNT: u8 = 8;
+
+/// The vendor identifier read from a function that does not exist.
+const INVALID_VENDOR: u16 = 0xFFFF;
+
+/// The offset of the header type register in the configuration space.
+const HEADER_TYPE: u8 = 0x0E;
+
+/// Set in the header type if the device has more than one function.
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+/// The offsets of the interrupt line and interrupt pin registers in the configuration space.
+pub const INTERRUPT_LINE: u8 = 0x3C;
+pub const INTERRUPT_PIN: u8 = 0x3D;
+
+/// The offset of the command register in the configuration space.
+pub const COMMAND: u8 = 0x04;
+
+/// The offset of the status register in the configuration space.
+pub const STATUS: u8 = 0x06;
+
+/// The offset of the first base address register in the configuration space.
+pub const BAR0: u8 = 0x10;
+
+/// The offset of the pointer to the first capability in the configuration space.
+pub const CAPABILITIES_POINTER: u8 = 0x34;
+
+/// Enable the decoding of the I/O and memory BARs of the device.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+
+/// Allow the device to access the memory by itself (DMA) and to send message signaled interrupts.
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Disable the legacy `INTx` interrupt of the device.
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Set if the device has a capability list.
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The address selection and the data ports must be used atomically.
+static CONFIG_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// The PCI functions found by [`setup`]. The buses are only scanned once, at boot: hot-plugging is
+/// not supported.
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+/// The location of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    #[must_use]
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Read the 32 bits register at the given offset of the configuration space. The offset is
+    /// rounded down to a multiple of 4.
+    #[must_use]
+    pub fn read32(self, offset: u8) -> u32 {
+        let address = self.config_address(offset);
+        x86_64::irq::without(|| {
+            let _guard = CONFIG_LOCK.lock();
+            unsafe {
+                CONFIG_ADDRESS.write(address);
+                CONFIG_DATA.read()
+            }
+        })
+    }
+
+    /// Write the 32 bits register at the given offset of the configuration space. The offset is
+    /// rounded down to a multiple of 4.
+    pub fn write32(self, offset: u8, value: u32) {
+        let address = self.config_address(offset);
+        x86_64::irq::without(|| {
+            let _guard = CONFIG_LOCK.lock();
+            unsafe {
+                CONFIG_ADDRESS.write(address);
+                CONFIG_DATA.write(value);
+            }
+        });
+    }
+
+    /// Read the 16 bits register at the given offset of the configuration space. The offset is
+    /// rounded down to a multiple of 2.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read16(self, offset: u8) -> u16 {
+        let shift = (offset & 2) * 8;
+        (self.read32(offset) >> shift) as u16
+    }
+
+    /// Write the 16 bits register at the given offset of the configuration space. The offset is
+    /// rounded down to a multiple of 2. The other half of the 32 bits register is preserved.
+    pub fn write16(self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read32(offset) & !(0xFFFF << shift);
+        self.write32(offset, old | u32::from(value) << shift);
+    }
+
+    /// Read the 8 bits register at the given offset of the configuration space.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read8(self, offset: u8) -> u8 {
+        let shift = (offset & 3) * 8;
+        (self.read32(offset) >> shift) as u8
+    }
+
+    /// Write the 8 bits register at the given offset of the configuration space. The other bytes
+    /// of the 32 bits register are preserved.
+    pub fn write8(self, offset: u8, value: u8) {
+        let shift = (offset & 3) * 8;
+        let old = self.read32(offset) & !(0xFF << shift);
+        self.write32(offset, old | u32::from(value) << shift);
+    }
+
+    /// Returns an iterator over the capabilities of the function, as pairs of capability
+    /// identifier and offset in the configuration space.
+    #[must_use]
+    pub fn capabilities(self) -> Capabilities {
+        let next = if self.read16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read32(CAPABILITIES_POINTER).to_le_bytes()[0] & 0xFC
+        } else {
+            0
+        };
+        Capabilities {
+            address: self,
+            next,
+            remaining: 48,
+        }
+    }
+
+    /// Returns the offset of the first capability with the given identifier, if any.
+    #[must_use]
+    pub fn find_capability(self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .find(|&(capability, _)| capability == id)
+            .map(|(_, offset)| offset)
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device & 0x1F) << 11
+            | u32::from(self.function & 0x07) << 8
+            | u32::from(offset & 0xFC)
+    }
+}
+
+/// Scan all the functions of all the PCI buses through the legacy configuration ports, and record
+/// them in the registry queried with [`devices`] and [`find`]. The drivers registered before are
+/// then bound to the functions they match (see [`PciDriver`]).
+pub fn setup() {
+    let devices = DEVICES.call_once(scan);
+    for device in devices {
+        log::debug!(
+            "PCI {:02x}:{:02x}.{}: {:04x}:{:04x} {}",
+            device.address.bus,
+            device.address.device,
+            device.address.function,
+            device.vendor_id,
+            device.device_id,
+            device.class_name()
+        );
+    }
+    log::info!("PCI: {} functions found", devices.len());
+    driver::bind_all();
+}
+
+/// Returns all the PCI functions found at boot, sorted by address. Empty if the buses have not been
+/// scanned yet.
+#[must_use]
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns an iterator over the PCI functions with the given vendor and device identifiers.
+pub fn find(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// Returns an iterator over the PCI functions with the given class and subclass.
+pub fn find_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+/// Probe every possible function. The function 0 of a device tells if the other functions of the
+/// device must be probed.
+fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..BUS_COUNT {
+        let bus = u8::try_from(bus).unwrap();
+        for device in 0..DEVICE_COUNT {
+            let Some(first) = header_type(Address::new(bus, device, 0)) else {
+                continue;
+            };
+            let functions = if first & HEADER_TYPE_MULTI_FUNCTION != 0 {
+                FUNCTION_COUNT
+            } else {
+                1
+            };
+
+            for function in 0..functions {
+                let address = Address::new(bus, device, function);
+                if let Some(kind) = header_type(address) {
+                    devices.push(PciDevice::probe(
+                        address,
+                        kind & !HEADER_TYPE_MULTI_FUNCTION,
+                    ));
+                }
+            }
+        }
+    }
+    devices
+}
+
+/// Returns the header type of the function at the given address, or `None` if there is no function
+/// there.
+fn header_type(address: Address) -> Option<u8> {
+    if address.read16(0x00) == INVALID_VENDOR {
+        return None;
+    }
+    Some(address.read16(HEADER_TYPE).to_le_bytes()[0])
+}
+
+/// An iterator over the capability list of a PCI function. The number of capabilities is bounded
+/// to avoid looping forever on a malformed list.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    address: Address,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 || self.remaining == 0 {
+            return None;
+        }
+        let offset = self.next;
+        let [id, next, ..] = self.address.read32(offset).to_le_bytes();
+        self.next = next & 0xFC;
+        self.remaining -= 1;
+        Some((id, offset))
+    }
+}
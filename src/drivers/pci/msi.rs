@@ -0,0 +1,315 @@
+use alloc::vec::Vec;
+use x86_64::address::Virtual;
+
+use crate::{
+    arch::{acpi, mmio::Mmio, msi::Message, smp},
+    Spinlock,
+};
+
+use super::{Address, BAR0, COMMAND, COMMAND_INTX_DISABLE};
+
+/// The identifier of the MSI capability.
+pub const CAPABILITY_MSI: u8 = 0x05;
+
+/// The identifier of the MSI-X capability.
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+/// The size of an entry of the MSI-X table.
+const MSIX_ENTRY_SIZE: usize = 16;
+
+/// The mask bit of the vector control word of an MSI-X table entry.
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MsiError {
+    /// The function does not have the required capability.
+    NotSupported,
+
+    /// The BAR containing the MSI-X table is not a memory BAR.
+    InvalidBar,
+
+    /// An entry index is beyond the end of the MSI-X table.
+    InvalidEntry,
+
+    /// The MSI-X table could not be mapped.
+    MapFailed,
+
+    /// No function uses the vector.
+    NotFound,
+
+    /// The CPU is not online.
+    InvalidCpu,
+}
+
+/// Where the message raising an MSI vector is programmed, so that it can be delivered to another
+/// CPU later (see [`set_affinity`]).
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    address: Address,
+    message: Message,
+
+    /// The mapped MSI-X table and the entry of the message, or `None` for the MSI capability.
+    entry: Option<(Virtual, u16)>,
+}
+
+/// The vectors used by the functions with MSI or MSI-X enabled.
+static ROUTES: Spinlock<Vec<Route>> = Spinlock::new(Vec::new());
+
+/// Configure the MSI capability of the given function to send the given message, enable it and
+/// disable the legacy `INTx` interrupt. Only one vector is used, even if the function supports
+/// multiple messages.
+///
+/// # Errors
+/// - [`MsiError::NotSupported`]: The function does not have the MSI capability.
+pub fn enable_msi(address: Address, message: Message) -> Result<(), MsiError> {
+    let capability = address
+        .find_capability(CAPABILITY_MSI)
+        .ok_or(MsiError::NotSupported)?;
+    let control = address.read16(capability + 2);
+
+    // Disable MSI while the message is modified
+    address.write16(capability + 2, control & !MSI_CONTROL_ENABLE);
+    address.write32(capability + 4, low(message.address));
+    let data = u16::try_from(message.data).expect("MSI data does not fit in 16 bits");
+    if control & MSI_CONTROL_64BIT != 0 {
+        address.write32(capability + 8, high(message.address));
+        address.write16(capability + 12, data);
+    } else {
+        address.write16(capability + 8, data);
+    }
+
+    let control = control & !MSI_CONTROL_MULTIPLE_ENABLE | MSI_CONTROL_ENABLE;
+    address.write16(capability + 2, control);
+    disable_intx(address);
+    set_routes(
+        address,
+        [Route {
+            address,
+            message,
+            entry: None,
+        }],
+    );
+    Ok(())
+}
+
+/// Disable the MSI capability of the given function, if it has one.
+pub fn disable_msi(address: Address) {
+    if let Some(capability) = address.find_capability(CAPABILITY_MSI) {
+        let control = address.read16(capability + 2);
+        address.write16(capability + 2, control & !MSI_CONTROL_ENABLE);
+        set_routes(address, []);
+    }
+}
+
+/// Returns the number of entries in the MSI-X table of the given function, or `None` if the
+/// function does not have the MSI-X capability.
+#[must_use]
+pub fn msix_table_size(address: Address) -> Option<u16> {
+    let capability = address.find_capability(CAPABILITY_MSIX)?;
+    Some((address.read16(capability + 2) & MSIX_CONTROL_TABLE_SIZE) + 1)
+}
+
+/// Program the given entries of the MSI-X table of the given function, each with its entry index
+/// and message, enable MSI-X and disable the legacy `INTx` interrupt. The entries not given are
+/// masked. The MSI-X table is mapped in the kernel space and the mapping is never removed.
+///
+/// # Errors
+/// - [`MsiError::NotSupported`]: The function does not have the MSI-X capability.
+/// - [`MsiError::InvalidBar`]: The BAR containing the MSI-X table is not a memory BAR.
+/// - [`MsiError::InvalidEntry`]: An entry index is beyond the end of the MSI-X table.
+/// - [`MsiError::MapFailed`]: The MSI-X table could not be mapped.
+pub fn enable_msix(address: Address, entries: &[(u16, Message)]) -> Result<(), MsiError> {
+    let capability = address
+        .find_capability(CAPABILITY_MSIX)
+        .ok_or(MsiError::NotSupported)?;
+    let control = address.read16(capability + 2);
+    let size = (control & MSIX_CONTROL_TABLE_SIZE) + 1;
+    if entries.iter().any(|&(index, _)| index >= size) {
+        return Err(MsiError::InvalidEntry);
+    }
+
+    let table = address.read32(capability + 4);
+    let bir = u8::try_from(table & 0x7).unwrap();
+    let base = memory_bar(address, bir).ok_or(MsiError::InvalidBar)? + u64::from(table & !0x7);
+    let len = usize::from(size) * MSIX_ENTRY_SIZE;
+    let table = unsafe { acpi::remap_mmio(base, len) }.ok_or(MsiError::MapFailed)?;
+
+    // Mask the whole function while the table is modified
+    address.write16(
+        capability + 2,
+        control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+    );
+    for index in 0..size {
+        write_entry(table, index, None);
+    }
+    for &(index, message) in entries {
+        write_entry(table, index, Some(message));
+    }
+    address.write16(
+        capability + 2,
+        (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK,
+    );
+    disable_intx(address);
+    set_routes(
+        address,
+        entries.iter().map(|&(index, message)| Route {
+            address,
+            message,
+            entry: Some((table, index)),
+        }),
+    );
+    Ok(())
+}
+
+/// Disable the MSI-X capability of the given function, if it has one.
+pub fn disable_msix(address: Address) {
+    if let Some(capability) = address.find_capability(CAPABILITY_MSIX) {
+        let control = address.read16(capability + 2);
+        address.write16(capability + 2, control & !MSIX_CONTROL_ENABLE);
+        set_routes(address, []);
+    }
+}
+
+/// Deliver the given MSI vector to the CPU with the given id, by rewriting the message of the
+/// function using it. With MSI-X, the entry is masked while it is rewritten, so an interrupt
+/// raised in the meantime is delivered once it is unmasked. With MSI, only the low half of the
+/// address is rewritten, in a single write.
+///
+/// # Errors
+/// - [`MsiError::NotFound`]: No function uses the vector.
+/// - [`MsiError::InvalidCpu`]: The CPU is not online.
+pub fn set_affinity(vector: u8, cpu: u32) -> Result<(), MsiError> {
+    let lapic = smp::lapic_id(cpu).ok_or(MsiError::InvalidCpu)?;
+    x86_64::irq::without(|| {
+        let mut routes = ROUTES.lock();
+        let route = routes
+            .iter_mut()
+            .find(|route| route.message.vector() == vector)
+            .ok_or(MsiError::NotFound)?;
+
+        route.message = route.message.with_destination(lapic);
+        if let Some((table, index)) = route.entry {
+            write_entry(table, index, Some(route.message));
+        } else {
+            let capability = route
+                .address
+                .find_capability(CAPABILITY_MSI)
+                .ok_or(MsiError::NotSupported)?;
+            route
+                .address
+                .write32(capability + 4, low(route.message.address));
+        }
+        Ok(())
+    })?;
+    log::debug!("MSI vector {vector:#x} delivered to CPU {cpu}");
+    Ok(())
+}
+
+/// Returns the id of the CPU the given MSI vector is delivered to, or `None` if no function uses
+/// the vector.
+#[must_use]
+pub fn affinity(vector: u8) -> Option<u32> {
+    let lapic = x86_64::irq::without(|| {
+        ROUTES
+            .lock()
+            .iter()
+            .find(|route| route.message.vector() == vector)
+            .map(|route| route.message.destination())
+    })?;
+    (0..)
+        .take(crate::config::MAX_CPU)
+        .find(|&cpu| smp::lapic_id(cpu) == Some(lapic))
+}
+
+/// Deliver all the MSI vectors delivered to the given CPU to the `target` CPU instead. This must
+/// be done before a CPU is taken offline, while both CPUs are still online.
+///
+/// # Errors
+/// - [`MsiError::InvalidCpu`]: One of the CPUs is not online.
+pub fn evacuate(cpu: u32, target: u32) -> Result<(), MsiError> {
+    let lapic = smp::lapic_id(cpu).ok_or(MsiError::InvalidCpu)?;
+    let vectors: Vec<u8> = x86_64::irq::without(|| {
+        ROUTES
+            .lock()
+            .iter()
+            .filter(|route| route.message.destination() == lapic)
+            .map(|route| route.message.vector())
+            .collect()
+    });
+    for vector in vectors {
+        set_affinity(vector, target)?;
+    }
+    Ok(())
+}
+
+/// Replace the routes of the given function.
+fn set_routes(address: Address, routes: impl IntoIterator<Item = Route>) {
+    x86_64::irq::without(|| {
+        let mut all = ROUTES.lock();
+        all.retain(|route| route.address != address);
+        all.extend(routes);
+    });
+}
+
+/// Write an entry of a mapped MSI-X table. The entry is masked if no message is given.
+fn write_entry(table: Virtual, index: u16, message: Option<Message>) {
+    let entry = unsafe { Mmio::<u32>::new(table + u64::from(index) * MSIX_ENTRY_SIZE as u64) };
+    let (address_low, address_high, data, control) = unsafe {
+        (
+            entry,
+            entry.offset::<u32>(4),
+            entry.offset::<u32>(8),
+            entry.offset::<u32>(12),
+        )
+    };
+    control.write(MSIX_ENTRY_MASKED);
+    if let Some(message) = message {
+        address_low.write(low(message.address));
+        address_high.write(high(message.address));
+        data.write(message.data);
+        control.write(0);
+    }
+}
+
+/// Returns the physical address of the given memory BAR, or `None` if it is an I/O BAR.
+fn memory_bar(address: Address, bar: u8) -> Option<u64> {
+    if bar > 5 {
+        return None;
+    }
+    let offset = BAR0 + bar * 4;
+    let low = address.read32(offset);
+    if low & 1 != 0 {
+        return None;
+    }
+
+    // A 64 bits BAR uses the next BAR for the upper half of the address
+    let base = u64::from(low & !0xF);
+    if (low >> 1) & 0b11 == 0b10 {
+        Some(base | u64::from(address.read32(offset + 4)) << 32)
+    } else {
+        Some(base)
+    }
+}
+
+fn disable_intx(address: Address) {
+    let command = address.read16(COMMAND);
+    address.write16(COMMAND, command | COMMAND_INTX_DISABLE);
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn low(value: u64) -> u32 {
+    value as u32
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn high(value: u64) -> u32 {
+    (value >> 32) as u32
+}
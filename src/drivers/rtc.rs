@@ -0,0 +1,186 @@
+use core::time::Duration;
+
+use crate::{arch::port::Port, Spinlock};
+
+/// The port used to select the CMOS register accessed through [`CMOS_DATA`].
+const CMOS_ADDRESS: Port<u8> = Port::new(0x70);
+
+/// The port used to read the CMOS register selected with [`CMOS_ADDRESS`].
+const CMOS_DATA: Port<u8> = Port::new(0x71);
+
+/// The CMOS registers of the RTC.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+/// Set in the status register A while the RTC updates its registers, which must not be read then.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+/// Set in the status register B if the hours are in 24 hours format rather than 12 hours format.
+const STATUS_B_24_HOURS: u8 = 1 << 1;
+
+/// Set in the status register B if the registers are in binary rather than in BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// Set in the hours register in 12 hours format for the hours after noon.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Without a century register described in the FADT, the years before this one are assumed to be
+/// in the next century.
+const CENTURY_PIVOT: u64 = 70;
+
+/// The smallest plausible value of the century register: a smaller value is ignored.
+const MIN_CENTURY: u64 = 19;
+
+/// The maximum number of attempts to read two identical consecutive dates.
+const MAX_READ_ATTEMPTS: usize = 16;
+
+/// The index and the data ports must be used atomically.
+static CMOS_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// A date and a time read from the RTC. The RTC is assumed to keep the UTC time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hours: u64,
+    pub minutes: u64,
+    pub seconds: u64,
+}
+
+impl DateTime {
+    /// Returns the time elapsed between the Unix epoch (1970-01-01 00:00:00 UTC) and this date.
+    #[must_use]
+    pub fn unix_time(&self) -> Duration {
+        // Days from the civil date, counting the years from March so that the leap day is the last
+        // day of the year (see http://howardhinnant.github.io/date_algorithms.html)
+        let (year, month) = if self.month <= 2 {
+            (self.year - 1, self.month + 9)
+        } else {
+            (self.year, self.month - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+
+        Duration::from_secs(days * 86_400 + self.hours * 3_600 + self.minutes * 60 + self.seconds)
+    }
+}
+
+/// The raw content of the time registers of the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+
+    /// The century register, if the FADT describes one.
+    century: Option<u8>,
+}
+
+/// Read the current date and time from the RTC. The registers are read twice until two
+/// consecutive reads give the same values, so that the date is not torn by an update of the RTC
+/// between two register reads.
+///
+/// Returns `None` if the RTC returns an invalid date, or if it never gives a stable date.
+#[must_use]
+pub fn read() -> Option<DateTime> {
+    let mut previous = read_registers();
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let current = read_registers();
+        if current == previous {
+            return decode(current, x86_64::irq::without(|| read_cmos(STATUS_B)));
+        }
+        previous = current;
+    }
+    log::warn!("RTC: the date is not stable");
+    None
+}
+
+fn read_registers() -> Registers {
+    x86_64::irq::without(|| {
+        while read_cmos(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        Registers {
+            seconds: read_cmos(SECONDS),
+            minutes: read_cmos(MINUTES),
+            hours: read_cmos(HOURS),
+            day: read_cmos(DAY),
+            month: read_cmos(MONTH),
+            year: read_cmos(YEAR),
+            century: century_register().map(read_cmos),
+        }
+    })
+}
+
+/// Convert the registers of the RTC to a date, according to the format given by the status
+/// register B.
+fn decode(registers: Registers, status: u8) -> Option<DateTime> {
+    let convert = |value: u8| {
+        if status & STATUS_B_BINARY != 0 {
+            u64::from(value)
+        } else {
+            u64::from((value >> 4) * 10 + (value & 0x0F))
+        }
+    };
+
+    let mut hours = convert(registers.hours & !HOURS_PM);
+    if status & STATUS_B_24_HOURS == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hours %= 12;
+        if registers.hours & HOURS_PM != 0 {
+            hours += 12;
+        }
+    }
+
+    let year = convert(registers.year);
+    let date = DateTime {
+        year: match registers.century.map(convert) {
+            // Some firmwares describe a century register that they never update
+            Some(century) if century >= MIN_CENTURY => century * 100 + year,
+            _ if year < CENTURY_PIVOT => 2000 + year,
+            _ => 1900 + year,
+        },
+        month: convert(registers.month),
+        day: convert(registers.day),
+        hours,
+        minutes: convert(registers.minutes),
+        seconds: convert(registers.seconds),
+    };
+
+    let valid = (1..=12).contains(&date.month)
+        && (1..=31).contains(&date.day)
+        && date.hours < 24
+        && date.minutes < 60
+        && date.seconds < 60;
+    if !valid {
+        log::warn!("RTC: invalid date {date:?}");
+        return None;
+    }
+    Some(date)
+}
+
+/// Returns the CMOS register holding the century, as described by the FADT.
+fn century_register() -> Option<u8> {
+    crate::arch::acpi::fadt().and_then(|fadt| fadt.century)
+}
+
+fn read_cmos(register: u8) -> u8 {
+    let _guard = CMOS_LOCK.lock();
+    unsafe {
+        CMOS_ADDRESS.write(register);
+        CMOS_DATA.read()
+    }
+}
@@ -0,0 +1,80 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use sync::{MpscQueue, Once};
+
+use crate::arch::irq::{self, IrqFlags, IrqReturn};
+
+use super::uart::{InterruptEnable, ModemControl, Uart};
+
+/// The serial port the logs and the console are written to.
+static UART: Once<Uart> = Once::new();
+
+/// The bytes read by the IRQ handler and not yet given to the console. If the console thread is
+/// too slow and the queue is full, the new bytes are lost.
+static RECEIVED: MpscQueue<u8, 256> = MpscQueue::new();
+
+/// The number of bytes received with a line error (see [`super::uart::UartError`]) since the IRQ
+/// thread last reported them.
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Enable the reception of the serial port used by the logs (see [`crate::log::serial_port`]).
+/// The output of the serial port is still done by polling by the logger, but the received bytes
+/// now raise an interrupt and are given to the console (see [`crate::console::input`]) by the IRQ
+/// thread, so that the console is never run in interrupt context. Does nothing if the logs are not
+/// written to a serial port.
+pub fn setup() {
+    let Some(port) = crate::log::serial_port() else {
+        log::info!("No serial port, the serial console is disabled");
+        return;
+    };
+
+    let uart = UART.call_once(|| Uart::new(port));
+    uart.set_modem_control(ModemControl::DTR | ModemControl::RTS | ModemControl::OUT2);
+    if let Err(error) =
+        irq::request_threaded_irq(port.irq(), receive, deliver, IrqFlags::NONE, "serial")
+    {
+        log::warn!("Failed to request the IRQ of {port:?}: {error:?}");
+        return;
+    }
+    uart.set_interrupts(InterruptEnable::RECEIVED_DATA);
+}
+
+/// The IRQ handler of the serial port: all the received bytes are read until the FIFO is empty,
+/// which acknowledges the interrupt, and are queued for the IRQ thread. The bytes received with a
+/// line error are dropped.
+fn receive(_: u8) -> IrqReturn {
+    let Some(uart) = UART.get() else {
+        return IrqReturn::None;
+    };
+
+    let mut received = false;
+    loop {
+        match uart.read_byte() {
+            Ok(Some(byte)) => {
+                let _ = RECEIVED.push(byte);
+            }
+            Ok(None) => break,
+            Err(_) => {
+                ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        received = true;
+    }
+    if received {
+        IrqReturn::WakeThread
+    } else {
+        IrqReturn::None
+    }
+}
+
+/// The IRQ thread of the serial port: give the received bytes to the console, and report the line
+/// errors.
+fn deliver(_: u8) {
+    let errors = ERRORS.swap(0, Ordering::Relaxed);
+    if errors > 0 {
+        log::warn!("{errors} bytes received with an error on the serial port");
+    }
+    while let Some(byte) = RECEIVED.pop() {
+        crate::console::input(byte);
+    }
+}
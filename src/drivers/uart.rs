@@ -0,0 +1,364 @@
+//! The 16550 UART of the legacy serial ports. The logs and the console are written to the first
+//! serial port found at boot (see [`detect`]), configured with [`crate::config::SERIAL_BAUD`]
+//! bauds, so that they can be read on machines whose serial port is not at COM1 or does not run
+//! at 115200 bauds.
+use core::fmt;
+
+use bitflags::bitflags;
+
+use crate::arch::port::{Port, ReadOnlyPort, WriteOnlyPort};
+
+/// The frequency of the UART clock divided by 16: the baud rate is this value divided by the
+/// divisor latch.
+const MAX_BAUD: u32 = 115_200;
+
+/// The offsets of the UART registers from the base port. When the DLAB bit of the line control
+/// register is set, the first two registers are the low and high bytes of the divisor latch.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+/// The bits of the line control register: the divisor latch access bit, and the stop bits.
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+
+/// The bits of the FIFO control register: enable the FIFOs and clear them.
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR: u8 = (1 << 1) | (1 << 2);
+
+/// The byte sent in loopback mode to check that a UART works, and the number of times the line
+/// status is read while waiting for it. Each read takes about a microsecond.
+const LOOPBACK_TEST: u8 = 0xAE;
+const SELF_TEST_POLLS: u32 = 100_000;
+
+/// A legacy serial port, at its conventional I/O ports and IRQ line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataBits {
+    Five = 0b00,
+    Six = 0b01,
+    Seven = 0b10,
+    Eight = 0b11,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+    Mark = 0b101,
+    Space = 0b111,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// The number of bytes received before the UART raises the receive interrupt. The interrupt is
+/// also raised after a timeout if fewer bytes are waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FifoTrigger {
+    One = 0b00,
+    Four = 0b01,
+    Eight = 0b10,
+    Fourteen = 0b11,
+}
+
+/// The configuration of the line and of the FIFOs of a UART.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Config {
+    /// The baud rate, which must divide 115200.
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+
+    /// The receive trigger level, or `None` to disable the FIFOs.
+    pub fifo: Option<FifoTrigger>,
+}
+
+bitflags! {
+    /// The interrupts raised by the UART.
+    pub struct InterruptEnable : u8 {
+        const RECEIVED_DATA = 1 << 0;
+        const TRANSMITTER_EMPTY = 1 << 1;
+        const LINE_STATUS = 1 << 2;
+        const MODEM_STATUS = 1 << 3;
+    }
+}
+
+bitflags! {
+    /// The modem control register.
+    pub struct ModemControl : u8 {
+        const DTR = 1 << 0;
+        const RTS = 1 << 1;
+        const OUT1 = 1 << 2;
+        /// Connect the interrupt output of the UART to its IRQ line.
+        const OUT2 = 1 << 3;
+        /// Connect the output of the UART to its input, to test it.
+        const LOOPBACK = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// The line status register. Reading it clears the error bits.
+    pub struct LineStatus : u8 {
+        const DATA_READY = 1 << 0;
+        const OVERRUN_ERROR = 1 << 1;
+        const PARITY_ERROR = 1 << 2;
+        const FRAMING_ERROR = 1 << 3;
+        const BREAK = 1 << 4;
+        /// The transmit holding register (or the transmit FIFO) is empty.
+        const TRANSMITTER_HOLDING_EMPTY = 1 << 5;
+        /// All the bytes are transmitted.
+        const TRANSMITTER_EMPTY = 1 << 6;
+        /// At least one byte of the receive FIFO has an error.
+        const FIFO_ERROR = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// The modem status register.
+    pub struct ModemStatus : u8 {
+        const DELTA_CTS = 1 << 0;
+        const DELTA_DSR = 1 << 1;
+        const TRAILING_EDGE_RI = 1 << 2;
+        const DELTA_DCD = 1 << 3;
+        const CTS = 1 << 4;
+        const DSR = 1 << 5;
+        const RI = 1 << 6;
+        const DCD = 1 << 7;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UartError {
+    /// The baud rate does not divide 115200.
+    InvalidBaudRate,
+
+    /// A byte was received before the previous one was read, and was lost. The byte read with
+    /// this error is dropped as well.
+    Overrun,
+
+    /// The parity of the received byte is wrong.
+    Parity,
+
+    /// The received byte has no valid stop bit, usually because the baud rate does not match.
+    Framing,
+
+    /// The line was held low for longer than a byte.
+    Break,
+}
+
+impl ComPort {
+    pub const ALL: [Self; 4] = [Self::Com1, Self::Com2, Self::Com3, Self::Com4];
+
+    /// Returns the first I/O port of the registers of the UART.
+    #[must_use]
+    pub const fn base(self) -> u16 {
+        match self {
+            Self::Com1 => 0x3F8,
+            Self::Com2 => 0x2F8,
+            Self::Com3 => 0x3E8,
+            Self::Com4 => 0x2E8,
+        }
+    }
+
+    /// Returns the legacy IRQ line of the UART. COM3 and COM4 share the lines of COM1 and COM2.
+    #[must_use]
+    pub const fn irq(self) -> u8 {
+        match self {
+            Self::Com1 | Self::Com3 => 4,
+            Self::Com2 | Self::Com4 => 3,
+        }
+    }
+}
+
+impl Config {
+    /// 115200 bauds, 8 data bits, no parity and one stop bit, with the FIFOs enabled.
+    pub const DEFAULT: Self = Self {
+        baud: MAX_BAUD,
+        data_bits: DataBits::Eight,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        fifo: Some(FifoTrigger::Fourteen),
+    };
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// A 16550 UART.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Uart {
+    port: ComPort,
+}
+
+impl Uart {
+    #[must_use]
+    pub const fn new(port: ComPort) -> Self {
+        Self { port }
+    }
+
+    #[must_use]
+    pub const fn port(self) -> ComPort {
+        self.port
+    }
+
+    /// Returns true if a UART answers at this port: its scratch register keeps the value written
+    /// to it.
+    #[must_use]
+    pub fn probe(self) -> bool {
+        unsafe {
+            self.register(SCRATCH).write(0x5A);
+            self.register(SCRATCH).read() == 0x5A
+        }
+    }
+
+    /// Returns true if the UART receives the byte it sends in loopback mode. This must be done
+    /// once the UART is configured (see [`Uart::configure`]), because it does not transmit
+    /// anything before. The bytes waiting to be read are lost.
+    #[must_use]
+    pub fn self_test(self) -> bool {
+        let control = unsafe { self.register(MODEM_CONTROL).read() };
+        self.set_modem_control(ModemControl::LOOPBACK | ModemControl::RTS | ModemControl::DTR);
+        // The FIFOs were cleared by the configuration, so at most one byte is waiting
+        let _ = self.read_byte();
+
+        // The byte takes about a millisecond to be received at 9600 bauds
+        self.write_byte(LOOPBACK_TEST);
+        let received = (0..SELF_TEST_POLLS).find_map(|_| self.read_byte().ok().flatten());
+        unsafe { self.register(MODEM_CONTROL).write(control) };
+        received == Some(LOOPBACK_TEST)
+    }
+
+    /// Configure the line and the FIFOs of the UART. The interrupts are disabled, and DTR and RTS
+    /// are asserted.
+    ///
+    /// # Errors
+    /// - [`UartError::InvalidBaudRate`]: The baud rate does not divide 115200.
+    pub fn configure(self, config: &Config) -> Result<(), UartError> {
+        if config.baud == 0 || !MAX_BAUD.is_multiple_of(config.baud) {
+            return Err(UartError::InvalidBaudRate);
+        }
+        let divisor = u16::try_from(MAX_BAUD / config.baud)
+            .map_err(|_| UartError::InvalidBaudRate)?
+            .to_le_bytes();
+
+        let mut line = config.data_bits as u8 | (config.parity as u8) << 3;
+        if config.stop_bits == StopBits::Two {
+            line |= LCR_TWO_STOP_BITS;
+        }
+        let fifo = config
+            .fifo
+            .map_or(0, |trigger| FCR_ENABLE | FCR_CLEAR | (trigger as u8) << 6);
+
+        unsafe {
+            self.register(INTERRUPT_ENABLE).write(0);
+            self.register(LINE_CONTROL).write(LCR_DLAB);
+            self.register(DIVISOR_LOW).write(divisor[0]);
+            self.register(DIVISOR_HIGH).write(divisor[1]);
+            self.register(LINE_CONTROL).write(line);
+            WriteOnlyPort::<u8>::new(self.port.base() + FIFO_CONTROL).write(fifo);
+        }
+        self.set_modem_control(ModemControl::DTR | ModemControl::RTS);
+        Ok(())
+    }
+
+    pub fn set_interrupts(self, interrupts: InterruptEnable) {
+        unsafe { self.register(INTERRUPT_ENABLE).write(interrupts.bits()) };
+    }
+
+    pub fn set_modem_control(self, control: ModemControl) {
+        unsafe { self.register(MODEM_CONTROL).write(control.bits()) };
+    }
+
+    #[must_use]
+    pub fn line_status(self) -> LineStatus {
+        let port = ReadOnlyPort::<u8>::new(self.port.base() + LINE_STATUS);
+        LineStatus::from_bits_truncate(unsafe { port.read() })
+    }
+
+    #[must_use]
+    pub fn modem_status(self) -> ModemStatus {
+        let port = ReadOnlyPort::<u8>::new(self.port.base() + MODEM_STATUS);
+        ModemStatus::from_bits_truncate(unsafe { port.read() })
+    }
+
+    /// Wait until the transmitter can accept a byte, then send it.
+    pub fn write_byte(self, byte: u8) {
+        while !self
+            .line_status()
+            .contains(LineStatus::TRANSMITTER_HOLDING_EMPTY)
+        {
+            core::hint::spin_loop();
+        }
+        unsafe { self.register(DATA).write(byte) };
+    }
+
+    /// Read a received byte, or returns `None` if no byte is waiting.
+    ///
+    /// # Errors
+    /// The error of the line when the byte was received. The byte is consumed, so that the next
+    /// call returns the next byte.
+    pub fn read_byte(self) -> Result<Option<u8>, UartError> {
+        let status = self.line_status();
+        if !status.contains(LineStatus::DATA_READY) {
+            return Ok(None);
+        }
+
+        let byte = unsafe { self.register(DATA).read() };
+        if status.contains(LineStatus::BREAK) {
+            Err(UartError::Break)
+        } else if status.contains(LineStatus::FRAMING_ERROR) {
+            Err(UartError::Framing)
+        } else if status.contains(LineStatus::PARITY_ERROR) {
+            Err(UartError::Parity)
+        } else if status.contains(LineStatus::OVERRUN_ERROR) {
+            Err(UartError::Overrun)
+        } else {
+            Ok(Some(byte))
+        }
+    }
+
+    /// Returns the read-write register at the given offset. The read-only and write-only registers
+    /// are accessed through their own port type.
+    const fn register(self, offset: u16) -> Port<u8> {
+        Port::new(self.port.base() + offset)
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.write_byte(byte));
+        Ok(())
+    }
+}
+
+/// Returns the serial ports where a UART answers, in the order of their number. The UARTs are not
+/// configured, and may still fail their self test (see [`Uart::self_test`]).
+pub fn detect() -> impl Iterator<Item = Uart> {
+    ComPort::ALL
+        .into_iter()
+        .map(Uart::new)
+        .filter(|uart| uart.probe())
+}
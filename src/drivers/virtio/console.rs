@@ -0,0 +1,244 @@
+use alloc::{vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use sync::Once;
+
+use crate::{
+    arch::{
+        address::phys_to_virt,
+        msi::{self, Message, TriggerMode},
+    },
+    console::{self, Sink},
+    drivers::pci::{self, driver::ProbeError, DeviceId, PciDevice, PciDriver},
+    mm::{
+        frame::{AllocationFlags, Allocator},
+        FRAME_ALLOCATOR,
+    },
+    Spinlock,
+};
+
+use super::{
+    Buffer, Transport, Virtqueue, NO_VECTOR, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK,
+    STATUS_FAILED, VENDOR_ID,
+};
+
+/// The device identifier of the transitional virtio console.
+const DEVICE_ID: u16 = 0x1003;
+
+/// The queues of the first port of the console. The other ports require the multiport feature,
+/// which is not negotiated.
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The receive buffers, which all fit in a single page.
+const RECEIVE_BUFFERS: usize = 16;
+const RECEIVE_BUFFER_SIZE: usize = 256;
+
+/// The number of times the transmit queue is polled before a write is abandoned.
+const TRANSMIT_POLL_LOOPS: usize = 1_000_000;
+
+/// The devices handled by the driver.
+const ID_TABLE: [DeviceId; 1] = [DeviceId::device(VENDOR_ID, DEVICE_ID)];
+
+static DRIVER: Driver = Driver;
+static SINK: ConsoleSink = ConsoleSink;
+
+/// The console bound to the driver. Only one virtio console is supported.
+static CONSOLE: Once<Console> = Once::new();
+
+struct Driver;
+struct ConsoleSink;
+
+struct Console {
+    transport: Transport,
+    receiver: Spinlock<Receiver>,
+    transmit: Spinlock<Virtqueue>,
+
+    /// The physical address of the page holding the receive buffers, and of the page holding the
+    /// data being transmitted.
+    receive_page: u64,
+    transmit_page: u64,
+
+    /// Set when the device is removed from the driver.
+    removed: AtomicBool,
+}
+
+struct Receiver {
+    queue: Virtqueue,
+
+    /// The receive buffer given to the device with each head descriptor.
+    buffers: Vec<Option<usize>>,
+}
+
+/// Register the virtio console driver. The first port of the console is then used as an
+/// additional sink and input device of the kernel console.
+pub fn setup() {
+    if let Err(error) = pci::register_driver(&DRIVER) {
+        log::warn!("Failed to register the virtio console driver: {error:?}");
+    }
+}
+
+impl PciDriver for Driver {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+
+    fn id_table(&self) -> &'static [DeviceId] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<(), ProbeError> {
+        if CONSOLE.get().is_some() {
+            return Err(ProbeError::NotSupported);
+        }
+        let mut transport = Transport::new(device).map_err(|_| ProbeError::NotSupported)?;
+        transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        transport.set_guest_features(0);
+
+        let console =
+            init(device, &mut transport).inspect_err(|_| transport.add_status(STATUS_FAILED))?;
+        let console = CONSOLE.call_once(|| console);
+        console.transport.add_status(STATUS_DRIVER_OK);
+        console.transport.notify(RECEIVE_QUEUE);
+
+        if let Err(error) = console::register_sink(&SINK) {
+            log::warn!("Failed to register the virtio console sink: {error:?}");
+        }
+        Ok(())
+    }
+
+    fn remove(&self, _: &PciDevice) {
+        if let Some(console) = CONSOLE.get() {
+            console.removed.store(true, Ordering::Relaxed);
+            console.transport.set_status(0);
+        }
+    }
+}
+
+impl Sink for ConsoleSink {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+
+    /// Copy the string to the transmit page, chunk by chunk, and wait until the device has
+    /// consumed each chunk before reusing the page.
+    fn write(&self, s: &str) {
+        let Some(console) = CONSOLE.get() else {
+            return;
+        };
+        if console.removed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let page = phys_to_virt(x86_64::address::Physical::new(console.transmit_page));
+        x86_64::irq::without(|| {
+            let mut transmit = console.transmit.lock();
+            for chunk in s.as_bytes().chunks(x86_64::paging::PAGE_SIZE) {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        chunk.as_ptr(),
+                        page.as_mut_ptr::<u8>(),
+                        chunk.len(),
+                    );
+                }
+                let buffer = Buffer {
+                    address: console.transmit_page,
+                    len: u32::try_from(chunk.len()).unwrap(),
+                    writable: false,
+                };
+                if transmit.push(&[buffer]).is_none() {
+                    return;
+                }
+                console.transport.notify(TRANSMIT_QUEUE);
+
+                let sent = (0..TRANSMIT_POLL_LOOPS).any(|_| {
+                    core::hint::spin_loop();
+                    transmit.pop_used().is_some()
+                });
+                if !sent {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Set up the interrupt, the queues and the buffers of the console.
+fn init(device: &PciDevice, transport: &mut Transport) -> Result<Console, ProbeError> {
+    let vector = msi::allocate(interrupt, "virtio-console").ok_or(ProbeError::InitFailed)?;
+    let lapic = crate::arch::smp::get_cpu_info().lapic_id;
+    let message = Message::new(vector, lapic, TriggerMode::Edge);
+    if pci::msi::enable_msix(device.address, &[(0, message)]).is_err() {
+        msi::free(vector);
+        return Err(ProbeError::NotSupported);
+    }
+    transport.set_msix(true);
+    transport.set_config_vector(NO_VECTOR);
+
+    let receive = Virtqueue::new(transport, RECEIVE_QUEUE, 0);
+    let transmit = Virtqueue::new(transport, TRANSMIT_QUEUE, NO_VECTOR);
+    let flags = AllocationFlags::KERNEL | AllocationFlags::ZEROED;
+    let pages = x86_64::irq::without(|| unsafe { FRAME_ALLOCATOR.lock().allocate_range(2, flags) });
+    let (Ok(mut receive), Ok(transmit), Some(pages)) = (receive, transmit, pages) else {
+        return Err(ProbeError::InitFailed);
+    };
+
+    let receive_page = pages.start.start().as_u64();
+    let mut buffers = vec![None; usize::from(receive.size())];
+    for slot in 0..RECEIVE_BUFFERS.min(usize::from(receive.size())) {
+        let head = receive
+            .push(&[receive_buffer(receive_page, slot)])
+            .ok_or(ProbeError::InitFailed)?;
+        buffers[usize::from(head)] = Some(slot);
+    }
+
+    Ok(Console {
+        transport: *transport,
+        receiver: Spinlock::new(Receiver {
+            queue: receive,
+            buffers,
+        }),
+        transmit: Spinlock::new(transmit),
+        receive_page,
+        transmit_page: receive_page + x86_64::paging::PAGE_SIZE as u64,
+        removed: AtomicBool::new(false),
+    })
+}
+
+/// The interrupt handler of the receive queue: the received bytes are given to the console, and
+/// the buffers are given back to the device.
+fn interrupt(_: u8) {
+    let Some(console) = CONSOLE.get() else {
+        return;
+    };
+
+    let mut receiver = console.receiver.lock();
+    while let Some((head, len)) = receiver.queue.pop_used() {
+        let Some(slot) = receiver.buffers[usize::from(head)].take() else {
+            continue;
+        };
+
+        let buffer = receive_buffer(console.receive_page, slot);
+        let len = usize::try_from(len.min(buffer.len)).unwrap();
+        let data = phys_to_virt(x86_64::address::Physical::new(buffer.address));
+        let data = unsafe { core::slice::from_raw_parts(data.as_ptr::<u8>(), len) };
+        for &byte in data {
+            console::input(byte);
+        }
+
+        if let Some(head) = receiver.queue.push(&[buffer]) {
+            receiver.buffers[usize::from(head)] = Some(slot);
+        }
+    }
+    drop(receiver);
+    console.transport.notify(RECEIVE_QUEUE);
+}
+
+/// Returns the receive buffer with the given index.
+#[allow(clippy::cast_possible_truncation)]
+fn receive_buffer(page: u64, slot: usize) -> Buffer {
+    Buffer {
+        address: page + (slot * RECEIVE_BUFFER_SIZE) as u64,
+        len: RECEIVE_BUFFER_SIZE as u32,
+        writable: true,
+    }
+}
@@ -0,0 +1,340 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{
+    arch::{
+        address::phys_to_virt,
+        port::{Port, PortValue},
+    },
+    mm::{
+        frame::{AllocationFlags, Allocator},
+        FRAME_ALLOCATOR,
+    },
+};
+
+use super::pci::{Bar, PciDevice, COMMAND, COMMAND_BUS_MASTER, COMMAND_IO_SPACE};
+
+pub mod console;
+
+/// The vendor identifier of the virtio devices.
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// The registers of the legacy PCI transport, as offsets in the I/O BAR 0 of the device.
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+const CONFIG_MSIX_VECTOR: u16 = 0x14;
+const QUEUE_MSIX_VECTOR: u16 = 0x16;
+
+/// The offset of the device-specific configuration, which depends on whether MSI-X is enabled.
+const DEVICE_CONFIG: u16 = 0x14;
+const DEVICE_CONFIG_MSIX: u16 = 0x18;
+
+/// The MSI-X vector value meaning that no vector is used.
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+/// The bits of the device status register.
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+pub const STATUS_DRIVER: u8 = 1 << 1;
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+/// The legacy transport requires the used ring of a virtqueue to be aligned on a page.
+const QUEUE_ALIGN: usize = 4096;
+
+/// The flags of a descriptor: the buffer continues in the next descriptor, and the buffer is
+/// written by the device.
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+/// The size of a descriptor, and of an element of the used ring.
+const DESCRIPTOR_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VirtioError {
+    /// The BAR 0 of the device is not an I/O BAR: only the legacy transport is supported.
+    UnsupportedTransport,
+
+    /// The queue does not exist.
+    NoQueue,
+
+    /// The memory of a queue or of the buffers could not be allocated.
+    OutOfMemory,
+}
+
+/// The legacy (virtio 0.9.5) PCI transport of a virtio device, used by the transitional devices
+/// that QEMU exposes by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Transport {
+    port: u16,
+    msix: bool,
+}
+
+impl Transport {
+    /// Create the transport of the given device: the decoding of its I/O BAR and the bus
+    /// mastering are enabled, and the device is reset.
+    ///
+    /// # Errors
+    /// - [`VirtioError::UnsupportedTransport`]: The BAR 0 of the device is not an I/O BAR.
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        let Some(Bar::Io { port, .. }) = device.bars[0] else {
+            return Err(VirtioError::UnsupportedTransport);
+        };
+        let command = device.address.read16(COMMAND);
+        device
+            .address
+            .write16(COMMAND, command | COMMAND_IO_SPACE | COMMAND_BUS_MASTER);
+
+        let transport = Self { port, msix: false };
+        transport.set_status(0);
+        Ok(transport)
+    }
+
+    /// Tell the transport whether MSI-X is enabled on the device, which moves the device
+    /// configuration to make room for the MSI-X vector registers.
+    pub fn set_msix(&mut self, msix: bool) {
+        self.msix = msix;
+    }
+
+    /// Returns the features offered by the device.
+    #[must_use]
+    pub fn device_features(&self) -> u32 {
+        unsafe { self.register(DEVICE_FEATURES).read() }
+    }
+
+    /// Accept the given features, which must be a subset of the features offered by the device.
+    pub fn set_guest_features(&self, features: u32) {
+        unsafe { self.register(GUEST_FEATURES).write(features) };
+    }
+
+    /// Returns the device status.
+    #[must_use]
+    pub fn status(&self) -> u8 {
+        unsafe { self.register(DEVICE_STATUS).read() }
+    }
+
+    /// Set the device status. Writing zero resets the device.
+    pub fn set_status(&self, status: u8) {
+        unsafe { self.register(DEVICE_STATUS).write(status) };
+    }
+
+    /// Add the given bits to the device status.
+    pub fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    /// Read and acknowledge the interrupt status: bit 0 is set for a used buffer notification, and
+    /// bit 1 for a configuration change. This is only useful with the legacy `INTx` interrupt.
+    #[must_use]
+    pub fn isr(&self) -> u8 {
+        unsafe { self.register(ISR_STATUS).read() }
+    }
+
+    /// Set the MSI-X vector used for the configuration changes.
+    pub fn set_config_vector(&self, vector: u16) {
+        unsafe { self.register(CONFIG_MSIX_VECTOR).write(vector) };
+    }
+
+    /// Read a byte of the device-specific configuration.
+    #[must_use]
+    pub fn config8(&self, offset: u16) -> u8 {
+        let base = if self.msix {
+            DEVICE_CONFIG_MSIX
+        } else {
+            DEVICE_CONFIG
+        };
+        unsafe { self.register(base + offset).read() }
+    }
+
+    /// Notify the device that new buffers are available in the given queue.
+    pub fn notify(&self, queue: u16) {
+        unsafe { self.register(QUEUE_NOTIFY).write(queue) };
+    }
+
+    /// Returns the port of the register at the given offset of the I/O BAR.
+    fn register<T: PortValue>(self, offset: u16) -> Port<T> {
+        Port::new(self.port + offset)
+    }
+}
+
+/// A buffer given to the device, as part of a chain of buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Buffer {
+    /// The physical address of the buffer.
+    pub address: u64,
+    pub len: u32,
+
+    /// Set if the buffer is written by the device, clear if it is read by the device.
+    pub writable: bool,
+}
+
+/// A split virtqueue, in the layout of the legacy transport: the descriptor table and the
+/// available ring, then the used ring on the next page boundary. The memory of the queue is never
+/// freed.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: *mut u8,
+    available: *mut u8,
+    used: *mut u8,
+
+    /// The descriptors not used by a chain given to the device.
+    free: Vec<u16>,
+
+    /// The index of the next available entry, and of the next used entry to read.
+    next_available: u16,
+    next_used: u16,
+}
+
+/// The memory of a virtqueue is only accessed through the `&mut self` methods.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// Allocate the given queue of the device and give its memory to the device, with the given
+    /// MSI-X vector (or [`NO_VECTOR`]) for its notifications.
+    ///
+    /// # Errors
+    /// - [`VirtioError::NoQueue`]: The device does not have this queue.
+    /// - [`VirtioError::OutOfMemory`]: The memory of the queue could not be allocated.
+    pub fn new(transport: &Transport, index: u16, vector: u16) -> Result<Self, VirtioError> {
+        let size = unsafe {
+            transport.register(QUEUE_SELECT).write(index);
+            transport.register(QUEUE_SIZE).read()
+        };
+        if size == 0 {
+            return Err(VirtioError::NoQueue);
+        }
+
+        let entries = usize::from(size);
+        let driver_area = entries * DESCRIPTOR_SIZE + 6 + 2 * entries;
+        let device_area = 6 + USED_ELEMENT_SIZE * entries;
+        let used_offset = driver_area.next_multiple_of(QUEUE_ALIGN);
+        let pages = (used_offset + device_area).div_ceil(x86_64::paging::PAGE_SIZE);
+        let flags = AllocationFlags::KERNEL | AllocationFlags::ZEROED;
+        let range =
+            x86_64::irq::without(|| unsafe { FRAME_ALLOCATOR.lock().allocate_range(pages, flags) })
+                .ok_or(VirtioError::OutOfMemory)?;
+
+        let physical = range.start.start();
+        let base = phys_to_virt(physical).as_mut_ptr::<u8>();
+        let pfn = u32::try_from(physical.as_u64() / QUEUE_ALIGN as u64)
+            .map_err(|_| VirtioError::OutOfMemory)?;
+        unsafe {
+            transport.register(QUEUE_ADDRESS).write(pfn);
+            if transport.msix {
+                transport.register(QUEUE_MSIX_VECTOR).write(vector);
+            }
+        }
+
+        Ok(Self {
+            index,
+            size,
+            descriptors: base,
+            available: unsafe { base.add(entries * DESCRIPTOR_SIZE) },
+            used: unsafe { base.add(used_offset) },
+            free: (0..size).rev().collect(),
+            next_available: 0,
+            next_used: 0,
+        })
+    }
+
+    /// Returns the index of the queue.
+    #[must_use]
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the number of descriptors of the queue.
+    #[must_use]
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Give a chain of buffers to the device, and returns the index of its head descriptor, which
+    /// identifies the chain when the device is done with it (see [`Virtqueue::pop_used`]). The
+    /// device must then be notified (see [`Transport::notify`]). Returns `None` if there are not
+    /// enough free descriptors.
+    #[allow(clippy::cast_ptr_alignment)]
+    pub fn push(&mut self, chain: &[Buffer]) -> Option<u16> {
+        if chain.is_empty() || chain.len() > self.free.len() {
+            return None;
+        }
+
+        let indices: Vec<u16> = (0..chain.len()).filter_map(|_| self.free.pop()).collect();
+        for (position, (buffer, &index)) in chain.iter().zip(&indices).enumerate() {
+            let next = indices.get(position + 1).copied();
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            unsafe {
+                let descriptor = self.descriptors.add(usize::from(index) * DESCRIPTOR_SIZE);
+                descriptor.cast::<u64>().write_volatile(buffer.address);
+                descriptor.add(8).cast::<u32>().write_volatile(buffer.len);
+                descriptor.add(12).cast::<u16>().write_volatile(flags);
+                descriptor
+                    .add(14)
+                    .cast::<u16>()
+                    .write_volatile(next.unwrap_or(0));
+            }
+        }
+
+        // The entry must be visible to the device before the index that publishes it
+        let head = indices[0];
+        let slot = usize::from(self.next_available % self.size);
+        unsafe {
+            self.available
+                .add(4 + 2 * slot)
+                .cast::<u16>()
+                .write_volatile(head);
+            fence(Ordering::SeqCst);
+            self.next_available = self.next_available.wrapping_add(1);
+            self.available
+                .add(2)
+                .cast::<u16>()
+                .write_volatile(self.next_available);
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Returns the next chain the device is done with, as the index of its head descriptor and the
+    /// number of bytes the device wrote to it. Its descriptors are freed.
+    #[allow(clippy::cast_ptr_alignment)]
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe { self.used.add(2).cast::<u16>().read_volatile() };
+        if used == self.next_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = usize::from(self.next_used % self.size);
+        let (head, len) = unsafe {
+            let element = self.used.add(4 + USED_ELEMENT_SIZE * slot);
+            (
+                element.cast::<u32>().read_volatile(),
+                element.add(4).cast::<u32>().read_volatile(),
+            )
+        };
+        self.next_used = self.next_used.wrapping_add(1);
+
+        let head = u16::try_from(head).expect("Invalid used descriptor");
+        let mut index = head;
+        loop {
+            self.free.push(index);
+            let descriptor = unsafe { self.descriptors.add(usize::from(index) * DESCRIPTOR_SIZE) };
+            let flags = unsafe { descriptor.add(12).cast::<u16>().read_volatile() };
+            if flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            index = unsafe { descriptor.add(14).cast::<u16>().read_volatile() };
+        }
+        Some((head, len))
+    }
+}
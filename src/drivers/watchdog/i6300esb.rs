@@ -0,0 +1,201 @@
+use core::time::Duration;
+
+use sync::Once;
+
+use crate::drivers::pci::{
+    self, driver::ProbeError, Address, Bar, DeviceId, PciDevice, PciDriver, COMMAND,
+    COMMAND_MEMORY_SPACE,
+};
+
+use super::Watchdog;
+
+/// The identifier of the watchdog of the Intel 6300ESB chipset, which QEMU emulates with
+/// `-device i6300esb`.
+const VENDOR_ID: u16 = 0x8086;
+const DEVICE_ID: u16 = 0x25AB;
+
+/// The registers of the configuration space of the watchdog.
+const CONFIG: u8 = 0x60;
+const LOCK: u8 = 0x68;
+
+/// The memory-mapped registers, as offsets in the memory BAR 0: the preload values of the two
+/// stages of the countdown, and the reload register.
+const TIMER1: usize = 0x00;
+const TIMER2: usize = 0x04;
+const RELOAD: usize = 0x0C;
+const REGISTERS_SIZE: usize = 0x10;
+
+/// The configuration bits: no interrupt at the end of the first stage, the timer frequency of
+/// about 1 kHz and the reset output enabled (both clear).
+const CONFIG_INTERRUPT_DISABLED: u16 = 0b11;
+
+/// The bits of the lock register.
+const LOCK_LOCKED: u8 = 1 << 0;
+const LOCK_ENABLE: u8 = 1 << 1;
+
+/// The bits of the reload register: writing the first one restarts the countdown, and the second
+/// one is set after a reset caused by the watchdog (and cleared by writing one to it).
+const RELOAD_RELOAD: u16 = 1 << 8;
+const RELOAD_TIMEOUT: u16 = 1 << 9;
+
+/// The sequence written to the reload register to unlock the next write to a register.
+const UNLOCK: [u16; 2] = [0x80, 0x86];
+
+/// The timers count at about 1 kHz: with a preload value of a number of seconds shifted left by
+/// 9, each stage lasts about half this number of seconds, and both stages together about this
+/// number of seconds. The preload value has 20 bits, which bounds the timeout.
+const PRELOAD_SHIFT: u32 = 9;
+const MAX_TIMEOUT: Duration = Duration::from_secs(2046);
+
+/// The devices handled by the driver.
+const ID_TABLE: [DeviceId; 1] = [DeviceId::device(VENDOR_ID, DEVICE_ID)];
+
+static DRIVER: Driver = Driver;
+
+/// The watchdog bound to the driver. Only one watchdog is supported.
+static WATCHDOG: Once<I6300Esb> = Once::new();
+
+struct Driver;
+
+/// The watchdog of the Intel 6300ESB chipset. After its timeout, it counts down a second time
+/// before resetting the system. The first countdown would raise an interrupt, which is disabled.
+pub struct I6300Esb {
+    address: Address,
+    registers: *mut u8,
+}
+
+/// The registers are only written with the unlock sequence and a single write, which the watchdog
+/// serializes itself.
+unsafe impl Send for I6300Esb {}
+unsafe impl Sync for I6300Esb {}
+
+/// Register the i6300esb driver. The watchdog is started as soon as it is found.
+pub fn setup() {
+    if let Err(error) = pci::register_driver(&DRIVER) {
+        log::warn!("Failed to register the i6300esb driver: {error:?}");
+    }
+}
+
+impl PciDriver for Driver {
+    fn name(&self) -> &'static str {
+        "i6300esb"
+    }
+
+    fn id_table(&self) -> &'static [DeviceId] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<(), ProbeError> {
+        if WATCHDOG.get().is_some() {
+            return Err(ProbeError::NotSupported);
+        }
+        let Some(Bar::Memory { base, .. }) = device.bars[0] else {
+            return Err(ProbeError::NotSupported);
+        };
+        let registers = unsafe { crate::arch::acpi::remap_mmio(base, REGISTERS_SIZE) }
+            .ok_or(ProbeError::InitFailed)?;
+        let command = device.address.read16(COMMAND);
+        device
+            .address
+            .write16(COMMAND, command | COMMAND_MEMORY_SPACE);
+
+        let watchdog = WATCHDOG.call_once(|| I6300Esb {
+            address: device.address,
+            registers: registers.as_mut_ptr::<u8>(),
+        });
+        watchdog.init();
+        if let Err(error) = super::register(watchdog) {
+            log::warn!("Failed to register the i6300esb watchdog: {error:?}");
+        }
+        Ok(())
+    }
+
+    fn remove(&self, _: &PciDevice) {
+        super::stop();
+    }
+}
+
+impl I6300Esb {
+    /// Configure the watchdog, stopped, and report a previous reset caused by it.
+    fn init(&self) {
+        self.address.write16(CONFIG, CONFIG_INTERRUPT_DISABLED);
+        if self.address.read8(LOCK) & LOCK_LOCKED != 0 {
+            log::warn!("The i6300esb watchdog is locked by the firmware and cannot be stopped");
+        }
+        self.stop();
+
+        if self.read16(RELOAD) & RELOAD_TIMEOUT != 0 {
+            log::warn!("The system was reset by the i6300esb watchdog");
+            self.unlock();
+            self.write16(RELOAD, RELOAD_TIMEOUT);
+        }
+    }
+
+    /// Unlock the next write to a memory-mapped register.
+    fn unlock(&self) {
+        for value in UNLOCK {
+            self.write16(RELOAD, value);
+        }
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { self.registers.add(offset).cast::<u16>().read_volatile() }
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    fn write16(&self, offset: usize, value: u16) {
+        unsafe {
+            self.registers
+                .add(offset)
+                .cast::<u16>()
+                .write_volatile(value);
+        }
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe {
+            self.registers
+                .add(offset)
+                .cast::<u32>()
+                .write_volatile(value);
+        }
+    }
+}
+
+impl Watchdog for I6300Esb {
+    fn name(&self) -> &'static str {
+        "i6300esb"
+    }
+
+    fn max_timeout(&self) -> Duration {
+        MAX_TIMEOUT
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn start(&self, timeout: Duration) {
+        // The timeout is split between the two stages of the countdown
+        let seconds = timeout.as_secs().clamp(1, MAX_TIMEOUT.as_secs()) as u32;
+        let preload = seconds << PRELOAD_SHIFT;
+        self.unlock();
+        self.write32(TIMER1, preload);
+        self.unlock();
+        self.write32(TIMER2, preload);
+        self.ping();
+
+        let lock = self.address.read8(LOCK);
+        self.address.write8(LOCK, lock | LOCK_ENABLE);
+    }
+
+    fn stop(&self) {
+        let lock = self.address.read8(LOCK);
+        self.address.write8(LOCK, lock & !LOCK_ENABLE);
+        self.ping();
+    }
+
+    fn ping(&self) {
+        self.unlock();
+        self.write16(RELOAD, RELOAD_RELOAD);
+    }
+}
@@ -0,0 +1,92 @@
+use core::time::Duration;
+
+use crate::{
+    config::WATCHDOG_TIMEOUT,
+    sched::{self, thread::Thread},
+    sys::time,
+    Spinlock,
+};
+
+pub mod i6300esb;
+
+/// The watchdog in use. Only one watchdog is used, the first one registered.
+static WATCHDOG: Spinlock<Option<&'static dyn Watchdog>> = Spinlock::new(None);
+
+/// The number of times the watchdog is pinged during its timeout, so that a thread delayed by a
+/// busy system does not reset it.
+const PINGS_PER_TIMEOUT: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchdogError {
+    /// A watchdog is already registered.
+    AlreadyRegistered,
+}
+
+/// A hardware watchdog: once started, it resets the system if it is not pinged before its timeout
+/// expires.
+pub trait Watchdog: Sync {
+    /// A short name of the watchdog, used in the logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns the longest timeout supported by the watchdog.
+    fn max_timeout(&self) -> Duration;
+
+    /// Start the watchdog with the given timeout, which is not longer than
+    /// [`Watchdog::max_timeout`].
+    fn start(&self, timeout: Duration);
+
+    /// Stop the watchdog. Some watchdogs can be locked by the firmware and cannot be stopped.
+    fn stop(&self);
+
+    /// Restart the countdown of the watchdog.
+    fn ping(&self);
+}
+
+/// Register a watchdog and start it with the [`WATCHDOG_TIMEOUT`], or its longest timeout if
+/// shorter. A kernel thread then pings it regularly: if the kernel hangs, or if it panics and
+/// halts the CPUs, the watchdog is no longer pinged and resets the system.
+///
+/// # Errors
+/// - [`WatchdogError::AlreadyRegistered`]: A watchdog is already registered.
+pub fn register(watchdog: &'static dyn Watchdog) -> Result<(), WatchdogError> {
+    x86_64::irq::without(|| {
+        let mut current = WATCHDOG.lock();
+        if current.is_some() {
+            return Err(WatchdogError::AlreadyRegistered);
+        }
+        *current = Some(watchdog);
+        Ok(())
+    })?;
+
+    let timeout = WATCHDOG_TIMEOUT.min(watchdog.max_timeout());
+    let period = time::duration_to_jiffies(timeout / PINGS_PER_TIMEOUT).max(1);
+    watchdog.start(timeout);
+    sched::spawn(Thread::kernel(kicker, period, 0));
+    log::info!(
+        "Watchdog {} started with a timeout of {} s",
+        watchdog.name(),
+        timeout.as_secs()
+    );
+    Ok(())
+}
+
+/// Stop the watchdog, if any, for example before a long operation that blocks the scheduler on
+/// purpose. It is not started again.
+pub fn stop() {
+    if let Some(watchdog) = x86_64::irq::without(|| WATCHDOG.lock().take()) {
+        watchdog.stop();
+        log::info!("Watchdog {} stopped", watchdog.name());
+    }
+}
+
+/// The entry point of the thread pinging the watchdog every `period` clock ticks. The thread
+/// exits once the watchdog is stopped.
+unsafe extern "C" fn kicker(period: u64, _: u64) -> ! {
+    loop {
+        sched::sleep(period);
+        let Some(watchdog) = x86_64::irq::without(|| *WATCHDOG.lock()) else {
+            sched::exit();
+        };
+        watchdog.ping();
+    }
+}
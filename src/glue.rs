@@ -1,26 +1,109 @@
-use core::sync::atomic::Ordering;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::{self, cpu::State};
 
-use x86_64::{
-    self,
-    lapic::{self, IpiDestination, IpiPriority},
+use crate::{
+    arch::{
+        self,
+        backtrace::{self, Backtrace},
+        lapic::{self, DeliveryMode, IpiDestination},
+    },
+    Spinlock,
 };
 
-use crate::{arch, EARLY};
+/// A function called when the kernel panics, after the other cores have been halted and the
+/// panic message has been logged. Panic hooks are used by subsystems that must put their devices
+/// or data in a safe state before the system is halted, for example to freeze the block queues so
+/// that no write is half-applied.
+///
+/// Panic hooks are executed in a very fragile context: they must not allocate memory, must not
+/// wait on a lock (use `try_lock` instead) and must return as quickly as possible.
+pub type PanicHook = fn();
+
+/// The maximum number of panic hooks that can be registered. Hooks are stored in a fixed-size
+/// array to avoid allocating memory during a panic.
+const MAX_PANIC_HOOKS: usize = 8;
+
+static PANIC_HOOKS: Spinlock<[Option<PanicHook>; MAX_PANIC_HOOKS]> =
+    Spinlock::new([None; MAX_PANIC_HOOKS]);
+
+/// Set when a panic is being handled. If a panic hook panics, the hooks are not executed again
+/// by the nested panic.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The names of the registers saved in a crash record, in the order of [`CrashRecord::registers`].
+const REGISTER_NAMES: [&str; 20] = [
+    "rip", "rsp", "rflags", "cs", "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "r8", "r9",
+    "r10", "r11", "r12", "r13", "r14", "r15", "ss",
+];
+
+/// The number of iterations the panicking CPU waits for the other CPUs to save their registers. A
+/// CPU may not respond to the NMI, for example if it is stuck in a triple fault loop.
+const CRASH_WAIT_ITERATIONS: u64 = 100_000_000;
+
+/// The state of a CPU halted by a panic on another CPU, saved by its NMI handler (see
+/// [`halted_by_panic`]) so that the panicking CPU can print it. Atomics are used so that the
+/// record can be written from an NMI without locking.
+struct CrashRecord {
+    /// Set once the record is written, and cleared when it is printed.
+    saved: AtomicBool,
+    tid: AtomicU64,
+    registers: [AtomicU64; REGISTER_NAMES.len()],
+
+    /// The backtrace of the interrupted code, walked by the halted CPU itself because only it
+    /// knows the bounds of its stacks.
+    frames: [AtomicU64; backtrace::MAX_FRAMES],
+    frame_count: AtomicUsize,
+}
+
+impl CrashRecord {
+    const fn new() -> Self {
+        Self {
+            saved: AtomicBool::new(false),
+            tid: AtomicU64::new(0),
+            registers: [const { AtomicU64::new(0) }; REGISTER_NAMES.len()],
+            frames: [const { AtomicU64::new(0) }; backtrace::MAX_FRAMES],
+            frame_count: AtomicUsize::new(0),
+        }
+    }
+}
+
+crate::per_cpu! {
+    /// The state of each CPU when it was halted by a panic.
+    static CRASH_RECORDS: CrashRecord = CrashRecord::new();
+}
+
+/// Register a function that will be called when the kernel panics. Hooks are called in the order
+/// in which they were registered.
+pub fn register_panic_hook(hook: PanicHook) {
+    x86_64::irq::without(|| {
+        let mut hooks = PANIC_HOOKS.lock();
+        let slot = hooks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("Too many panic hooks registered");
+        *slot = Some(hook);
+    });
+}
 
 #[cold]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     halt_other_core();
-    // TODO: Dump stack trace
-    // TODO: Dump registers
     // TODO: Dump memory
-    let cpu_id = if EARLY.load(Ordering::Relaxed) {
-        0
-    } else {
-        arch::smp::current_id()
-    };
+    let cpu_id = arch::smp::try_current_id().unwrap_or(0);
 
     log::error!("CPU {cpu_id} {info}");
+    log::error!("Backtrace:\n{}", Backtrace::capture());
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        dump_other_cores(cpu_id);
+        run_panic_hooks();
+        #[cfg(feature = "reboot-on-panic")]
+        crate::sys::power::reboot();
+    }
     log::error!("System halted");
     x86_64::cpu::freeze();
 }
@@ -31,10 +114,119 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("Allocation error: {:?}", layout)
 }
 
+/// Called by the NMI handler of a CPU halted by a panic on another CPU (see [`halt_other_core`]).
+/// The interrupted state and the thread running on the CPU are saved in its crash record, and the
+/// CPU is frozen.
+pub fn halted_by_panic(state: &State) -> ! {
+    if let Some(record) = CRASH_RECORDS.try_local() {
+        let registers = [
+            state.rip,
+            state.rsp,
+            state.rflags,
+            state.cs,
+            state.rax,
+            state.rbx,
+            state.rcx,
+            state.rdx,
+            state.rsi,
+            state.rdi,
+            state.rbp,
+            state.r8,
+            state.r9,
+            state.r10,
+            state.r11,
+            state.r12,
+            state.r13,
+            state.r14,
+            state.r15,
+            state.ss,
+        ];
+        for (slot, value) in record.registers.iter().zip(registers) {
+            slot.store(value, Ordering::Relaxed);
+        }
+        let backtrace = Backtrace::from_state(state);
+        for (slot, &address) in record.frames.iter().zip(backtrace.frames()) {
+            slot.store(address, Ordering::Relaxed);
+        }
+        record
+            .frame_count
+            .store(backtrace.frames().len(), Ordering::Relaxed);
+        let tid = crate::sched::running_tid(arch::smp::current_id());
+        record.tid.store(tid, Ordering::Relaxed);
+        record.saved.store(true, Ordering::Release);
+    }
+    x86_64::cpu::freeze();
+}
+
+/// Wait for the other online CPUs to save their state after being halted, and print it. The CPUs
+/// that do not respond in time are reported as such, which usually means that they are stuck with
+/// interrupts disabled in a way that even the NMI cannot break, or that they triple-faulted.
+fn dump_other_cores(current: u32) {
+    let mut pending = 0u64;
+    CRASH_RECORDS.for_each_cpu(|cpu, _| {
+        if cpu != current {
+            pending |= 1 << cpu;
+        }
+    });
+
+    for _ in 0..CRASH_WAIT_ITERATIONS {
+        if CRASH_RECORDS
+            .iter()
+            .all(|(cpu, record)| pending & (1 << cpu) == 0 || record.saved.load(Ordering::Acquire))
+        {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+
+    CRASH_RECORDS
+        .iter()
+        .filter(|(cpu, _)| pending & (1 << cpu) != 0)
+        .for_each(|(cpu, record)| {
+            if !record.saved.swap(false, Ordering::Acquire) {
+                log::error!("CPU {cpu} did not respond to the halt NMI");
+                return;
+            }
+            let tid = record.tid.load(Ordering::Relaxed);
+            log::error!("CPU {cpu} halted while running thread {tid}:");
+            for (names, values) in REGISTER_NAMES.chunks(4).zip(record.registers.chunks(4)) {
+                log::error!("{}", Registers(names, values));
+            }
+            let count = record.frame_count.load(Ordering::Relaxed);
+            let backtrace: Backtrace = record.frames[..count]
+                .iter()
+                .map(|frame| frame.load(Ordering::Relaxed))
+                .collect();
+            log::error!("Backtrace:\n{backtrace}");
+        });
+}
+
+/// Formats some registers of a crash record on a single line, without allocating memory.
+struct Registers<'a>(&'a [&'a str], &'a [AtomicU64]);
+
+impl fmt::Display for Registers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.0.iter().zip(self.1) {
+            write!(f, " {name:>6}={:#018x}", value.load(Ordering::Relaxed))?;
+        }
+        Ok(())
+    }
+}
+
+/// Run all registered panic hooks. If the hooks are locked (because the panic occurred while a
+/// hook was being registered), they are not executed.
+fn run_panic_hooks() {
+    if let Some(hooks) = PANIC_HOOKS.try_lock() {
+        hooks.iter().flatten().for_each(|hook| hook());
+    }
+}
+
+/// Halt the other cores with an NMI, which cannot be masked: each core saves its state in its crash
+/// record (see [`halted_by_panic`]) and freezes.
 fn halt_other_core() {
     if lapic::initialized() {
         unsafe {
-            x86_64::lapic::send_ipi(IpiDestination::OtherCores, IpiPriority::Nmi, 2);
+            lapic::send_ipi(IpiDestination::OtherCores, DeliveryMode::Nmi, 2);
         }
     }
 }
@@ -0,0 +1,108 @@
+use alloc::sync::Arc;
+use x86_64::paging::PAGE_SIZE;
+
+use crate::{
+    mm::space::{AddressSpace, Placement, Protection, Sharing},
+    sched::{self, thread::Thread},
+    sys::time::vdso,
+};
+
+/// The address where the init program is loaded in the user address space.
+const INIT_BASE: u64 = 0x40_0000;
+
+/// The top of the stack of the init program, right below the time page. The stack grows downwards
+/// from this address.
+const INIT_STACK_TOP: u64 = vdso::TIME_PAGE_ADDR;
+
+/// The number of pages of the stack of the init program.
+const INIT_STACK_PAGES: u64 = 4;
+
+extern "C" {
+    static __init_start: u8;
+    static __init_end: u8;
+}
+
+// A tiny position-independent program used to test the system calls until the kernel is able to
+// load an executable from a file system. It prints a message, sleeps for one second, prints an
+// other message and exits with its process identifier as exit code.
+core::arch::global_asm!(
+    r#"
+.pushsection .rodata.init_program, "a"
+.global __init_start
+__init_start:
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + .Linit_hello]
+    mov edx, offset .Linit_hello_end - .Linit_hello
+    syscall
+
+    mov eax, 35
+    lea rdi, [rip + .Linit_timespec]
+    xor esi, esi
+    syscall
+
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + .Linit_bye]
+    mov edx, offset .Linit_bye_end - .Linit_bye
+    syscall
+
+    mov eax, 39
+    syscall
+    mov edi, eax
+    mov eax, 60
+    syscall
+    ud2
+
+.balign 8
+.Linit_timespec:
+    .quad 1, 0
+.Linit_hello:
+    .ascii "Hello from user space!\n"
+.Linit_hello_end:
+.Linit_bye:
+    .ascii "Goodbye from user space!\n"
+.Linit_bye_end:
+.global __init_end
+__init_end:
+.popsection
+"#
+);
+
+/// Create the init process and add it to the ready queue of the scheduler.
+pub fn spawn() {
+    let code = unsafe {
+        let start = core::ptr::addr_of!(__init_start);
+        let end = core::ptr::addr_of!(__init_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    let space = AddressSpace::new();
+    space
+        .map(
+            Placement::Fixed(INIT_BASE),
+            code.len() as u64,
+            Protection::READ | Protection::EXEC,
+            Sharing::Private,
+            None,
+        )
+        .expect("Failed to map the init program");
+    space
+        .write(INIT_BASE, code)
+        .expect("Failed to load the init program");
+
+    let stack_size = INIT_STACK_PAGES * PAGE_SIZE as u64;
+    space
+        .map(
+            Placement::Fixed(INIT_STACK_TOP - stack_size),
+            stack_size,
+            Protection::READ | Protection::WRITE,
+            Sharing::Private,
+            None,
+        )
+        .expect("Failed to map the stack of the init program");
+
+    let thread = Thread::user(Arc::new(space), INIT_BASE, INIT_STACK_TOP);
+    log::info!("Starting init process (pid {})", thread.pid().as_u64());
+    sched::spawn(thread);
+}
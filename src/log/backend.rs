@@ -0,0 +1,109 @@
+//! The terminal sinks of the logger: the serial port and the debug console of the emulator. Each
+//! sink writes a whole record under its own lock, so that the records logged concurrently by
+//! several CPUs are not interleaved.
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::debugcon,
+    drivers::uart::{ComPort, Uart},
+    Spinlock,
+};
+
+use super::{Header, Sink};
+
+pub static SERIAL: SerialSink = SerialSink {
+    uart: Spinlock::new(None),
+};
+
+pub static DEBUGCON: DebugconSink = DebugconSink {
+    enabled: AtomicBool::new(false),
+    lock: Spinlock::new(()),
+};
+
+/// The first serial port found at boot, which also carries the console (see [`crate::console`]).
+pub struct SerialSink {
+    uart: Spinlock<Option<Uart>>,
+}
+
+/// The debug console of the emulator (see [`debugcon`]). It keeps the logs even if the serial
+/// port is misconfigured.
+pub struct DebugconSink {
+    enabled: AtomicBool,
+    lock: Spinlock<()>,
+}
+
+/// Writes to the debug console.
+struct Debugcon;
+
+impl Write for Debugcon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        debugcon::write(s);
+        Ok(())
+    }
+}
+
+impl SerialSink {
+    pub fn set_port(&self, uart: Uart) {
+        x86_64::irq::without(|| *self.uart.lock() = Some(uart));
+    }
+
+    #[must_use]
+    pub fn port(&self) -> Option<ComPort> {
+        x86_64::irq::without(|| self.uart.lock().map(Uart::port))
+    }
+}
+
+impl Sink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn log(&self, header: &Header, args: fmt::Arguments) {
+        if let Some(uart) = self.uart.lock().as_mut() {
+            let _ = super::format_record(uart, header, args);
+        }
+    }
+}
+
+impl DebugconSink {
+    fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+impl Sink for DebugconSink {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn log(&self, header: &Header, args: fmt::Arguments) {
+        let _guard = self.lock.lock();
+        let _ = super::format_record(&mut Debugcon, header, args);
+    }
+}
+
+/// Enable the raw output to the debug console (see [`write`]). The debug console must have been
+/// detected.
+pub fn enable_debugcon() {
+    DEBUGCON.enable();
+}
+
+/// Write the given string to the serial port and to the debug console, if they are present.
+pub fn write(s: &str) {
+    x86_64::irq::without(|| {
+        if DEBUGCON.enabled() {
+            let _guard = DEBUGCON.lock.lock();
+            debugcon::write(s);
+        }
+        if let Some(uart) = SERIAL.uart.lock().as_mut() {
+            let _ = uart.write_str(s);
+        }
+    });
+}
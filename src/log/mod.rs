@@ -0,0 +1,274 @@
+//! The kernel logger. The records are dispatched to the registered sinks (see [`Sink`]), each with
+//! its own level filter: the ring buffer (see [`read_since`]), the first serial port found, and
+//! the debug console of the emulator if there is one. A sink registered after the boot, such as a
+//! framebuffer console, first receives the records still in the ring buffer, so that it does not
+//! miss the boot messages.
+//!
+//! Each record is tagged with the time it was logged and the CPU that logged it (see [`Header`]),
+//! so that the records logged concurrently by several CPUs can be told apart and ordered.
+use core::{
+    fmt::{self, Write},
+    time::Duration,
+};
+
+use log::LevelFilter;
+
+use crate::{
+    arch::debugcon,
+    config::SERIAL_BAUD,
+    drivers::uart::{self, ComPort, Config},
+    sys::time::clocksource,
+    Spinlock,
+};
+
+mod backend;
+mod ring;
+
+pub use ring::{next_sequence, read_since, truncated_count, Record, Records};
+
+/// The maximum number of sinks that can be registered.
+const MAX_SINKS: usize = 8;
+
+pub struct SiliciumLogger;
+
+pub static LOGGER: SiliciumLogger = SiliciumLogger;
+
+/// The registered sinks, with their level filter.
+static SINKS: Spinlock<[Option<Registration>; MAX_SINKS]> = Spinlock::new([None; MAX_SINKS]);
+
+/// A destination of the log records.
+pub trait Sink: Sync {
+    /// A short name of the sink, used to change its level filter (see [`set_sink_level`]).
+    fn name(&self) -> &'static str;
+
+    /// Write the given record. This is called with interrupts disabled, from any context including
+    /// the interrupt handlers and the panic handler, so it must not sleep nor allocate memory.
+    fn log(&self, header: &Header, args: fmt::Arguments);
+
+    /// Returns true if the records logged before the sink was registered must be replayed to it.
+    fn replay(&self) -> bool {
+        true
+    }
+}
+
+/// When a record was logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Timestamp {
+    /// The time since the boot, measured with the selected clock source.
+    Monotonic(Duration),
+    /// The raw value of the TSC of the CPU, used until a clock source is registered: the TSC is
+    /// not calibrated yet, but it still orders the records of the early boot.
+    Tsc(u64),
+}
+
+/// The information attached to a record in addition to its text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Header {
+    pub level: log::Level,
+    pub timestamp: Timestamp,
+
+    /// The CPU that logged the record, or `None` if it was logged before the thread local storage
+    /// of the CPU was allocated.
+    pub cpu: Option<u32>,
+}
+
+#[derive(Clone, Copy)]
+struct Registration {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SinkError {
+    /// [`MAX_SINKS`] sinks are already registered.
+    TooManySinks,
+    /// No sink with the given name is registered.
+    NotFound,
+}
+
+impl log::Log for SiliciumLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        x86_64::irq::without(|| {
+            // The header is built with interrupts disabled, so that the CPU id is still right when
+            // the record is written
+            let header = Header::new(record.level());
+
+            // The sinks are copied so that the lock is not held while they write, which would
+            // deadlock if a sink logged something
+            let sinks = *SINKS.lock();
+            for registration in sinks.iter().flatten() {
+                if record.level() <= registration.level {
+                    registration.sink.log(&header, *record.args());
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+impl Timestamp {
+    /// Returns the current time, or the TSC of the current CPU if no clock source is registered.
+    #[must_use]
+    pub fn now() -> Self {
+        match clocksource::nanoseconds() {
+            Some(nanoseconds) => Self::Monotonic(Duration::from_nanos(nanoseconds)),
+            None => Self::Tsc(crate::arch::tsc::rdtsc()),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Monotonic(time) => write!(f, "{:5}.{:06}", time.as_secs(), time.subsec_micros()),
+            Self::Tsc(tsc) => write!(f, "tsc:{tsc:#x}"),
+        }
+    }
+}
+
+impl Header {
+    /// Create the header of a record logged now by the current CPU.
+    #[must_use]
+    pub fn new(level: log::Level) -> Self {
+        Self {
+            level,
+            timestamp: Timestamp::now(),
+            cpu: crate::arch::smp::try_current_id(),
+        }
+    }
+}
+
+/// Write a record as a line of text prefixed with its timestamp, the CPU that logged it and a
+/// colored level marker, as the terminal sinks do.
+///
+/// # Errors
+/// Returns an error if the writer fails.
+pub fn format_record(
+    writer: &mut impl Write,
+    header: &Header,
+    args: fmt::Arguments,
+) -> fmt::Result {
+    let marker = match header.level {
+        log::Level::Error => "\x1b[1m\x1b[31m[!]\x1b[0m",
+        log::Level::Warn => "\x1b[1m\x1b[33m[-]\x1b[0m",
+        log::Level::Info => "\x1b[1m\x1b[32m[*]\x1b[0m",
+        log::Level::Debug => "\x1b[1m\x1b[34m[#]\x1b[0m",
+        log::Level::Trace => "\x1b[1m[~]\x1b[0m",
+    };
+    match header.cpu {
+        Some(cpu) => write!(writer, "[{}] [{cpu:>2}] ", header.timestamp)?,
+        None => write!(writer, "[{}] [ ?] ", header.timestamp)?,
+    }
+    writeln!(writer, "{marker} {args}")
+}
+
+/// Register a sink receiving the records up to the given level. Unless it opts out (see
+/// [`Sink::replay`]), the records still in the ring buffer are replayed to it first.
+///
+/// # Errors
+/// - [`SinkError::TooManySinks`]: [`MAX_SINKS`] sinks are already registered.
+pub fn register_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), SinkError> {
+    // The records logged from now on are dispatched to the sink, the older ones are replayed
+    let end = x86_64::irq::without(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(SinkError::TooManySinks)?;
+        *slot = Some(Registration { sink, level });
+        update_max_level(&*sinks);
+        Ok(ring::next_sequence())
+    })?;
+
+    if sink.replay() {
+        for record in ring::read_since(0).take_while(|record| record.sequence < end) {
+            if record.header.level <= level {
+                x86_64::irq::without(|| {
+                    sink.log(&record.header, format_args!("{}", record.text()));
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Change the level filter of the sink with the given name.
+///
+/// # Errors
+/// - [`SinkError::NotFound`]: no sink with this name is registered.
+pub fn set_sink_level(name: &str, level: LevelFilter) -> Result<(), SinkError> {
+    x86_64::irq::without(|| {
+        let mut sinks = SINKS.lock();
+        let registration = sinks
+            .iter_mut()
+            .flatten()
+            .find(|registration| registration.sink.name() == name)
+            .ok_or(SinkError::NotFound)?;
+        registration.level = level;
+        update_max_level(&*sinks);
+        Ok(())
+    })
+}
+
+/// Write the given string directly to the serial port and the debug console, without any prefix
+/// or formatting. This is used by the serial sink of the console.
+pub fn write(s: &str) {
+    backend::write(s);
+}
+
+#[cold]
+pub fn init() {
+    log::set_logger(&LOGGER).unwrap(); // Fail only if a logger was already set
+    register_sink(&ring::RING_SINK, LevelFilter::Trace).unwrap();
+
+    let config = Config {
+        baud: SERIAL_BAUD,
+        ..Config::DEFAULT
+    };
+    let serial =
+        uart::detect().find(|serial| serial.configure(&config).is_ok() && serial.self_test());
+    let debugcon = debugcon::detect();
+    if let Some(serial) = serial {
+        backend::SERIAL.set_port(serial);
+        register_sink(&backend::SERIAL, LevelFilter::Trace).unwrap();
+    }
+    if debugcon {
+        backend::enable_debugcon();
+        register_sink(&backend::DEBUGCON, LevelFilter::Trace).unwrap();
+    }
+
+    match serial {
+        Some(serial) => log::info!("Logs written to {:?} at {SERIAL_BAUD} bauds", serial.port()),
+        None => log::warn!("No usable serial port, the logs are only written to the debug console"),
+    }
+    if debugcon {
+        log::info!("Debug console detected, the logs are also written to it");
+    }
+}
+
+/// Returns the serial port the logs and the console are written to, or `None` if there is none.
+#[must_use]
+pub fn serial_port() -> Option<ComPort> {
+    backend::SERIAL.port()
+}
+
+/// Set the maximum level of the `log` crate to the most verbose level filter of the sinks, so that
+/// the records no sink wants are not even formatted.
+fn update_max_level(sinks: &[Option<Registration>]) {
+    let level = sinks
+        .iter()
+        .flatten()
+        .map(|registration| registration.level)
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    log::set_max_level(level);
+}
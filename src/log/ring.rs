@@ -0,0 +1,318 @@
+//! The kernel log ring buffer: every log record is kept in memory in addition to being written to
+//! the log backends, so that the boot messages can be replayed after the fact (see
+//! [`read_since`]), like the `dmesg` buffer of Linux.
+//!
+//! The buffer never blocks, so that it can be written from any context, including the NMI and
+//! panic paths. Each record gets a sequence number and is written to the slot selected by it: a
+//! writer never waits for another one, and the oldest records are silently overwritten once the
+//! buffer is full. Each slot is protected by a sequence lock, so that a reader detects the records
+//! overwritten while it copies them instead of returning garbage.
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::config::{LOG_RECORD_SIZE, LOG_RING_RECORDS};
+
+use super::{Header, Timestamp};
+
+/// Set in the state of a slot while a record is written to it.
+const WRITING: u64 = 1;
+
+/// The CPU id stored for the records logged by an unknown CPU.
+const NO_CPU: u32 = u32::MAX;
+
+/// The number of times a reader retries to copy a record that is being overwritten.
+const READ_RETRIES: usize = 4;
+
+/// The sequence number of the next record.
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// The number of records whose text did not fit in a slot and was truncated.
+static TRUNCATED: AtomicU64 = AtomicU64::new(0);
+
+static RING: [Slot; LOG_RING_RECORDS] = [const { Slot::new() }; LOG_RING_RECORDS];
+
+/// A slot of the ring buffer. Its state is the sequence number of the record it holds plus one,
+/// shifted left by one, with [`WRITING`] set while the record is written: an empty slot has a
+/// null state. The fields are atomics so that a reader racing with a writer is not undefined
+/// behaviour, only a torn copy detected with the state.
+struct Slot {
+    state: AtomicU64,
+    level: AtomicU8,
+    cpu: AtomicU32,
+
+    /// The timestamp of the record, in nanoseconds or in TSC cycles if `tsc` is set.
+    timestamp: AtomicU64,
+    tsc: AtomicBool,
+    truncated: AtomicBool,
+    len: AtomicUsize,
+    text: [AtomicU8; LOG_RECORD_SIZE],
+}
+
+/// A copy of a record of the ring buffer.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub sequence: u64,
+    pub header: Header,
+
+    /// Set if the text of the record was longer than [`LOG_RECORD_SIZE`] bytes.
+    pub truncated: bool,
+    len: usize,
+    text: [u8; LOG_RECORD_SIZE],
+}
+
+/// An iterator over the records of the ring buffer, from the oldest one still available. The
+/// iteration stops at the first record that is not fully written yet: it can be resumed later
+/// from [`Records::next_sequence`].
+#[derive(Debug, Clone)]
+pub struct Records {
+    next: u64,
+    lost: u64,
+}
+
+/// Why the record with a given sequence number cannot be read.
+enum Missing {
+    /// The record is not fully written yet.
+    Pending,
+    /// The record was overwritten by a newer one.
+    Overwritten,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU64::new(0),
+            level: AtomicU8::new(0),
+            cpu: AtomicU32::new(NO_CPU),
+            timestamp: AtomicU64::new(0),
+            tsc: AtomicBool::new(false),
+            truncated: AtomicBool::new(false),
+            len: AtomicUsize::new(0),
+            text: [const { AtomicU8::new(0) }; LOG_RECORD_SIZE],
+        }
+    }
+}
+
+/// Formats a record directly into the text of a slot, truncating it on a character boundary if it
+/// does not fit.
+struct SlotWriter<'a> {
+    slot: &'a Slot,
+    len: usize,
+    truncated: bool,
+}
+
+impl Write for SlotWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = LOG_RECORD_SIZE - self.len;
+        let mut count = s.len().min(room);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.truncated |= count < s.len();
+
+        for (byte, slot) in s.bytes().take(count).zip(&self.slot.text[self.len..]) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+        self.len += count;
+        Ok(())
+    }
+}
+
+impl Record {
+    /// Returns the text of the record, without its header.
+    #[must_use]
+    pub fn text(&self) -> &str {
+        let text = &self.text[..self.len];
+        // A torn copy is detected by the reader, but be safe if the text was cut in a character
+        core::str::from_utf8(text).unwrap_or_else(|error| {
+            core::str::from_utf8(&text[..error.valid_up_to()]).unwrap_or_default()
+        })
+    }
+}
+
+impl Records {
+    /// Returns the sequence number of the next record the iterator would return.
+    #[must_use]
+    pub const fn next_sequence(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns the number of records skipped so far because they were overwritten before being
+    /// read.
+    #[must_use]
+    pub const fn lost(&self) -> u64 {
+        self.lost
+    }
+}
+
+impl Iterator for Records {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let end = NEXT.load(Ordering::Acquire);
+            if self.next >= end {
+                return None;
+            }
+
+            let oldest = end.saturating_sub(LOG_RING_RECORDS as u64);
+            if self.next < oldest {
+                self.lost += oldest - self.next;
+                self.next = oldest;
+            }
+
+            match read(self.next) {
+                Ok(record) => {
+                    self.next += 1;
+                    return Some(record);
+                }
+                Err(Missing::Overwritten) => {
+                    self.lost += 1;
+                    self.next += 1;
+                }
+                Err(Missing::Pending) => return None,
+            }
+        }
+    }
+}
+
+/// The sink feeding the ring buffer, registered first so that it sees all the records.
+pub static RING_SINK: RingSink = RingSink;
+
+pub struct RingSink;
+
+impl super::Sink for RingSink {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn log(&self, header: &Header, args: fmt::Arguments) {
+        push(header, args);
+    }
+
+    fn replay(&self) -> bool {
+        false
+    }
+}
+
+/// Append a record to the ring buffer.
+pub fn push(header: &Header, args: fmt::Arguments) {
+    let sequence = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = slot(sequence);
+    let state = (sequence + 1) << 1;
+
+    slot.state.store(state | WRITING, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    let mut writer = SlotWriter {
+        slot,
+        len: 0,
+        truncated: false,
+    };
+    let _ = writer.write_fmt(args);
+    if writer.truncated {
+        TRUNCATED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let (timestamp, tsc) = match header.timestamp {
+        Timestamp::Monotonic(time) => (u64::try_from(time.as_nanos()).unwrap_or(u64::MAX), false),
+        Timestamp::Tsc(cycles) => (cycles, true),
+    };
+    slot.level.store(header.level as u8, Ordering::Relaxed);
+    slot.cpu
+        .store(header.cpu.unwrap_or(NO_CPU), Ordering::Relaxed);
+    slot.timestamp.store(timestamp, Ordering::Relaxed);
+    slot.tsc.store(tsc, Ordering::Relaxed);
+    slot.truncated.store(writer.truncated, Ordering::Relaxed);
+    slot.len.store(writer.len, Ordering::Relaxed);
+    slot.state.store(state, Ordering::Release);
+}
+
+/// Returns an iterator over the records from the given sequence number, or from the oldest record
+/// still in the buffer if it was overwritten. Use 0 to replay all the records still available.
+#[must_use]
+pub fn read_since(sequence: u64) -> Records {
+    Records {
+        next: sequence,
+        lost: 0,
+    }
+}
+
+/// Returns the sequence number the next record will get, which is also the number of records
+/// logged since the boot.
+#[must_use]
+pub fn next_sequence() -> u64 {
+    NEXT.load(Ordering::Relaxed)
+}
+
+/// Returns the number of records whose text was truncated because it did not fit in a slot.
+#[must_use]
+pub fn truncated_count() -> u64 {
+    TRUNCATED.load(Ordering::Relaxed)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn slot(sequence: u64) -> &'static Slot {
+    &RING[(sequence % LOG_RING_RECORDS as u64) as usize]
+}
+
+/// Copy the record with the given sequence number, retrying if it is overwritten while it is
+/// copied.
+fn read(sequence: u64) -> Result<Record, Missing> {
+    let slot = slot(sequence);
+    let expected = (sequence + 1) << 1;
+    for _ in 0..READ_RETRIES {
+        let state = slot.state.load(Ordering::Acquire);
+        if state < expected || state == expected | WRITING {
+            return Err(Missing::Pending);
+        }
+        if state != expected {
+            return Err(Missing::Overwritten);
+        }
+
+        let mut text = [0; LOG_RECORD_SIZE];
+        for (byte, slot) in text.iter_mut().zip(&slot.text) {
+            *byte = slot.load(Ordering::Relaxed);
+        }
+        let record = Record {
+            sequence,
+            header: header(slot),
+            truncated: slot.truncated.load(Ordering::Relaxed),
+            len: slot.len.load(Ordering::Relaxed).min(LOG_RECORD_SIZE),
+            text,
+        };
+
+        fence(Ordering::Acquire);
+        if slot.state.load(Ordering::Relaxed) == expected {
+            return Ok(record);
+        }
+    }
+    Err(Missing::Overwritten)
+}
+
+/// Rebuild the header of the record held by the given slot.
+fn header(slot: &Slot) -> Header {
+    let timestamp = slot.timestamp.load(Ordering::Relaxed);
+    let cpu = slot.cpu.load(Ordering::Relaxed);
+    Header {
+        level: level(slot.level.load(Ordering::Relaxed)),
+        timestamp: if slot.tsc.load(Ordering::Relaxed) {
+            Timestamp::Tsc(timestamp)
+        } else {
+            Timestamp::Monotonic(Duration::from_nanos(timestamp))
+        },
+        cpu: (cpu != NO_CPU).then_some(cpu),
+    }
+}
+
+fn level(value: u8) -> log::Level {
+    match value {
+        1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
@@ -7,7 +7,6 @@
 #![allow(clippy::missing_safety_doc)]
 #![feature(asm_const)]
 #![feature(fn_traits)]
-#![feature(thread_local)]
 #![feature(const_mut_refs)]
 #![feature(naked_functions)]
 #![feature(core_intrinsics)]
@@ -17,20 +16,20 @@ extern crate alloc;
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use ::log::info;
+use ::log::{error, info};
 use limine::{
-    LimineHhdmRequest, LimineMemmapRequest, LimineRsdpRequest, LimineSmpRequest,
-    LimineStackSizeRequest,
+    LimineHhdmRequest, LimineMemmapRequest, LimineModuleRequest, LimineRsdpRequest,
+    LimineSmpRequest, LimineStackSizeRequest,
 };
 
-/// Request a 128 kio stack for the kernel and the APs. This is absolutely humongous, but it may
-/// be required for unoptimized debug builds and to avoid any stack overflow and strange behaviour.
+/// Request a [`config::BOOT_STACK_SIZE`] stack for the kernel and the APs.
 pub static LIMINE_STACK_REQUEST: LimineStackSizeRequest =
-    LimineStackSizeRequest::new(0).stack_size(1024 * 128);
+    LimineStackSizeRequest::new(0).stack_size(config::BOOT_STACK_SIZE);
 pub static LIMINE_MEMMAP: LimineMemmapRequest = LimineMemmapRequest::new(0);
 pub static LIMINE_HHDM: LimineHhdmRequest = LimineHhdmRequest::new(0);
 pub static LIMINE_RSDP: LimineRsdpRequest = LimineRsdpRequest::new(0);
 pub static LIMINE_SMP: LimineSmpRequest = LimineSmpRequest::new(0);
+pub static LIMINE_MODULES: LimineModuleRequest = LimineModuleRequest::new(0);
 
 /// This is used to determine if the kernel is running in early mode or not. This is absolutely
 /// required to avoid any undefined behaviour during the initialization of the kernel, when some
@@ -46,15 +45,25 @@ pub static LIMINE_SMP: LimineSmpRequest = LimineSmpRequest::new(0);
 pub static EARLY: AtomicBool = AtomicBool::new(true);
 
 /// A spinlock type alias. This is used to avoid the confusion between a spinlock (which does not
-/// sleep or yield) and a mutex (which does).
-type Spinlock<T> = spin::Mutex<T>;
+/// sleep or yield) and a mutex (which does, see [`sync::Mutex`]).
+type Spinlock<T> = sync::Spinlock<T>;
 
 pub mod config;
 
 pub mod arch;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod console;
+pub mod drivers;
 pub mod glue;
+pub mod init;
 pub mod log;
 pub mod mm;
+pub mod sched;
+#[cfg(feature = "selftest")]
+pub mod selftest;
+pub mod sys;
+pub mod syscall;
 
 /// This function performs some checks to ensure that the kernel is running in a valid environment.
 /// This function is called before any other initialization function (except for the logging) and
@@ -94,16 +103,21 @@ pub unsafe fn start() -> ! {
     arch::gdt::setup();
     arch::idt::setup();
     arch::irq::setup();
+    arch::msi::setup();
     arch::exception::setup();
 
     // Initialise the memory subsystem
     mm::setup();
 
-    // Initialise the BSP and external devices (PIT, PIC, etc.)
+    // Initialise the BSP and external devices (PIC, etc.)
     arch::init_bsp();
 
-    // Setup ACPI and everything related to it (LAPIC, HPET, etc.)
-    arch::acpi::setup();
+    // Setup ACPI and everything related to it (LAPIC and its timer, IOAPIC, etc.). Without
+    // ACPI, the kernel continues with the 8259 PIC and the PIT on the BSP only
+    if let Err(error) = arch::acpi::setup() {
+        error!("ACPI is unusable ({error:?}), falling back to the PIC and the PIT");
+        arch::pit::start_clock_tick();
+    }
 
     // Initialise the APs
     arch::smp::start_cpus();
@@ -111,10 +125,28 @@ pub unsafe fn start() -> ! {
     // Disable early mode and unlock all features of the kernel
     EARLY.store(false, Ordering::Relaxed);
 
-    // Enable interrupts and loop forever
+    // Start the scheduler and the init process
+    sys::time::setup();
+    sched::setup();
+    console::setup();
+    drivers::serial::setup();
+    drivers::i8042::setup();
+    arch::sci::setup();
+    drivers::block::setup();
+    drivers::block::ramdisk::setup();
+    drivers::pci::setup();
+    drivers::virtio::console::setup();
+    drivers::watchdog::i6300esb::setup();
+    #[cfg(feature = "page-merging")]
+    mm::merge::setup();
+    #[cfg(not(any(feature = "bench", feature = "selftest")))]
+    init::spawn();
+    #[cfg(feature = "bench")]
+    bench::spawn();
+    #[cfg(feature = "selftest")]
+    selftest::spawn();
+
+    // Enable interrupts and become the idle thread
     info!("Silicium booted successfully!");
-    loop {
-        x86_64::irq::enable();
-        x86_64::cpu::hlt();
-    }
+    sched::idle();
 }
@@ -1,7 +1,13 @@
+use core::ops::{ControlFlow, Range};
+
 use crate::arch::address::phys_to_virt;
 use x86_64::paging::PAGE_SIZE;
 
-use super::{AllocationFlags, Frame, FrameFlags, Stats};
+use super::{state::FrameInfo, AllocationFlags, Frame, FrameFlags, Stats};
+
+/// The number of frames examined by a scan of the frame state before the frame state is unlocked,
+/// so that the other users of the frame state are not kept waiting for the whole scan.
+const SCAN_CHUNK: usize = 4096;
 
 /// A dummy allocator that allocates frames from the frame state. This allocator is very inefficient
 /// and should only be used when no other allocator is available. But it could be easily improved,
@@ -20,6 +26,48 @@ impl Allocator {
             statistic: Stats::new(),
         }
     }
+
+    /// Mark the given free frame as allocated.
+    ///
+    /// # Safety
+    /// The frame must be free, and must not be used by anything else.
+    unsafe fn claim(&mut self, frame: &mut FrameInfo, flags: AllocationFlags) {
+        self.statistic.allocated += 1;
+        if flags.contains(AllocationFlags::KERNEL) {
+            frame.get_flags_mut().insert(FrameFlags::KERNEL);
+            self.statistic.kernel += 1;
+        }
+        if flags.contains(AllocationFlags::ZEROED) {
+            let frame = phys_to_virt(frame.get_frame().start()).as_mut_ptr::<u8>();
+            frame.write_bytes(0, PAGE_SIZE);
+        }
+        frame.get_flags_mut().remove(FrameFlags::FREE);
+        frame.retain();
+    }
+}
+
+/// Scan the frame state by chunks of [`SCAN_CHUNK`] frames: `scan` is called with the frame array
+/// and the indexes of each chunk, until it breaks with a result or until the whole array has been
+/// scanned. The frame state is unlocked between two chunks. It is only modified by the allocator,
+/// which is borrowed during the scan, so it does not change between two chunks.
+fn scan<T>(mut scan: impl FnMut(&mut [FrameInfo], Range<usize>) -> ControlFlow<T>) -> Option<T> {
+    let mut start = 0;
+    loop {
+        let flow = x86_64::irq::without(|| {
+            let mut state = crate::mm::FRAME_STATE.lock();
+            let array = state.get_state_array_mut();
+            if start >= array.len() {
+                return None;
+            }
+            let end = core::cmp::min(start + SCAN_CHUNK, array.len());
+            Some(scan(array, start..end))
+        })?;
+
+        if let ControlFlow::Break(result) = flow {
+            return Some(result);
+        }
+        start += SCAN_CHUNK;
+    }
 }
 
 unsafe impl super::Allocator for Allocator {
@@ -36,29 +84,17 @@ unsafe impl super::Allocator for Allocator {
     /// Furthermore, many allocations flags are not supported (e.g. `AllocationFlags::BIOS`,
     /// `AllocationFlags::ISA`, `AllocationFlags::X86`)
     unsafe fn allocate(&mut self, flags: super::AllocationFlags) -> Option<Frame> {
-        // Acquire the frame state and the frame statistics, the order is important and should be
-        // consistent in all functions that use the frame state and the frame statistics.
-        x86_64::irq::without(|| {
-            let mut state = crate::mm::FRAME_STATE.lock();
-            state
-                .get_state_array_mut()
+        scan(|array, chunk| {
+            match array[chunk]
                 .iter_mut()
                 .find(|frame| frame.get_flags().contains(FrameFlags::FREE))
-                .map(|frame| {
-                    self.statistic.allocated += 1;
-                    if flags.contains(AllocationFlags::KERNEL) {
-                        frame.get_flags_mut().insert(FrameFlags::KERNEL);
-                        self.statistic.kernel += 1;
-                    }
-                    if flags.contains(AllocationFlags::ZEROED) {
-                        let frame = phys_to_virt(frame.get_frame().start()).as_mut_ptr::<u8>();
-                        frame.write_bytes(0, PAGE_SIZE);
-                    }
-                    frame.get_flags_mut().remove(FrameFlags::FREE);
-                    frame.retain();
-                    frame
-                })
-                .map(|f| *f.get_frame())
+            {
+                Some(frame) => {
+                    self.claim(frame, flags);
+                    ControlFlow::Break(*frame.get_frame())
+                }
+                None => ControlFlow::Continue(()),
+            }
         })
     }
 
@@ -76,40 +112,29 @@ unsafe impl super::Allocator for Allocator {
         count: usize,
         flags: AllocationFlags,
     ) -> Option<super::Range> {
-        x86_64::irq::without(|| {
-            // Find `count` contiguous frames that are free
-            let mut state = crate::mm::FRAME_STATE.lock();
-            let len = state.get_state_array().len();
-            let array = state.get_state_array_mut();
-            let mut i = 0;
-            while i + count <= len {
+        // Find `count` contiguous frames that are free, starting at an index of the chunk
+        scan(|array, chunk| {
+            for i in chunk {
+                if i + count > array.len() {
+                    return ControlFlow::Break(None);
+                }
                 if array[i..i + count]
                     .iter()
                     .all(|e| e.get_flags().contains(super::FrameFlags::FREE))
                 {
-                    for frame in array[i..i + count].iter_mut() {
-                        self.statistic.allocated += 1;
-                        if flags.contains(AllocationFlags::KERNEL) {
-                            frame.get_flags_mut().insert(FrameFlags::KERNEL);
-                            self.statistic.kernel += 1;
-                        }
-                        if flags.contains(AllocationFlags::ZEROED) {
-                            let frame = phys_to_virt(frame.get_frame().start()).as_mut_ptr::<u8>();
-                            frame.write_bytes(0, PAGE_SIZE);
-                        }
-                        frame.get_flags_mut().remove(FrameFlags::FREE);
-                        frame.retain();
+                    for frame in &mut array[i..i + count] {
+                        self.claim(frame, flags);
                     }
 
-                    return Some(super::Range {
+                    return ControlFlow::Break(Some(super::Range {
                         start: *array[i].get_frame(),
                         end: *array[i + count].get_frame(),
-                    });
+                    }));
                 }
-                i += 1;
             }
-            None
+            ControlFlow::Continue(())
         })
+        .flatten()
     }
 
     /// Reference a frame in the frame state, meaning that the frame is used many times. This method
@@ -0,0 +1,186 @@
+use crate::{
+    arch::percpu::PerCpuCounter,
+    config::KERNEL_HZ,
+    sched::{self, thread::Thread},
+    Spinlock,
+};
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use super::{
+    frame::{Allocator, Frame},
+    space::AddressSpace,
+    FRAME_ALLOCATOR, FRAME_STATE,
+};
+
+/// The interval between two scans, in clock ticks.
+const SCAN_INTERVAL: u64 = KERNEL_HZ;
+
+/// The address spaces containing mergeable pages.
+static SPACES: Spinlock<Vec<Weak<AddressSpace>>> = Spinlock::new(Vec::new());
+
+/// The stable frames, indexed by the checksum of their content. Several frames may have the same
+/// checksum without having the same content. The scanner holds a reference on each of them.
+static STABLE: Spinlock<BTreeMap<u64, Vec<Frame>>> = Spinlock::new(BTreeMap::new());
+
+/// The total number of pages merged since the boot.
+static MERGED: PerCpuCounter = PerCpuCounter::new();
+
+/// The statistics of the page merging.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Stats {
+    /// The number of stable frames currently shared.
+    pub shared: usize,
+
+    /// The number of pages currently mapping a stable frame.
+    pub sharing: usize,
+
+    /// The total number of pages merged since the boot.
+    pub merged: u64,
+}
+
+/// Start the thread merging identical anonymous pages.
+///
+/// The thread periodically scans the mergeable pages (see [`super::space::Advice::Mergeable`]) of
+/// the registered address spaces and hashes their content. When two pages with the same content
+/// are found, one of them becomes a *stable* frame, mapped read-only in both address spaces, and
+/// the other frame is released. The next write to a merged page triggers a copy-on-write fault
+/// that gives back a private copy to the writer.
+///
+/// There is no reverse mapping yet: the scanner only knows the address spaces registered with
+/// [`register`], and a stable frame is only released when the scanner notices that it holds the
+/// last reference to it.
+pub fn setup() {
+    sched::spawn(Thread::kernel(scanner, 0, 0));
+}
+
+/// Register an address space containing mergeable pages. Registering the same address space
+/// several times has no effect.
+pub fn register(space: &Arc<AddressSpace>) {
+    x86_64::irq::without(|| {
+        let mut spaces = SPACES.lock();
+        spaces.retain(|space| space.strong_count() > 0);
+        if !spaces
+            .iter()
+            .any(|other| other.ptr_eq(&Arc::downgrade(space)))
+        {
+            spaces.push(Arc::downgrade(space));
+        }
+    });
+}
+
+/// Returns the statistics of the page merging.
+#[must_use]
+pub fn stats() -> Stats {
+    let (shared, references) = x86_64::irq::without(|| {
+        let stable = STABLE.lock();
+        let state = FRAME_STATE.lock();
+        stable
+            .values()
+            .flatten()
+            .filter_map(|frame| state.get_frame_info(frame.start()))
+            .fold((0, 0), |(shared, references), info| {
+                (shared + 1, references + info.get_count())
+            })
+    });
+
+    Stats {
+        shared,
+        sharing: usize::try_from(references).unwrap() - shared,
+        merged: MERGED.sum(),
+    }
+}
+
+/// The entry point of the scanner thread.
+unsafe extern "C" fn scanner(_: u64, _: u64) -> ! {
+    loop {
+        prune();
+        scan();
+        sched::sleep(SCAN_INTERVAL);
+    }
+}
+
+/// Scan all the mergeable pages of the registered address spaces once.
+fn scan() {
+    let spaces = x86_64::irq::without(|| {
+        let mut spaces = SPACES.lock();
+        spaces.retain(|space| space.strong_count() > 0);
+        spaces.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+    });
+
+    // The pages seen during this scan that are not merged yet, indexed by checksum. Only one page
+    // is kept for each checksum: it will be merged with the next page with the same content.
+    let mut unstable: BTreeMap<u64, (&Arc<AddressSpace>, u64)> = BTreeMap::new();
+
+    for space in &spaces {
+        for page in space.mergeable_pages() {
+            crate::preempt_point!();
+            let Some(checksum) = space.checksum(page) else {
+                continue;
+            };
+
+            let candidates = x86_64::irq::without(|| STABLE.lock().get(&checksum).cloned());
+            if let Some(candidates) = candidates {
+                if candidates.iter().any(|&frame| space.merge(page, frame)) {
+                    MERGED.add(1);
+                    continue;
+                }
+            }
+
+            match unstable.entry(checksum) {
+                Entry::Vacant(entry) => {
+                    entry.insert((space, page));
+                }
+                Entry::Occupied(entry) => {
+                    let (other, other_page) = entry.remove();
+                    let Some(frame) = other.write_protect(other_page) else {
+                        continue;
+                    };
+                    if space.merge(page, frame) {
+                        MERGED.add(1);
+                        x86_64::irq::without(|| {
+                            STABLE.lock().entry(checksum).or_default().push(frame);
+                        });
+                    } else {
+                        free_frame(frame);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Release the stable frames that are no longer mapped by any address space.
+fn prune() {
+    let unused = x86_64::irq::without(|| {
+        let mut stable = STABLE.lock();
+        let state = FRAME_STATE.lock();
+        let mut unused = Vec::new();
+        for frames in stable.values_mut() {
+            frames.retain(|frame| {
+                let last = state
+                    .get_frame_info(frame.start())
+                    .is_some_and(|info| info.get_count() <= 1);
+                if last {
+                    unused.push(*frame);
+                }
+                !last
+            });
+        }
+        stable.retain(|_, frames| !frames.is_empty());
+        unused
+    });
+
+    for frame in unused {
+        free_frame(frame);
+    }
+}
+
+fn free_frame(frame: Frame) {
+    x86_64::irq::without(|| unsafe {
+        FRAME_ALLOCATOR.lock().deallocate(frame);
+    });
+}
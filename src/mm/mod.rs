@@ -3,10 +3,22 @@ use frame::Allocator;
 
 pub mod allocator;
 pub mod frame;
+pub mod merge;
+pub mod reclaim;
+pub mod space;
+pub mod user;
 pub mod vmm;
 
 pub const KERNEL_BASE: u64 = 0xFFFF_8000_0000_0000;
 
+/// The first page of the user address space is never mapped, to catch null pointer dereferences.
+pub const USER_START: u64 = 0x0000_0000_0000_1000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// The last page of the user address space is never mapped either: a `syscall` instruction at its
+/// end would return to the non-canonical address [`USER_END`] (see [`crate::arch::syscall`]).
+pub const USER_MAP_END: u64 = USER_END - 0x1000;
+
 pub const HHDM_START: u64 = 0xFFFF_8000_0000_0000;
 pub const HHDM_END: u64 = 0xFFFF_9000_0000_0000;
 pub const HEAP_START: u64 = 0xFFFF_9000_0000_0000;
@@ -0,0 +1,47 @@
+use crate::Spinlock;
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use super::space::AddressSpace;
+
+/// The address spaces containing pages given back with [`super::space::Advice::Free`].
+static SPACES: Spinlock<Vec<Weak<AddressSpace>>> = Spinlock::new(Vec::new());
+
+/// Register an address space containing pages given back with [`super::space::Advice::Free`], so
+/// that they can be released by [`reclaim`]. Registering the same address space several times has
+/// no effect.
+pub fn register(space: &Arc<AddressSpace>) {
+    x86_64::irq::without(|| {
+        let mut spaces = SPACES.lock();
+        spaces.retain(|space| space.strong_count() > 0);
+        if !spaces
+            .iter()
+            .any(|other| other.ptr_eq(&Arc::downgrade(space)))
+        {
+            spaces.push(Arc::downgrade(space));
+        }
+    });
+}
+
+/// Release the pages given back with [`super::space::Advice::Free`] that were not written since,
+/// in all the registered address spaces (see [`AddressSpace::reclaim`]). Returns the number of
+/// released pages.
+///
+/// This is called when a frame cannot be allocated for a user page, and must be called without
+/// holding any lock of an address space, because it waits for all the CPUs to flush their TLB.
+#[must_use]
+pub fn reclaim() -> usize {
+    let spaces = x86_64::irq::without(|| {
+        let mut spaces = SPACES.lock();
+        spaces.retain(|space| space.strong_count() > 0);
+        spaces.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+    });
+
+    let released = spaces.iter().map(|space| space.reclaim()).sum();
+    if released > 0 {
+        log::debug!("Reclaimed {released} freed pages");
+    }
+    released
+}
@@ -0,0 +1,1008 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
+use bitflags::bitflags;
+use log::trace;
+use x86_64::{
+    address::{Physical, Virtual},
+    paging::{PageEntryFlags, PageFaultErrorCode, PageTable, PAGE_SIZE},
+};
+
+use crate::{
+    arch::{
+        address::phys_to_virt,
+        paging::{self, MapError, PageFaultError, PageFaultType, TableRoot},
+    },
+    sys::time::vdso,
+    Spinlock,
+};
+
+use super::{
+    frame::{AllocationFlags, Allocator, Frame},
+    reclaim, FRAME_ALLOCATOR, USER_MAP_END, USER_START,
+};
+
+/// The address from which the kernel searches a free range when the user does not request a
+/// specific address for a mapping.
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+bitflags! {
+    /// The access rights of a mapping. The values are the same as the `PROT_*` constants of Linux.
+    pub struct Protection : u64 {
+        const NONE = 0;
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sharing {
+    /// Modifications are private to the address space: a write to a file-backed page first
+    /// copies the page (copy-on-write), and the file is never modified.
+    Private,
+
+    /// Modifications are visible to everyone mapping the same object, and are written back to
+    /// the object when the page is unmapped or synchronized.
+    Shared,
+}
+
+/// Where a new mapping should be placed in the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Placement {
+    /// The kernel chooses a free range.
+    Anywhere,
+
+    /// The kernel tries to use the given address, and chooses another free range if it is not
+    /// available.
+    Hint(u64),
+
+    /// The mapping must be placed at the given address. Any existing mapping in the range is
+    /// removed.
+    Fixed(u64),
+}
+
+/// A hint given by the user about the future use of a range of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Advice {
+    /// The pages will not be accessed in the near future: they are immediately released. The next
+    /// access to an anonymous page will return a zeroed page, and the next access to a file-backed
+    /// page will fetch it again from its object.
+    DontNeed,
+
+    /// The pages will be accessed in the near future: they are populated immediately.
+    WillNeed,
+
+    /// The content of the pages is no longer needed, but the kernel may keep them until memory is
+    /// needed. If a page is written before being reclaimed, it is kept. Only valid for private
+    /// anonymous mappings.
+    Free,
+
+    /// The pages may be merged with identical pages of other address spaces (see
+    /// [`crate::mm::merge`]). Only valid for private anonymous mappings.
+    Mergeable,
+
+    /// The pages must no longer be merged. Pages already merged stay shared until they are
+    /// written.
+    Unmergeable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpaceError {
+    /// The range is empty, not page aligned or not entirely contained in the user address space.
+    InvalidRange,
+
+    /// There is no free range large enough for the mapping.
+    NoSpace,
+
+    /// A part of the range is not mapped.
+    NotMapped,
+}
+
+/// An object that provides the pages of a file-backed mapping, typically a file through the page
+/// cache.
+pub trait Pager: Send + Sync {
+    /// Returns the frame containing the page at `offset` bytes in the object, or `None` if the
+    /// page cannot be read (e.g. because the offset is beyond the end of the object). A reference
+    /// is taken on the frame for the caller, which will release it with the frame allocator when
+    /// the page is unmapped.
+    fn page(&self, offset: u64) -> Option<Frame>;
+
+    /// Called when a page of a shared mapping has been modified and must be written back to the
+    /// object.
+    fn writeback(&self, offset: u64, frame: Frame);
+}
+
+/// The object backing a file mapping, and the offset of the start of the mapping in the object.
+#[derive(Clone)]
+pub struct Backing {
+    pub pager: Arc<dyn Pager>,
+    pub offset: u64,
+}
+
+/// A contiguous range of the user address space with the same access rights and backing.
+#[derive(Clone)]
+struct Area {
+    start: u64,
+    end: u64,
+    protection: Protection,
+    sharing: Sharing,
+    backing: Option<Backing>,
+
+    /// Set if the pages of this area can be merged with identical pages.
+    mergeable: bool,
+}
+
+/// The frames unmapped from an address space. Another CPU may still access them through a stale
+/// TLB entry, so they are only freed by [`StaleFrames::free`], once all the CPUs have flushed their
+/// TLB.
+#[must_use]
+struct StaleFrames(Vec<Frame>);
+
+impl StaleFrames {
+    const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn push(&mut self, frame: Frame) {
+        self.0.push(frame);
+    }
+
+    /// Wait until all the CPUs have flushed their TLB, and free the frames. This must be called
+    /// without holding the locks of the address space (see [`paging::tlb::shootdown_sync`]).
+    fn free(self) {
+        if !self.0.is_empty() {
+            paging::tlb::shootdown_sync();
+            self.0.into_iter().for_each(free_frame);
+        }
+    }
+}
+
+impl Area {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Returns the offset in the backing object of the page at the given address.
+    fn offset(&self, backing: &Backing, addr: u64) -> u64 {
+        backing.offset + (addr - self.start)
+    }
+
+    /// Returns the page table flags used to map a page of this area.
+    fn flags(&self) -> PageEntryFlags {
+        let mut flags = PageEntryFlags::PRESENT | PageEntryFlags::USER;
+        if self.protection.contains(Protection::WRITE) {
+            flags.insert(PageEntryFlags::WRITABLE);
+        }
+        if !self.protection.contains(Protection::EXEC) {
+            flags.insert(PageEntryFlags::NO_EXECUTE);
+        }
+        flags
+    }
+
+    /// Returns true if the pages of this area that are not yet modified must be mapped read-only
+    /// to be copied on the first write.
+    fn copy_on_write(&self) -> bool {
+        self.sharing == Sharing::Private && self.backing.is_some()
+    }
+
+    /// Returns true if the area is a private anonymous mapping.
+    fn private_anonymous(&self) -> bool {
+        self.sharing == Sharing::Private && self.backing.is_none()
+    }
+
+    /// Split the area at the given address, keeping the lower part and returning the upper one.
+    fn split_off(&mut self, at: u64) -> Area {
+        let mut upper = self.clone();
+        upper.start = at;
+        if let Some(backing) = &mut upper.backing {
+            backing.offset += at - self.start;
+        }
+        self.end = at;
+        upper
+    }
+}
+
+/// The user address space of a process: its page table and the list of its mappings. Pages are
+/// only allocated or fetched from their backing object when they are first accessed.
+pub struct AddressSpace {
+    table: Arc<Spinlock<TableRoot>>,
+    areas: Spinlock<BTreeMap<u64, Area>>,
+
+    /// The pages given back with [`Advice::Free`], that can be released by [`Self::reclaim`]
+    /// if they were not written since.
+    lazy_free: Spinlock<BTreeSet<u64>>,
+}
+
+impl AddressSpace {
+    /// Create a new empty address space. The kernel space is shared with all other address spaces,
+    /// and the time page (see [`crate::sys::time::vdso`]) is mapped read-only at the end of the
+    /// user space.
+    #[must_use]
+    pub fn new() -> Self {
+        let space = Self {
+            table: Arc::new(Spinlock::new(TableRoot::new())),
+            areas: Spinlock::new(BTreeMap::new()),
+            lazy_free: Spinlock::new(BTreeSet::new()),
+        };
+
+        if let Some(backing) = vdso::backing() {
+            space
+                .map(
+                    Placement::Fixed(vdso::TIME_PAGE_ADDR),
+                    PAGE_SIZE as u64,
+                    Protection::READ,
+                    Sharing::Shared,
+                    Some(backing),
+                )
+                .expect("Failed to map the time page");
+        }
+        space
+    }
+
+    /// Returns the page table of this address space.
+    #[must_use]
+    pub fn table(&self) -> &Arc<Spinlock<TableRoot>> {
+        &self.table
+    }
+
+    /// Create a new mapping of `len` bytes. The mapping is anonymous and filled with zeros if no
+    /// backing object is given. Returns the start address of the mapping.
+    ///
+    /// # Errors
+    /// - [`SpaceError::InvalidRange`]: The length is zero or larger than the user address space,
+    ///   or the requested fixed address is not page aligned or the range is not in the user
+    ///   address space.
+    /// - [`SpaceError::NoSpace`]: There is no free range large enough for the mapping.
+    pub fn map(
+        &self,
+        placement: Placement,
+        len: u64,
+        protection: Protection,
+        sharing: Sharing,
+        backing: Option<Backing>,
+    ) -> Result<u64, SpaceError> {
+        let len = page_align_up(len).ok_or(SpaceError::InvalidRange)?;
+        if len == 0 || len > USER_MAP_END - USER_START {
+            return Err(SpaceError::InvalidRange);
+        }
+
+        let start = match placement {
+            Placement::Fixed(addr) => {
+                check_range(addr, len)?;
+                self.unmap(addr, len)?;
+                addr
+            }
+            Placement::Hint(addr) if check_range(addr, len).is_ok() && self.is_free(addr, len) => {
+                addr
+            }
+            Placement::Hint(_) | Placement::Anywhere => {
+                self.find_free(len).ok_or(SpaceError::NoSpace)?
+            }
+        };
+
+        let area = Area {
+            start,
+            end: start + len,
+            protection,
+            sharing,
+            backing,
+            mergeable: false,
+        };
+        x86_64::irq::without(|| self.areas.lock().insert(start, area));
+        Ok(start)
+    }
+
+    /// Remove all the mappings in the given range. The mappings partially contained in the range
+    /// are split. Modified pages of shared mappings are written back to their object.
+    ///
+    /// # Errors
+    /// - [`SpaceError::InvalidRange`]: The range is not page aligned or not in the user address
+    ///   space.
+    pub fn unmap(&self, start: u64, len: u64) -> Result<(), SpaceError> {
+        let len = page_align_up(len).ok_or(SpaceError::InvalidRange)?;
+        check_range(start, len)?;
+        let end = start + len;
+
+        let mut stale = StaleFrames::new();
+        x86_64::irq::without(|| {
+            let mut areas = self.areas.lock();
+            let mut table = self.table.lock();
+            let overlapping = areas
+                .range(..end)
+                .filter(|(_, area)| area.end > start)
+                .map(|(&key, _)| key)
+                .collect::<Vec<_>>();
+
+            for key in overlapping {
+                let mut area = areas.remove(&key).unwrap();
+                let upper = (area.end > end).then(|| area.split_off(end));
+                let removed = (area.start < start).then(|| area.split_off(start));
+
+                // After the splits, `area` is the part below the range (if any) and `removed` the
+                // part in the range. If the area starts in the range, the whole `area` is removed.
+                let (lower, removed) = match removed {
+                    Some(removed) => (Some(area), removed),
+                    None => (None, area),
+                };
+
+                for page in (removed.start..removed.end).step_by(PAGE_SIZE) {
+                    release(&mut table, &removed, page, &mut stale);
+                }
+                for area in [lower, upper].into_iter().flatten() {
+                    areas.insert(area.start, area);
+                }
+            }
+            self.lazy_free
+                .lock()
+                .retain(|&page| page < start || page >= end);
+        });
+        stale.free();
+        Ok(())
+    }
+
+    /// Apply the given advice to all the pages in the given range.
+    ///
+    /// # Errors
+    /// - [`SpaceError::InvalidRange`]: The range is not page aligned or not in the user address
+    ///   space, or [`Advice::Free`], [`Advice::Mergeable`] or [`Advice::Unmergeable`] is used on a
+    ///   mapping that is not private and anonymous.
+    /// - [`SpaceError::NotMapped`]: A part of the range is not mapped.
+    pub fn advise(&self, start: u64, len: u64, advice: Advice) -> Result<(), SpaceError> {
+        let len = page_align_up(len).ok_or(SpaceError::InvalidRange)?;
+        check_range(start, len)?;
+        let end = start + len;
+
+        let mut stale = StaleFrames::new();
+        let result = x86_64::irq::without(|| {
+            let mut areas = self.areas.lock();
+            let mut table = self.table.lock();
+
+            // Check the whole range before modifying anything
+            let anonymous_only = matches!(
+                advice,
+                Advice::Free | Advice::Mergeable | Advice::Unmergeable
+            );
+            for page in (start..end).step_by(PAGE_SIZE) {
+                let area = find(&areas, page).ok_or(SpaceError::NotMapped)?;
+                if anonymous_only && !area.private_anonymous() {
+                    return Err(SpaceError::InvalidRange);
+                }
+            }
+
+            if let Advice::Mergeable | Advice::Unmergeable = advice {
+                split_at(&mut areas, start);
+                split_at(&mut areas, end);
+                for (_, area) in areas.range_mut(start..end) {
+                    area.mergeable = advice == Advice::Mergeable;
+                }
+                return Ok(());
+            }
+
+            for page in (start..end).step_by(PAGE_SIZE) {
+                let area = find(&areas, page).unwrap();
+                let virt = Virtual::new(page);
+                match advice {
+                    Advice::DontNeed => release(&mut table, area, page, &mut stale),
+                    Advice::WillNeed => {
+                        if paging::translate(&table, virt).is_none() {
+                            // Prefaulting is only a hint, so errors are ignored here and will be
+                            // reported on the real access.
+                            let _ = populate(&mut table, area, page, false);
+                        }
+                    }
+                    Advice::Free => {
+                        if let Some(flags) = paging::protection(&mut table, virt) {
+                            let mut clean = flags;
+                            clean.remove(PageEntryFlags::DIRTY);
+                            paging::change_protection(&mut table, virt, clean);
+                            self.lazy_free.lock().insert(page);
+                        }
+                    }
+                    Advice::Mergeable | Advice::Unmergeable => unreachable!(),
+                }
+            }
+            if advice == Advice::DontNeed {
+                self.lazy_free
+                    .lock()
+                    .retain(|&page| page < start || page >= end);
+            }
+            Ok(())
+        });
+        stale.free();
+
+        // A CPU may still write to a page through a stale TLB entry that has the dirty bit set,
+        // without setting it again in the page table: the cleared dirty bits must be seen by all
+        // the CPUs before returning, or the page could be reclaimed with data written after it.
+        if advice == Advice::Free && result.is_ok() {
+            paging::tlb::shootdown_sync();
+        }
+        result
+    }
+
+    /// Release the pages given back with [`Advice::Free`] that were not written since. Returns the
+    /// number of released pages. This is called when a frame cannot be allocated (see
+    /// [`super::reclaim`]), and must be called without holding any lock of the address space.
+    pub fn reclaim(&self) -> usize {
+        // A CPU may still write to a page through a stale TLB entry without setting its dirty bit
+        // again, so the clean pages are first write-protected: once all the CPUs have flushed
+        // their TLB, a page that is still clean can no longer be written without a fault.
+        let candidates = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            let pages = core::mem::take(&mut *self.lazy_free.lock());
+            let mut candidates = Vec::new();
+            for page in pages {
+                let clean = find(&areas, page).is_some_and(Area::private_anonymous)
+                    && paging::protection(&mut table, Virtual::new(page))
+                        .is_some_and(|flags| !flags.contains(PageEntryFlags::DIRTY));
+                if clean {
+                    write_protect(&mut table, page);
+                    candidates.push(page);
+                }
+            }
+            candidates
+        });
+        if candidates.is_empty() {
+            return 0;
+        }
+        paging::tlb::shootdown_sync();
+
+        let mut stale = StaleFrames::new();
+        let released = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            let mut released = 0;
+            for page in candidates {
+                let Some(area) = find(&areas, page).filter(|area| area.private_anonymous()) else {
+                    continue;
+                };
+                let virt = Virtual::new(page);
+                let Some(flags) = paging::protection(&mut table, virt) else {
+                    continue;
+                };
+
+                // A written page was either copied on write, or dirtied through a stale TLB
+                // entry before the shootdown: it is kept, and made writable again in the latter
+                // case
+                if flags.contains(PageEntryFlags::WRITABLE) {
+                    continue;
+                }
+                if flags.contains(PageEntryFlags::DIRTY) {
+                    if area.protection.contains(Protection::WRITE) {
+                        paging::change_protection(
+                            &mut table,
+                            virt,
+                            flags | PageEntryFlags::WRITABLE,
+                        );
+                    }
+                    continue;
+                }
+                release(&mut table, area, page, &mut stale);
+                released += 1;
+            }
+            released
+        });
+        stale.free();
+        released
+    }
+
+    /// Write back to their object all the modified pages of the shared mappings in the given range.
+    ///
+    /// # Errors
+    /// - [`SpaceError::InvalidRange`]: The range is not page aligned or not in the user address
+    ///   space.
+    pub fn sync(&self, start: u64, len: u64) -> Result<(), SpaceError> {
+        let len = page_align_up(len).ok_or(SpaceError::InvalidRange)?;
+        check_range(start, len)?;
+        let end = start + len;
+
+        x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            for area in areas.range(..end).map(|(_, area)| area) {
+                let Some(backing) = area.backing.as_ref() else {
+                    continue;
+                };
+                if area.end <= start || area.sharing != Sharing::Shared {
+                    continue;
+                }
+
+                let first = core::cmp::max(area.start, start);
+                let last = core::cmp::min(area.end, end);
+                for page in (first..last).step_by(PAGE_SIZE) {
+                    let virt = Virtual::new(page);
+                    let Some(flags) = paging::protection(&mut table, virt) else {
+                        continue;
+                    };
+                    if flags.contains(PageEntryFlags::DIRTY) {
+                        let frame = Frame::new(paging::translate(&table, virt).unwrap());
+                        let mut clean = flags;
+                        clean.remove(PageEntryFlags::DIRTY);
+                        paging::change_protection(&mut table, virt, clean);
+                        backing.pager.writeback(area.offset(backing, page), frame);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Write the given data at the given address of this address space, which does not need to
+    /// be the current one. The pages are populated if needed, regardless of the protection of
+    /// the mappings. This is used by the kernel to load a program in a new address space.
+    ///
+    /// # Errors
+    /// - [`PageFaultError::MISSING_PAGE`]: A page of the range is not part of a mapping.
+    /// - [`PageFaultError::OUT_OF_MEMORY`]: A page could not be allocated.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<(), PageFaultError> {
+        let mut written = 0;
+        while written < data.len() {
+            let current = addr + written as u64;
+            let page = current & !(PAGE_SIZE as u64 - 1);
+            let offset = usize::try_from(current - page).unwrap();
+            let len = core::cmp::min(PAGE_SIZE - offset, data.len() - written);
+
+            let phys = retry_after_reclaim(|| self.writable_page(page))?;
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr::<u8>().add(offset),
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Handle a page fault in this address space, which must be the current one.
+    ///
+    /// # Errors
+    /// - [`PageFaultError::MISSING_PAGE`]: The address is not part of a mapping.
+    /// - [`PageFaultError::WRITE_PROTECTED`]: The mapping is not writable.
+    /// - [`PageFaultError::NOT_EXECUTABLE`]: The mapping is not executable.
+    /// - [`PageFaultError::PROTECTION_VIOLATION`]: The mapping is not readable.
+    /// - [`PageFaultError::OUT_OF_MEMORY`]: A page could not be allocated.
+    /// - [`PageFaultError::NOT_MAPPABLE`]: The page could not be read from the backing object.
+    pub fn page_fault(
+        &self,
+        code: PageFaultErrorCode,
+        addr: Virtual,
+    ) -> Result<PageFaultType, PageFaultError> {
+        retry_after_reclaim(|| self.handle_fault(code, addr))
+    }
+
+    /// Returns the physical address of the given page, populated and privately writable. See
+    /// [`Self::write`].
+    fn writable_page(&self, page: u64) -> Result<Physical, PageFaultError> {
+        let mut stale = StaleFrames::new();
+        let result = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            let area = find(&areas, page).ok_or(PageFaultError::MISSING_PAGE)?;
+            let virt = Virtual::new(page);
+            match paging::protection(&mut table, virt) {
+                None => populate(&mut table, area, page, true)?,
+                Some(flags)
+                    if area.sharing == Sharing::Private
+                        && !flags.contains(PageEntryFlags::WRITABLE) =>
+                {
+                    // The frame may be shared with other mappings (copy-on-write file page or
+                    // merged page): write to a private copy
+                    copy_page(&mut table, area, page, &mut stale)?;
+                }
+                Some(_) => (),
+            }
+            Ok(paging::translate(&table, virt).unwrap())
+        });
+        stale.free();
+        result
+    }
+
+    /// Handle a page fault in this address space. See [`Self::page_fault`].
+    fn handle_fault(
+        &self,
+        code: PageFaultErrorCode,
+        addr: Virtual,
+    ) -> Result<PageFaultType, PageFaultError> {
+        let write = code.contains(PageFaultErrorCode::WRITE_ACCESS);
+        let fetch = code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+        let page = addr.page_align_down().as_u64();
+
+        let mut stale = StaleFrames::new();
+        let result = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            let area = find(&areas, page).ok_or(PageFaultError::MISSING_PAGE)?;
+
+            if write && !area.protection.contains(Protection::WRITE) {
+                return Err(PageFaultError::WRITE_PROTECTED);
+            } else if fetch && !area.protection.contains(Protection::EXEC) {
+                return Err(PageFaultError::NOT_EXECUTABLE);
+            } else if !area.protection.contains(Protection::READ) {
+                return Err(PageFaultError::PROTECTION_VIOLATION);
+            }
+
+            match paging::protection(&mut table, Virtual::new(page)) {
+                Some(flags) if write && !flags.contains(PageEntryFlags::WRITABLE) => {
+                    copy_page(&mut table, area, page, &mut stale)?;
+                    Ok(PageFaultType::DemandPaging)
+                }
+                Some(_) => {
+                    #[cfg(feature = "tlb-debug")]
+                    paging::tlb::debug::verify(&table, addr);
+                    paging::tlb::shootdown();
+                    Ok(PageFaultType::LazyTlbInvalidation)
+                }
+                None => {
+                    populate(&mut table, area, page, write)?;
+                    Ok(PageFaultType::DemandPaging)
+                }
+            }
+        });
+        stale.free();
+        result
+    }
+
+    /// Returns the mapped pages of the mergeable areas, except those given back with
+    /// [`Advice::Free`].
+    pub(super) fn mergeable_pages(&self) -> Vec<u64> {
+        x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let table = self.table.lock();
+            let lazy_free = self.lazy_free.lock();
+            areas
+                .values()
+                .filter(|area| area.mergeable)
+                .flat_map(|area| (area.start..area.end).step_by(PAGE_SIZE))
+                .filter(|page| !lazy_free.contains(page))
+                .filter(|&page| paging::translate(&table, Virtual::new(page)).is_some())
+                .collect()
+        })
+    }
+
+    /// Returns a checksum of the content of the given page of a mergeable area, or `None` if
+    /// the page is not mapped or no longer mergeable.
+    pub(super) fn checksum(&self, page: u64) -> Option<u64> {
+        x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let table = self.table.lock();
+            find(&areas, page).filter(|area| area.mergeable)?;
+            let frame = Frame::new(paging::translate(&table, Virtual::new(page))?);
+            Some(checksum(frame))
+        })
+    }
+
+    /// Map the given page of a mergeable area read-only, so that its content can no longer change
+    /// without a copy-on-write fault once this function returns, and returns its frame with a
+    /// reference taken for the caller. Returns `None` if the page is not mapped or no longer
+    /// mergeable.
+    pub(super) fn write_protect(&self, page: u64) -> Option<Frame> {
+        let frame = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            find(&areas, page).filter(|area| area.mergeable)?;
+            let frame = Frame::new(paging::translate(&table, Virtual::new(page))?);
+            write_protect(&mut table, page);
+            unsafe {
+                FRAME_ALLOCATOR.lock().reference(frame);
+            }
+            Some(frame)
+        })?;
+
+        // Another CPU may still write to the page through a stale TLB entry until it is flushed
+        paging::tlb::shootdown_sync();
+        Some(frame)
+    }
+
+    /// Replace the frame of the given page of a mergeable area by the given read-only frame if
+    /// their content is identical, and release the old frame. Returns true if the page now maps
+    /// the given frame.
+    pub(super) fn merge(&self, page: u64, stable: Frame) -> bool {
+        let virt = Virtual::new(page);
+
+        // The page must not be modified during the comparison: it is write-protected, and compared
+        // once no CPU can write to it through a stale TLB entry anymore
+        let protected = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            find(&areas, page).filter(|area| area.mergeable)?;
+            let phys = paging::translate(&table, virt)?;
+            write_protect(&mut table, page);
+            Some(Frame::new(phys))
+        });
+        let Some(old) = protected else {
+            return false;
+        };
+        if old.start() == stable.start() {
+            return true;
+        }
+        paging::tlb::shootdown_sync();
+
+        let mut unmapped = StaleFrames::new();
+        let merged = x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut table = self.table.lock();
+            let Some(area) = find(&areas, page).filter(|area| area.mergeable) else {
+                return false;
+            };
+
+            // The page may have been written, unmapped or remapped while the lock was released
+            let protected = paging::protection(&mut table, virt)
+                .is_some_and(|flags| !flags.contains(PageEntryFlags::WRITABLE));
+            if !protected || paging::translate(&table, virt) != Some(old.start()) {
+                return false;
+            }
+            if !same_content(old, stable) {
+                return false;
+            }
+
+            let mut flags = area.flags();
+            flags.remove(PageEntryFlags::WRITABLE);
+            unsafe {
+                FRAME_ALLOCATOR.lock().reference(stable);
+                let _ = paging::unmap(&mut table, virt);
+            }
+            unmapped.push(old);
+            map_frame(&mut table, page, stable, flags).is_ok()
+        });
+        unmapped.free();
+        merged
+    }
+
+    /// Returns true if no mapping overlaps the given range.
+    fn is_free(&self, start: u64, len: u64) -> bool {
+        let end = start + len;
+        x86_64::irq::without(|| {
+            self.areas
+                .lock()
+                .range(..end)
+                .all(|(_, area)| area.end <= start)
+        })
+    }
+
+    /// Find the first free range of `len` bytes above [`MMAP_BASE`].
+    fn find_free(&self, len: u64) -> Option<u64> {
+        x86_64::irq::without(|| {
+            let areas = self.areas.lock();
+            let mut candidate = MMAP_BASE;
+            for area in areas.values().filter(|area| area.end > MMAP_BASE) {
+                if area.start >= candidate.checked_add(len)? {
+                    break;
+                }
+                candidate = core::cmp::max(candidate, area.end);
+            }
+            (candidate.checked_add(len)? <= USER_MAP_END).then_some(candidate)
+        })
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Release all the pages of the address space, and then the page tables of its user space.
+    fn drop(&mut self) {
+        let areas = core::mem::take(self.areas.get_mut());
+        let mut stale = StaleFrames::new();
+        let mut table = self.table.lock();
+        for area in areas.values() {
+            for page in (area.start..area.end).step_by(PAGE_SIZE) {
+                release(&mut table, area, page, &mut stale);
+            }
+        }
+
+        // No thread runs in this address space anymore, so its pages are no longer accessed and
+        // the frames can be freed without waiting for a shootdown. Waiting would even deadlock,
+        // because an address space can be dropped by the scheduler with its lock held.
+        stale.0.into_iter().for_each(free_frame);
+
+        // For the same reason, the page tables are no longer walked to access the user space
+        unsafe {
+            paging::free_user_tables(&mut table);
+        }
+    }
+}
+
+/// Find the area containing the given address.
+fn find(areas: &BTreeMap<u64, Area>, addr: u64) -> Option<&Area> {
+    areas
+        .range(..=addr)
+        .next_back()
+        .map(|(_, area)| area)
+        .filter(|area| area.contains(addr))
+}
+
+/// Split the area containing the given address, if any, so that an area starts at this address.
+fn split_at(areas: &mut BTreeMap<u64, Area>, addr: u64) {
+    let Some((_, area)) = areas.range_mut(..addr).next_back() else {
+        return;
+    };
+    if area.contains(addr) {
+        let upper = area.split_off(addr);
+        areas.insert(upper.start, upper);
+    }
+}
+
+/// Run the given operation, and run it again if it failed because a frame could not be allocated
+/// but some pages given back with [`Advice::Free`] could be released (see [`super::reclaim`]).
+fn retry_after_reclaim<T>(
+    mut operation: impl FnMut() -> Result<T, PageFaultError>,
+) -> Result<T, PageFaultError> {
+    match operation() {
+        Err(error) if error.contains(PageFaultError::OUT_OF_MEMORY) && reclaim::reclaim() > 0 => {
+            operation()
+        }
+        result => result,
+    }
+}
+
+/// Check that the given range is page aligned and entirely contained in the part of the user space
+/// that can be mapped.
+fn check_range(start: u64, len: u64) -> Result<(), SpaceError> {
+    let end = start.checked_add(len).ok_or(SpaceError::InvalidRange)?;
+    if !start.is_multiple_of(PAGE_SIZE as u64) || start < USER_START || end > USER_MAP_END {
+        return Err(SpaceError::InvalidRange);
+    }
+    Ok(())
+}
+
+/// Round the given length up to a multiple of the page size, or returns `None` on overflow.
+fn page_align_up(len: u64) -> Option<u64> {
+    Some(len.checked_add(PAGE_SIZE as u64 - 1)? & !(PAGE_SIZE as u64 - 1))
+}
+
+/// Map the page at the given address of the area, which is not mapped yet. Anonymous pages are
+/// filled with zeros, and file-backed pages are fetched from their backing object. If the page
+/// is a private file-backed page, it is mapped read-only unless the fault was caused by a write,
+/// in which case it is directly copied.
+fn populate(
+    table: &mut PageTable,
+    area: &Area,
+    page: u64,
+    write: bool,
+) -> Result<(), PageFaultError> {
+    let mut flags = area.flags();
+    let frame = match &area.backing {
+        None => allocate_frame()?,
+        Some(backing) => {
+            let frame = backing
+                .pager
+                .page(area.offset(backing, page))
+                .ok_or(PageFaultError::NOT_MAPPABLE)?;
+            if area.copy_on_write() {
+                if write {
+                    let copy = allocate_frame()?;
+                    copy_frame(frame, copy);
+                    free_frame(frame);
+                    copy
+                } else {
+                    flags.remove(PageEntryFlags::WRITABLE);
+                    frame
+                }
+            } else {
+                frame
+            }
+        }
+    };
+
+    trace!(
+        "User demand paging: {:016x} -> {:016x}",
+        page,
+        frame.start().as_u64()
+    );
+    map_frame(table, page, frame, flags)
+}
+
+/// Replace the read-only page at the given address of a private mapping by a writable copy. The
+/// reference of the mapping on the old frame is added to the stale frames.
+fn copy_page(
+    table: &mut PageTable,
+    area: &Area,
+    page: u64,
+    stale: &mut StaleFrames,
+) -> Result<(), PageFaultError> {
+    let virt = Virtual::new(page);
+    let old = Frame::new(paging::translate(table, virt).unwrap());
+    let copy = allocate_frame()?;
+    copy_frame(old, copy);
+
+    unsafe {
+        let _ = paging::unmap(table, virt);
+    }
+    stale.push(old);
+    map_frame(table, page, copy, area.flags())
+}
+
+/// Unmap the page at the given address of the area, writing it back to the backing object if it
+/// was modified through a shared mapping. The reference of the mapping on the frame is added to
+/// the stale frames.
+fn release(table: &mut PageTable, area: &Area, page: u64, stale: &mut StaleFrames) {
+    let virt = Virtual::new(page);
+    let Some(flags) = paging::protection(table, virt) else {
+        return;
+    };
+    let phys = unsafe { paging::unmap(table, virt) }.unwrap();
+    let frame = Frame::new(phys);
+
+    if let Some(backing) = &area.backing {
+        if area.sharing == Sharing::Shared && flags.contains(PageEntryFlags::DIRTY) {
+            backing.pager.writeback(area.offset(backing, page), frame);
+        }
+    }
+    stale.push(frame);
+}
+
+/// Remove the write access to the page at the given address, if it is mapped.
+fn write_protect(table: &mut PageTable, page: u64) {
+    let virt = Virtual::new(page);
+    if let Some(flags) = paging::protection(table, virt) {
+        if flags.contains(PageEntryFlags::WRITABLE) {
+            let mut protected = flags;
+            protected.remove(PageEntryFlags::WRITABLE);
+            paging::change_protection(table, virt, protected);
+        }
+    }
+}
+
+fn map_frame(
+    table: &mut PageTable,
+    page: u64,
+    frame: Frame,
+    flags: PageEntryFlags,
+) -> Result<(), PageFaultError> {
+    unsafe { paging::map(table, Virtual::new(page), frame, flags) }.map_err(|e| match e {
+        MapError::OutOfMemory => PageFaultError::OUT_OF_MEMORY,
+        MapError::AlreadyMapped => PageFaultError::ALREADY_MAPPED,
+    })
+}
+
+fn allocate_frame() -> Result<Frame, PageFaultError> {
+    x86_64::irq::without(|| unsafe {
+        FRAME_ALLOCATOR
+            .lock()
+            .allocate(AllocationFlags::ZEROED)
+            .ok_or(PageFaultError::OUT_OF_MEMORY)
+    })
+}
+
+fn free_frame(frame: Frame) {
+    x86_64::irq::without(|| unsafe {
+        FRAME_ALLOCATOR.lock().deallocate(frame);
+    });
+}
+
+fn copy_frame(src: Frame, dst: Frame) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(src.start()).as_ptr::<u8>(),
+            phys_to_virt(dst.start()).as_mut_ptr::<u8>(),
+            PAGE_SIZE,
+        );
+    }
+}
+
+/// Compute a FNV-1a hash of the content of the given frame, processed by 64 bits words.
+fn checksum(frame: Frame) -> u64 {
+    let content = unsafe {
+        core::slice::from_raw_parts(phys_to_virt(frame.start()).as_ptr::<u64>(), PAGE_SIZE / 8)
+    };
+    content.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &word| {
+        (hash ^ word).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+fn same_content(a: Frame, b: Frame) -> bool {
+    unsafe {
+        let a = core::slice::from_raw_parts(phys_to_virt(a.start()).as_ptr::<u8>(), PAGE_SIZE);
+        let b = core::slice::from_raw_parts(phys_to_virt(b.start()).as_ptr::<u8>(), PAGE_SIZE);
+        a == b
+    }
+}
@@ -0,0 +1,180 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::cpu;
+
+use crate::arch::{
+    control::Cr4,
+    features::{self, CpuFeatures},
+};
+
+use super::{USER_END, USER_START};
+
+/// Set if the CPU supports SMAP and if it was enabled. When SMAP is enabled, the kernel cannot
+/// access user memory unless the `AC` flag is set in RFLAGS, which is done with the `stac` and
+/// `clac` instructions around each user memory access.
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UserAccessError {
+    /// The given range is not entirely contained in the user address space, or its end
+    /// overflows the address space.
+    OutOfRange,
+
+    /// A page fault occurred during the copy, because the range is not mapped or because the
+    /// access is not allowed (e.g. writing to a read-only page).
+    Fault,
+}
+
+/// An entry in the exception table. When a fault occurs at the `instruction` address, the fault
+/// handler will not panic and will resume the execution at the `fixup` address instead. This
+/// allows the kernel to safely access user memory without having to check that every page of
+/// the user range is mapped and accessible before the access.
+#[repr(C)]
+struct ExceptionTableEntry {
+    instruction: u64,
+    fixup: u64,
+}
+
+extern "C" {
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+
+    /// Copy `len` bytes from `src` to `dst`. Returns the number of bytes that were not copied
+    /// because a fault occurred during the copy, or 0 if the copy was successful.
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+// The copy is done with `rep movsb`, which is fast enough on modern CPUs. If a fault occurs while
+// copying, the fault handler will jump to the fixup code that simply returns the number of bytes
+// remaining in `rcx`.
+core::arch::global_asm!(
+    r#"
+.global __copy_user
+__copy_user:
+    mov rcx, rdx
+.Lcopy_user_insn:
+    rep movsb
+    xor eax, eax
+    ret
+.Lcopy_user_fixup:
+    mov rax, rcx
+    ret
+
+.pushsection .ex_table, "a"
+.balign 8
+.quad .Lcopy_user_insn, .Lcopy_user_fixup
+.popsection
+"#
+);
+
+/// Enable SMAP on the current CPU if it is supported. This must be called on each CPU, because
+/// the CR4 register is not shared between cores.
+pub fn setup() {
+    // Once enabled, SMAP is used on all the CPUs: the APs without it cannot be used
+    if features::require(CpuFeatures::SMAP) {
+        unsafe {
+            Cr4::update(|cr4| cr4 | Cr4::SMAP);
+        }
+        SMAP.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Check that the given range is entirely contained in the user address space.
+///
+/// # Errors
+/// Returns [`UserAccessError::OutOfRange`] if the range is not entirely contained in the user
+/// address space, or if the end of the range overflows.
+pub fn check_range(start: u64, len: usize) -> Result<(), UserAccessError> {
+    let end = start
+        .checked_add(len as u64)
+        .ok_or(UserAccessError::OutOfRange)?;
+    if start < USER_START || end > USER_END {
+        return Err(UserAccessError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from the user address `src` to the kernel buffer `dst`. If a fault
+/// occurs during the copy, the content of `dst` is unspecified.
+///
+/// # Errors
+/// - [`UserAccessError::OutOfRange`]: The source range is not in the user address space.
+/// - [`UserAccessError::Fault`]: A fault occurred while reading the user memory.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), UserAccessError> {
+    check_range(src, dst.len())?;
+    let remaining =
+        with_user_access(|| unsafe { __copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) });
+    if remaining != 0 {
+        return Err(UserAccessError::Fault);
+    }
+    Ok(())
+}
+
+/// Copy the content of the kernel buffer `src` to the user address `dst`. If a fault occurs
+/// during the copy, the user memory may have been partially written.
+///
+/// # Errors
+/// - [`UserAccessError::OutOfRange`]: The destination range is not in the user address space.
+/// - [`UserAccessError::Fault`]: A fault occurred while writing the user memory.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), UserAccessError> {
+    check_range(dst, src.len())?;
+    let remaining =
+        with_user_access(|| unsafe { __copy_user(dst as *mut u8, src.as_ptr(), src.len()) });
+    if remaining != 0 {
+        return Err(UserAccessError::Fault);
+    }
+    Ok(())
+}
+
+/// Search the exception table for a fixup corresponding to the instruction that caused the
+/// fault. If one is found, the instruction pointer of the saved state is changed to the fixup
+/// address and `true` is returned, so the fault handler can return and resume the execution
+/// at the fixup. Otherwise, `false` is returned and the state is left untouched.
+pub fn fixup(state: &mut cpu::State) -> bool {
+    let table = unsafe {
+        let start = core::ptr::addr_of!(__ex_table_start);
+        let end = core::ptr::addr_of!(__ex_table_end);
+        let count = (end as usize - start as usize) / core::mem::size_of::<ExceptionTableEntry>();
+        core::slice::from_raw_parts(start, count)
+    };
+
+    match table.iter().find(|entry| entry.instruction == state.rip) {
+        Some(entry) => {
+            state.rip = entry.fixup;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Allow the kernel to access user memory during the execution of the given closure, if SMAP
+/// is enabled. Otherwise, the closure is simply executed.
+fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        unsafe { cpu_stac() };
+    }
+    let ret = f();
+    if smap {
+        unsafe { cpu_clac() };
+    }
+    ret
+}
+
+/// Set the `AC` flag in RFLAGS, allowing the kernel to access user memory when SMAP is enabled.
+///
+/// # Safety
+/// This function is unsafe because it can only be executed if the CPU supports SMAP, and because
+/// it disables a protection of the kernel against bugs that could lead to user memory accesses.
+unsafe fn cpu_stac() {
+    core::arch::asm!("stac", options(nostack));
+}
+
+/// Clear the `AC` flag in RFLAGS, forbidding the kernel to access user memory when SMAP is
+/// enabled.
+///
+/// # Safety
+/// This function is unsafe because it can only be executed if the CPU supports SMAP.
+unsafe fn cpu_clac() {
+    core::arch::asm!("clac", options(nostack));
+}
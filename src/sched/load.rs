@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::config::KERNEL_HZ;
+
+use super::thread::Tid;
+
+/// The number of bits used for the fractional part of the load averages.
+pub const FSHIFT: u32 = 11;
+
+/// The fixed-point representation of 1.0.
+pub const FIXED_1: u64 = 1 << FSHIFT;
+
+/// The interval between two updates of the load averages, in clock ticks. This is a little more
+/// than 5 seconds to avoid being synchronized with periodic tasks.
+const LOAD_FREQ: u64 = 5 * KERNEL_HZ + 1;
+
+/// The decay factors of the 1, 5 and 15 minutes load averages, computed as `FIXED_1 / exp(5 / 60)`,
+/// `FIXED_1 / exp(5 / 300)` and `FIXED_1 / exp(5 / 900)`.
+const EXP: [u64; 3] = [1884, 2014, 2037];
+
+/// The 1, 5 and 15 minutes load averages, in fixed-point with [`FSHIFT`] fractional bits.
+static AVERAGES: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// The number of threads waiting in the ready queue, updated on each clock tick of the
+/// timekeeper. All the CPUs share the same ready queue (see [`cpu`] for the view of each CPU).
+static RUNQUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+crate::per_cpu! {
+    /// The number of threads waiting in the ready queue, updated on each clock tick of each CPU.
+    static CPU_READY: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// A snapshot of the load of the system.
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    /// The 1, 5 and 15 minutes load averages, in fixed-point with [`FSHIFT`] fractional bits.
+    pub averages: [u64; 3],
+
+    /// The number of threads waiting in the ready queue.
+    pub runqueue: usize,
+}
+
+/// A snapshot of the run queue of a CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuLoad {
+    /// The thread running on the CPU, or `None` if the CPU runs its idle thread.
+    pub current: Option<Tid>,
+
+    /// The number of threads waiting for a CPU, as seen by this CPU on its last clock tick. The
+    /// CPUs all pick their threads from the same ready queue, so a ready thread is counted by each
+    /// of them.
+    pub ready: usize,
+}
+
+/// Update the load metrics. This function must be called on each clock tick of the timekeeper,
+/// with the depth of the ready queue and the number of threads that are running or ready to run.
+/// The load averages are only updated every [`LOAD_FREQ`] ticks.
+pub fn tick(now: u64, depth: usize, active: usize) {
+    RUNQUEUE_DEPTH.store(depth, Ordering::Relaxed);
+    if !now.is_multiple_of(LOAD_FREQ) {
+        return;
+    }
+
+    let active = active as u64 * FIXED_1;
+    for (average, exp) in AVERAGES.iter().zip(EXP) {
+        let load = average.load(Ordering::Relaxed);
+        let load = (load * exp + active * (FIXED_1 - exp)) >> FSHIFT;
+        average.store(load, Ordering::Relaxed);
+    }
+}
+
+/// Record the depth of the ready queue seen by the current CPU. This function must be called on
+/// each clock tick of each CPU.
+pub fn cpu_tick(depth: usize) {
+    CPU_READY.local().store(depth, Ordering::Relaxed);
+}
+
+/// Returns the number of threads waiting in the ready queue.
+#[must_use]
+pub fn runqueue_depth() -> usize {
+    RUNQUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Returns the 1, 5 and 15 minutes load averages, in fixed-point with [`FSHIFT`] fractional bits.
+#[must_use]
+pub fn averages() -> [u64; 3] {
+    [
+        AVERAGES[0].load(Ordering::Relaxed),
+        AVERAGES[1].load(Ordering::Relaxed),
+        AVERAGES[2].load(Ordering::Relaxed),
+    ]
+}
+
+/// Returns a snapshot of the load of the system.
+#[must_use]
+pub fn snapshot() -> Load {
+    Load {
+        averages: averages(),
+        runqueue: runqueue_depth(),
+    }
+}
+
+/// Returns a snapshot of the run queue of the given CPU. The running thread is read without
+/// locking the scheduler, so it may have changed when this function returns.
+#[must_use]
+pub fn cpu(cpu: u32) -> CpuLoad {
+    let current = Tid::from_u64(super::running_tid(cpu));
+    CpuLoad {
+        current: (current != Tid::IDLE).then_some(current),
+        ready: CPU_READY.cpu(cpu).load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,676 @@
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::{self, paging, percpu::CacheAligned},
+    config::MAX_CPU,
+    mm::space::AddressSpace,
+    sys::time::{
+        self,
+        wheel::{self, Timer},
+    },
+    Spinlock,
+};
+
+use self::thread::{State, Thread, Tid};
+
+pub mod load;
+pub mod parker;
+pub mod thread;
+
+/// The global scheduler. Each CPU has its own current and idle threads, and all the CPUs execute
+/// the threads of the same ready queue: a thread can therefore migrate from a CPU to another each
+/// time it is scheduled.
+///
+/// The scheduler is also used by the clock tick interrupt handler, so it must always be locked
+/// with interrupts disabled to avoid deadlocks.
+static SCHEDULER: Spinlock<Scheduler> = Spinlock::new(Scheduler::new());
+
+/// Set once the BSP has initialized the scheduler, so that the APs can register with it (see
+/// [`ap_setup`]).
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+crate::per_cpu! {
+    /// Set by the clock tick of a CPU when another thread is ready to run, and cleared on each
+    /// context switch of this CPU. Threads running in kernel mode are not preempted by the clock
+    /// tick: they check this flag at the preemption points of their long loops (see
+    /// [`preempt_point!`]).
+    ///
+    /// The flag of an idle CPU is also monitored by its idle loop, so that another CPU can wake it
+    /// up by setting the flag: each flag is therefore alone in its cache line.
+    static NEED_RESCHED: CacheAligned<AtomicBool> = CacheAligned(AtomicBool::new(false));
+}
+
+crate::per_cpu! {
+    /// Set while a CPU waits in its idle loop. A CPU making a thread ready wakes up one of those
+    /// CPUs (see [`wake_idle`]).
+    static WAITING: AtomicBool = AtomicBool::new(false);
+}
+
+crate::per_cpu! {
+    /// The identifier of the thread running on each CPU. Unlike [`current_tid`], it can be read
+    /// without locking the scheduler, for example by the crash dump of a CPU halted while holding
+    /// the lock (see [`running_tid`]).
+    static RUNNING: AtomicU64 = AtomicU64::new(0);
+}
+
+crate::per_cpu! {
+    /// Set when a CPU must be parked (see [`arch::smp::offline`]): the CPU then gives up its
+    /// current thread, and its idle thread parks it.
+    static PARKING: AtomicBool = AtomicBool::new(false);
+}
+
+/// Give up the CPU if another thread is waiting for it and if it is safe to do so. This should be
+/// used in long kernel loops, that could otherwise keep the CPU for a long time because threads
+/// running in kernel mode are never preempted by the clock tick.
+///
+/// This is a no-op when called with interrupts disabled, because the caller may hold a spinlock
+/// or rely on not being interrupted, and during the early boot.
+#[macro_export]
+macro_rules! preempt_point {
+    () => {
+        $crate::sched::preempt_point()
+    };
+}
+
+/// Threads are always boxed, so that they are never moved in memory: this allows [`schedule`] to
+/// keep a pointer to the saved stack pointer of a thread after releasing the lock.
+#[allow(clippy::vec_box)]
+struct Scheduler {
+    /// The threads owned by each CPU, indexed by CPU id.
+    cpus: [Cpu; MAX_CPU],
+
+    /// The threads ready to run, in the order in which they will be executed.
+    ready: VecDeque<Box<Thread>>,
+
+    /// The threads sleeping until a given tick. They are woken up by a timer of the timer wheel.
+    sleeping: Vec<Box<Thread>>,
+
+    /// The threads blocked until they are woken up with [`wake`].
+    blocked: Vec<Box<Thread>>,
+
+    /// The threads that have exited. They cannot be destroyed immediately because they are still
+    /// running on their own kernel stack when they exit, so they are destroyed during the next
+    /// scheduling.
+    zombies: Vec<Box<Thread>>,
+}
+
+/// The threads owned by a CPU.
+struct Cpu {
+    /// The thread currently running on the CPU.
+    current: Option<Box<Thread>>,
+
+    /// The idle thread of the CPU, when it is not running.
+    idle: Option<Box<Thread>>,
+
+    /// The thread the CPU is switching from. It is only put back in the scheduler once its
+    /// context is saved (see [`finish_switch`]), so that another CPU cannot resume it before.
+    prev: Option<Box<Thread>>,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Self {
+            current: None,
+            idle: None,
+            prev: None,
+        }
+    }
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            cpus: [const { Cpu::new() }; MAX_CPU],
+            ready: VecDeque::new(),
+            sleeping: Vec::new(),
+            blocked: Vec::new(),
+            zombies: Vec::new(),
+        }
+    }
+
+    /// Returns the threads owned by the current CPU.
+    fn cpu(&mut self) -> &mut Cpu {
+        &mut self.cpus[arch::smp::current_id() as usize]
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        self.cpu()
+            .current
+            .as_mut()
+            .expect("Scheduler used before being initialized")
+    }
+
+    /// Returns the threads running on a CPU or being switched from, which cannot be found in the
+    /// queues of the scheduler.
+    fn on_cpu(&mut self) -> impl Iterator<Item = &mut Box<Thread>> {
+        self.cpus
+            .iter_mut()
+            .flat_map(|cpu| cpu.current.iter_mut().chain(cpu.prev.iter_mut()))
+            .filter(|thread| thread.tid() != Tid::IDLE)
+    }
+
+    /// Pick the next thread to execute on the current CPU. Returns `None` if the current thread
+    /// should continue to run.
+    fn pick_next(&mut self) -> Option<Box<Thread>> {
+        if PARKING.load() {
+            let idle = self.current().tid() == Tid::IDLE;
+            return if idle { None } else { self.cpu().idle.take() };
+        }
+
+        let current = self.current();
+        let runnable = current.state() == State::Running && current.tid() != Tid::IDLE;
+        match self.ready.pop_front() {
+            Some(next) => Some(next),
+            None if runnable => None,
+            None if self.current().tid() == Tid::IDLE => None,
+            None => self.cpu().idle.take(),
+        }
+    }
+
+    /// Put the thread that has just been preempted on the current CPU in the right place according
+    /// to its state.
+    fn put_back(&mut self, mut thread: Box<Thread>) {
+        if thread.tid() == Tid::IDLE {
+            thread.set_state(State::Ready);
+            self.cpu().idle = Some(thread);
+            return;
+        }
+
+        match thread.state() {
+            State::Running | State::Ready => {
+                thread.set_state(State::Ready);
+                self.ready.push_back(thread);
+            }
+            State::Sleeping(_) => self.sleeping.push(thread),
+            State::Blocked => self.blocked.push(thread),
+            State::Exited => self.zombies.push(thread),
+        }
+    }
+
+    /// Returns the number of threads that are running or ready to run, excluding the idle
+    /// threads.
+    fn active(&mut self) -> usize {
+        let running = self
+            .cpus
+            .iter()
+            .filter_map(|cpu| cpu.current.as_ref())
+            .filter(|thread| thread.tid() != Tid::IDLE)
+            .count();
+        self.ready.len() + running
+    }
+
+    /// Returns the number of threads alive, excluding the idle threads.
+    fn count(&mut self) -> usize {
+        let on_cpu = self
+            .on_cpu()
+            .filter(|thread| thread.state() != State::Exited)
+            .count();
+        self.ready.len() + self.sleeping.len() + self.blocked.len() + on_cpu
+    }
+
+    /// Wake up the sleeping thread with the given identifier. If the thread has not given up its
+    /// CPU yet, it simply continues to run.
+    fn wake_sleeper(&mut self, tid: u64) {
+        if let Some(index) = self.sleeping.iter().position(|t| t.tid().as_u64() == tid) {
+            let mut thread = self.sleeping.swap_remove(index);
+            thread.set_state(State::Ready);
+            self.ready.push_back(thread);
+            wake_idle();
+            return;
+        }
+
+        let thread = self.on_cpu().find(|thread| thread.tid().as_u64() == tid);
+        if let Some(thread) = thread {
+            if matches!(thread.state(), State::Sleeping(_)) {
+                thread.set_state(State::Running);
+            }
+        }
+    }
+}
+
+/// Initialize the scheduler on the BSP. The current execution context becomes the idle thread of
+/// the BSP, which will be executed when no other thread is ready to run. The APs can then register
+/// with the scheduler, and the sleeping mutexes start to put the threads to sleep.
+pub fn setup() {
+    x86_64::irq::without(|| {
+        SCHEDULER.lock().cpu().current = Some(Box::new(Thread::idle()));
+    });
+    arch::smp::set_active(arch::smp::current_id(), true);
+    STARTED.store(true, Ordering::Release);
+    parker::setup();
+}
+
+/// Register the current AP with the scheduler once the BSP has initialized it (see [`setup`]).
+/// The current execution context becomes the idle thread of the AP: the AP must then enter the
+/// idle loop with [`idle`]. Interrupts are enabled while waiting, so that the AP handles the
+/// cross-CPU calls sent by the BSP during the boot.
+pub fn ap_setup() {
+    while !STARTED.load(Ordering::Acquire) {
+        x86_64::irq::enable();
+        x86_64::cpu::hlt();
+    }
+    x86_64::irq::without(|| {
+        SCHEDULER.lock().cpu().current = Some(Box::new(Thread::idle()));
+    });
+    arch::smp::set_active(arch::smp::current_id(), true);
+}
+
+/// Add a new thread to the ready queue.
+pub fn spawn(thread: Thread) {
+    x86_64::irq::without(|| {
+        SCHEDULER.lock().ready.push_back(Box::new(thread));
+        wake_idle();
+    });
+}
+
+/// Switch to the next ready thread, if any. If the current thread is still running, it is put
+/// at the end of the ready queue. If the current thread is sleeping or has exited and no other
+/// thread is ready to run, the idle thread is executed.
+pub fn schedule() {
+    switch(Switch::Voluntary);
+}
+
+/// Preempt the current thread. This is the same as [`schedule`], but must be used by interrupt
+/// handlers so that involuntary context switches can be told apart from the voluntary ones. Does
+/// nothing if called from a nested interrupt handler (see [`arch::interrupt::nested`]): the
+/// outermost handler will preempt the thread when it is resumed.
+pub fn preempt() {
+    if arch::interrupt::nested() {
+        return;
+    }
+    switch(Switch::Preemption);
+}
+
+/// The reason of a context switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Switch {
+    /// The current thread gave up the CPU by itself.
+    Voluntary,
+
+    /// The current thread was preempted by an interrupt.
+    Preemption,
+}
+
+/// Returns true if the current thread should give up the CPU because another thread is ready to
+/// run.
+#[must_use]
+pub fn need_resched() -> bool {
+    NEED_RESCHED.local().load(Ordering::Relaxed)
+}
+
+/// The function behind [`preempt_point!`]. There is no deferred interrupt work in the kernel yet,
+/// so a rescheduling request is the only thing that can be pending here.
+pub fn preempt_point() {
+    if need_resched() && !crate::EARLY.load(Ordering::Relaxed) && arch::irq::enabled() {
+        preempt();
+    }
+}
+
+#[cfg_attr(not(feature = "bench"), allow(unused_variables))]
+fn switch(reason: Switch) {
+    assert!(
+        !arch::interrupt::nested(),
+        "Scheduler called from a nested interrupt handler"
+    );
+    x86_64::irq::without(|| {
+        let (prev, next) = {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler.zombies.clear();
+            NEED_RESCHED.local().store(false, Ordering::Relaxed);
+
+            let Some(mut next) = scheduler.pick_next() else {
+                return;
+            };
+
+            next.set_state(State::Running);
+
+            // The FPU state is switched eagerly. Only user threads have one: it must be saved even
+            // when switching to a kernel thread, because the thread may be resumed by another CPU
+            if let Some(fpu) = scheduler.current().fpu.as_mut() {
+                fpu.save();
+            }
+            if let Some(fpu) = &next.fpu {
+                fpu.restore();
+            }
+            if let Some(top) = next.kernel_stack_top() {
+                arch::context::set_kernel_stack(top);
+            }
+            arch::backtrace::set_thread_stack(next.kernel_stack());
+            if let Some(space) = next.space() {
+                paging::set_current_table(space.table().clone());
+            }
+
+            let next_rsp = next.rsp;
+            RUNNING.store(next.tid().as_u64());
+            let cpu = scheduler.cpu();
+            let mut prev = cpu.current.replace(next).unwrap();
+            let prev_rsp = core::ptr::addr_of_mut!(prev.rsp);
+            cpu.prev = Some(prev);
+            (prev_rsp, next_rsp)
+        };
+
+        #[cfg(feature = "bench")]
+        crate::bench::before_switch(reason);
+        // The interrupt depth belongs to the thread, not to the CPU
+        let depth = arch::interrupt::take_depth();
+        unsafe {
+            arch::context::switch(prev, next);
+        }
+        finish_switch();
+        arch::interrupt::restore_depth(depth);
+        #[cfg(feature = "bench")]
+        crate::bench::after_switch();
+    });
+}
+
+/// Put the thread the current CPU has switched from back in the scheduler, now that its context is
+/// saved and that it can be resumed by any CPU. This is called by [`switch`] once the next thread
+/// runs, and by new threads before their entry point (see [`arch::context::prepare_stack`]).
+pub extern "C" fn finish_switch() {
+    x86_64::irq::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(prev) = scheduler.cpu().prev.take() {
+            scheduler.put_back(prev);
+        }
+    });
+}
+
+/// Called on each clock tick of each CPU. On the timekeeper (see [`time::tick`]), this function
+/// also runs the expired timers of the timer wheel, which wake up the sleeping threads whose
+/// deadline has passed, and updates the load averages. Returns true if another thread is ready to
+/// run and the current one should be preempted.
+#[must_use]
+pub fn tick(timekeeper: bool) -> bool {
+    let now = time::jiffies();
+    if timekeeper {
+        wheel::run(now);
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.cpu().current.is_none() {
+        return false;
+    }
+    load::cpu_tick(scheduler.ready.len());
+    if timekeeper {
+        let active = scheduler.active();
+        load::tick(now, scheduler.ready.len(), active);
+    }
+
+    let parking = PARKING.load() && scheduler.current().tid() != Tid::IDLE;
+    let reschedule = !scheduler.ready.is_empty() || parking;
+    if reschedule {
+        NEED_RESCHED.local().store(true, Ordering::Relaxed);
+    }
+    reschedule
+}
+
+/// Put the current thread to sleep for at least the given number of clock ticks (see
+/// [`time::jiffies`]).
+pub fn sleep(ticks: u64) {
+    let deadline = time::jiffies().saturating_add(ticks);
+    x86_64::irq::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        current.set_state(State::Sleeping(deadline));
+        wheel::add_timer(&Timer::new(wake_sleeper, current.tid().as_u64()), deadline);
+    });
+    schedule();
+}
+
+/// The callback of the timer of a sleeping thread.
+fn wake_sleeper(tid: u64) {
+    x86_64::irq::without(|| SCHEDULER.lock().wake_sleeper(tid));
+}
+
+/// The reason why [`sleep_interruptible`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Wakeup {
+    /// The deadline of the sleep was reached.
+    Deadline,
+
+    /// A signal was sent to the thread before the deadline (see [`send_signal`]).
+    Signal,
+}
+
+/// Put the current thread to sleep for at least the given duration, rounded up to the next clock
+/// tick, unless a signal is sent to it in the meantime. Returns immediately with
+/// [`Wakeup::Signal`] if a signal is already pending. The pending signal is not taken: the caller
+/// must leave it for the code delivering the signals.
+#[must_use]
+pub fn sleep_interruptible(duration: Duration) -> Wakeup {
+    let deadline = time::jiffies().saturating_add(time::duration_to_jiffies(duration));
+    let timer = x86_64::irq::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        if current.signal_pending {
+            return None;
+        }
+        current.set_state(State::Sleeping(deadline));
+        current.interruptible = true;
+
+        let timer = Timer::new(wake_sleeper, current.tid().as_u64());
+        wheel::add_timer(&timer, deadline);
+        Some(timer)
+    });
+    let Some(timer) = timer else {
+        return Wakeup::Signal;
+    };
+
+    schedule();
+    x86_64::irq::without(|| SCHEDULER.lock().current().interruptible = false);
+
+    // The timer is still pending only if the thread was woken up by a signal
+    if wheel::del_timer(&timer) {
+        Wakeup::Signal
+    } else {
+        Wakeup::Deadline
+    }
+}
+
+/// Send a signal to the thread with the given identifier, and wake it up if it sleeps in
+/// [`sleep_interruptible`]. The signal stays pending until it is taken with [`take_signal`]. The
+/// signals are not delivered to the user space yet, and do not carry any number. This can be called
+/// from an interrupt handler.
+pub fn send_signal(tid: Tid) {
+    x86_64::irq::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = &mut *scheduler;
+        let thread = scheduler
+            .cpus
+            .iter_mut()
+            .flat_map(|cpu| cpu.current.iter_mut().chain(cpu.prev.iter_mut()))
+            .chain(scheduler.ready.iter_mut())
+            .chain(scheduler.sleeping.iter_mut())
+            .chain(scheduler.blocked.iter_mut())
+            .find(|thread| thread.tid() == tid);
+        let Some(thread) = thread else {
+            return;
+        };
+
+        thread.signal_pending = true;
+        if thread.interruptible {
+            scheduler.wake_sleeper(tid.as_u64());
+        }
+    });
+}
+
+/// Returns true if a signal is pending for the current thread.
+#[must_use]
+pub fn signal_pending() -> bool {
+    x86_64::irq::without(|| SCHEDULER.lock().current().signal_pending)
+}
+
+/// Take the pending signal of the current thread. Returns true if a signal was pending.
+#[must_use]
+pub fn take_signal() -> bool {
+    x86_64::irq::without(|| core::mem::take(&mut SCHEDULER.lock().current().signal_pending))
+}
+
+/// Block the current thread until it is woken up with [`wake`]. If the thread was woken up since
+/// the last time it blocked, this function returns immediately: the caller must therefore check
+/// the condition it is waiting for again after each return.
+pub fn block() {
+    let blocked = x86_64::irq::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        if core::mem::take(&mut current.wakeup) {
+            return false;
+        }
+        current.set_state(State::Blocked);
+        true
+    });
+    if blocked {
+        schedule();
+    }
+}
+
+/// Wake up the thread with the given identifier if it is blocked. If the thread is not blocked,
+/// its next call to [`block`] will return immediately. This can be called from an interrupt
+/// handler.
+pub fn wake(tid: Tid) {
+    x86_64::irq::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(index) = scheduler.blocked.iter().position(|t| t.tid() == tid) {
+            let mut thread = scheduler.blocked.swap_remove(index);
+            thread.set_state(State::Ready);
+            scheduler.ready.push_back(thread);
+            wake_idle();
+            return;
+        }
+
+        // The thread may be about to block on another CPU, or may not be blocked at all
+        let scheduler = &mut *scheduler;
+        let thread = scheduler
+            .cpus
+            .iter_mut()
+            .flat_map(|cpu| cpu.current.iter_mut().chain(cpu.prev.iter_mut()))
+            .chain(scheduler.ready.iter_mut())
+            .chain(scheduler.sleeping.iter_mut())
+            .find(|thread| thread.tid() == tid);
+        if let Some(thread) = thread {
+            if thread.state() == State::Blocked {
+                thread.set_state(State::Running);
+            } else {
+                thread.wakeup = true;
+            }
+        }
+    });
+}
+
+/// Terminate the current thread. Its resources will be freed during the next scheduling.
+pub fn exit() -> ! {
+    x86_64::irq::without(|| {
+        SCHEDULER.lock().current().set_state(State::Exited);
+    });
+    schedule();
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Returns the identifier of the current thread.
+#[must_use]
+pub fn current_tid() -> Tid {
+    x86_64::irq::without(|| SCHEDULER.lock().current().tid())
+}
+
+/// Returns the identifier of the thread running on the given CPU, without locking the scheduler.
+/// The thread may have changed when this function returns, so this should only be used for
+/// diagnostics.
+#[must_use]
+pub fn running_tid(cpu: u32) -> u64 {
+    RUNNING.cpu(cpu).load(Ordering::Relaxed)
+}
+
+/// Returns the identifier of the thread running on the current CPU given to the lock dependency
+/// checker, which must not take any lock. The idle threads all have the same identifier, so they
+/// are told apart by their CPU.
+#[cfg(feature = "lockdep")]
+#[must_use]
+pub fn lockdep_thread() -> Option<u64> {
+    let cpu = arch::smp::try_current_id()?;
+    let tid = running_tid(cpu);
+    if tid == Tid::IDLE.as_u64() {
+        Some(u64::MAX - u64::from(cpu))
+    } else {
+        Some(tid)
+    }
+}
+
+/// Returns the identifier of the process of the current thread.
+#[must_use]
+pub fn current_pid() -> Tid {
+    x86_64::irq::without(|| SCHEDULER.lock().current().pid())
+}
+
+/// Returns the address space of the current thread, or `None` if the current thread is a kernel
+/// thread.
+#[must_use]
+pub fn current_space() -> Option<Arc<AddressSpace>> {
+    x86_64::irq::without(|| SCHEDULER.lock().current().space().cloned())
+}
+
+/// Returns the current load of the system: the load averages and the depth of the ready queue.
+#[must_use]
+pub fn load() -> load::Load {
+    load::snapshot()
+}
+
+/// Returns the load of the given CPU: the thread it runs and the depth of the ready queue it saw
+/// on its last clock tick.
+#[must_use]
+pub fn cpu_load(cpu: u32) -> load::CpuLoad {
+    load::cpu(cpu)
+}
+
+/// Returns the number of threads alive in the system, excluding the idle threads.
+#[must_use]
+pub fn thread_count() -> usize {
+    x86_64::irq::without(|| SCHEDULER.lock().count())
+}
+
+/// Ask the given CPU to give up its current thread and to park itself in its idle thread (see
+/// [`arch::smp::offline`]). The threads of the CPU migrate to the other CPUs, since they all
+/// share the same ready queue.
+pub fn request_park(cpu: u32) {
+    PARKING.cpu(cpu).store(true, Ordering::Relaxed);
+    NEED_RESCHED.cpu(cpu).store(true, Ordering::Relaxed);
+}
+
+/// The idle loop, executed by the idle thread. It executes the ready threads and halts the CPU
+/// when there is nothing to do, or parks it when it is taken offline. When MWAIT is supported, the
+/// halted CPU also monitors its need-resched flag, so that it is woken up as soon as another CPU
+/// makes a thread ready (see [`wake_idle`]) rather than on its next clock tick.
+pub fn idle() -> ! {
+    loop {
+        schedule();
+        if PARKING.swap(false) {
+            arch::smp::park();
+            continue;
+        }
+        WAITING.store(true);
+        arch::idle::wait(NEED_RESCHED.local());
+        WAITING.store(false);
+    }
+}
+
+/// Wake up a CPU waiting in its idle loop, if there is one, so that it runs the thread that has
+/// just been made ready without waiting for its next clock tick. The need-resched flag of the CPU
+/// is set, which is enough to wake it up when its idle loop monitors it with MWAIT: otherwise, a
+/// reschedule IPI is sent to it.
+fn wake_idle() {
+    let current = arch::smp::try_current_id();
+    let active = arch::smp::active_mask();
+    let idle = WAITING.iter().find(|&(cpu, waiting)| {
+        Some(cpu) != current && active.contains(cpu) && waiting.load(Ordering::Relaxed)
+    });
+    if let Some((cpu, waiting)) = idle {
+        waiting.store(false, Ordering::Relaxed);
+        NEED_RESCHED.cpu(cpu).store(true, Ordering::Release);
+        if !arch::idle::mwait() {
+            arch::smp::send_reschedule(cpu);
+        }
+    }
+}
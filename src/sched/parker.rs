@@ -0,0 +1,143 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::time::Duration;
+
+use sync::{ParkResult, SpinlockIrq};
+
+use crate::{
+    arch,
+    sys::time::{
+        self,
+        wheel::{self, Timer},
+    },
+};
+
+use super::thread::Tid;
+
+/// The number of buckets of the parking table. The keys are hashed into the buckets, so that the
+/// threads parked on different primitives rarely contend on the same lock.
+const BUCKETS: usize = 64;
+
+/// The parker of the kernel, registered with the `sync` crate by [`setup`].
+static PARKER: SchedParker = SchedParker;
+
+/// The threads parked on each bucket, in the order they were parked, with the key they are parked
+/// on. Like the scheduler, the buckets are locked with interrupts disabled, because they are
+/// locked by [`sched::wake`](super::wake) callers.
+static TABLE: [SpinlockIrq<VecDeque<(usize, Tid)>>; BUCKETS] =
+    [const { SpinlockIrq::new(VecDeque::new()) }; BUCKETS];
+
+/// Parks the threads with [`super::block`] and unparks them with [`super::wake`].
+struct SchedParker;
+
+impl sync::Parker for SchedParker {
+    fn park(
+        &self,
+        key: usize,
+        validate: &dyn Fn() -> bool,
+        before_sleep: &dyn Fn(),
+        timeout: Option<Duration>,
+    ) -> ParkResult {
+        // A thread cannot sleep in an interrupt handler or with interrupts disabled: it spins
+        // instead, and the primitive checks its condition again
+        if !arch::irq::enabled() || arch::interrupt::in_interrupt() {
+            if !validate() {
+                return ParkResult::Invalid;
+            }
+            before_sleep();
+            core::hint::spin_loop();
+            return ParkResult::Spurious;
+        }
+
+        let tid = super::current_tid();
+        let parked = {
+            let mut bucket = bucket(key).lock();
+            let valid = validate();
+            if valid {
+                bucket.push_back((key, tid));
+            }
+            valid
+        };
+        if !parked {
+            return ParkResult::Invalid;
+        }
+        before_sleep();
+
+        let timer = timeout.map(|timeout| {
+            let deadline = time::jiffies().saturating_add(time::duration_to_jiffies(timeout));
+            let timer = Timer::new(wake_parked, tid.as_u64());
+            wheel::add_timer(&timer, deadline);
+            timer
+        });
+
+        // The thread may be woken up for another reason: it stays parked until it is removed from
+        // its bucket by `unpark_one` or `unpark_all`, or until its timer expires
+        while parked_on(key, tid) {
+            if timer.is_some_and(|timer| !timer.pending()) {
+                // The thread may have been unparked after its timer expired
+                return if unpark(key, tid) {
+                    ParkResult::TimedOut
+                } else {
+                    ParkResult::Unparked
+                };
+            }
+            super::block();
+        }
+        if let Some(timer) = timer {
+            let _ = wheel::del_timer(&timer);
+        }
+        ParkResult::Unparked
+    }
+
+    fn unpark_one(&self, key: usize) -> bool {
+        let thread = {
+            let mut bucket = bucket(key).lock();
+            let index = bucket.iter().position(|&(k, _)| k == key);
+            index
+                .and_then(|index| bucket.remove(index))
+                .map(|(_, tid)| tid)
+        };
+        thread.map(super::wake).is_some()
+    }
+
+    fn unpark_all(&self, key: usize) -> usize {
+        let mut threads = Vec::new();
+        bucket(key).lock().retain(|&(k, tid)| {
+            if k == key {
+                threads.push(tid);
+            }
+            k != key
+        });
+        threads.iter().copied().for_each(super::wake);
+        threads.len()
+    }
+}
+
+/// Register the parker of the kernel, so that the sleeping primitives of the `sync` crate put the
+/// threads to sleep instead of spinning. Must be called once the scheduler is initialized.
+pub fn setup() {
+    sync::parker::register(&PARKER);
+}
+
+/// Returns the bucket in which the threads parked on the given key are stored.
+fn bucket(key: usize) -> &'static SpinlockIrq<VecDeque<(usize, Tid)>> {
+    // The keys are addresses: the low bits are mostly zeros because of the alignment
+    &TABLE[(key >> 4) % BUCKETS]
+}
+
+/// Returns true if the given thread is still parked on the given key.
+fn parked_on(key: usize, tid: Tid) -> bool {
+    bucket(key).lock().contains(&(key, tid))
+}
+
+/// Remove the given thread from the threads parked on the given key. Returns false if it was not
+/// parked on it anymore.
+fn unpark(key: usize, tid: Tid) -> bool {
+    let mut bucket = bucket(key).lock();
+    let index = bucket.iter().position(|&entry| entry == (key, tid));
+    index.and_then(|index| bucket.remove(index)).is_some()
+}
+
+/// The callback of the timer of a thread parked with a timeout.
+fn wake_parked(tid: u64) {
+    super::wake(Tid::from_u64(tid));
+}
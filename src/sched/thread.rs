@@ -0,0 +1,208 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    arch::{context, fpu::FpuState},
+    mm::space::AddressSpace,
+};
+
+/// The size of the kernel stack of each thread. This is quite large, but unoptimized debug builds
+/// use a lot of stack space.
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+
+/// The next thread identifier to allocate. The identifier 0 is reserved for the idle thread.
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+/// A thread identifier. Processes are identified by the identifier of their first thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tid(u64);
+
+impl Tid {
+    /// The identifier of the idle thread.
+    pub const IDLE: Tid = Tid(0);
+
+    /// Allocate a new unique thread identifier.
+    #[must_use]
+    pub fn generate() -> Self {
+        Self(NEXT_TID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the identifier with the given value, as returned by [`Tid::as_u64`].
+    #[must_use]
+    pub(super) const fn from_u64(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    /// The thread is waiting in the run queue to be executed.
+    Ready,
+
+    /// The thread is currently executed by a CPU.
+    Running,
+
+    /// The thread is sleeping until the given tick.
+    Sleeping(u64),
+
+    /// The thread is blocked until another thread or an interrupt handler wakes it up.
+    Blocked,
+
+    /// The thread has exited and is waiting to be destroyed.
+    Exited,
+}
+
+/// The kernel stack of a thread. The stack is allocated on the heap and is entirely touched
+/// when allocated, because the kernel cannot handle a page fault on its own stack with the current
+/// demand paging implementation.
+struct KernelStack {
+    memory: Box<[u128]>,
+}
+
+impl KernelStack {
+    fn new() -> Self {
+        Self {
+            memory: alloc::vec![0; KERNEL_STACK_SIZE / 16].into_boxed_slice(),
+        }
+    }
+
+    /// Returns the top of the stack, aligned on 16 bytes.
+    fn top(&self) -> u64 {
+        self.memory.as_ptr_range().end as u64
+    }
+}
+
+pub struct Thread {
+    tid: Tid,
+    pid: Tid,
+    state: State,
+
+    /// The kernel stack of the thread. This is `None` for the idle thread, which runs on the stack
+    /// provided by the bootloader.
+    kstack: Option<KernelStack>,
+
+    /// The saved stack pointer of the thread when it is not running.
+    pub(super) rsp: u64,
+
+    /// Set if the thread was woken up while it was not blocked. The next attempt to block the
+    /// thread will then return immediately, so that a wake up sent just before the thread blocks
+    /// is not lost.
+    pub(super) wakeup: bool,
+
+    /// Set while the thread sleeps in [`super::sleep_interruptible`]: a signal then wakes it up
+    /// before its deadline.
+    pub(super) interruptible: bool,
+
+    /// Set when a signal is sent to the thread, until it is taken with [`super::take_signal`].
+    pub(super) signal_pending: bool,
+
+    /// The address space of the thread. Kernel threads do not have their own address space and
+    /// use the one of the previous thread instead, because the kernel space is the same in all
+    /// address spaces.
+    space: Option<Arc<AddressSpace>>,
+
+    /// The floating point and SIMD registers of the thread when it is not running. Only user
+    /// threads have them, because the kernel does not use these registers.
+    pub(super) fpu: Option<FpuState>,
+}
+
+impl Thread {
+    /// Create the idle thread for the current CPU. The idle thread represents the execution context
+    /// of the CPU when it booted, and is executed when no other thread is ready to run.
+    #[must_use]
+    pub fn idle() -> Self {
+        Self {
+            tid: Tid::IDLE,
+            pid: Tid::IDLE,
+            state: State::Running,
+            kstack: None,
+            rsp: 0,
+            wakeup: false,
+            interruptible: false,
+            signal_pending: false,
+            space: None,
+            fpu: None,
+        }
+    }
+
+    /// Create a new kernel thread that will execute the given function with the given arguments.
+    #[must_use]
+    pub fn kernel(entry: unsafe extern "C" fn(u64, u64) -> !, arg0: u64, arg1: u64) -> Self {
+        let tid = Tid::generate();
+        let kstack = KernelStack::new();
+        let rsp = unsafe { context::prepare_stack(kstack.top(), entry, arg0, arg1) };
+        Self {
+            tid,
+            pid: tid,
+            state: State::Ready,
+            kstack: Some(kstack),
+            rsp,
+            wakeup: false,
+            interruptible: false,
+            signal_pending: false,
+            space: None,
+            fpu: None,
+        }
+    }
+
+    /// Create a new user thread, that will start in user mode at the given instruction pointer
+    /// and with the given stack pointer in the given address space. The thread is the first
+    /// thread of a new process.
+    #[must_use]
+    pub fn user(space: Arc<AddressSpace>, rip: u64, rsp: u64) -> Self {
+        Self {
+            space: Some(space),
+            fpu: Some(FpuState::new()),
+            ..Self::kernel(context::enter_user, rip, rsp)
+        }
+    }
+
+    #[must_use]
+    pub const fn tid(&self) -> Tid {
+        self.tid
+    }
+
+    #[must_use]
+    pub const fn pid(&self) -> Tid {
+        self.pid
+    }
+
+    #[must_use]
+    pub const fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Returns the top of the kernel stack of the thread, or `None` if the thread is the idle
+    /// thread.
+    #[must_use]
+    pub fn kernel_stack_top(&self) -> Option<u64> {
+        self.kstack.as_ref().map(KernelStack::top)
+    }
+
+    /// Returns the bounds of the kernel stack of the thread, or `None` if the thread is the idle
+    /// thread.
+    #[must_use]
+    pub fn kernel_stack(&self) -> Option<Range<u64>> {
+        self.kstack
+            .as_ref()
+            .map(|kstack| kstack.top() - KERNEL_STACK_SIZE as u64..kstack.top())
+    }
+
+    #[must_use]
+    pub fn space(&self) -> Option<&Arc<AddressSpace>> {
+        self.space.as_ref()
+    }
+}
@@ -0,0 +1,229 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::{vec, vec::Vec};
+use log::{error, info};
+
+use crate::{
+    arch::{address::phys_to_virt, ioapic, smp},
+    config::KERNEL_HZ,
+    drivers::block::{self, BlockDevice, BlockError, Disk, Operation, Request},
+    mm::{
+        frame::{AllocationFlags, Allocator},
+        FRAME_ALLOCATOR,
+    },
+    sched::{self, thread::Thread},
+    sys::{
+        qemu::{self, ExitCode},
+        time,
+    },
+    Spinlock, LIMINE_SMP,
+};
+use x86_64::paging::PAGE_SIZE;
+
+/// The number of clock ticks the timer test sleeps.
+const SLEEP_TICKS: u64 = KERNEL_HZ / 10;
+
+/// The maximum number of clock ticks a test may wait for another thread.
+const TIMEOUT_TICKS: u64 = KERNEL_HZ;
+
+/// The size of the heap allocation of the heap test.
+const HEAP_TEST_SIZE: usize = 1024 * 1024;
+
+/// The number of requests queued at once by the block test.
+const BLOCK_TEST_REQUESTS: u64 = 3;
+
+/// The size of a block of the device of the block test.
+const BLOCK_TEST_SIZE: usize = 512;
+
+/// Set by the thread spawned by the scheduler test.
+static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+/// The device of the block test.
+static DEFERRED: DeferredDevice = DeferredDevice {
+    started: Spinlock::new(Vec::new()),
+};
+
+/// The number of requests of the block test completed in order.
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+
+/// A self test, returning an error message if it fails.
+type Test = fn() -> Result<(), &'static str>;
+
+const TESTS: [(&str, Test); 6] = [
+    ("cpus", cpus),
+    ("frames", frames),
+    ("heap", heap),
+    ("clock", clock),
+    ("scheduler", scheduler),
+    ("block", block),
+];
+
+/// Start the self tests in a kernel thread. They replace the init process when the kernel is built
+/// with the `selftest` feature, and QEMU exits with a failure status if a test fails.
+///
+/// The results are written on the serial port, one per line, in a format meant to be parsed by
+/// `cargo xtask test-matrix`: `selftest: name=<test> result=<pass|fail>`, followed by a line
+/// describing the machine (`selftest: cpus=<count> ioapic=<bool>`) and a final
+/// `selftest: result=<pass|fail>` line.
+pub fn spawn() {
+    sched::spawn(Thread::kernel(runner, 0, 0));
+}
+
+/// The entry point of the self test thread.
+unsafe extern "C" fn runner(_: u64, _: u64) -> ! {
+    let mut success = true;
+    for (name, test) in TESTS {
+        match test() {
+            Ok(()) => info!("selftest: name={name} result=pass"),
+            Err(reason) => {
+                error!("selftest: name={name} result=fail reason=\"{reason}\"");
+                success = false;
+            }
+        }
+    }
+
+    info!(
+        "selftest: cpus={} ioapic={}",
+        smp::present_mask().count(),
+        ioapic::enabled()
+    );
+    info!("selftest: result={}", if success { "pass" } else { "fail" });
+    let code = if success {
+        ExitCode::Success
+    } else {
+        ExitCode::Failure
+    };
+    qemu::exit(code.into());
+}
+
+/// Check that all the CPUs reported by the bootloader have started.
+fn cpus() -> Result<(), &'static str> {
+    let expected = LIMINE_SMP.get_response().get_mut().unwrap().cpus().len();
+    if smp::present_mask().count() as usize != expected {
+        return Err("some CPUs did not start");
+    }
+    Ok(())
+}
+
+/// Check that a zeroed frame can be allocated and freed.
+fn frames() -> Result<(), &'static str> {
+    let frame = x86_64::irq::without(|| unsafe {
+        FRAME_ALLOCATOR
+            .lock()
+            .allocate(AllocationFlags::KERNEL | AllocationFlags::ZEROED)
+    })
+    .ok_or("frame allocation failed")?;
+
+    let content = unsafe {
+        core::slice::from_raw_parts(phys_to_virt(frame.start()).as_ptr::<u8>(), PAGE_SIZE)
+    };
+    let zeroed = content.iter().all(|&byte| byte == 0);
+    x86_64::irq::without(|| unsafe { FRAME_ALLOCATOR.lock().deallocate(frame) });
+
+    if !zeroed {
+        return Err("allocated frame is not zeroed");
+    }
+    Ok(())
+}
+
+/// Check that a large heap allocation can be written and read back.
+fn heap() -> Result<(), &'static str> {
+    let buffer: Vec<u8> = (0..HEAP_TEST_SIZE).map(|i| i.to_le_bytes()[0]).collect();
+    if !buffer
+        .iter()
+        .enumerate()
+        .all(|(i, &b)| b == i.to_le_bytes()[0])
+    {
+        return Err("heap allocation corrupted");
+    }
+    Ok(())
+}
+
+/// Check that the clock ticks while sleeping.
+fn clock() -> Result<(), &'static str> {
+    let start = time::jiffies();
+    sched::sleep(SLEEP_TICKS);
+    if time::jiffies() < start + SLEEP_TICKS {
+        return Err("woke up before the deadline");
+    }
+    Ok(())
+}
+
+/// Check that a spawned kernel thread runs.
+fn scheduler() -> Result<(), &'static str> {
+    sched::spawn(Thread::kernel(spawned, 0, 0));
+    let deadline = time::jiffies() + TIMEOUT_TICKS;
+    while !SPAWNED.load(Ordering::Relaxed) {
+        if time::jiffies() > deadline {
+            return Err("spawned thread did not run");
+        }
+        sched::sleep(1);
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn spawned(_: u64, _: u64) -> ! {
+    SPAWNED.store(true, Ordering::Relaxed);
+    sched::exit();
+}
+
+/// A block device that does not complete its requests when they are started, like a device
+/// completing them later from its interrupt handler. The block test completes them itself.
+struct DeferredDevice {
+    started: Spinlock<Vec<Request>>,
+}
+
+impl BlockDevice for DeferredDevice {
+    fn name(&self) -> &'static str {
+        "selftest"
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_TEST_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        BLOCK_TEST_REQUESTS
+    }
+
+    fn start(&self, _: &'static Disk, request: Request) {
+        x86_64::irq::without(|| self.started.lock().push(request));
+    }
+}
+
+/// Check that the requests queued on a busy device are started one at a time and in order, once
+/// the request in flight completes.
+fn block() -> Result<(), &'static str> {
+    let disk = block::register(&DEFERRED).map_err(|_| "device registration failed")?;
+    for data in 0..BLOCK_TEST_REQUESTS {
+        disk.submit(Request {
+            operation: Operation::Read,
+            block: data,
+            count: 1,
+            buffer: vec![0; BLOCK_TEST_SIZE],
+            completion: deferred_complete,
+            data,
+        });
+    }
+
+    for data in 0..BLOCK_TEST_REQUESTS {
+        let mut started = x86_64::irq::without(|| core::mem::take(&mut *DEFERRED.started.lock()));
+        match started.len() {
+            0 => return Err("queued request not started"),
+            1 => {}
+            _ => return Err("requests started while the device is busy"),
+        }
+        disk.complete(started.pop().unwrap(), Ok(()));
+        if COMPLETED.load(Ordering::Relaxed) != data + 1 {
+            return Err("request not completed in order");
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn deferred_complete(request: Request, result: Result<(), BlockError>) {
+    if result.is_ok() && COMPLETED.load(Ordering::Relaxed) == request.data {
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+    }
+}
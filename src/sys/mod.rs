@@ -0,0 +1,3 @@
+pub mod power;
+pub mod qemu;
+pub mod time;
@@ -0,0 +1,169 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::{
+    acpi::{self, FixedHardware, ResetRegister},
+    port::{Port, WriteOnlyPort},
+};
+
+/// The number of iterations waited after a reset method, to give it the time to take effect
+/// before the next method is tried.
+const RESET_DELAY_LOOPS: usize = 10_000_000;
+
+/// The command and status port of the 8042 keyboard controller, the status bit set while it has
+/// not consumed the last byte written to it, and the command pulsing the CPU reset line.
+const I8042_COMMAND: Port<u8> = Port::new(0x64);
+const I8042_INPUT_FULL: u8 = 1 << 1;
+const I8042_RESET: u8 = 0xFE;
+
+/// The ports of the PCI configuration mechanism #1, used for a reset register in the PCI
+/// configuration space. They are accessed directly, without the lock of the PCI driver, because
+/// the system may be rebooted from a panic.
+const PCI_CONFIG_ADDRESS: Port<u32> = Port::new(0xCF8);
+const PCI_CONFIG_DATA: Port<u32> = Port::new(0xCFC);
+
+/// The sleep type field and the sleep enable bit of the PM1 control registers.
+const PM1_CONTROL_SLEEP_TYPE: u16 = 0x07 << PM1_CONTROL_SLEEP_TYPE_SHIFT;
+const PM1_CONTROL_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_CONTROL_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// The ports and values powering off the usual emulators, when ACPI is not usable: QEMU, Bochs and
+/// older versions of QEMU, and `VirtualBox`. Writing to them has no effect on real hardware.
+const EMULATOR_POWER_OFF: [(u16, u16); 3] = [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+/// Set once the shutdown of the system has started.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Shut the system down cleanly and power it off: the block request queues are frozen so that no
+/// write is started after this point, then the system is put in the ACPI S5 state (soft off). If
+/// this fails, the power off ports of the usual emulators are tried, and the CPU is halted if the
+/// system is still running. This must be called in the context of a thread. If the shutdown has
+/// already started, the calling thread is blocked forever instead.
+pub fn shutdown() -> ! {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        loop {
+            crate::sched::block();
+        }
+    }
+
+    log::info!("Shutting down the system");
+    crate::drivers::block::freeze();
+    unsafe {
+        core::arch::asm!("cli", options(nomem, nostack));
+    }
+
+    if let Some(hardware) = acpi::fadt() {
+        if unsafe { acpi_power_off(hardware) } {
+            settle();
+            log::warn!("ACPI power off failed, trying the emulator ports");
+        }
+    }
+
+    for (port, value) in EMULATOR_POWER_OFF {
+        unsafe { WriteOnlyPort::new(port).write(value) };
+    }
+    settle();
+
+    log::info!("It is now safe to turn off the computer");
+    x86_64::cpu::freeze();
+}
+
+/// Reboot the system. The ACPI reset register is tried first, then the reset line pulsed by the
+/// 8042 keyboard controller, and if both failed, a triple fault is forced. This does not allocate
+/// memory, and the only locks it takes are those of the log sinks, which the panic handler already
+/// uses before calling it, so it can be called when the kernel panics.
+pub fn reboot() -> ! {
+    crate::drivers::block::freeze();
+    log::info!("Rebooting the system");
+    unsafe {
+        core::arch::asm!("cli", options(nomem, nostack));
+    }
+
+    if let Some(hardware) = acpi::fadt() {
+        if let Some(register) = hardware.reset_register {
+            unsafe { acpi_reset(register, hardware.reset_value) };
+            settle();
+            log::warn!("ACPI reset failed, trying the keyboard controller");
+        }
+    }
+
+    unsafe { i8042_reset() };
+    settle();
+    log::warn!("Keyboard controller reset failed, forcing a triple fault");
+
+    unsafe { triple_fault() }
+}
+
+/// Put the system in the S5 state, by writing its sleep type and the sleep enable bit to the PM1
+/// control registers. Returns false if ACPI cannot power off the system.
+unsafe fn acpi_power_off(hardware: &FixedHardware) -> bool {
+    let (Some(control), Some((sleep_type_a, sleep_type_b))) =
+        (hardware.pm1a_control, hardware.s5_sleep_type)
+    else {
+        return false;
+    };
+
+    let sleep = |port: u16, sleep_type: u8| {
+        let port = Port::<u16>::new(port);
+        let value = port.read() & !PM1_CONTROL_SLEEP_TYPE;
+        let sleep_type = u16::from(sleep_type & 0x07) << PM1_CONTROL_SLEEP_TYPE_SHIFT;
+        port.write(value | sleep_type | PM1_CONTROL_SLEEP_ENABLE);
+    };
+    sleep(control, sleep_type_a);
+    if let Some(control) = hardware.pm1b_control {
+        sleep(control, sleep_type_b);
+    }
+    true
+}
+
+/// Write the reset value to the ACPI reset register.
+unsafe fn acpi_reset(register: ResetRegister, value: u8) {
+    match register {
+        ResetRegister::Io(port) => WriteOnlyPort::new(port).write(value),
+        ResetRegister::Memory(address) => core::ptr::write_volatile(address as *mut u8, value),
+        ResetRegister::PciConfig {
+            device,
+            function,
+            offset,
+        } => {
+            let address = 1 << 31
+                | u32::from(device & 0x1F) << 11
+                | u32::from(function & 0x07) << 8
+                | u32::from(offset & 0xFC);
+            let shift = (offset & 3) * 8;
+            PCI_CONFIG_ADDRESS.write(address);
+            let old = PCI_CONFIG_DATA.read() & !(0xFF << shift);
+            PCI_CONFIG_DATA.write(old | u32::from(value) << shift);
+        }
+    }
+}
+
+/// Ask the 8042 keyboard controller to pulse the CPU reset line, once it is ready to accept a
+/// command. The controller may be missing, so the wait is bounded.
+unsafe fn i8042_reset() {
+    for _ in 0..RESET_DELAY_LOOPS {
+        if I8042_COMMAND.read() & I8042_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    I8042_COMMAND.write(I8042_RESET);
+}
+
+/// Load an empty IDT and raise an exception: the CPU cannot deliver it nor the resulting double
+/// fault, and resets itself.
+unsafe fn triple_fault() -> ! {
+    let idtr = [0u16; 5];
+    core::arch::asm!(
+        "lidt [{}]",
+        "int3",
+        in(reg) idtr.as_ptr(),
+        options(noreturn)
+    );
+}
+
+/// Wait a little for a reset to take effect.
+fn settle() {
+    for _ in 0..RESET_DELAY_LOOPS {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,34 @@
+//! Support for the QEMU `isa-debug-exit` device, which lets the kernel exit QEMU with a status
+//! chosen by the kernel. This is used by the test harnesses running under QEMU to report their
+//! result to the host process.
+use crate::arch::port::WriteOnlyPort;
+
+/// The I/O port of the QEMU `isa-debug-exit` device. QEMU must be started with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04` for this device to exist.
+const DEBUG_EXIT: WriteOnlyPort<u32> = WriteOnlyPort::new(0xF4);
+
+/// The codes used by the test harnesses to report their result. QEMU exits with the status
+/// `(code << 1) | 1`, so a success is reported as 33 and a failure as 35 to be distinguishable
+/// from QEMU own errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+impl From<ExitCode> for u32 {
+    fn from(code: ExitCode) -> Self {
+        code as u32
+    }
+}
+
+/// Exit QEMU with the given code: the QEMU process exits with the status `(code << 1) | 1`, and
+/// only the low 8 bits of this status are seen by the host. If the kernel is not running under
+/// QEMU or if the exit device is missing, the CPU is frozen instead.
+pub fn exit(code: u32) -> ! {
+    unsafe {
+        DEBUG_EXIT.write(code);
+    }
+    x86_64::cpu::freeze();
+}
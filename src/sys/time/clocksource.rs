@@ -0,0 +1,211 @@
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+use sync::Once;
+
+use crate::Spinlock;
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A free-running hardware counter used to measure the time, such as the TSC or the HPET main
+/// counter. The backends are registered with [`register`], and the best one is selected
+/// automatically to provide the time since the boot (see [`nanoseconds`]).
+pub trait ClockSource: Sync {
+    /// A short name of the clock source, used in the logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns the current value of the counter. Only the bits of [`ClockSource::mask`] are
+    /// significant, and the counter must increase monotonically until it wraps around.
+    fn read(&self) -> u64;
+
+    /// Returns the frequency of the counter, in Hz.
+    fn frequency(&self) -> u64;
+
+    /// Returns the quality of the clock source: the source with the highest rating is selected.
+    /// As a guideline, 300 is a precise and cheap to read counter, 200 a precise but slow to read
+    /// one, and 100 a counter that is only usable as a last resort.
+    fn rating(&self) -> u32;
+
+    /// Returns the mask of the significant bits of the counter. The counter wraps around at this
+    /// value, and must be read at least once per wrap around: this is done on each clock tick (see
+    /// [`update`]).
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+/// The maximum number of clock sources that can be registered.
+const MAX_SOURCES: usize = 8;
+
+/// The registered clock sources. A source is never unregistered.
+static SOURCES: [Once<&'static dyn ClockSource>; MAX_SOURCES] =
+    [const { Once::new() }; MAX_SOURCES];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The index of the selected clock source in [`SOURCES`], or [`NONE`] if no source is registered.
+static SELECTED: AtomicUsize = AtomicUsize::new(NONE);
+const NONE: usize = usize::MAX;
+
+/// The value of the counter of the selected source at the last update.
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/// The number of counter cycles elapsed between the selection of the source and the last update.
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// The time at which the source was selected, in nanoseconds since the boot.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The state of the selected source is protected by a sequence counter, so that it can be read
+/// from any context without taking a lock, even from an interrupt handler that interrupted an
+/// update: the counter is odd while the state is updated, and readers retry if it changed while
+/// they were reading. The writers are serialized by [`WRITER`].
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+static WRITER: Spinlock<()> = Spinlock::new(());
+
+/// A consistent copy of the state of the selected clock source.
+struct Snapshot {
+    source: &'static dyn ClockSource,
+    last: u64,
+    cycles: u64,
+    offset: u64,
+}
+
+impl Snapshot {
+    fn elapsed(&self) -> u64 {
+        self.source.read().wrapping_sub(self.last) & self.source.mask()
+    }
+
+    fn nanoseconds(&self) -> u64 {
+        let cycles = u128::from(self.cycles + self.elapsed());
+        let elapsed = cycles * NANOSECONDS_PER_SECOND / u128::from(self.source.frequency());
+        self.offset + u64::try_from(elapsed).unwrap_or(u64::MAX)
+    }
+}
+
+/// Register a clock source. If its rating is higher than the rating of the selected clock source,
+/// it becomes the selected one: the time continues from the value given by the previous source
+/// (or by the clock ticks for the first source), so it never goes backwards.
+///
+/// # Panics
+/// Panics if the frequency of the clock source is 0, or if too many clock sources are registered.
+pub fn register(source: &'static dyn ClockSource) {
+    assert!(
+        source.frequency() > 0,
+        "Clock source {} has a null frequency",
+        source.name()
+    );
+    let index = COUNT.fetch_add(1, Ordering::Relaxed);
+    assert!(index < MAX_SOURCES, "Too many clock sources");
+    SOURCES[index].call_once(|| source);
+    log::info!(
+        "Clock source {}: {} Hz, rating {}",
+        source.name(),
+        source.frequency(),
+        source.rating()
+    );
+
+    let previous = write(|| {
+        let (previous, offset) = match load() {
+            Some(selected) if selected.source.rating() >= source.rating() => return None,
+            Some(selected) => (Some(selected.source.name()), selected.nanoseconds()),
+            None => (None, super::tick_nanoseconds()),
+        };
+        SELECTED.store(index, Ordering::Relaxed);
+        LAST.store(source.read(), Ordering::Relaxed);
+        CYCLES.store(0, Ordering::Relaxed);
+        OFFSET.store(offset, Ordering::Relaxed);
+        previous
+    });
+
+    // The logger reads the time, so it cannot be used while the state is updated
+    if let Some(previous) = previous {
+        log::info!("Switched clock source from {previous} to {}", source.name());
+    }
+}
+
+/// Returns the name of the selected clock source, or `None` if no clock source is registered.
+#[must_use]
+pub fn current() -> Option<&'static str> {
+    snapshot().map(|selected| selected.source.name())
+}
+
+/// Returns the names of all the registered clock sources.
+#[must_use]
+pub fn sources() -> Vec<&'static str> {
+    SOURCES
+        .iter()
+        .filter_map(Once::get)
+        .map(|source| source.name())
+        .collect()
+}
+
+/// Returns the number of nanoseconds elapsed since the boot, measured with the selected clock
+/// source, or `None` if no clock source is registered yet. This function never blocks and can be
+/// used from any context.
+#[must_use]
+pub fn nanoseconds() -> Option<u64> {
+    snapshot().map(|selected| selected.nanoseconds())
+}
+
+/// Accumulate the cycles elapsed since the last update, so that the counter of the selected clock
+/// source can wrap around without losing time. This is called by the BSP on each clock tick.
+pub fn update() {
+    write(|| {
+        if let Some(selected) = load() {
+            let elapsed = selected.elapsed();
+            LAST.store(
+                selected.last.wrapping_add(elapsed) & selected.source.mask(),
+                Ordering::Relaxed,
+            );
+            CYCLES.store(selected.cycles + elapsed, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Read a consistent copy of the state of the selected clock source, or `None` if no clock source
+/// is registered yet.
+fn snapshot() -> Option<Snapshot> {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        if sequence & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+
+        let snapshot = load();
+        fence(Ordering::Acquire);
+        if SEQUENCE.load(Ordering::Relaxed) == sequence {
+            return snapshot;
+        }
+    }
+}
+
+/// Read the state of the selected clock source without checking the sequence counter. The result
+/// is only consistent when called by a writer (see [`write`]), or if the sequence counter did not
+/// change during the read.
+fn load() -> Option<Snapshot> {
+    let source = *SOURCES
+        .get(SELECTED.load(Ordering::Relaxed))
+        .and_then(Once::get)?;
+    Some(Snapshot {
+        source,
+        last: LAST.load(Ordering::Relaxed),
+        cycles: CYCLES.load(Ordering::Relaxed),
+        offset: OFFSET.load(Ordering::Relaxed),
+    })
+}
+
+/// Update the state of the selected clock source with the given closure, making the sequence
+/// counter odd during the update. The closure must not read the time, otherwise it would wait
+/// forever for the end of the update.
+fn write<T>(f: impl FnOnce() -> T) -> T {
+    x86_64::irq::without(|| {
+        let _guard = WRITER.lock();
+        SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let result = f();
+        fence(Ordering::Release);
+        SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        result
+    })
+}
@@ -0,0 +1,125 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{smp, timer},
+    Spinlock,
+};
+
+use super::Instant;
+
+/// The function called when a high-resolution timer expires, with the data given to
+/// [`HrTimer::start`]. It is called from the clock interrupt handler of the CPU that started the
+/// timer, with interrupts disabled, so it must be short and must not sleep.
+pub type Callback = fn(data: u64);
+
+/// A pending timer in the queue of a CPU.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    id: u64,
+    deadline: Instant,
+    callback: Callback,
+    data: u64,
+}
+
+crate::per_cpu! {
+    /// The pending timers of each CPU, sorted by deadline.
+    static QUEUES: Spinlock<Vec<Entry>> = Spinlock::new(Vec::new());
+}
+
+/// The identifier of the next timer, used to cancel a timer.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A high-resolution timer, started with [`HrTimer::start`]. Unlike the clock tick, which has the
+/// resolution of [`crate::config::KERNEL_HZ`], the expiration of a high-resolution timer is
+/// programmed directly in the timer of the CPU, so it has a resolution of a few microseconds.
+/// Timeouts that do not need this precision should use a [`super::wheel::Timer`] instead, which
+/// does not reprogram the timer of the CPU.
+///
+/// Dropping the handle does not cancel the timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[must_use]
+pub struct HrTimer {
+    id: u64,
+    cpu: u32,
+}
+
+impl HrTimer {
+    /// Start a timer on the current CPU, which calls `callback` with `data` once the monotonic
+    /// clock reaches `deadline`. If the deadline is already passed, the callback is called on the
+    /// next timer interrupt.
+    pub fn start(deadline: Instant, callback: Callback, data: u64) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        x86_64::irq::without(|| {
+            let cpu = smp::current_id();
+            let first = {
+                let mut queue = QUEUES.cpu(cpu).lock();
+                let index = queue.partition_point(|entry| entry.deadline <= deadline);
+                queue.insert(
+                    index,
+                    Entry {
+                        id,
+                        deadline,
+                        callback,
+                        data,
+                    },
+                );
+                index == 0
+            };
+
+            // The timer expires before the event programmed on this CPU
+            if first {
+                timer::program_next_event();
+            }
+            Self { id, cpu }
+        })
+    }
+
+    /// Cancel the timer. Returns true if the timer was pending, or false if it has already expired
+    /// (its callback may be running on another CPU) or was already cancelled.
+    #[must_use]
+    pub fn cancel(self) -> bool {
+        x86_64::irq::without(|| {
+            let mut queue = QUEUES.cpu(self.cpu).lock();
+            let index = queue.iter().position(|entry| entry.id == self.id);
+            index.map(|index| queue.remove(index)).is_some()
+        })
+    }
+
+    /// Returns true if the timer is still pending.
+    #[must_use]
+    pub fn pending(self) -> bool {
+        x86_64::irq::without(|| {
+            QUEUES
+                .cpu(self.cpu)
+                .lock()
+                .iter()
+                .any(|entry| entry.id == self.id)
+        })
+    }
+}
+
+/// Returns the deadline of the first pending timer of the current CPU, if any.
+#[must_use]
+pub fn next_deadline() -> Option<Instant> {
+    x86_64::irq::without(|| QUEUES.local().lock().first().map(|entry| entry.deadline))
+}
+
+/// Run the callbacks of the expired timers of the current CPU. This is called by the clock
+/// interrupt handler of each CPU (see [`timer::tick`]), with interrupts disabled. The lock of the
+/// queue is not held while a callback runs, so callbacks can start new timers.
+pub fn expire() {
+    let queue = QUEUES.local();
+    loop {
+        let now = Instant::now();
+        let entry = {
+            let mut queue = queue.lock();
+            match queue.first() {
+                Some(entry) if entry.deadline <= now => queue.remove(0),
+                _ => break,
+            }
+        };
+        (entry.callback)(entry.data);
+    }
+}
@@ -0,0 +1,117 @@
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+/// A point in time measured with the monotonic clock, which starts at 0 when the kernel boots and
+/// never goes backwards. It is only meaningful inside the kernel and across CPUs, and is backed by
+/// the selected clock source (see [`super::clocksource`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The time at which the kernel booted.
+    pub const BOOT: Self = Self(0);
+
+    /// Returns the current time. This function never blocks and can be used from any context,
+    /// including interrupt handlers.
+    #[must_use]
+    pub fn now() -> Self {
+        Self(super::nanoseconds())
+    }
+
+    /// Create an instant from a number of nanoseconds since the boot.
+    #[must_use]
+    pub const fn from_nanos(nanoseconds: u64) -> Self {
+        Self(nanoseconds)
+    }
+
+    /// Returns the number of nanoseconds elapsed between the boot and this instant.
+    #[must_use]
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time elapsed since this instant.
+    #[must_use]
+    pub fn elapsed(self) -> Duration {
+        Self::now().saturating_duration_since(self)
+    }
+
+    /// Returns the time elapsed from `earlier` to this instant, or `None` if `earlier` is later
+    /// than this instant.
+    #[must_use]
+    pub fn checked_duration_since(self, earlier: Self) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// Returns the time elapsed from `earlier` to this instant, or zero if `earlier` is later than
+    /// this instant.
+    #[must_use]
+    pub fn saturating_duration_since(self, earlier: Self) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Returns the instant `duration` after this one, or `None` if it cannot be represented.
+    #[must_use]
+    pub fn checked_add(self, duration: Duration) -> Option<Self> {
+        let nanoseconds = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanoseconds).map(Self)
+    }
+
+    /// Returns the instant `duration` before this one, or `None` if it is before the boot.
+    #[must_use]
+    pub fn checked_sub(self, duration: Duration) -> Option<Self> {
+        let nanoseconds = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanoseconds).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    /// # Panics
+    /// Panics if the result cannot be represented.
+    fn add(self, duration: Duration) -> Self {
+        self.checked_add(duration)
+            .expect("Overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Self;
+
+    /// # Panics
+    /// Panics if the result is before the boot.
+    fn sub(self, duration: Duration) -> Self {
+        self.checked_sub(duration)
+            .expect("Overflow when subtracting a duration from an instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// Returns the time elapsed from `earlier` to this instant, or zero if `earlier` is later.
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+/// Returns the time elapsed since the boot.
+#[must_use]
+pub fn uptime() -> Duration {
+    Instant::now().saturating_duration_since(Instant::BOOT)
+}
@@ -0,0 +1,114 @@
+pub mod clocksource;
+pub mod hrtimer;
+pub mod instant;
+pub mod vdso;
+pub mod wheel;
+
+pub use clocksource::ClockSource;
+pub use hrtimer::HrTimer;
+pub use instant::{uptime, Instant};
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{arch::smp, config::KERNEL_HZ, drivers::rtc};
+
+const NANOSECONDS_PER_TICK: u64 = 1_000_000_000 / KERNEL_HZ;
+
+/// The CPU keeping the time: the jiffies only advance on its clock ticks, even though all the CPUs
+/// receive their own clock tick.
+const TIMEKEEPER: u32 = 0;
+
+/// The number of clock ticks of the timekeeper since the boot (see [`jiffies`]).
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+
+/// The wall-clock time at the boot, in nanoseconds since the Unix epoch. The wall-clock time is
+/// derived from it and from the monotonic clock, so it advances with the clock ticks.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Initialize the time subsystem. The time page is allocated before the clock sources provided by
+/// the hardware are registered, so that they can fill it, and the best clock source is selected.
+/// The wall-clock time is then read from the RTC.
+pub fn setup() {
+    vdso::setup();
+    crate::arch::clocksource::register();
+
+    match rtc::read() {
+        Some(date) => {
+            let boot = date.unix_time().saturating_sub(uptime());
+            BOOT_TIME.store(
+                u64::try_from(boot.as_nanos()).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
+            log::info!(
+                "Wall-clock time: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                date.year,
+                date.month,
+                date.day,
+                date.hours,
+                date.minutes,
+                date.seconds
+            );
+        }
+        None => log::warn!("Failed to read the RTC, the wall-clock time starts at the epoch"),
+    }
+}
+
+/// Returns the wall-clock time, as the time elapsed since the Unix epoch (1970-01-01 00:00:00
+/// UTC). Unlike the monotonic clock (see [`Instant`]), it should only be used to timestamp events
+/// for the user, because it could jump if the wall-clock time is changed.
+#[must_use]
+pub fn now() -> Duration {
+    Duration::from_nanos(BOOT_TIME.load(Ordering::Relaxed)) + uptime()
+}
+
+/// Called on each clock tick of each CPU. On the timekeeper, the jiffies are advanced and the time
+/// is updated. Returns true if the jiffies were advanced, which the caller must pass to the
+/// scheduler tick (see [`crate::sched::tick`]).
+#[must_use]
+pub fn tick() -> bool {
+    if smp::current_id() != TIMEKEEPER {
+        return false;
+    }
+    let jiffies = JIFFIES.fetch_add(1, Ordering::Relaxed) + 1;
+    clocksource::update();
+    vdso::update(jiffies);
+    true
+}
+
+/// Returns the number of clock ticks elapsed since the boot, which are called jiffies. The jiffies
+/// advance at [`KERNEL_HZ`] Hz and never go backwards, but they only have the resolution of a clock
+/// tick: [`Instant`] should be used to measure short durations.
+#[must_use]
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+/// Convert a number of jiffies to a duration.
+#[must_use]
+pub const fn jiffies_to_duration(jiffies: u64) -> Duration {
+    Duration::from_nanos(jiffies.saturating_mul(NANOSECONDS_PER_TICK))
+}
+
+/// Convert a duration to a number of jiffies, rounded up so that a timeout never expires early.
+#[must_use]
+pub fn duration_to_jiffies(duration: Duration) -> u64 {
+    let jiffies = duration
+        .as_nanos()
+        .div_ceil(u128::from(NANOSECONDS_PER_TICK));
+    u64::try_from(jiffies).unwrap_or(u64::MAX)
+}
+
+/// Returns the number of nanoseconds elapsed since the boot. If no clock source is registered yet,
+/// the time is derived from the number of clock ticks, with the resolution of a tick.
+#[must_use]
+pub fn nanoseconds() -> u64 {
+    clocksource::nanoseconds().unwrap_or_else(tick_nanoseconds)
+}
+
+/// Returns the time elapsed since the boot measured with the clock ticks, in nanoseconds.
+fn tick_nanoseconds() -> u64 {
+    jiffies() * NANOSECONDS_PER_TICK
+}
@@ -0,0 +1,130 @@
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+use alloc::sync::Arc;
+use sync::Once;
+use x86_64::paging::PAGE_SIZE;
+
+use crate::{
+    arch::address::phys_to_virt,
+    config::KERNEL_HZ,
+    mm::{
+        frame::{AllocationFlags, Allocator, Frame},
+        space::{Backing, Pager},
+        FRAME_ALLOCATOR, USER_MAP_END,
+    },
+};
+
+/// The address where the time page is mapped in every user address space. This is the last page
+/// of the user space that can be mapped.
+pub const TIME_PAGE_ADDR: u64 = USER_MAP_END - PAGE_SIZE as u64;
+
+const NANOSECONDS_PER_TICK: u64 = 1_000_000_000 / KERNEL_HZ;
+
+/// The content of the time page, read by the user space to get the time without a system call.
+///
+/// The page is protected by a sequence counter: the kernel increments it before and after each
+/// update, so it is odd while the page is being updated. A reader must read the counter, read
+/// the data, and read the counter again: if the two values differ or are odd, the read must be
+/// retried.
+///
+/// The current monotonic time in nanoseconds is `monotonic + ((rdtsc() - tsc) * mult) >> shift`.
+/// If `mult` is 0, the TSC is not usable and `monotonic` is only updated on each clock tick.
+#[repr(C)]
+pub struct TimeData {
+    pub sequence: AtomicU32,
+    pub version: u32,
+    pub hz: u64,
+    pub ticks: u64,
+    pub monotonic: u64,
+    pub tsc: u64,
+    pub mult: u32,
+    pub shift: u32,
+}
+
+/// The layout version of [`TimeData`], incremented each time the layout changes.
+const VERSION: u32 = 1;
+
+/// The frame containing the time page. A reference on the frame is kept forever by the kernel.
+static PAGE: Once<Frame> = Once::new();
+
+/// The object backing the mappings of the time page.
+struct TimePager;
+
+impl Pager for TimePager {
+    fn page(&self, offset: u64) -> Option<Frame> {
+        let frame = *PAGE.get()?;
+        if offset != 0 {
+            return None;
+        }
+        x86_64::irq::without(|| unsafe { FRAME_ALLOCATOR.lock().reference(frame) });
+        Some(frame)
+    }
+
+    fn writeback(&self, _: u64, _: Frame) {
+        unreachable!("The time page is mapped read-only");
+    }
+}
+
+/// Allocate and initialize the time page.
+pub fn setup() {
+    PAGE.call_once(|| {
+        let frame = x86_64::irq::without(|| unsafe {
+            FRAME_ALLOCATOR
+                .lock()
+                .allocate(AllocationFlags::KERNEL | AllocationFlags::ZEROED)
+                .expect("Failed to allocate the time page")
+        });
+        let data = unsafe { &mut *phys_to_virt(frame.start()).as_mut_ptr::<TimeData>() };
+        data.version = VERSION;
+        data.hz = KERNEL_HZ;
+        frame
+    });
+}
+
+/// Returns the backing object used to map the time page in an user address space, or `None` if
+/// the time page is not initialized yet.
+#[must_use]
+pub fn backing() -> Option<Backing> {
+    PAGE.get()?;
+    Some(Backing {
+        pager: Arc::new(TimePager),
+        offset: 0,
+    })
+}
+
+/// Set the multiplier and the shift used to convert TSC cycles to nanoseconds. This is called when
+/// the TSC is registered as a clock source, if it is invariant and synchronized across the CPUs
+/// (see [`crate::arch::clocksource::register`]).
+pub fn set_tsc_conversion(mult: u32, shift: u32) {
+    write(|data| {
+        data.mult = mult;
+        data.shift = shift;
+    });
+}
+
+/// Update the time page. This function is called by the BSP on each clock tick.
+pub fn update(ticks: u64) {
+    write(|data| {
+        data.ticks = ticks;
+        data.monotonic = ticks * NANOSECONDS_PER_TICK;
+        data.tsc = crate::arch::tsc::rdtsc();
+    });
+}
+
+/// Update the time page with the given closure, incrementing the sequence counter before and
+/// after the update.
+fn write(f: impl FnOnce(&mut TimeData)) {
+    let Some(frame) = PAGE.get() else {
+        return;
+    };
+
+    // Only the BSP updates the page, with interrupts disabled, so there is no concurrent writer
+    x86_64::irq::without(|| {
+        let data = unsafe { &mut *phys_to_virt(frame.start()).as_mut_ptr::<TimeData>() };
+        data.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        f(data);
+        fence(Ordering::Release);
+        data.sequence.fetch_add(1, Ordering::Relaxed);
+    });
+}
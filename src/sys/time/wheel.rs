@@ -0,0 +1,202 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::Spinlock;
+
+/// The number of levels of the wheel, and the number of slots of each level. A slot of the level
+/// `n` covers `SLOTS^n` ticks, so the wheel can hold timers expiring up to `SLOTS^LEVELS` ticks in
+/// the future without scanning them on each tick.
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+
+/// The longest delay that can be represented by the wheel, in ticks. Timers expiring later are put
+/// in the last slot of the wheel, and are moved to their real slot when this slot is cascaded.
+const MAX_DELAY: u64 = (1 << shift(LEVELS)) - 1;
+
+/// Returns the position of the slot index of the given level in a tick number.
+#[allow(clippy::cast_possible_truncation)]
+const fn shift(level: usize) -> u32 {
+    SLOT_BITS * level as u32
+}
+
+/// The function called when a timer expires, with the data of the timer. It is called from the
+/// clock tick of the BSP, with interrupts disabled, so it must be short and must not sleep.
+pub type Callback = fn(data: u64);
+
+/// The global timer wheel.
+static WHEEL: Spinlock<Wheel> = Spinlock::new(Wheel::new());
+
+/// The identifier of the next timer created.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A coarse timer, with the resolution of a clock tick (see [`crate::config::KERNEL_HZ`]). It is
+/// meant for timeouts that do not need to be precise, like the sleeping threads or the watchdogs,
+/// and that are often cancelled before they expire: adding and removing a timer does not depend on
+/// the number of pending timers. Callers that need a better resolution should use an
+/// [`super::HrTimer`] instead.
+///
+/// A timer is inactive when created. It is activated with [`add_timer`] or [`mod_timer`], and is
+/// deactivated when it expires or when it is removed with [`del_timer`]. Dropping the handle does
+/// not remove the timer.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    id: u64,
+    callback: Callback,
+    data: u64,
+}
+
+impl Timer {
+    /// Create an inactive timer that will call `callback` with `data` when it expires.
+    #[must_use]
+    pub fn new(callback: Callback, data: u64) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            callback,
+            data,
+        }
+    }
+
+    /// Returns true if the timer is active and has not expired yet.
+    #[must_use]
+    pub fn pending(&self) -> bool {
+        x86_64::irq::without(|| WHEEL.lock().slots.contains_key(&self.id))
+    }
+}
+
+/// A timer in a slot of the wheel.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    timer: Timer,
+    expires: u64,
+}
+
+struct Wheel {
+    /// The next tick to be processed by [`run`].
+    next: u64,
+
+    /// The timers of each slot of each level. The timers of a slot are not sorted.
+    levels: [[Vec<Entry>; SLOTS]; LEVELS],
+
+    /// The level and the slot of each pending timer, indexed by the identifier of the timer.
+    slots: BTreeMap<u64, (usize, usize)>,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            next: 0,
+            levels: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+            slots: BTreeMap::new(),
+        }
+    }
+
+    /// Put a timer in the slot matching its expiration tick. The lower levels are used for the
+    /// timers expiring soon, and the higher levels, which are coarser, for the others. A timer whose
+    /// expiration tick is already passed is put in the slot processed on the next tick.
+    fn insert(&mut self, entry: Entry) {
+        let delay = entry.expires.saturating_sub(self.next).min(MAX_DELAY);
+        let expires = self.next + delay;
+        let level = (0..LEVELS)
+            .find(|&level| delay >> shift(level + 1) == 0)
+            .unwrap_or(LEVELS - 1);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let slot = ((expires >> shift(level)) & SLOT_MASK) as usize;
+        self.levels[level][slot].push(entry);
+        self.slots.insert(entry.timer.id, (level, slot));
+    }
+
+    /// Remove a timer from the wheel. Returns the timer if it was pending.
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        let (level, slot) = self.slots.remove(&id)?;
+        let timers = &mut self.levels[level][slot];
+        let index = timers.iter().position(|entry| entry.timer.id == id)?;
+        Some(timers.swap_remove(index))
+    }
+
+    /// Move all the timers of a slot to the lower levels. This is done when the ticks covered by
+    /// the slot are about to be processed.
+    fn cascade(&mut self, level: usize, slot: usize) {
+        for entry in core::mem::take(&mut self.levels[level][slot]) {
+            self.insert(entry);
+        }
+    }
+
+    /// Process the next tick: cascade the higher levels if needed, and take the timers expiring
+    /// during this tick.
+    fn advance(&mut self) -> Vec<Entry> {
+        #[allow(clippy::cast_possible_truncation)]
+        let index = (self.next & SLOT_MASK) as usize;
+        if index == 0 {
+            for level in 1..LEVELS {
+                #[allow(clippy::cast_possible_truncation)]
+                let slot = ((self.next >> shift(level)) & SLOT_MASK) as usize;
+                self.cascade(level, slot);
+                if slot != 0 {
+                    break;
+                }
+            }
+        }
+
+        let expired = core::mem::take(&mut self.levels[0][index]);
+        for entry in &expired {
+            self.slots.remove(&entry.timer.id);
+        }
+        self.next += 1;
+        expired
+    }
+}
+
+/// Activate a timer that will expire on the given clock tick (see [`super::jiffies`]).
+/// If the tick is already passed, the timer expires on the next clock tick.
+///
+/// # Panics
+/// Panics if the timer is already pending: use [`mod_timer`] to change the expiration of a pending
+/// timer.
+pub fn add_timer(timer: &Timer, expires: u64) {
+    assert!(!mod_timer(timer, expires), "Timer added twice");
+}
+
+/// Change the expiration tick of a timer, and activate it if it was inactive. Returns true if the
+/// timer was pending.
+#[must_use]
+pub fn mod_timer(timer: &Timer, expires: u64) -> bool {
+    x86_64::irq::without(|| {
+        let mut wheel = WHEEL.lock();
+        let pending = wheel.remove(timer.id).is_some();
+        wheel.insert(Entry {
+            timer: *timer,
+            expires,
+        });
+        pending
+    })
+}
+
+/// Deactivate a timer. Returns true if the timer was pending, or false if it has already expired
+/// (its callback may still be running) or was not active.
+#[must_use]
+pub fn del_timer(timer: &Timer) -> bool {
+    x86_64::irq::without(|| WHEEL.lock().remove(timer.id).is_some())
+}
+
+/// Run the callbacks of the timers expiring up to the given clock tick. This is called by the
+/// scheduler on each clock tick of the timekeeper (see [`crate::sched::tick`]), with interrupts
+/// disabled.
+/// The wheel is not locked while the callbacks run, so they can add or modify timers.
+pub fn run(now: u64) {
+    loop {
+        let expired = {
+            let mut wheel = WHEEL.lock();
+            if wheel.next > now {
+                break;
+            }
+            wheel.advance()
+        };
+        for entry in expired {
+            (entry.timer.callback)(entry.timer.data);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{
+    arch::paging::MapError,
+    mm::{space::SpaceError, user::UserAccessError, vmm::AllocationError},
+};
+
+/// An error returned by a system call. The values are the same as on Linux, and are returned
+/// negated to the user in the `rax` register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(isize)]
+pub enum Errno {
+    /// Operation not permitted
+    EPERM = 1,
+
+    /// No such file or directory
+    ENOENT = 2,
+
+    /// No such process
+    ESRCH = 3,
+
+    /// Interrupted system call
+    EINTR = 4,
+
+    /// I/O error
+    EIO = 5,
+
+    /// Bad file descriptor
+    EBADF = 9,
+
+    /// Resource temporarily unavailable
+    EAGAIN = 11,
+
+    /// Out of memory
+    ENOMEM = 12,
+
+    /// Bad address
+    EFAULT = 14,
+
+    /// Device or resource busy
+    EBUSY = 16,
+
+    /// File exists
+    EEXIST = 17,
+
+    /// Invalid argument
+    EINVAL = 22,
+
+    /// Too many open files
+    EMFILE = 24,
+
+    /// No space left on device
+    ENOSPC = 28,
+
+    /// Result too large
+    ERANGE = 34,
+
+    /// File name too long
+    ENAMETOOLONG = 36,
+
+    /// Function not implemented
+    ENOSYS = 38,
+}
+
+impl Errno {
+    /// Returns the value that must be returned to the user for this error, which is the negated
+    /// error number.
+    #[must_use]
+    pub const fn as_return(self) -> isize {
+        -(self as isize)
+    }
+}
+
+impl From<MapError> for Errno {
+    fn from(error: MapError) -> Self {
+        match error {
+            MapError::OutOfMemory => Errno::ENOMEM,
+            MapError::AlreadyMapped => Errno::EEXIST,
+        }
+    }
+}
+
+impl From<AllocationError> for Errno {
+    fn from(error: AllocationError) -> Self {
+        match error {
+            AllocationError::OutOfMemory => Errno::ENOMEM,
+            AllocationError::WouldBlock => Errno::EAGAIN,
+        }
+    }
+}
+
+impl From<SpaceError> for Errno {
+    fn from(error: SpaceError) -> Self {
+        match error {
+            SpaceError::InvalidRange => Errno::EINVAL,
+            SpaceError::NoSpace | SpaceError::NotMapped => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<UserAccessError> for Errno {
+    fn from(_: UserAccessError) -> Self {
+        Errno::EFAULT
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    mm::{frame::Allocator, user, FRAME_ALLOCATOR},
+    sched,
+    sys::time,
+};
+
+use super::{errno::Errno, validate};
+
+/// The size of each field of the `utsname` structure, including the null terminator.
+const UTSNAME_LENGTH: usize = 65;
+
+/// The number of fractional bits of the load averages returned by `sysinfo`.
+const SI_LOAD_SHIFT: u32 = 16;
+
+/// The `utsname` structure, as defined by Linux.
+#[repr(C)]
+struct Utsname {
+    sysname: [u8; UTSNAME_LENGTH],
+    nodename: [u8; UTSNAME_LENGTH],
+    release: [u8; UTSNAME_LENGTH],
+    version: [u8; UTSNAME_LENGTH],
+    machine: [u8; UTSNAME_LENGTH],
+    domainname: [u8; UTSNAME_LENGTH],
+}
+
+/// The `sysinfo` structure, as defined by Linux for 64-bit architectures. The implicit padding of
+/// the C structure is made explicit, so that no uninitialized byte is copied to the user space.
+#[repr(C)]
+#[derive(Default)]
+struct Sysinfo {
+    uptime: i64,
+    loads: [u64; 3],
+    totalram: u64,
+    freeram: u64,
+    sharedram: u64,
+    bufferram: u64,
+    totalswap: u64,
+    freeswap: u64,
+    procs: u16,
+    pad: u16,
+    pad2: u32,
+    totalhigh: u64,
+    freehigh: u64,
+    mem_unit: u32,
+    pad3: u32,
+}
+
+/// Copy the given string in a null-terminated `utsname` field, truncating it if needed.
+fn utsname_field(s: &str) -> [u8; UTSNAME_LENGTH] {
+    let mut field = [0; UTSNAME_LENGTH];
+    let len = core::cmp::min(s.len(), UTSNAME_LENGTH - 1);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+    field
+}
+
+/// Returns the bytes of the given structure, to copy it to the user space.
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            core::ptr::from_ref(value).cast::<u8>(),
+            core::mem::size_of::<T>(),
+        )
+    }
+}
+
+/// `uname(buf)`: Write the name, the version and the architecture of the kernel in the `utsname`
+/// structure pointed by `buf`. The version contains the hash of the commit the kernel was built
+/// from.
+///
+/// # Errors
+/// - [`Errno::EFAULT`]: The buffer is not entirely writable by the user.
+pub fn uname(args: &[u64; 6]) -> Result<usize, Errno> {
+    let buf = validate::user_pointer::<Utsname>(args[0])?;
+    let utsname = Utsname {
+        sysname: utsname_field("Silicium"),
+        nodename: utsname_field("silicium"),
+        release: utsname_field(env!("CARGO_PKG_VERSION")),
+        version: utsname_field(env!("SILICIUM_GIT_HASH")),
+        machine: utsname_field("x86_64"),
+        domainname: utsname_field("(none)"),
+    };
+
+    user::copy_to_user(buf, as_bytes(&utsname))?;
+    Ok(0)
+}
+
+/// `sysinfo(info)`: Write statistics about the system in the `sysinfo` structure pointed by
+/// `info`: the uptime, the load averages, the memory usage and the number of threads. There is no
+/// swap or shared memory, so the corresponding fields are always 0.
+///
+/// # Errors
+/// - [`Errno::EFAULT`]: The buffer is not entirely writable by the user.
+pub fn sysinfo(args: &[u64; 6]) -> Result<usize, Errno> {
+    let buf = validate::user_pointer::<Sysinfo>(args[0])?;
+    let stats = x86_64::irq::without(|| FRAME_ALLOCATOR.lock().statistics());
+    let loads = sched::load::averages().map(|load| load << (SI_LOAD_SHIFT - sched::load::FSHIFT));
+    let info = Sysinfo {
+        uptime: i64::try_from(time::uptime().as_secs()).unwrap_or(i64::MAX),
+        loads,
+        totalram: stats.usable as u64,
+        freeram: (stats.usable - stats.allocated) as u64,
+        procs: u16::try_from(sched::thread_count()).unwrap_or(u16::MAX),
+        mem_unit: 4096,
+        ..Default::default()
+    };
+
+    user::copy_to_user(buf, as_bytes(&info))?;
+    Ok(0)
+}
@@ -0,0 +1,33 @@
+use crate::mm::user;
+
+use super::{errno::Errno, validate};
+
+/// The size of the kernel buffer used to copy the user data.
+const CHUNK_SIZE: usize = 256;
+
+/// `write(fd, buf, count)`: Write `count` bytes from the user buffer `buf` to the file descriptor
+/// `fd`. There is no file system yet, so only the standard output and the standard error are
+/// supported, and both are written to the console (see [`crate::console`]). Invalid UTF-8
+/// sequences are replaced by the replacement character.
+///
+/// # Errors
+/// - [`Errno::EBADF`]: The file descriptor is not the standard output or the standard error.
+/// - [`Errno::EFAULT`]: The buffer is not entirely readable by the user.
+pub fn write(args: &[u64; 6]) -> Result<usize, Errno> {
+    let fd = validate::fd(args[0])?;
+    let (buf, count) = validate::user_buffer(args[1], args[2])?;
+    if fd != 1 && fd != 2 {
+        return Err(Errno::EBADF);
+    }
+
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut written = 0;
+    while written < count {
+        let len = core::cmp::min(CHUNK_SIZE, count - written);
+        user::copy_from_user(&mut chunk[..len], buf + written as u64)?;
+        crate::console::write(&alloc::string::String::from_utf8_lossy(&chunk[..len]));
+        written += len;
+    }
+
+    Ok(written)
+}
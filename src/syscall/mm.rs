@@ -0,0 +1,145 @@
+use alloc::sync::Arc;
+
+use crate::{
+    mm::{
+        self,
+        space::{Advice, Backing, Pager, Placement, Protection, Sharing},
+    },
+    sched,
+};
+
+use super::{errno::Errno, validate};
+
+/// Changes are shared with other mappings of the same object.
+const MAP_SHARED: u64 = 0x01;
+
+/// Changes are private to the process.
+const MAP_PRIVATE: u64 = 0x02;
+
+/// The mapping must be placed exactly at the given address.
+const MAP_FIXED: u64 = 0x10;
+
+/// The mapping is not backed by any file and is filled with zeros.
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// The pages will be accessed in the near future.
+const MADV_WILLNEED: u64 = 3;
+
+/// The pages will not be accessed in the near future.
+const MADV_DONTNEED: u64 = 4;
+
+/// The content of the pages can be freed.
+const MADV_FREE: u64 = 8;
+
+/// The pages can be merged with identical pages.
+const MADV_MERGEABLE: u64 = 12;
+
+/// The pages must no longer be merged.
+const MADV_UNMERGEABLE: u64 = 13;
+
+/// Returns the object backing the file referred by the given file descriptor.
+///
+/// TODO: There is no file descriptor table yet, so no file can be mapped. This will look up the
+/// file and return its page cache once the VFS exists.
+fn file_pager(fd: u64) -> Result<Arc<dyn Pager>, Errno> {
+    validate::fd(fd)?;
+    Err(Errno::EBADF)
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`: Create a new mapping of `len` bytes in the address
+/// space of the current process, and returns its address. File-backed mappings start at the given
+/// offset in the file referred by `fd`, which must be page aligned. Pages are only allocated
+/// or read from the file when they are first accessed.
+///
+/// # Errors
+/// - [`Errno::EINVAL`]: The length is zero, the offset is not page aligned, the flags do not
+///   contain exactly one of `MAP_SHARED` or `MAP_PRIVATE`, or the fixed address is invalid.
+/// - [`Errno::EBADF`]: The mapping is not anonymous and `fd` is not a valid file descriptor.
+/// - [`Errno::ENOMEM`]: There is no free range large enough for the mapping.
+pub fn mmap(args: &[u64; 6]) -> Result<usize, Errno> {
+    let [addr, len, prot, flags, fd, offset] = *args;
+    let space = sched::current_space().ok_or(Errno::EINVAL)?;
+
+    let protection = Protection::from_bits(prot).ok_or(Errno::EINVAL)?;
+    let sharing = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => Sharing::Shared,
+        MAP_PRIVATE => Sharing::Private,
+        _ => return Err(Errno::EINVAL),
+    };
+    let placement = if flags & MAP_FIXED != 0 {
+        Placement::Fixed(addr)
+    } else if addr != 0 {
+        Placement::Hint(addr)
+    } else {
+        Placement::Anywhere
+    };
+
+    let backing = if flags & MAP_ANONYMOUS == 0 {
+        if !offset.is_multiple_of(x86_64::paging::PAGE_SIZE as u64) {
+            return Err(Errno::EINVAL);
+        }
+        Some(Backing {
+            pager: file_pager(fd)?,
+            offset,
+        })
+    } else {
+        None
+    };
+
+    let start = space.map(placement, len, protection, sharing, backing)?;
+    Ok(usize::try_from(start).unwrap())
+}
+
+/// `munmap(addr, len)`: Remove the mappings in the given range of the address space of the
+/// current process. Modified pages of shared mappings are written back to their file.
+///
+/// # Errors
+/// - [`Errno::EINVAL`]: The range is not page aligned or not in the user address space.
+pub fn munmap(args: &[u64; 6]) -> Result<usize, Errno> {
+    let space = sched::current_space().ok_or(Errno::EINVAL)?;
+    space.unmap(args[0], args[1])?;
+    Ok(0)
+}
+
+/// `msync(addr, len, flags)`: Write back the modified pages of the shared mappings in the given
+/// range to their file. The write back is always synchronous, so the flags are ignored.
+///
+/// # Errors
+/// - [`Errno::EINVAL`]: The range is not page aligned or not in the user address space.
+pub fn msync(args: &[u64; 6]) -> Result<usize, Errno> {
+    let space = sched::current_space().ok_or(Errno::EINVAL)?;
+    space.sync(args[0], args[1])?;
+    Ok(0)
+}
+
+/// `madvise(addr, len, advice)`: Give a hint about the future use of the given range of the
+/// address space of the current process:
+/// - `MADV_DONTNEED`: The pages are released immediately.
+/// - `MADV_WILLNEED`: The pages are populated immediately.
+/// - `MADV_FREE`: The pages may be released later if they are not written again.
+/// - `MADV_MERGEABLE`: The pages may be merged with identical pages (see [`mm::merge`]).
+/// - `MADV_UNMERGEABLE`: The pages must no longer be merged.
+///
+/// # Errors
+/// - [`Errno::EINVAL`]: The advice is unknown, the range is not page aligned, or `MADV_FREE`,
+///   `MADV_MERGEABLE` or `MADV_UNMERGEABLE` is used on a mapping that is not private and
+///   anonymous.
+/// - [`Errno::ENOMEM`]: A part of the range is not mapped.
+pub fn madvise(args: &[u64; 6]) -> Result<usize, Errno> {
+    let space = sched::current_space().ok_or(Errno::EINVAL)?;
+    let advice = match args[2] {
+        MADV_WILLNEED => Advice::WillNeed,
+        MADV_DONTNEED => Advice::DontNeed,
+        MADV_FREE => Advice::Free,
+        MADV_MERGEABLE => Advice::Mergeable,
+        MADV_UNMERGEABLE => Advice::Unmergeable,
+        _ => return Err(Errno::EINVAL),
+    };
+    space.advise(args[0], args[1], advice)?;
+    match advice {
+        Advice::Mergeable => mm::merge::register(&space),
+        Advice::Free => mm::reclaim::register(&space),
+        _ => (),
+    }
+    Ok(0)
+}
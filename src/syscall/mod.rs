@@ -0,0 +1,61 @@
+use log::trace;
+
+use self::errno::Errno;
+
+pub mod errno;
+pub mod info;
+pub mod io;
+pub mod mm;
+pub mod process;
+pub mod stats;
+pub mod time;
+pub mod validate;
+
+/// The signature of a system call handler. The handler receives the six arguments of the system
+/// call, and returns a value on success or an error that will be returned negated to the user,
+/// like on Linux.
+pub type Handler = fn(&[u64; 6]) -> Result<usize, Errno>;
+
+/// The maximum number of system calls.
+pub const MAX_SYSCALL: usize = 512;
+
+/// The system call table. System calls use the same numbers as on Linux, to be able to run
+/// simple programs compiled for Linux without any modification.
+static TABLE: [Option<Handler>; MAX_SYSCALL] = {
+    let mut table: [Option<Handler>; MAX_SYSCALL] = [None; MAX_SYSCALL];
+    table[1] = Some(io::write);
+    table[9] = Some(mm::mmap);
+    table[11] = Some(mm::munmap);
+    table[26] = Some(mm::msync);
+    table[28] = Some(mm::madvise);
+    table[35] = Some(time::nanosleep);
+    table[39] = Some(process::getpid);
+    table[60] = Some(process::exit);
+    table[63] = Some(info::uname);
+    table[99] = Some(info::sysinfo);
+    table[186] = Some(process::gettid);
+    table
+};
+
+/// Execute the system call with the given number and arguments. Returns the value that must be
+/// returned to the user in the `rax` register.
+#[must_use]
+pub fn dispatch(number: u64, args: &[u64; 6]) -> isize {
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|number| TABLE.get(number).copied().flatten());
+
+    let Some(handler) = handler else {
+        trace!("Unknown system call {number}");
+        return Errno::ENOSYS.as_return();
+    };
+
+    let start = stats::timestamp();
+    let result = handler(args);
+    stats::record(number, start);
+
+    match result {
+        Ok(value) => isize::try_from(value).unwrap_or(isize::MAX),
+        Err(error) => error.as_return(),
+    }
+}
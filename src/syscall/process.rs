@@ -0,0 +1,37 @@
+use log::info;
+
+use crate::sched;
+
+use super::errno::Errno;
+
+/// `exit(code)`: Terminate the current thread with the given exit code.
+///
+/// # Errors
+/// This system call never fails, and never returns.
+#[allow(clippy::cast_possible_truncation)]
+pub fn exit(args: &[u64; 6]) -> Result<usize, Errno> {
+    // Like on Linux, only the lower 32 bits of the exit code are used
+    let code = args[0] as i32;
+    info!(
+        "Thread {} exited with code {}",
+        sched::current_tid().as_u64(),
+        code
+    );
+    sched::exit();
+}
+
+/// `getpid()`: Returns the process identifier of the current thread.
+///
+/// # Errors
+/// This system call never fails.
+pub fn getpid(_: &[u64; 6]) -> Result<usize, Errno> {
+    Ok(usize::try_from(sched::current_pid().as_u64()).unwrap())
+}
+
+/// `gettid()`: Returns the identifier of the current thread.
+///
+/// # Errors
+/// This system call never fails.
+pub fn gettid(_: &[u64; 6]) -> Result<usize, Errno> {
+    Ok(usize::try_from(sched::current_tid().as_u64()).unwrap())
+}
@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+
+use crate::{arch::smp, config::MAX_CPU};
+
+use super::MAX_SYSCALL;
+
+/// The number of spinlocks logged by [`dump_locks`].
+#[cfg(feature = "lockstat")]
+const DUMPED_LOCKS: usize = 16;
+
+/// The counters of a single system call on a single CPU. Each CPU only updates its own counters,
+/// so relaxed atomic operations are enough and there is no contention between CPUs.
+struct Counter {
+    calls: AtomicU64,
+    cycles: AtomicU64,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The statistics of a system call, merged from the counters of all CPUs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Stats {
+    /// The number of times the system call was invoked.
+    pub calls: u64,
+
+    /// The cumulative time spent in the system call, in TSC cycles.
+    pub cycles: u64,
+}
+
+static COUNTERS: [[Counter; MAX_SYSCALL]; MAX_CPU] =
+    [const { [const { Counter::new() }; MAX_SYSCALL] }; MAX_CPU];
+
+/// Returns the current value of the TSC, used to measure the time spent in a system call.
+#[must_use]
+pub fn timestamp() -> u64 {
+    crate::arch::tsc::rdtsc()
+}
+
+/// Record an invocation of the given system call that started at the `start` timestamp (see
+/// [`timestamp`]). Unknown system call numbers are ignored.
+pub fn record(number: u64, start: u64) {
+    let Some(counter) = usize::try_from(number)
+        .ok()
+        .and_then(|number| COUNTERS[smp::current_id() as usize].get(number))
+    else {
+        return;
+    };
+
+    let elapsed = timestamp().saturating_sub(start);
+    counter.calls.fetch_add(1, Ordering::Relaxed);
+    counter.cycles.fetch_add(elapsed, Ordering::Relaxed);
+}
+
+/// Returns the statistics of the given system call, merged from all CPUs.
+#[must_use]
+pub fn get(number: usize) -> Stats {
+    COUNTERS
+        .iter()
+        .filter_map(|cpu| cpu.get(number))
+        .fold(Stats::default(), |stats, counter| Stats {
+            calls: stats.calls + counter.calls.load(Ordering::Relaxed),
+            cycles: stats.cycles + counter.cycles.load(Ordering::Relaxed),
+        })
+}
+
+/// Log the statistics of all the system calls that were invoked at least once, and those of the
+/// spinlocks with the `lockstat` feature.
+pub fn dump() {
+    for number in 0..MAX_SYSCALL {
+        let stats = get(number);
+        if stats.calls > 0 {
+            info!(
+                "syscall {:3}: {} calls, {} cycles ({} cycles/call)",
+                number,
+                stats.calls,
+                stats.cycles,
+                stats.cycles / stats.calls
+            );
+        }
+    }
+
+    #[cfg(feature = "lockstat")]
+    dump_locks();
+}
+
+/// Log the places where the most contended spinlocks were created, sorted by the time spent
+/// waiting for them.
+#[cfg(feature = "lockstat")]
+pub fn dump_locks() {
+    let mut locks = alloc::vec::Vec::new();
+    sync::lockstat::for_each(|stats| locks.push(stats));
+    locks.sort_unstable_by_key(|stats| core::cmp::Reverse(stats.wait_cycles));
+    for stats in locks.iter().take(DUMPED_LOCKS) {
+        info!(
+            "lock {}: {} acquisitions, {} contended, {} cycles waiting",
+            stats.site, stats.acquisitions, stats.contentions, stats.wait_cycles
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use core::time::Duration;
+
+use crate::{mm::user, sched, sys::time};
+
+use super::{errno::Errno, validate};
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// `nanosleep(req, rem)`: Suspend the current thread for at least the duration given in the
+/// `timespec` structure pointed by `req`. The sleep is rounded up to the next clock tick.
+///
+/// Sleeping threads cannot be interrupted yet, so the remaining time is never written to `rem`.
+///
+/// # Errors
+/// - [`Errno::EFAULT`]: The `timespec` structure is not readable by the user.
+/// - [`Errno::EINVAL`]: The duration is negative or the nanoseconds are out of range.
+pub fn nanosleep(args: &[u64; 6]) -> Result<usize, Errno> {
+    let mut timespec = [0u8; 16];
+    let req = validate::user_pointer::<[i64; 2]>(args[0])?;
+    user::copy_from_user(&mut timespec, req)?;
+
+    let seconds = i64::from_ne_bytes(timespec[0..8].try_into().unwrap());
+    let nanoseconds = i64::from_ne_bytes(timespec[8..16].try_into().unwrap());
+    let seconds = u64::try_from(seconds).map_err(|_| Errno::EINVAL)?;
+    let nanoseconds = u32::try_from(nanoseconds).map_err(|_| Errno::EINVAL)?;
+    if u64::from(nanoseconds) >= NANOSECONDS_PER_SECOND {
+        return Err(Errno::EINVAL);
+    }
+
+    let ticks = time::duration_to_jiffies(Duration::new(seconds, nanoseconds));
+    if ticks > 0 {
+        sched::sleep(ticks);
+    }
+    Ok(0)
+}
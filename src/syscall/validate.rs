@@ -0,0 +1,75 @@
+use alloc::{string::String, vec::Vec};
+use x86_64::paging::PAGE_SIZE;
+
+use crate::mm::user;
+
+use super::errno::Errno;
+
+/// The maximum number of file descriptors that a process can open.
+pub const MAX_FD: usize = 256;
+
+/// The maximum length of a string copied from the user space, including the null terminator.
+pub const MAX_STRING_LENGTH: usize = 4096;
+
+/// Check that the user buffer starting at `addr` and of `len` bytes is entirely contained in the
+/// user address space. This does not check that the buffer is mapped: this will be detected when
+/// copying from or to the buffer.
+///
+/// # Errors
+/// - [`Errno::EFAULT`]: The buffer is not entirely contained in the user address space.
+pub fn user_buffer(addr: u64, len: u64) -> Result<(u64, usize), Errno> {
+    let len = usize::try_from(len).map_err(|_| Errno::EFAULT)?;
+    user::check_range(addr, len)?;
+    Ok((addr, len))
+}
+
+/// Check that the user pointer is a valid pointer to a `T` in the user address space. The pointer
+/// does not need to be aligned, since the structure is always copied byte by byte.
+///
+/// # Errors
+/// - [`Errno::EFAULT`]: The pointer is not in the user address space.
+pub fn user_pointer<T>(addr: u64) -> Result<u64, Errno> {
+    user::check_range(addr, core::mem::size_of::<T>())?;
+    Ok(addr)
+}
+
+/// Copy a null-terminated string from the user space. The string is copied page by page, so that
+/// a string ending just before an unmapped page is correctly copied. Invalid UTF-8 sequences are
+/// rejected.
+///
+/// # Errors
+/// - [`Errno::EFAULT`]: The string is not entirely readable by the user.
+/// - [`Errno::ENAMETOOLONG`]: The string is longer than [`MAX_STRING_LENGTH`] bytes.
+/// - [`Errno::EINVAL`]: The string is not valid UTF-8.
+pub fn user_string(addr: u64) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 256];
+    let mut current = addr;
+
+    while bytes.len() < MAX_STRING_LENGTH {
+        // Never cross a page boundary in a single copy
+        let page_remaining = PAGE_SIZE as u64 - (current % PAGE_SIZE as u64);
+        let len = core::cmp::min(chunk.len(), usize::try_from(page_remaining).unwrap());
+        user::copy_from_user(&mut chunk[..len], current)?;
+
+        if let Some(end) = chunk[..len].iter().position(|&c| c == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+        }
+        bytes.extend_from_slice(&chunk[..len]);
+        current += len as u64;
+    }
+
+    Err(Errno::ENAMETOOLONG)
+}
+
+/// Check that the given file descriptor is in the valid range.
+///
+/// # Errors
+/// - [`Errno::EBADF`]: The file descriptor is negative or greater than [`MAX_FD`].
+pub fn fd(fd: u64) -> Result<usize, Errno> {
+    usize::try_from(fd)
+        .ok()
+        .filter(|&fd| fd < MAX_FD)
+        .ok_or(Errno::EBADF)
+}
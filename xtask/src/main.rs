@@ -0,0 +1,365 @@
+use std::{
+    env, fs,
+    path::Path,
+    process::{Command, ExitCode, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The QEMU exit statuses produced by the `isa-debug-exit` device when the kernel exits with
+/// `arch::qemu::ExitCode::Success` and `arch::qemu::ExitCode::Failure`.
+const EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+const EXIT_FAILURE: i32 = (0x11 << 1) | 1;
+
+/// The prefix of the lines written on the serial port by the in-kernel self tests.
+const PROTOCOL_PREFIX: &str = "selftest: ";
+
+/// The default time allowed to a configuration to boot and run the self tests.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Accel {
+    Tcg,
+    Kvm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Irqchip {
+    /// The IOAPIC is emulated by KVM, or by QEMU itself with TCG.
+    Kernel,
+
+    /// The IOAPIC is emulated by QEMU while the LAPICs are emulated by KVM. QEMU cannot remove
+    /// the IOAPIC of a PC machine, so this is the closest thing to a machine without one.
+    Split,
+}
+
+/// A QEMU configuration the kernel is booted on.
+struct Config {
+    name: &'static str,
+    accel: Accel,
+    cpus: u32,
+    memory: u32,
+    hpet: bool,
+    irqchip: Irqchip,
+}
+
+const CONFIGS: &[Config] = &[
+    Config {
+        name: "tcg-1cpu",
+        accel: Accel::Tcg,
+        cpus: 1,
+        memory: 128,
+        hpet: true,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "tcg-2cpu",
+        accel: Accel::Tcg,
+        cpus: 2,
+        memory: 256,
+        hpet: true,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "tcg-8cpu",
+        accel: Accel::Tcg,
+        cpus: 8,
+        memory: 512,
+        hpet: true,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "tcg-8cpu-2g-nohpet",
+        accel: Accel::Tcg,
+        cpus: 8,
+        memory: 2048,
+        hpet: false,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "kvm-1cpu",
+        accel: Accel::Kvm,
+        cpus: 1,
+        memory: 128,
+        hpet: true,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "kvm-2cpu-nohpet",
+        accel: Accel::Kvm,
+        cpus: 2,
+        memory: 512,
+        hpet: false,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "kvm-8cpu-2g",
+        accel: Accel::Kvm,
+        cpus: 8,
+        memory: 2048,
+        hpet: true,
+        irqchip: Irqchip::Kernel,
+    },
+    Config {
+        name: "kvm-8cpu-split-irqchip",
+        accel: Accel::Kvm,
+        cpus: 8,
+        memory: 512,
+        hpet: true,
+        irqchip: Irqchip::Split,
+    },
+];
+
+/// The result of a configuration.
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skipped(&'static str),
+}
+
+struct Options {
+    release: bool,
+    only: Vec<String>,
+    timeout: Duration,
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    if args.next().as_deref() != Some("test-matrix") {
+        usage();
+        return ExitCode::FAILURE;
+    }
+
+    match parse_options(args) {
+        Ok(options) => test_matrix(&options),
+        Err(error) => {
+            eprintln!("error: {error}");
+            usage();
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() {
+    eprintln!("usage: cargo xtask test-matrix [--release] [--timeout <seconds>] [<config>...]");
+    eprintln!();
+    eprintln!("Boot the kernel built with the `selftest` feature under each QEMU configuration");
+    eprintln!("and report the result of the self tests. Available configurations:");
+    for config in CONFIGS {
+        eprintln!("    {}", config.name);
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        release: false,
+        only: Vec::new(),
+        timeout: DEFAULT_TIMEOUT,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--release" => options.release = true,
+            "--timeout" => {
+                let seconds = args
+                    .next()
+                    .and_then(|seconds| seconds.parse().ok())
+                    .ok_or("--timeout expects a number of seconds")?;
+                options.timeout = Duration::from_secs(seconds);
+            }
+            name if CONFIGS.iter().any(|config| config.name == name) => {
+                options.only.push(arg);
+            }
+            _ => return Err(format!("unknown argument or configuration `{arg}`")),
+        }
+    }
+    Ok(options)
+}
+
+fn test_matrix(options: &Options) -> ExitCode {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    let logs = root.join("target").join("xtask");
+
+    if let Err(error) = build(&root, options.release) {
+        eprintln!("error: {error}");
+        return ExitCode::FAILURE;
+    }
+    fs::create_dir_all(&logs).expect("Failed to create the log directory");
+
+    let kvm = Path::new("/dev/kvm").exists();
+    let mut results = Vec::new();
+    for config in CONFIGS
+        .iter()
+        .filter(|config| options.only.is_empty() || options.only.iter().any(|n| n == config.name))
+    {
+        let outcome = if config.accel == Accel::Kvm && !kvm {
+            Outcome::Skipped("KVM is not available")
+        } else {
+            println!("Running {}...", config.name);
+            let log = logs.join(format!("{}.log", config.name));
+            run(&root, config, &log, options.timeout)
+        };
+        results.push((config, outcome));
+    }
+
+    println!();
+    let mut failed = 0;
+    for (config, outcome) in &results {
+        match outcome {
+            Outcome::Pass => println!("{:<24} pass", config.name),
+            Outcome::Skipped(reason) => println!("{:<24} skipped ({reason})", config.name),
+            Outcome::Fail(reason) => {
+                println!("{:<24} FAIL ({reason})", config.name);
+                failed += 1;
+            }
+        }
+    }
+    println!();
+    println!(
+        "{} configurations, {failed} failed, serial logs in {}",
+        results.len(),
+        logs.display()
+    );
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Build the kernel with the self tests and put it in the bootable ISO.
+fn build(root: &Path, release: bool) -> Result<(), String> {
+    let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| String::from("cargo")));
+    cargo
+        .current_dir(root)
+        .args(["build", "--features", "selftest"]);
+    if release {
+        cargo.arg("--release");
+    }
+    check(cargo.status(), "cargo build")?;
+
+    let profile = if release { "release" } else { "debug" };
+    let kernel = root
+        .join("target")
+        .join("x86_64")
+        .join(profile)
+        .join("silicium");
+    let status = Command::new("./scripts/build_iso.sh")
+        .current_dir(root)
+        .arg(kernel)
+        .stdout(Stdio::null())
+        .status();
+    check(status, "build_iso.sh")
+}
+
+fn check(status: std::io::Result<std::process::ExitStatus>, what: &str) -> Result<(), String> {
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{what} failed with {status}")),
+        Err(error) => Err(format!("failed to run {what}: {error}")),
+    }
+}
+
+/// Boot the kernel under the given configuration and wait for it to exit through the
+/// `isa-debug-exit` device, then check the results written on the serial port.
+fn run(root: &Path, config: &Config, log: &Path, timeout: Duration) -> Outcome {
+    let mut machine = format!("pc,hpet={}", if config.hpet { "on" } else { "off" });
+    match config.accel {
+        Accel::Tcg => machine.push_str(",accel=tcg"),
+        Accel::Kvm => machine.push_str(",accel=kvm"),
+    }
+    if config.irqchip == Irqchip::Split {
+        machine.push_str(",kernel-irqchip=split");
+    }
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.current_dir(root)
+        .args(["-machine", &machine])
+        .args(["-m", &config.memory.to_string()])
+        .args(["-smp", &config.cpus.to_string()])
+        .args(["-drive", "format=raw,media=cdrom,file=bin/silicium.iso"])
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+        .args(["-serial", &format!("file:{}", log.display())])
+        .args(["-display", "none"])
+        .arg("-no-reboot")
+        .stdout(Stdio::null());
+    if config.accel == Accel::Kvm {
+        qemu.args(["-cpu", "host"]);
+    }
+
+    let mut child = match qemu.spawn() {
+        Ok(child) => child,
+        Err(error) => return Outcome::Fail(format!("failed to start QEMU: {error}")),
+    };
+
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if start.elapsed() > timeout => {
+                _ = child.kill();
+                _ = child.wait();
+                return Outcome::Fail(format!("timed out after {}s", timeout.as_secs()));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(100)),
+            Err(error) => return Outcome::Fail(format!("failed to wait for QEMU: {error}")),
+        }
+    };
+
+    let output = fs::read_to_string(log).unwrap_or_default();
+    let report = parse(&output);
+    match status.code() {
+        Some(EXIT_SUCCESS) => check_report(config, &report),
+        Some(EXIT_FAILURE) => Outcome::Fail(format!("failed tests: {}", report.failed.join(", "))),
+        Some(0) => Outcome::Fail(String::from("the kernel crashed or rebooted")),
+        Some(code) => Outcome::Fail(format!("QEMU exited with status {code}")),
+        None => Outcome::Fail(String::from("QEMU was killed by a signal")),
+    }
+}
+
+/// The results of the self tests, as written on the serial port.
+#[derive(Default)]
+struct Report {
+    failed: Vec<String>,
+    cpus: Option<u32>,
+    passed: bool,
+}
+
+fn parse(output: &str) -> Report {
+    let mut report = Report::default();
+    for line in output.lines() {
+        let Some((_, message)) = line.split_once(PROTOCOL_PREFIX) else {
+            continue;
+        };
+
+        let fields = message
+            .split_whitespace()
+            .filter_map(|field| field.split_once('='))
+            .collect::<Vec<_>>();
+        let field = |key: &str| fields.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+
+        match (field("name"), field("result"), field("cpus")) {
+            (Some(name), Some("fail"), _) => report.failed.push(String::from(name)),
+            (None, Some("pass"), _) => report.passed = true,
+            (_, _, Some(cpus)) => report.cpus = cpus.parse().ok(),
+            _ => (),
+        }
+    }
+    report
+}
+
+/// Check that the kernel reported a success and saw the machine it was booted on.
+fn check_report(config: &Config, report: &Report) -> Outcome {
+    if !report.passed {
+        return Outcome::Fail(String::from("no result on the serial port"));
+    }
+    match report.cpus {
+        Some(cpus) if cpus == config.cpus => Outcome::Pass,
+        Some(cpus) => Outcome::Fail(format!("{cpus} CPUs started, {} expected", config.cpus)),
+        None => Outcome::Fail(String::from("the CPU count was not reported")),
+    }
+}